@@ -4,26 +4,30 @@ pub mod ops;
 
 mod current_stream_state;
 mod tcp_connect;
+mod reconnect;
 
 use std::error::Error;
 use std::collections::VecDeque;
 use std::io;
 use std::fmt::{self, Debug};
+use std::time::Duration;
 
 use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
 #[allow(deprecated)]
 use tokio_core::io::Io;
 use futures::{Stream, Sink};
 
-use protocol::{ProtocolMessage, ErrorMessage};
+use protocol::{ProtocolMessage, ErrorMessage, Capabilities};
 use event::{FloEventId, ActorId, VersionVector, OwnedFloEvent};
 use codec::EventCodec;
 use self::recv::MessageRecvStream;
 use self::send::MessageSendSink;
-use self::ops::{ProduceOne, ProduceAll, EventToProduce, Consume, Handshake};
+use self::ops::{ProduceOne, ProduceAll, ProduceBatch, EventToProduce, Consume, Handshake, GetCapabilities, GetStreamStatus, GetConsumerLag, AwaitStreamPosition, ProduceAndAwaitReply};
 
 
 pub use self::tcp_connect::{tcp_connect, tcp_connect_with, AsyncTcpClientConnect};
+pub use self::reconnect::{ReconnectingConnection, ReconnectOptions, ReconnectError, ReconnectingConnect, ReconnectingProduce, ReconnectingConsume, Connector};
 pub use self::current_stream_state::{CurrentStreamState, PartitionState};
 
 pub type ClientProtocolMessage = ProtocolMessage<OwnedFloEvent>;
@@ -75,6 +79,7 @@ impl <D: Debug> AsyncConnection<D> {
             recv: Some(recv),
             codec: codec,
             current_stream: None,
+            server_capabilities: None,
             current_op_id: 0,
             received_message_buffer: VecDeque::with_capacity(8),
         };
@@ -112,6 +117,14 @@ impl <D: Debug> AsyncConnection<D> {
         ProduceAll::new(self, events)
     }
 
+    /// Produce all of the given events without waiting for each one to be acknowledged before sending the next. Returns a
+    /// future that resolves to the result of producing each event, in the same order as `events`, along with this connection.
+    /// Unlike `produce_all`, an error producing one event does not stop the rest from being produced. See `ProduceBatch`
+    /// for details.
+    pub fn produce_batch(self, events: Vec<EventToProduce<D>>) -> ProduceBatch<D> {
+        ProduceBatch::new(self, events)
+    }
+
     /// Start consuming events from the server. Returns a `Stream` that yields events continuously until the `event_limit` is reached.
     /// If `event_limit` is `None`, then the resulting `Stream` will never terminate unless there's an error.
     /// The `version_vector` represents the exclusive starting `EventCounter` for each partition on the stream that the consumer
@@ -120,6 +133,29 @@ impl <D: Debug> AsyncConnection<D> {
         Consume::new(self, namespace.into(), version_vector, event_limit, await_new)
     }
 
+    /// Start consuming only the events matching `namespace` that are produced from now on, stopping after `max_events` if
+    /// it's `Some`. The server starts the cursor after the newest event in each partition, so no event that's produced after
+    /// the cursor starts can be missed. Requires the `protocol::features::START_AT_TAIL` feature.
+    pub fn consume_from_tail<N: Into<String>>(self, namespace: N, max_events: Option<u64>) -> Consume<D> {
+        Consume::from_tail(self, namespace.into(), max_events)
+    }
+
+    /// Waits until the current stream has reached the given event id, meaning that an event with at least the target's
+    /// counter has been persisted to the target's partition. This works regardless of which producer the event came from,
+    /// so it can be used to coordinate with other producers. The returned `Future` resolves to this connection. See
+    /// `AwaitStreamPosition` for how targets that can never be reached are handled.
+    pub fn await_stream_position(self, target: FloEventId) -> AwaitStreamPosition<D> {
+        AwaitStreamPosition::new(self, target)
+    }
+
+    /// Produces the `request` event and then waits for another event in `reply_namespace` whose `parent_id` is the id of the
+    /// request, for implementing request/response on top of the event stream. The returned `Future` resolves to the first
+    /// reply along with this connection, or fails with a `TimedOut` error if no reply is received within `timeout` of the
+    /// request being acknowledged. See `ProduceAndAwaitReply` for details.
+    pub fn produce_and_await_reply<N: Into<String>>(self, request: EventToProduce<D>, reply_namespace: N, timeout: Duration, handle: &Handle) -> ProduceAndAwaitReply<D> {
+        ProduceAndAwaitReply::new(self, request, reply_namespace.into(), timeout, handle)
+    }
+
     /// Initiates the handshake with the server. The returned `Future` resolves the this connection, which will then be guaranteed
     /// to have the `current_stream()` return `Some`.
     pub fn connect(self) -> Handshake<D> {
@@ -134,6 +170,33 @@ impl <D: Debug> AsyncConnection<D> {
         Handshake::new(self)
     }
 
+    /// Asks the server which optional features it supports. The returned `Future` resolves to this connection, after which
+    /// `supports_feature` will reflect the server's answer.
+    pub fn get_capabilities(self) -> GetCapabilities<D> {
+        GetCapabilities::new(self)
+    }
+
+    /// Asks the server for the current status of the named event stream, including the head of each partition. The returned
+    /// `Future` resolves to the status along with this connection, and doesn't change the stream that this connection uses.
+    /// Requires the `protocol::features::STREAM_STATUS` feature.
+    pub fn stream_status<N: Into<String>>(self, stream_name: N) -> GetStreamStatus<D> {
+        GetStreamStatus::new(self, stream_name.into())
+    }
+
+    /// Asks the server how many events matching the namespace are newer than the given version vector, which is how far
+    /// behind the head of the current stream a consumer at that position is. The namespace may be any glob, and the counts
+    /// from every partition are summed. Requires the `protocol::features::CONSUMER_LAG` feature.
+    pub fn consumer_lag<N: Into<String>>(self, namespace: N, version_vector: &VersionVector) -> GetConsumerLag<D> {
+        GetConsumerLag::new(self, namespace.into(), version_vector)
+    }
+
+    /// Returns true if the server has advertised support for all of the given `protocol::features` flags. This always
+    /// returns false until `get_capabilities` has completed, so it's safe to use against older servers that don't know
+    /// about capabilities at all.
+    pub fn supports_feature(&self, feature_flags: u64) -> bool {
+        self.inner.server_capabilities.as_ref().map(|caps| caps.supports(feature_flags)).unwrap_or(false)
+    }
+
     fn take_sender(&mut self) -> MessageSender {
         self.inner.send.take().unwrap()
     }
@@ -151,9 +214,17 @@ impl <D: Debug> AsyncConnection<D> {
         self.inner.received_message_buffer.push_back(message);
     }
 
+    /// Op ids wrap around after `u32::MAX`. `0` is always skipped, since it's what `get_op_id` returns for messages that
+    /// don't have one. Ids of any responses that are still buffered are skipped too, so that a stale response can't be
+    /// mistaken for the response to a new operation
     fn next_op_id(&mut self) -> u32 {
-        self.inner.current_op_id += 1;
-        self.inner.current_op_id
+        loop {
+            self.inner.current_op_id = self.inner.current_op_id.wrapping_add(1);
+            let op_id = self.inner.current_op_id;
+            if op_id != 0 && !self.inner.received_message_buffer.iter().any(|message| message.get_op_id() == op_id) {
+                return op_id;
+            }
+        }
     }
 }
 
@@ -195,6 +266,7 @@ struct AsyncConnectionInner<D: Debug> {
     recv: Option<MessageReceiver>,
     codec: Box<EventCodec<EventData=D>>,
     current_stream: Option<CurrentStreamState>,
+    server_capabilities: Option<Capabilities>,
     current_op_id: u32,
     received_message_buffer: VecDeque<ClientProtocolMessage>,
 }
@@ -384,6 +456,9 @@ mod test {
                 partition: 1,
                 namespace: "/foo".to_owned(),
                 parent_id: None,
+                ttl: None,
+                compression: Compression::None,
+                trace: false,
                 data: Vec::new(),
             }),
             ProtocolMessage::ProduceEvent(ProduceEvent {
@@ -391,6 +466,9 @@ mod test {
                 partition: 2,
                 namespace: "/bar".to_owned(),
                 parent_id: None,
+                ttl: None,
+                compression: Compression::None,
+                trace: false,
                 data: Vec::new(),
             }),
             ProtocolMessage::ProduceEvent(ProduceEvent {
@@ -398,6 +476,9 @@ mod test {
                 partition: 3,
                 namespace: "/baz".to_owned(),
                 parent_id: None,
+                ttl: None,
+                compression: Compression::None,
+                trace: false,
                 data: Vec::new(),
             })
         ];
@@ -454,6 +535,59 @@ mod test {
         assert_eq!(expected_ids, result.events_produced);
     }
 
+    #[test]
+    fn produce_batch_sends_every_event_and_returns_a_result_for_each_in_order() {
+        let produce = |op_id: u32, namespace: &str| {
+            ProtocolMessage::ProduceEvent(ProduceEvent {
+                op_id: op_id,
+                partition: 1,
+                namespace: namespace.to_owned(),
+                parent_id: None,
+                ttl: None,
+                compression: Compression::None,
+                trace: false,
+                data: Vec::new(),
+            })
+        };
+        let expected_sent = vec![produce(1, "/foo"), produce(2, "/bar"), produce(3, "/baz")];
+        let error = ErrorMessage {
+            op_id: 2,
+            kind: ErrorKind::InvalidEventId,
+            description: "no such parent".to_owned(),
+        };
+        let to_recv = vec![
+            ProtocolMessage::AckEvent(EventAck{
+                op_id: 1,
+                event_id: FloEventId::new(1, 1),
+            }),
+            ProtocolMessage::Error(error.clone()),
+            ProtocolMessage::AckEvent(EventAck{
+                op_id: 3,
+                event_id: FloEventId::new(1, 2),
+            }),
+        ];
+        let events_to_produce = vec![
+            EventToProduce::witout_parent(1, "/foo", String::new()),
+            EventToProduce::witout_parent(1, "/bar", String::new()),
+            EventToProduce::witout_parent(1, "/baz", String::new()),
+        ];
+
+        let recv = MockReceiveStream::will_produce(to_recv);
+        let (send, mut send_verify) = MockSendStream::new();
+        let connection = create_client(recv, send);
+
+        let (results, _connection) = run_future(connection.produce_batch(events_to_produce)).expect("failed to run produce_batch");
+        assert_eq!(expected_sent, send_verify.get_received());
+
+        assert_eq!(3, results.len());
+        assert_eq!(FloEventId::new(1, 1), *results[0].as_ref().unwrap());
+        match results[1] {
+            Err(ErrorType::Server(ref err)) => assert_eq!(error, *err),
+            ref other @ _ => panic!("expected server error, got: {:?}", other),
+        }
+        assert_eq!(FloEventId::new(1, 2), *results[2].as_ref().unwrap());
+    }
+
     #[test]
     fn produce_all_returns_immediate_success_when_iterator_is_empty() {
         let recv = MockReceiveStream::empty();
@@ -519,6 +653,90 @@ mod test {
         assert_eq!(Some(&expected_stream), connection.current_stream());
     }
 
+    #[test]
+    fn stream_status_resolves_to_status_of_named_stream_without_changing_current_stream() {
+        let to_recv = vec![ProtocolMessage::StreamStatus(EventStreamStatus {
+            op_id: 1,
+            name: "bar".to_owned(),
+            partitions: vec![
+                PartitionStatus {
+                    partition_num: 1,
+                    head: 12,
+                    primary: true
+                },
+            ],
+        })];
+        let recv = MockReceiveStream::will_produce(to_recv);
+        let (send, mut send_verify) = MockSendStream::new();
+        let connection = create_client(recv, send);
+
+        let (status, connection) = run_future(connection.stream_status("bar")).expect("failed to get stream status");
+
+        let expected_request = ProtocolMessage::GetStreamStatus(::protocol::GetStreamStatus {
+            op_id: 1,
+            name: "bar".to_owned(),
+        });
+        assert_eq!(vec![expected_request], send_verify.get_received());
+        let expected_status = CurrentStreamState {
+            name: "bar".to_owned(),
+            partitions: vec![
+                PartitionState {
+                    partition_num: 1,
+                    head: 12,
+                    writable: true,
+                },
+            ]
+        };
+        assert_eq!(expected_status, status);
+        assert!(connection.current_stream().is_none());
+    }
+
+    #[test]
+    fn consumer_lag_resolves_to_count_of_events_after_version_vector() {
+        use event::{VersionVector, FloEventId};
+
+        let to_recv = vec![ProtocolMessage::CountResult(::protocol::CountResult {
+            op_id: 1,
+            count: 42,
+        })];
+        let recv = MockReceiveStream::will_produce(to_recv);
+        let (send, mut send_verify) = MockSendStream::new();
+        let connection = create_client(recv, send);
+
+        let mut version_vec = VersionVector::new();
+        version_vec.set(FloEventId::new(1, 5));
+        version_vec.set(FloEventId::new(2, 9));
+        let (lag, _connection) = run_future(connection.consumer_lag("/foo/*", &version_vec)).expect("failed to get consumer lag");
+
+        let expected_request = ProtocolMessage::GetConsumerLag(::protocol::GetConsumerLag {
+            op_id: 1,
+            namespace: "/foo/*".to_owned(),
+            version_vector: vec![FloEventId::new(1, 5), FloEventId::new(2, 9)],
+        });
+        assert_eq!(vec![expected_request], send_verify.get_received());
+        assert_eq!(42, lag);
+    }
+
+    #[test]
+    fn supports_feature_returns_false_for_features_the_server_did_not_advertise() {
+        let to_recv = vec![ProtocolMessage::Capabilities(Capabilities {
+            op_id: 1,
+            flags: features::EVENT_TTL,
+            features: vec!["event_ttl".to_owned()],
+        })];
+        let recv = MockReceiveStream::will_produce(to_recv);
+        let (send, mut send_verify) = MockSendStream::new();
+        let connection = create_client(recv, send);
+        assert!(!connection.supports_feature(features::EVENT_TTL));
+
+        let connection = run_future(connection.get_capabilities()).expect("failed to get capabilities");
+
+        assert_eq!(vec![ProtocolMessage::GetCapabilities(1)], send_verify.get_received());
+        assert!(connection.supports_feature(features::EVENT_TTL));
+        assert!(!connection.supports_feature(features::BODY_PREFIX));
+        assert!(!connection.supports_feature(features::EVENT_TTL | features::BODY_PREFIX));
+    }
+
     #[test]
     fn send_sends_all_messages() {
         let recv = MockReceiveStream::empty();
@@ -555,6 +773,20 @@ mod test {
         assert_eq!(expected_buffer, actual_buffer);
     }
 
+    #[test]
+    fn next_op_id_wraps_around_and_skips_zero_and_ids_of_buffered_responses() {
+        let recv = MockReceiveStream::will_produce(Vec::new());
+        let (send, _send_verify) = MockSendStream::new();
+        let mut connection = create_client(recv, send);
+
+        connection.inner.current_op_id = u32::max_value() - 1;
+        connection.buffer_received(ProtocolMessage::AckEvent(EventAck { op_id: 1, event_id: FloEventId::new(1, 9) }));
+
+        assert_eq!(u32::max_value(), connection.next_op_id());
+        assert_eq!(2, connection.next_op_id());
+        assert_eq!(3, connection.next_op_id());
+    }
+
     #[test]
     fn consume_yields_stream_of_events() {
         use protocol::CursorInfo;
@@ -604,6 +836,7 @@ mod test {
                 version_vector: vec![FloEventId::new(1, 2), FloEventId::new(2, 8), FloEventId::new(3, 4)],
                 max_events: 2,
                 namespace: "/foo/*".to_owned(),
+                ..Default::default()
             }),
             ProtocolMessage::NextBatch,
         ];
@@ -627,4 +860,385 @@ mod test {
         ];
         assert_eq!(expected, results);
     }
+
+    #[test]
+    fn batched_consume_yields_one_vec_per_server_batch() {
+        use protocol::CursorInfo;
+        use event::{OwnedFloEvent, VersionVector, FloEventId, time};
+
+        fn event(counter: u64) -> ClientProtocolMessage {
+            ProtocolMessage::ReceiveEvent(OwnedFloEvent {
+                id: FloEventId::new(1, counter),
+                timestamp: time::from_millis_since_epoch(8),
+                parent_id: None,
+                namespace: "/foo".to_owned(),
+                data: format!("event {}", counter).into_bytes(),
+            })
+        }
+
+        let to_receive = || vec![
+            ProtocolMessage::CursorCreated(CursorInfo{ op_id: 1, batch_size: 2 }),
+            event(1),
+            event(2),
+            ProtocolMessage::EndOfBatch,
+            event(3),
+            ProtocolMessage::AwaitingEvents,
+        ];
+        let receiver = MockReceiveStream::will_produce(to_receive());
+        let (sender, _send_verify) = MockSendStream::new();
+        let connection = create_client(receiver, sender);
+
+        let batches = connection.consume("/**/*", &VersionVector::new(), None, false).batched(None);
+        let results = get_stream_results(batches).into_iter().map(|batch| {
+            batch.into_iter().map(|event| event.data).collect::<Vec<_>>()
+        }).collect::<Vec<_>>();
+
+        let expected = vec![
+            vec!["event 1".to_owned(), "event 2".to_owned()],
+            vec!["event 3".to_owned()],
+        ];
+        assert_eq!(expected, results);
+
+        // a max batch size of 0 can't be satisfied, so each batch gets one event instead
+        let receiver = MockReceiveStream::will_produce(to_receive());
+        let (sender, _send_verify) = MockSendStream::new();
+        let connection = create_client(receiver, sender);
+        let batches = connection.consume("/**/*", &VersionVector::new(), None, false).batched(Some(0));
+        let sizes = get_stream_results(batches).into_iter().map(|batch| batch.len()).collect::<Vec<_>>();
+        assert_eq!(vec![1, 1, 1], sizes);
+    }
+
+    #[test]
+    fn multi_codec_connection_consumes_namespaces_with_different_codecs() {
+        use event::{OwnedFloEvent, VersionVector, FloEventId, time};
+        use codec::{MultiCodec, MultiCodecError, RawCodec};
+
+        fn event(counter: u64, namespace: &str, data: Vec<u8>) -> ClientProtocolMessage {
+            ProtocolMessage::ReceiveEvent(OwnedFloEvent {
+                id: FloEventId::new(1, counter),
+                timestamp: time::from_millis_since_epoch(8),
+                parent_id: None,
+                namespace: namespace.to_owned(),
+                data: data,
+            })
+        }
+
+        let to_receive = vec![
+            ProtocolMessage::CursorCreated(CursorInfo{ op_id: 1, batch_size: 10 }),
+            event(1, "/strings/foo", b"hello".to_vec()),
+            event(2, "/bytes/bar", vec![1, 2, 3]),
+            event(3, "/unknown", vec![4]),
+            event(4, "/strings/foo", b"world".to_vec()),
+            ProtocolMessage::AwaitingEvents,
+        ];
+        let receiver = MockReceiveStream::will_produce(to_receive);
+        let (sender, _send_verify) = MockSendStream::new();
+        let codec = MultiCodec::new()
+                .with_codec("/strings", StringCodec)
+                .with_codec("/bytes", RawCodec);
+        let connection = AsyncConnection::new("multiCodecClient".to_owned(), sender, receiver, Box::new(codec));
+
+        let mut results = get_stream_results(connection.consume("/**/*", &VersionVector::new(), None, false));
+        assert_eq!(4, results.len());
+
+        let fourth = results.pop().unwrap().data.unwrap();
+        assert_eq!("world", fourth.downcast_ref::<String>().unwrap());
+        let third = results.pop().unwrap().data;
+        assert_eq!(Some(MultiCodecError::UnmappedNamespace("/unknown".to_owned())), third.err());
+        let second = results.pop().unwrap().data.unwrap();
+        assert_eq!(&vec![1u8, 2, 3], second.downcast_ref::<Vec<u8>>().unwrap());
+        let first = results.pop().unwrap().data.unwrap();
+        assert_eq!("hello", first.downcast_ref::<String>().unwrap());
+    }
+
+    fn consume_until_stopped(to_receive: Vec<ClientProtocolMessage>, event_limit: Option<u64>, await_new: bool) -> (usize, Option<StopResult>) {
+        use event::VersionVector;
+
+        let receiver = MockReceiveStream::will_produce(to_receive);
+        let (sender, _send_verify) = MockSendStream::new();
+        let connection = create_client(receiver, sender);
+        let mut consume = connection.consume("/**/*", &VersionVector::new(), event_limit, await_new);
+        assert_eq!(None, consume.stop_result());
+
+        let mut event_count = 0;
+        for _ in 0..20 {
+            match consume.poll() {
+                Ok(Async::Ready(Some(_))) => event_count += 1,
+                Ok(Async::NotReady) => {}
+                Ok(Async::Ready(None)) | Err(_) => break,
+            }
+        }
+        (event_count, consume.stop_result())
+    }
+
+    fn consume_test_event(counter: u64) -> ClientProtocolMessage {
+        use event::{OwnedFloEvent, FloEventId, time};
+
+        ProtocolMessage::ReceiveEvent(OwnedFloEvent {
+            id: FloEventId::new(1, counter),
+            timestamp: time::from_millis_since_epoch(8),
+            parent_id: None,
+            namespace: "/foo".to_owned(),
+            data: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn consume_stop_result_is_reached_max_events_when_event_limit_is_reached() {
+        let to_receive = vec![
+            ProtocolMessage::CursorCreated(CursorInfo{ op_id: 1, batch_size: 10 }),
+            consume_test_event(1),
+            consume_test_event(2),
+            consume_test_event(3),
+        ];
+        let (event_count, result) = consume_until_stopped(to_receive, Some(2), true);
+        assert_eq!(2, event_count);
+        assert_eq!(Some(StopResult::ReachedMaxEvents), result);
+    }
+
+    #[test]
+    fn consume_stop_result_is_reached_end_of_stream_when_awaiting_events_is_received() {
+        let to_receive = vec![
+            ProtocolMessage::CursorCreated(CursorInfo{ op_id: 1, batch_size: 10 }),
+            consume_test_event(1),
+            ProtocolMessage::AwaitingEvents,
+            consume_test_event(2),
+        ];
+        let (event_count, result) = consume_until_stopped(to_receive, None, false);
+        assert_eq!(1, event_count);
+        assert_eq!(Some(StopResult::ReachedEndOfStream), result);
+    }
+
+    #[test]
+    fn consume_stop_result_is_stopped_by_server_when_server_sends_stop_consuming() {
+        let to_receive = vec![
+            ProtocolMessage::CursorCreated(CursorInfo{ op_id: 1, batch_size: 10 }),
+            consume_test_event(1),
+            ProtocolMessage::StopConsuming(1),
+            consume_test_event(2),
+        ];
+        let (event_count, result) = consume_until_stopped(to_receive, None, true);
+        assert_eq!(1, event_count);
+        assert_eq!(Some(StopResult::StoppedByServer), result);
+    }
+
+    #[test]
+    fn consume_stop_result_is_server_closed_when_connection_is_closed() {
+        let to_receive = vec![
+            ProtocolMessage::CursorCreated(CursorInfo{ op_id: 1, batch_size: 10 }),
+            consume_test_event(1),
+        ];
+        let (event_count, result) = consume_until_stopped(to_receive, None, true);
+        assert_eq!(1, event_count);
+        assert_eq!(Some(StopResult::ServerClosed), result);
+    }
+
+    #[test]
+    fn consume_stop_result_is_error_when_server_sends_error() {
+        let to_receive = vec![
+            ProtocolMessage::CursorCreated(CursorInfo{ op_id: 1, batch_size: 10 }),
+            ProtocolMessage::Error(ErrorMessage {
+                op_id: 1,
+                kind: ErrorKind::InvalidNamespaceGlob,
+                description: "bad glob".to_owned(),
+            }),
+        ];
+        let (event_count, result) = consume_until_stopped(to_receive, None, true);
+        assert_eq!(0, event_count);
+        assert_eq!(Some(StopResult::Error), result);
+    }
+
+    #[test]
+    fn consume_fails_with_codec_error_when_event_body_is_not_valid_utf8() {
+        use event::{OwnedFloEvent, VersionVector, FloEventId, time};
+
+        let to_receive = vec![
+            ProtocolMessage::CursorCreated(CursorInfo{ op_id: 1, batch_size: 10 }),
+            ProtocolMessage::ReceiveEvent(OwnedFloEvent {
+                id: FloEventId::new(1, 1),
+                timestamp: time::from_millis_since_epoch(8),
+                parent_id: None,
+                namespace: "/foo".to_owned(),
+                data: vec![0x66, 0x6f, 0xff, 0xfe],
+            }),
+        ];
+        let receiver = MockReceiveStream::will_produce(to_receive);
+        let (sender, _send_verify) = MockSendStream::new();
+        let connection = create_client(receiver, sender);
+        let mut consume = connection.consume("/**/*", &VersionVector::new(), None, true);
+
+        let mut result = None;
+        for _ in 0..20 {
+            match consume.poll() {
+                Ok(Async::NotReady) => {}
+                other @ _ => {
+                    result = Some(other);
+                    break;
+                }
+            }
+        }
+        match result {
+            Some(Err(ConsumeError{error: ErrorType::Codec(_), ..})) => {}
+            other @ _ => panic!("expected a codec error, got: {:?}", other),
+        }
+    }
+
+    struct NoopNotify;
+
+    impl ::futures::executor::Notify for NoopNotify {
+        fn notify(&self, _id: usize) {}
+    }
+
+    /// Polls the future until it completes, turning the reactor in between so that reconnect backoff timeouts can fire
+    fn run_with_reactor<F: Future>(future: F) -> Result<F::Item, F::Error> {
+        use std::time::Duration;
+        use futures::executor::{spawn, NotifyHandle};
+        use tokio_core::reactor::Core;
+
+        let mut core = Core::new().unwrap();
+        let notify = NotifyHandle::from(Arc::new(NoopNotify));
+        let mut spawned = spawn(future);
+        for _ in 0..500 {
+            match spawned.poll_future_notify(&notify, 0) {
+                Ok(Async::Ready(value)) => return Ok(value),
+                Err(err) => return Err(err),
+                Ok(Async::NotReady) => {
+                    core.turn(Some(Duration::from_millis(1)));
+                }
+            }
+        }
+        panic!("future never returned a value");
+    }
+
+    /// Creates a `ReconnectingConnection` that uses each of the given connections in order. A `None` fails to connect with
+    /// an I/O error
+    fn reconnecting_client(connections: Vec<Option<AsyncConnection<String>>>, max_attempts: Option<u32>, handle: &::tokio_core::reactor::Handle) -> ReconnectingConnection<String> {
+        use std::cell::RefCell;
+        use std::time::Duration;
+        use futures::future;
+
+        let connections = RefCell::new(connections.into_iter().collect::<VecDeque<_>>());
+        let connector = move || {
+            match connections.borrow_mut().pop_front().expect("no more mock connections") {
+                Some(connection) => Box::new(future::ok(connection)) as Box<Future<Item=AsyncConnection<String>, Error=HandshakeError>>,
+                None => {
+                    let error = HandshakeError {
+                        message: "mock connection refused",
+                        error_type: ErrorType::Io(io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused")),
+                    };
+                    Box::new(future::err(error)) as Box<Future<Item=AsyncConnection<String>, Error=HandshakeError>>
+                }
+            }
+        };
+        let options = ReconnectOptions {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+            max_attempts: max_attempts,
+        };
+        ReconnectingConnection::with_connector(Box::new(connector), options, handle)
+    }
+
+    #[test]
+    fn reconnecting_consume_resumes_after_the_last_event_received_when_the_connection_is_closed() {
+        use event::VersionVector;
+        use tokio_core::reactor::Core;
+
+        let first_recv = MockReceiveStream::will_produce(vec![
+            ProtocolMessage::CursorCreated(CursorInfo{ op_id: 1, batch_size: 10 }),
+            consume_test_event(1),
+            consume_test_event(2),
+        ]);
+        let (first_send, _first_verify) = MockSendStream::new();
+        let second_recv = MockReceiveStream::will_produce(vec![
+            ProtocolMessage::CursorCreated(CursorInfo{ op_id: 1, batch_size: 10 }),
+            consume_test_event(3),
+            ProtocolMessage::AwaitingEvents,
+        ]);
+        let (second_send, mut second_verify) = MockSendStream::new();
+        let connections = vec![
+            Some(create_client(first_recv, first_send)),
+            None,
+            Some(create_client(second_recv, second_send)),
+        ];
+
+        let core = Core::new().unwrap();
+        let client = reconnecting_client(connections, None, &core.handle());
+        let mut consume = client.consume("/foo", &VersionVector::new(), Some(10), false);
+
+        let events = run_with_reactor(consume.by_ref().collect()).expect("consume failed");
+        let ids = events.iter().map(|event| event.id).collect::<Vec<_>>();
+        assert_eq!(vec![FloEventId::new(1, 1), FloEventId::new(1, 2), FloEventId::new(1, 3)], ids);
+        assert_eq!(Some(7), consume.get_events_remaining());
+        assert_eq!(FloEventId::new(1, 3), consume.version_vector().max());
+
+        // the consumer was restarted after the last event that was received on the first connection
+        match second_verify.get_received().first() {
+            Some(&ProtocolMessage::NewStartConsuming(ref start)) => {
+                assert_eq!(vec![FloEventId::new(1, 2)], start.version_vector);
+                assert_eq!(8, start.max_events);
+                assert_eq!("/foo", start.namespace);
+            }
+            other @ _ => panic!("expected NewStartConsuming, got: {:?}", other),
+        }
+
+        let client = consume.into_connection().expect("connection should be returned");
+        assert_eq!(1, client.reconnect_count());
+        assert!(client.is_connected());
+    }
+
+    #[test]
+    fn reconnecting_produce_returns_error_and_reconnects_on_next_use_after_io_error() {
+        use tokio_core::reactor::Core;
+
+        let (first_send, _first_verify) = MockSendStream::new();
+        let second_recv = MockReceiveStream::will_produce(vec![
+            ProtocolMessage::AckEvent(EventAck{
+                op_id: 1,
+                event_id: FloEventId::new(1, 5),
+            }),
+        ]);
+        let (second_send, mut second_verify) = MockSendStream::new();
+        let connections = vec![
+            Some(create_client(MockReceiveStream::empty(), first_send)),
+            Some(create_client(second_recv, second_send)),
+        ];
+
+        let core = Core::new().unwrap();
+        let client = reconnecting_client(connections, None, &core.handle());
+
+        let err = run_with_reactor(client.produce_to(1, "/foo", None, "first".to_owned())).unwrap_err();
+        match err.error {
+            ErrorType::Io(_) => {}
+            other @ _ => panic!("expected io error, got: {:?}", other),
+        }
+        let client = err.connection;
+        assert!(!client.is_connected());
+        assert_eq!(0, client.reconnect_count());
+
+        let (event_id, client) = run_with_reactor(client.produce_to(1, "/foo", None, "second".to_owned())).expect("produce failed");
+        assert_eq!(FloEventId::new(1, 5), event_id);
+        assert_eq!(1, client.reconnect_count());
+
+        match second_verify.get_received().first() {
+            Some(&ProtocolMessage::ProduceEvent(ref produce)) => {
+                assert_eq!(b"second".to_vec(), produce.data);
+            }
+            other @ _ => panic!("expected ProduceEvent, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reconnecting_connect_gives_up_after_max_attempts() {
+        use tokio_core::reactor::Core;
+
+        let core = Core::new().unwrap();
+        let client = reconnecting_client(vec![None, None, None], Some(2), &core.handle());
+
+        let err = run_with_reactor(client.connect()).unwrap_err();
+        match err.error {
+            ErrorType::Io(ref io_err) => assert_eq!(io::ErrorKind::ConnectionRefused, io_err.kind()),
+            ref other @ _ => panic!("expected io error, got: {:?}", other),
+        }
+        assert!(!err.connection.is_connected());
+    }
 }
@@ -0,0 +1,87 @@
+use std::fmt::{self, Debug};
+use std::io;
+
+use futures::{Future, Stream, Async, Poll};
+
+use event::{FloEventId, VersionVector};
+use async::{AsyncConnection, ErrorType};
+use async::ops::{Consume, ConsumeError, StopResult};
+
+/// A `Future` that waits until the current stream contains an event at or after a target `FloEventId`, whichever producer
+/// it came from. Event counters are assigned in order across the whole stream, so once the target's partition has any event
+/// with a counter at least as high as the target's, the stream has reached that position. Resolves to the connection.
+///
+/// This works by consuming a single event from the target's partition, so that event is converted by the connection's
+/// codec, and any codec error is returned. If the partition doesn't exist then the server responds with an
+/// `InvalidVersionVector` error. A target that exists but is never produced will wait forever, so callers that can't
+/// guarantee it will be reached should combine this with a timeout.
+#[must_use = "futures must be polled in order to do any work"]
+pub struct AwaitStreamPosition<D: Debug> {
+    target: FloEventId,
+    consume: Option<Consume<D>>,
+}
+
+impl <D: Debug> AwaitStreamPosition<D> {
+    pub fn new(connection: AsyncConnection<D>, target: FloEventId) -> AwaitStreamPosition<D> {
+        let consume = start_consume(connection, FloEventId::new(target.actor, target.event_counter.saturating_sub(1)));
+        AwaitStreamPosition {
+            target: target,
+            consume: Some(consume),
+        }
+    }
+}
+
+/// Starts consuming the next event after `start`
+fn start_consume<D: Debug>(connection: AsyncConnection<D>, start: FloEventId) -> Consume<D> {
+    let mut version_vector = VersionVector::new();
+    version_vector.set(start);
+    Consume::new(connection, ::ALL_EVENTS_GLOB.to_owned(), &version_vector, Some(1), true)
+}
+
+impl <D: Debug> Future for AwaitStreamPosition<D> {
+    type Item = AsyncConnection<D>;
+    type Error = ConsumeError<D>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let result = self.consume.as_mut().expect("Attempted to poll AwaitStreamPosition after completion").poll()?;
+            match result {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(Some(event)) => {
+                    let connection: AsyncConnection<D> = self.consume.take().unwrap().into();
+                    if event.id.event_counter >= self.target.event_counter {
+                        debug!("Stream reached: {} while awaiting: {}", event.id, self.target);
+                        return Ok(Async::Ready(connection));
+                    }
+                    // a cursor that starts ahead of the partition head begins with the next event that's produced, even
+                    // if it's before the target, so keep going from there
+                    trace!("Received: {} before the stream reached: {}", event.id, self.target);
+                    self.consume = Some(start_consume(connection, event.id));
+                }
+                Async::Ready(None) => {
+                    let consume = self.consume.take().unwrap();
+                    let stop_result = consume.stop_result();
+                    let connection: AsyncConnection<D> = consume.into();
+                    if stop_result == Some(StopResult::StoppedByServer) {
+                        // the cursor outlived the stream's max_cursor_lifetime, so just start another one
+                        debug!("Restarting consumer awaiting: {} after it was stopped by the server", self.target);
+                        let start = FloEventId::new(self.target.actor, self.target.event_counter.saturating_sub(1));
+                        self.consume = Some(start_consume(connection, start));
+                    } else {
+                        let description = format!("Consumer stopped with: {:?} before the stream reached: {}", stop_result, self.target);
+                        return Err(ConsumeError {
+                            connection: connection,
+                            error: ErrorType::Io(io::Error::new(io::ErrorKind::UnexpectedEof, description)),
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl <D: Debug> Debug for AwaitStreamPosition<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AwaitStreamPosition{{ target: {}, consume: {:?} }}", self.target, self.consume)
+    }
+}
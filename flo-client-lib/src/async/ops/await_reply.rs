@@ -0,0 +1,208 @@
+use std::fmt::{self, Debug};
+use std::io;
+use std::time::Duration;
+
+use futures::{Future, Stream, Async, Poll};
+use tokio_core::reactor::{Handle, Timeout};
+
+use event::{FloEventId, VersionVector};
+use async::{AsyncConnection, ErrorType};
+use async::ops::{ProduceOne, EventToProduce, Consume, StopResult};
+use async::ops::consume::StopConsuming;
+use ::Event;
+
+/// A `Future` that produces a request event and then waits for a reply to it, which is any event in `reply_namespace`
+/// whose `parent_id` is the id of the request. Resolves to the first reply along with the connection.
+///
+/// Replies are found by consuming `reply_namespace` on every partition, starting after the request's counter, and skipping
+/// any events whose `parent_id` doesn't match. The `timeout` starts once the request has been acknowledged, and if it
+/// elapses first then this fails with a `TimedOut` error. Either way, the consumer is stopped before this resolves, so the
+/// connection can be reused.
+#[must_use = "futures must be polled in order to do any work"]
+pub struct ProduceAndAwaitReply<D: Debug> {
+    reply_namespace: String,
+    timeout: Duration,
+    handle: Handle,
+    state: State<D>,
+}
+
+enum State<D: Debug> {
+    Produce(ProduceOne<D>),
+    Consume {
+        request_id: FloEventId,
+        version_vector: VersionVector,
+        timeout: Timeout,
+        consume: Consume<D>,
+    },
+    Stop(StopConsuming<D>, Result<Event<D>, ErrorType>),
+    Done,
+}
+
+impl <D: Debug> ProduceAndAwaitReply<D> {
+    pub fn new(connection: AsyncConnection<D>, request: EventToProduce<D>, reply_namespace: String, timeout: Duration, handle: &Handle) -> ProduceAndAwaitReply<D> {
+        let EventToProduce{partition, namespace, parent_id, data} = request;
+        ProduceAndAwaitReply {
+            reply_namespace: reply_namespace,
+            timeout: timeout,
+            handle: handle.clone(),
+            state: State::Produce(ProduceOne::new(connection, partition, namespace, parent_id, data)),
+        }
+    }
+
+    fn start_awaiting_reply(&self, connection: AsyncConnection<D>, request_id: FloEventId) -> Result<State<D>, AwaitReplyError<D>> {
+        // counters are assigned in order across the whole stream, so a reply will always come after the request on
+        // whichever partition it's produced to
+        let mut version_vector = VersionVector::new();
+        version_vector.set(request_id);
+        if let Some(stream) = connection.current_stream() {
+            for partition in stream.partitions.iter() {
+                version_vector.set(FloEventId::new(partition.partition_num, request_id.event_counter));
+            }
+        }
+
+        let timeout = match Timeout::new(self.timeout, &self.handle) {
+            Ok(timeout) => timeout,
+            Err(io_err) => {
+                return Err(AwaitReplyError {
+                    connection: Some(connection),
+                    error: ErrorType::Io(io_err),
+                });
+            }
+        };
+        let consume = Consume::new(connection, self.reply_namespace.clone(), &version_vector, None, true);
+        Ok(State::Consume {
+            request_id: request_id,
+            version_vector: version_vector,
+            timeout: timeout,
+            consume: consume,
+        })
+    }
+}
+
+impl <D: Debug> Future for ProduceAndAwaitReply<D> {
+    type Item = (Event<D>, AsyncConnection<D>);
+    type Error = AwaitReplyError<D>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let state = ::std::mem::replace(&mut self.state, State::Done);
+            match state {
+                State::Produce(mut produce) => {
+                    match produce.poll() {
+                        Ok(Async::Ready((request_id, connection))) => {
+                            debug!("Produced request: {}, awaiting reply in namespace: '{}'", request_id, self.reply_namespace);
+                            self.state = self.start_awaiting_reply(connection, request_id)?;
+                        }
+                        Ok(Async::NotReady) => {
+                            self.state = State::Produce(produce);
+                            return Ok(Async::NotReady);
+                        }
+                        Err(produce_err) => {
+                            return Err(AwaitReplyError {
+                                connection: Some(produce_err.connection),
+                                error: produce_err.err,
+                            });
+                        }
+                    }
+                }
+                State::Consume {request_id, mut version_vector, mut timeout, mut consume} => {
+                    let result = match consume.poll() {
+                        Ok(result) => result,
+                        Err(consume_err) => {
+                            return Err(AwaitReplyError {
+                                connection: Some(consume_err.connection),
+                                error: consume_err.error,
+                            });
+                        }
+                    };
+                    match result {
+                        Async::Ready(Some(event)) => {
+                            version_vector.update_if_greater(event.id);
+                            if event.parent_id == Some(request_id) {
+                                debug!("Received reply: {} to request: {}", event.id, request_id);
+                                self.state = State::Stop(consume.stop(), Ok(event));
+                            } else {
+                                trace!("Skipping event: {} while awaiting reply to request: {}", event.id, request_id);
+                                self.state = State::Consume {request_id, version_vector, timeout, consume};
+                            }
+                        }
+                        Async::Ready(None) if consume.stop_result() == Some(StopResult::StoppedByServer) => {
+                            // the cursor outlived the stream's max_cursor_lifetime, so pick up where it left off
+                            debug!("Restarting consumer awaiting reply to request: {} after it was stopped by the server", request_id);
+                            let connection: AsyncConnection<D> = consume.into();
+                            let consume = Consume::new(connection, self.reply_namespace.clone(), &version_vector, None, true);
+                            self.state = State::Consume {request_id, version_vector, timeout, consume};
+                        }
+                        Async::Ready(None) => {
+                            let description = format!("Consumer stopped with: {:?} before receiving a reply to request: {}", consume.stop_result(), request_id);
+                            return Err(AwaitReplyError {
+                                connection: Some(consume.into()),
+                                error: ErrorType::Io(io::Error::new(io::ErrorKind::UnexpectedEof, description)),
+                            });
+                        }
+                        Async::NotReady => {
+                            match timeout.poll() {
+                                Ok(Async::NotReady) => {
+                                    self.state = State::Consume {request_id, version_vector, timeout, consume};
+                                    return Ok(Async::NotReady);
+                                }
+                                Ok(Async::Ready(())) => {
+                                    let description = format!("No reply to request: {} was received within {:?}", request_id, self.timeout);
+                                    let timed_out = io::Error::new(io::ErrorKind::TimedOut, description);
+                                    self.state = State::Stop(consume.stop(), Err(ErrorType::Io(timed_out)));
+                                }
+                                Err(io_err) => {
+                                    self.state = State::Stop(consume.stop(), Err(ErrorType::Io(io_err)));
+                                }
+                            }
+                        }
+                    }
+                }
+                State::Stop(mut stop, result) => {
+                    match stop.poll() {
+                        Ok(Async::Ready(connection)) => {
+                            return match result {
+                                Ok(reply) => Ok(Async::Ready((reply, connection))),
+                                Err(error) => Err(AwaitReplyError {
+                                    connection: Some(connection),
+                                    error: error,
+                                }),
+                            };
+                        }
+                        Ok(Async::NotReady) => {
+                            self.state = State::Stop(stop, result);
+                            return Ok(Async::NotReady);
+                        }
+                        Err(stop_err) => {
+                            return Err(AwaitReplyError {
+                                connection: None,
+                                error: stop_err,
+                            });
+                        }
+                    }
+                }
+                State::Done => panic!("Attempted to poll ProduceAndAwaitReply after completion"),
+            }
+        }
+    }
+}
+
+impl <D: Debug> Debug for ProduceAndAwaitReply<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state_desc = match self.state {
+            State::Produce(_) => "Produce",
+            State::Consume{..} => "Consume",
+            State::Stop(..) => "Stop",
+            State::Done => "Done",
+        };
+        write!(f, "ProduceAndAwaitReply{{ reply_namespace: '{}', timeout: {:?}, state: {} }}", self.reply_namespace, self.timeout, state_desc)
+    }
+}
+
+/// The error returned from `ProduceAndAwaitReply`. The `connection` is only `None` if there was an error while stopping
+/// the consumer, in which case the connection is closed.
+#[derive(Debug)]
+pub struct AwaitReplyError<D: Debug> {
+    pub connection: Option<AsyncConnection<D>>,
+    pub error: ErrorType,
+}
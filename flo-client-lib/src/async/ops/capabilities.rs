@@ -0,0 +1,96 @@
+use std::fmt::{self, Display, Debug};
+use std::io;
+
+use futures::{Future, Async, Poll};
+
+use protocol::ProtocolMessage;
+use async::{AsyncConnection, ErrorType, ClientProtocolMessage};
+use async::ops::{RequestResponse, RequestResponseError};
+
+/// A `Future` that asks the server which optional features it supports. Resolves to the connection, which will then return
+/// the server's answer from `supports_feature`.
+pub struct GetCapabilities<D: Debug> {
+    request_response: RequestResponse<D>
+}
+
+impl <D: Debug> GetCapabilities<D> {
+    pub fn new(mut connection: AsyncConnection<D>) -> GetCapabilities<D> {
+        let op_id = connection.next_op_id();
+        let request = ProtocolMessage::GetCapabilities(op_id);
+        let inner = RequestResponse::new(connection, request);
+
+        GetCapabilities {
+            request_response: inner
+        }
+    }
+}
+
+impl <D: Debug> Future for GetCapabilities<D> {
+    type Item = AsyncConnection<D>;
+    type Error = CapabilitiesError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let (response, connection) = try_ready!(self.request_response.poll());
+        result_from_response(response, connection)
+    }
+}
+
+impl <D: Debug> Into<AsyncConnection<D>> for GetCapabilities<D> {
+    fn into(self) -> AsyncConnection<D> {
+        self.request_response.into()
+    }
+}
+
+fn result_from_response<D: Debug>(response: ClientProtocolMessage, mut connection: AsyncConnection<D>) -> Poll<AsyncConnection<D>, CapabilitiesError> {
+    debug!("Received Response: {:?}", response);
+
+    match response {
+        ProtocolMessage::Capabilities(capabilities) => {
+            connection.inner.server_capabilities = Some(capabilities);
+            Ok(Async::Ready(connection))
+        }
+        ProtocolMessage::Error(err_msg) => {
+            Err(CapabilitiesError {
+                message: "Server error",
+                error_type: ErrorType::Server(err_msg),
+            })
+        }
+        other @ _ => {
+            Err(CapabilitiesError {
+                message: "Unexpected message from server",
+                error_type: ErrorType::unexpected_message("Capabilities", other)
+            })
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub struct CapabilitiesError {
+    pub message: &'static str,
+    pub error_type: ErrorType,
+}
+
+impl <D: Debug> From<RequestResponseError<D>> for CapabilitiesError {
+    fn from(err: RequestResponseError<D>) -> Self {
+        CapabilitiesError {
+            message: "Failed to get capabilities from server",
+            error_type: ErrorType::Io(err.error)
+        }
+    }
+}
+
+impl From<io::Error> for CapabilitiesError {
+    fn from(io_err: io::Error) -> Self {
+        CapabilitiesError {
+            message: "IO Error while getting capabilities",
+            error_type: io_err.into(),
+        }
+    }
+}
+
+impl Display for CapabilitiesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Error: '{}', caused by: {:?}", self.message, self.error_type)
+    }
+}
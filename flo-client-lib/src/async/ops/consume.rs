@@ -4,19 +4,39 @@ use std::io;
 
 use futures::{Future, Async, Poll, Stream};
 
-use event::{VersionVector, OwnedFloEvent};
-use protocol::{ProtocolMessage, NewConsumerStart, CONSUME_UNLIMITED};
+use event::{VersionVector, OwnedFloEvent, FloEventId};
+use protocol::{ProtocolMessage, NewConsumerStart, ReadConsistency, CONSUME_UNLIMITED};
 use async::{AsyncConnection, ErrorType, ClientProtocolMessage};
 use async::ops::{SendMessage, SendError, AwaitResponse, AwaitResponseError, RequestResponse};
 use ::Event;
 
 
+/// Describes why a `Consume` stream stopped yielding events, so that an application can tell whether it's done or whether
+/// it should retry
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopResult {
+    /// The `event_limit` that was given when the consumer was started has been reached
+    ReachedMaxEvents,
+    /// The end of the stream was reached, and the consumer was not set to await new events
+    ReachedEndOfStream,
+    /// The connection was closed by the server. The consumer may be restarted on a new connection
+    ServerClosed,
+    /// The server stopped the cursor because it reached the stream's `max_cursor_lifetime`. The consumer may be restarted
+    /// on the same connection, starting after the last event it received
+    StoppedByServer,
+    /// The stream returned some other error, which will have been returned from `poll`
+    Error,
+}
+
 pub struct Consume<D: Debug> {
     op_id: u32,
     batch_size: u32,
     namespace: String,
     await_new_events: bool,
     total_events_remaining: Option<u64>,
+    /// incremented each time the server signals the end of a batch, either with `EndOfBatch` or `AwaitingEvents`
+    batch_boundaries: u64,
+    stop_result: Option<StopResult>,
     state: State<D>,
 }
 
@@ -24,13 +44,36 @@ type PollState<D> = Poll<PollSuccess<D>, ConsumeError<D>>;
 
 impl <D: Debug> Consume<D> {
 
-    pub fn new(mut connection: AsyncConnection<D>, namespace: String, version_vec: &VersionVector, event_limit: Option<u64>, await_new: bool) -> Consume<D> {
+    pub fn new(connection: AsyncConnection<D>, namespace: String, version_vec: &VersionVector, event_limit: Option<u64>, await_new: bool) -> Consume<D> {
+        Consume::start(connection, namespace, version_vec.snapshot(), false, event_limit, await_new)
+    }
+
+    /// Starts consuming only the events that are produced after the server starts the cursor. Since the server decides where
+    /// the cursor starts, there's no gap between finding the newest event and starting after it. This always awaits new events
+    pub fn from_tail(connection: AsyncConnection<D>, namespace: String, event_limit: Option<u64>) -> Consume<D> {
+        Consume::start(connection, namespace, Vec::new(), true, event_limit, true)
+    }
+
+    fn start(mut connection: AsyncConnection<D>, namespace: String, version_vector: Vec<FloEventId>, start_at_tail: bool, event_limit: Option<u64>, await_new: bool) -> Consume<D> {
         let op_id = connection.next_op_id();
         let consumer_start = NewConsumerStart {
             op_id: op_id,
-            version_vector: version_vec.snapshot(),
+            version_vector: version_vector,
             max_events: event_limit.unwrap_or(CONSUME_UNLIMITED),
             namespace: namespace.clone(),
+            body_prefix_bytes: None,
+            start_tag: None,
+            max_delivery_rate: None,
+            namespace_regex: None,
+            error_on_empty: false,
+            unlimited_lifetime: false,
+            read_consistency: ReadConsistency::Local,
+            cursor_op_ids: false,
+            start_at_tail: start_at_tail,
+            snapshot: false,
+            idle_signal_interval: None,
+            see_own_writes: true,
+            trace: false,
         };
         let message = ProtocolMessage::NewStartConsuming(consumer_start);
         let initial_state = State::RequestStart(SendMessage::new(connection, message));
@@ -41,6 +84,8 @@ impl <D: Debug> Consume<D> {
             namespace: namespace,
             await_new_events: await_new,
             total_events_remaining: event_limit,
+            batch_boundaries: 0,
+            stop_result: None,
             state: initial_state
         }
     }
@@ -53,6 +98,24 @@ impl <D: Debug> Consume<D> {
         StopConsuming::new(self.into())
     }
 
+    /// Returns the reason that this stream stopped yielding events, or `None` if it has not stopped yet
+    pub fn stop_result(&self) -> Option<StopResult> {
+        self.stop_result
+    }
+
+    /// Converts this into a `Stream` that yields a `Vec` of events for each batch sent by the server, instead of yielding
+    /// events one at a time. A batch ends when the server sends `EndOfBatch` or `AwaitingEvents`, or when the consumer is
+    /// finished. If `max_batch_size` is `Some`, then server batches will be further split so that no batch is larger than that.
+    /// A `max_batch_size` of 0 is treated as 1, since batches are never empty.
+    pub fn batched(self, max_batch_size: Option<usize>) -> ConsumeBatches<D> {
+        ConsumeBatches {
+            consume: self,
+            max_batch_size: max_batch_size.map(|max| ::std::cmp::max(max, 1)),
+            current_batch: Vec::new(),
+            finished: false,
+        }
+    }
+
     fn decrement_events_remaining(&mut self) {
         if let Some(count) = self.total_events_remaining.as_mut() {
             *count -= 1;
@@ -116,8 +179,23 @@ impl <D: Debug> Stream for Consume<D> {
     type Error = ConsumeError<D>;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let result = self.poll_next();
+        if let Err(ref err) = result {
+            let stop_result = match err.error {
+                ErrorType::Io(ref io_err) if io_err.kind() == io::ErrorKind::UnexpectedEof => StopResult::ServerClosed,
+                _ => StopResult::Error
+            };
+            self.stop_result = Some(stop_result);
+        }
+        result
+    }
+}
+
+impl <D: Debug> Consume<D> {
+    fn poll_next(&mut self) -> Poll<Option<Event<D>>, ConsumeError<D>> {
         if self.event_limit_reached() {
             debug!("Consumer for op_id: {} is finished because event limit was reached", self.op_id);
+            self.stop_result = Some(StopResult::ReachedMaxEvents);
             return Ok(Async::Ready(None));
         }
 
@@ -147,11 +225,17 @@ impl <D: Debug> Stream for Consume<D> {
 
         match poll_success {
             PollSuccess::AwaitReceived if self.await_new_events => {
+                self.batch_boundaries += 1;
                 // just poll again to make sure we're registered to get notified when the next event is ready
-                self.poll()
+                self.poll_next()
             }
             PollSuccess::AwaitReceived => {
                 debug!("Consumer for op_id: {} is finished because AwaitingEvents was received and await_new=false", self.op_id);
+                self.stop_result = Some(StopResult::ReachedEndOfStream);
+                Ok(Async::Ready(None))
+            }
+            PollSuccess::StoppedByServer => {
+                self.stop_result = Some(StopResult::StoppedByServer);
                 Ok(Async::Ready(None))
             }
             PollSuccess::Event(event) => {
@@ -159,9 +243,12 @@ impl <D: Debug> Stream for Consume<D> {
                 Ok(Async::Ready(Some(event)))
             }
             PollSuccess::NewState(new_state) => {
+                if let State::SendNextBatch(_) = new_state {
+                    self.batch_boundaries += 1;
+                }
                 debug!("consumer for op_id: {} transitioning from state: {:?} to {:?}", self.op_id, self.state, new_state);
                 self.state = new_state;
-                self.poll()
+                self.poll_next()
             }
         }
     }
@@ -173,10 +260,101 @@ impl <D: Debug> Debug for Consume<D> {
     }
 }
 
+/// A `Stream` of batches of events, created by calling `Consume::batched`. Each batch is guaranteed to be non-empty. If an error
+/// is encountered, then any events that were buffered in the current batch are discarded along with the rest of the stream.
+pub struct ConsumeBatches<D: Debug> {
+    consume: Consume<D>,
+    max_batch_size: Option<usize>,
+    current_batch: Vec<Event<D>>,
+    finished: bool,
+}
+
+impl <D: Debug> ConsumeBatches<D> {
+    pub fn get_events_remaining(&self) -> Option<u64> {
+        self.consume.get_events_remaining()
+    }
+
+    /// Stops the consumer. Any events that have been received but not yet yielded as part of a batch are discarded
+    pub fn stop(self) -> StopConsuming<D> {
+        self.consume.stop()
+    }
+
+    /// Returns the reason that the underlying consumer stopped, or `None` if it has not stopped yet
+    pub fn stop_result(&self) -> Option<StopResult> {
+        self.consume.stop_result()
+    }
+
+    fn take_batch(&mut self) -> Poll<Option<Vec<Event<D>>>, ConsumeError<D>> {
+        let batch = ::std::mem::replace(&mut self.current_batch, Vec::new());
+        Ok(Async::Ready(Some(batch)))
+    }
+
+    fn batch_is_full(&self) -> bool {
+        self.max_batch_size.map(|max| self.current_batch.len() >= max).unwrap_or(false)
+    }
+}
+
+impl <D: Debug> Stream for ConsumeBatches<D> {
+    type Item = Vec<Event<D>>;
+    type Error = ConsumeError<D>;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if self.finished {
+                return if self.current_batch.is_empty() {
+                    Ok(Async::Ready(None))
+                } else {
+                    self.take_batch()
+                };
+            }
+            if self.batch_is_full() {
+                return self.take_batch();
+            }
+
+            let boundaries_before = self.consume.batch_boundaries;
+            let result = self.consume.poll()?;
+            let reached_boundary = self.consume.batch_boundaries != boundaries_before && !self.current_batch.is_empty();
+
+            match result {
+                Async::Ready(Some(event)) => {
+                    if reached_boundary {
+                        // this event is the start of the next batch
+                        let batch = ::std::mem::replace(&mut self.current_batch, vec![event]);
+                        return Ok(Async::Ready(Some(batch)));
+                    }
+                    self.current_batch.push(event);
+                }
+                Async::Ready(None) => {
+                    self.finished = true;
+                }
+                Async::NotReady if reached_boundary => {
+                    return self.take_batch();
+                }
+                Async::NotReady => {
+                    return Ok(Async::NotReady);
+                }
+            }
+        }
+    }
+}
+
+impl <D: Debug> Into<AsyncConnection<D>> for ConsumeBatches<D> {
+    fn into(self) -> AsyncConnection<D> {
+        self.consume.into()
+    }
+}
+
+impl <D: Debug> Debug for ConsumeBatches<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ConsumeBatches{{ current_batch_len: {}, consume: {:?} }}", self.current_batch.len(), self.consume)
+    }
+}
+
 enum PollSuccess<D: Debug> {
     Event(Event<D>),
     NewState(State<D>),
     AwaitReceived,
+    StoppedByServer,
     // TODO: Send StopConsuming message at the end
 }
 
@@ -264,6 +442,10 @@ impl <D: Debug> EventReceiver<D> {
                 debug!("Received AwaitingEvents for consumer with op_id: {}", op_id);
                 Ok(Async::Ready(PollSuccess::AwaitReceived))
             }
+            Some(ProtocolMessage::StopConsuming(stopped_op_id)) if stopped_op_id == op_id => {
+                debug!("Consumer with op_id: {} was stopped by the server", op_id);
+                Ok(Async::Ready(PollSuccess::StoppedByServer))
+            }
             Some(other) => {
                 Err(consume_error(self.0.take().unwrap(), other))
             }
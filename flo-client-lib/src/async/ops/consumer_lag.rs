@@ -0,0 +1,101 @@
+use std::fmt::{self, Display, Debug};
+use std::io;
+
+use futures::{Future, Async, Poll};
+
+use protocol::{self, ProtocolMessage};
+use event::VersionVector;
+use async::{AsyncConnection, ErrorType, ClientProtocolMessage};
+use async::ops::{RequestResponse, RequestResponseError};
+
+/// A `Future` that asks the server how many events matching a namespace have yet to be received by a consumer at the given
+/// version vector. Resolves to the number of events, summed across every partition, along with the connection.
+#[derive(Debug)]
+pub struct GetConsumerLag<D: Debug> {
+    request_response: RequestResponse<D>
+}
+
+impl <D: Debug> GetConsumerLag<D> {
+    pub fn new(mut connection: AsyncConnection<D>, namespace: String, version_vector: &VersionVector) -> GetConsumerLag<D> {
+        let op_id = connection.next_op_id();
+        let request = ProtocolMessage::GetConsumerLag(protocol::GetConsumerLag {
+            op_id: op_id,
+            namespace: namespace,
+            version_vector: version_vector.snapshot(),
+        });
+        let inner = RequestResponse::new(connection, request);
+
+        GetConsumerLag {
+            request_response: inner
+        }
+    }
+}
+
+impl <D: Debug> Future for GetConsumerLag<D> {
+    type Item = (u64, AsyncConnection<D>);
+    type Error = ConsumerLagError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let (response, connection) = try_ready!(self.request_response.poll());
+        result_from_response(response, connection)
+    }
+}
+
+impl <D: Debug> Into<AsyncConnection<D>> for GetConsumerLag<D> {
+    fn into(self) -> AsyncConnection<D> {
+        self.request_response.into()
+    }
+}
+
+fn result_from_response<D: Debug>(response: ClientProtocolMessage, connection: AsyncConnection<D>) -> Poll<(u64, AsyncConnection<D>), ConsumerLagError> {
+    debug!("Received Response: {:?}", response);
+
+    match response {
+        ProtocolMessage::CountResult(result) => {
+            Ok(Async::Ready((result.count, connection)))
+        }
+        ProtocolMessage::Error(err_msg) => {
+            Err(ConsumerLagError {
+                message: "Server error",
+                error_type: ErrorType::Server(err_msg),
+            })
+        }
+        other @ _ => {
+            Err(ConsumerLagError {
+                message: "Unexpected message from server",
+                error_type: ErrorType::unexpected_message("CountResult", other)
+            })
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub struct ConsumerLagError {
+    pub message: &'static str,
+    pub error_type: ErrorType,
+}
+
+impl <D: Debug> From<RequestResponseError<D>> for ConsumerLagError {
+    fn from(err: RequestResponseError<D>) -> Self {
+        ConsumerLagError {
+            message: "Failed to get consumer lag from server",
+            error_type: ErrorType::Io(err.error)
+        }
+    }
+}
+
+impl From<io::Error> for ConsumerLagError {
+    fn from(io_err: io::Error) -> Self {
+        ConsumerLagError {
+            message: "IO Error while getting consumer lag",
+            error_type: io_err.into(),
+        }
+    }
+}
+
+impl Display for ConsumerLagError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Error: '{}', caused by: {:?}", self.message, self.error_type)
+    }
+}
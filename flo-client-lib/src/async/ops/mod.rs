@@ -1,13 +1,25 @@
 mod produce;
+mod produce_batch;
 mod send_message;
 mod await_response;
 mod consume;
 mod request_response;
 mod handshake;
+mod capabilities;
+mod stream_status;
+mod consumer_lag;
+mod await_position;
+mod await_reply;
 
 pub use self::send_message::{SendMessage, SendError};
 pub use self::await_response::{AwaitResponse, AwaitResponseError};
 pub use self::produce::{ProduceOne, ProduceErr, EventToProduce, ProduceAll, ProduceAllError, ProduceAllResult};
-pub use self::consume::{Consume, ConsumeError};
+pub use self::produce_batch::{ProduceBatch, BatchProduceResult, MAX_PIPELINED_PRODUCES};
+pub use self::consume::{Consume, ConsumeBatches, ConsumeError, StopResult};
 pub use self::request_response::{RequestResponse, RequestResponseError};
 pub use self::handshake::{Handshake, HandshakeError};
+pub use self::capabilities::{GetCapabilities, CapabilitiesError};
+pub use self::stream_status::{GetStreamStatus, StreamStatusError};
+pub use self::consumer_lag::{GetConsumerLag, ConsumerLagError};
+pub use self::await_position::AwaitStreamPosition;
+pub use self::await_reply::{ProduceAndAwaitReply, AwaitReplyError};
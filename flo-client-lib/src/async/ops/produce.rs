@@ -4,7 +4,7 @@ use std::io;
 use futures::{Future, Poll, Async};
 
 use event::{FloEventId, ActorId};
-use protocol::{ProtocolMessage, ProduceEvent};
+use protocol::{ProtocolMessage, ProduceEvent, Compression};
 use async::{AsyncConnection, ErrorType, ClientProtocolMessage};
 use async::ops::{RequestResponse, RequestResponseError};
 
@@ -37,6 +37,9 @@ impl <D: Debug> ProduceOne<D> {
                     partition,
                     namespace,
                     parent_id,
+                    ttl: None,
+                    compression: Compression::None,
+                    trace: false,
                     data: converted,
                 };
                 Inner::RequestResp(RequestResponse::new(connection, ProtocolMessage::ProduceEvent(proto_msg)))
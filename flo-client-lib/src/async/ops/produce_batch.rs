@@ -0,0 +1,165 @@
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::io;
+
+use futures::{Future, Poll, Async};
+
+use event::FloEventId;
+use protocol::{ProtocolMessage, ProduceEvent, Compression};
+use async::{AsyncConnection, ErrorType, ClientProtocolMessage};
+use async::ops::{SendMessage, AwaitResponse, EventToProduce, ProduceErr};
+
+/// The maximum number of events from a `ProduceBatch` that may be sent to the server before their acknowledgements are
+/// read. This keeps the acknowledgements that are waiting to be read from growing without bound for very large batches.
+pub const MAX_PIPELINED_PRODUCES: usize = 256;
+
+/// The result of producing a single event as part of a `ProduceBatch`
+pub type BatchProduceResult = Result<FloEventId, ErrorType>;
+
+/// An operation that produces a batch of events without waiting for each one to be acknowledged before sending the next.
+/// Up to `MAX_PIPELINED_PRODUCES` events are sent back-to-back, and then acknowledgements are read in the same order as
+/// the events were sent, so a large batch only pays for a small number of round trips.
+///
+/// Each event gets its own result, in the same order as the input. An error producing one event, whether it's from the
+/// codec or the server, does not prevent the rest of the batch from being produced. The future only fails if there's an
+/// error on the connection itself, in which case none of the results are returned.
+#[derive(Debug)]
+#[must_use = "futures must be polled in order to do any work"]
+pub struct ProduceBatch<D: Debug> {
+    /// index into `results` and the message for each event that has not yet been sent
+    to_send: VecDeque<(usize, ClientProtocolMessage)>,
+    /// index into `results` and the op_id for each event that has been sent but not yet acknowledged
+    awaiting: VecDeque<(usize, u32)>,
+    results: Vec<Option<BatchProduceResult>>,
+    state: State<D>,
+}
+
+#[derive(Debug)]
+enum State<D: Debug> {
+    Idle(AsyncConnection<D>),
+    Sending(SendMessage<D>),
+    Receiving(AwaitResponse<D>),
+    Done,
+}
+
+impl <D: Debug> ProduceBatch<D> {
+    pub fn new(mut connection: AsyncConnection<D>, events: Vec<EventToProduce<D>>) -> ProduceBatch<D> {
+        let mut to_send = VecDeque::with_capacity(events.len());
+        let mut results = Vec::with_capacity(events.len());
+
+        for (index, EventToProduce{partition, namespace, parent_id, data}) in events.into_iter().enumerate() {
+            match connection.inner.codec.convert_produced(&namespace, data) {
+                Ok(converted) => {
+                    let produce = ProduceEvent {
+                        op_id: connection.next_op_id(),
+                        partition,
+                        namespace,
+                        parent_id,
+                        ttl: None,
+                        compression: Compression::None,
+                        trace: false,
+                        data: converted,
+                    };
+                    to_send.push_back((index, ProtocolMessage::ProduceEvent(produce)));
+                    results.push(None);
+                }
+                Err(codec_err) => {
+                    results.push(Some(Err(ErrorType::Codec(codec_err))));
+                }
+            }
+        }
+
+        ProduceBatch {
+            to_send: to_send,
+            awaiting: VecDeque::new(),
+            results: results,
+            state: State::Idle(connection),
+        }
+    }
+
+    fn response_received(&mut self, response: ClientProtocolMessage) {
+        let (index, op_id) = self.awaiting.pop_front().expect("received a response without awaiting one");
+        let result = match response {
+            ProtocolMessage::AckEvent(ack) => Ok(ack.event_id),
+            ProtocolMessage::Error(err_response) => Err(ErrorType::Server(err_response)),
+            other @ _ => {
+                let io_err = io::Error::new(io::ErrorKind::InvalidData, format!("Invalid response from server: {:?}", other));
+                Err(ErrorType::Io(io_err))
+            }
+        };
+        debug!("Received response for op_id: {}, event: {} of batch: {:?}", op_id, index, result);
+        self.results[index] = Some(result);
+    }
+}
+
+impl <D: Debug> Future for ProduceBatch<D> {
+    type Item = (Vec<BatchProduceResult>, AsyncConnection<D>);
+    type Error = ProduceErr<D>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let state = ::std::mem::replace(&mut self.state, State::Done);
+            match state {
+                State::Idle(connection) => {
+                    if !self.to_send.is_empty() && self.awaiting.len() < MAX_PIPELINED_PRODUCES {
+                        let (index, message) = self.to_send.pop_front().unwrap();
+                        self.awaiting.push_back((index, message.get_op_id()));
+                        self.state = State::Sending(SendMessage::new(connection, message));
+                    } else if let Some(&(_, op_id)) = self.awaiting.front() {
+                        self.state = State::Receiving(AwaitResponse::new(connection, op_id));
+                    } else {
+                        let results = self.results.drain(..).map(|result| {
+                            result.expect("ProduceBatch completed without a result for every event")
+                        }).collect();
+                        return Ok(Async::Ready((results, connection)));
+                    }
+                }
+                State::Sending(mut send) => {
+                    match send.poll() {
+                        Ok(Async::Ready(connection)) => self.state = State::Idle(connection),
+                        Ok(Async::NotReady) => {
+                            self.state = State::Sending(send);
+                            return Ok(Async::NotReady);
+                        }
+                        Err(send_err) => {
+                            return Err(ProduceErr {
+                                connection: send_err.connection,
+                                err: ErrorType::Io(send_err.err),
+                            });
+                        }
+                    }
+                }
+                State::Receiving(mut await_response) => {
+                    match await_response.poll() {
+                        Ok(Async::Ready((response, connection))) => {
+                            self.response_received(response);
+                            self.state = State::Idle(connection);
+                        }
+                        Ok(Async::NotReady) => {
+                            self.state = State::Receiving(await_response);
+                            return Ok(Async::NotReady);
+                        }
+                        Err(await_err) => {
+                            return Err(ProduceErr {
+                                connection: await_err.connection,
+                                err: ErrorType::Io(await_err.err),
+                            });
+                        }
+                    }
+                }
+                State::Done => panic!("Attempted to poll ProduceBatch after completion"),
+            }
+        }
+    }
+}
+
+impl <D: Debug> Into<AsyncConnection<D>> for ProduceBatch<D> {
+    fn into(self) -> AsyncConnection<D> {
+        match self.state {
+            State::Idle(connection) => connection,
+            State::Sending(send) => send.into(),
+            State::Receiving(await_response) => await_response.into(),
+            State::Done => panic!("ProduceBatch already completed"),
+        }
+    }
+}
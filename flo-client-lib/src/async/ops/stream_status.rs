@@ -0,0 +1,100 @@
+use std::fmt::{self, Display, Debug};
+use std::io;
+
+use futures::{Future, Async, Poll};
+
+use protocol::{self, ProtocolMessage};
+use async::{AsyncConnection, ErrorType, ClientProtocolMessage, CurrentStreamState};
+use async::ops::{RequestResponse, RequestResponseError};
+
+/// A `Future` that asks the server for the current status of a named event stream, including the head of each of its
+/// partitions, without starting to consume from it. Resolves to the status along with the connection. The connection
+/// keeps using its current stream, and `current_stream` is not updated, even if the named stream is the current one.
+#[derive(Debug)]
+pub struct GetStreamStatus<D: Debug> {
+    request_response: RequestResponse<D>
+}
+
+impl <D: Debug> GetStreamStatus<D> {
+    pub fn new(mut connection: AsyncConnection<D>, stream_name: String) -> GetStreamStatus<D> {
+        let op_id = connection.next_op_id();
+        let request = ProtocolMessage::GetStreamStatus(protocol::GetStreamStatus {
+            op_id: op_id,
+            name: stream_name,
+        });
+        let inner = RequestResponse::new(connection, request);
+
+        GetStreamStatus {
+            request_response: inner
+        }
+    }
+}
+
+impl <D: Debug> Future for GetStreamStatus<D> {
+    type Item = (CurrentStreamState, AsyncConnection<D>);
+    type Error = StreamStatusError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let (response, connection) = try_ready!(self.request_response.poll());
+        result_from_response(response, connection)
+    }
+}
+
+impl <D: Debug> Into<AsyncConnection<D>> for GetStreamStatus<D> {
+    fn into(self) -> AsyncConnection<D> {
+        self.request_response.into()
+    }
+}
+
+fn result_from_response<D: Debug>(response: ClientProtocolMessage, connection: AsyncConnection<D>) -> Poll<(CurrentStreamState, AsyncConnection<D>), StreamStatusError> {
+    debug!("Received Response: {:?}", response);
+
+    match response {
+        ProtocolMessage::StreamStatus(status) => {
+            Ok(Async::Ready((status.into(), connection)))
+        }
+        ProtocolMessage::Error(err_msg) => {
+            Err(StreamStatusError {
+                message: "Server error",
+                error_type: ErrorType::Server(err_msg),
+            })
+        }
+        other @ _ => {
+            Err(StreamStatusError {
+                message: "Unexpected message from server",
+                error_type: ErrorType::unexpected_message("StreamStatus", other)
+            })
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub struct StreamStatusError {
+    pub message: &'static str,
+    pub error_type: ErrorType,
+}
+
+impl <D: Debug> From<RequestResponseError<D>> for StreamStatusError {
+    fn from(err: RequestResponseError<D>) -> Self {
+        StreamStatusError {
+            message: "Failed to get stream status from server",
+            error_type: ErrorType::Io(err.error)
+        }
+    }
+}
+
+impl From<io::Error> for StreamStatusError {
+    fn from(io_err: io::Error) -> Self {
+        StreamStatusError {
+            message: "IO Error while getting stream status",
+            error_type: io_err.into(),
+        }
+    }
+}
+
+impl Display for StreamStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Error: '{}', caused by: {:?}", self.message, self.error_type)
+    }
+}
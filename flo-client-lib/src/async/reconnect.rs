@@ -0,0 +1,442 @@
+use std::fmt::{self, Debug};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::{Future, Stream, Async, Poll};
+use tokio_core::reactor::{Handle, Timeout};
+
+use event::{FloEventId, ActorId, VersionVector};
+use async::{AsyncConnection, ErrorType, tcp_connect_with};
+use async::ops::{ProduceOne, ProduceErr, EventToProduce, Consume, ConsumeError, StopResult, HandshakeError};
+use codec::EventCodec;
+use ::Event;
+
+/// Creates a new connection to the server, with the handshake already complete. This is called by a
+/// `ReconnectingConnection` each time it needs to connect.
+pub type Connector<D> = Box<Fn() -> Box<Future<Item=AsyncConnection<D>, Error=HandshakeError>>>;
+
+/// Controls how a `ReconnectingConnection` retries when it fails to connect to the server
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectOptions {
+    /// How long to wait after the first failed attempt before trying again
+    pub initial_backoff: Duration,
+    /// The backoff is doubled after each failed attempt, up to this maximum
+    pub max_backoff: Duration,
+    /// The number of failed attempts in a row before giving up and returning the error. If `None`, then the connection
+    /// will keep trying forever
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectOptions {
+    fn default() -> Self {
+        ReconnectOptions {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+/// A connection that transparently reconnects to the server whenever the underlying `AsyncConnection` fails with an I/O
+/// error, such as when the server is restarted. Connecting is retried with exponential backoff, according to the
+/// `ReconnectOptions`. Nothing is connected until the first operation is started.
+///
+/// Consumers started with `consume` remember their namespace, version vector, and remaining event limit, so they're
+/// restarted right after the last event that was received. Produce operations are _not_ retried, since it's impossible
+/// to know whether the server persisted an event before the connection failed. Instead, the error is returned along with
+/// this connection, which reconnects when it's used again.
+///
+/// Each reconnect is logged, and counted by `reconnect_count`, so that applications can react to them.
+pub struct ReconnectingConnection<D: Debug> {
+    connector: Connector<D>,
+    handle: Handle,
+    options: ReconnectOptions,
+    connection: Option<AsyncConnection<D>>,
+    attempt: Option<ConnectAttempt<D>>,
+    has_connected: bool,
+    reconnect_count: u64,
+}
+
+impl <D: Debug + 'static> ReconnectingConnection<D> {
+
+    /// Creates a connection that connects over TCP to the given `addr`. The `new_codec` function is called to create the
+    /// codec for each new connection. See `tcp_connect_with` for a description of the other arguments.
+    pub fn tcp<N, C, F>(client_name: N, addr: SocketAddr, consume_batch_size: Option<u32>, new_codec: F, options: ReconnectOptions, handle: &Handle) -> ReconnectingConnection<D>
+            where N: Into<String>, C: EventCodec<EventData=D> + 'static, F: Fn() -> C + 'static {
+        let client_name = client_name.into();
+        let connect_handle = handle.clone();
+        let connector = move || {
+            let connect = tcp_connect_with(client_name.clone(), &addr, consume_batch_size, new_codec(), &connect_handle);
+            Box::new(connect) as Box<Future<Item=AsyncConnection<D>, Error=HandshakeError>>
+        };
+        ReconnectingConnection::with_connector(Box::new(connector), options, handle)
+    }
+}
+
+impl <D: Debug> ReconnectingConnection<D> {
+
+    /// Creates a connection that uses the given `connector` to connect to the server. This allows reconnecting using any
+    /// transport, not just TCP.
+    pub fn with_connector(connector: Connector<D>, options: ReconnectOptions, handle: &Handle) -> ReconnectingConnection<D> {
+        ReconnectingConnection {
+            connector: connector,
+            handle: handle.clone(),
+            options: options,
+            connection: None,
+            attempt: None,
+            has_connected: false,
+            reconnect_count: 0,
+        }
+    }
+
+    /// The number of times that this connection has had to reconnect to the server. The first connection is not counted
+    pub fn reconnect_count(&self) -> u64 {
+        self.reconnect_count
+    }
+
+    /// Returns true if there's currently a live connection to the server
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    /// Connects to the server, if there's not already a live connection. The returned `Future` resolves to this connection
+    pub fn connect(self) -> ReconnectingConnect<D> {
+        ReconnectingConnect(Some(self))
+    }
+
+    /// Produces a single event, connecting first if needed. See `AsyncConnection::produce`
+    pub fn produce(self, event: EventToProduce<D>) -> ReconnectingProduce<D> {
+        ReconnectingProduce {
+            connection: Some(self),
+            to_produce: Some(event),
+            produce: None,
+        }
+    }
+
+    /// Produces a single event to the given partition, connecting first if needed. See `AsyncConnection::produce_to`
+    pub fn produce_to<N: Into<String>>(self, partition: ActorId, namespace: N, parent_id: Option<FloEventId>, data: D) -> ReconnectingProduce<D> {
+        self.produce(EventToProduce::new(partition, namespace, parent_id, data))
+    }
+
+    /// Starts consuming events, connecting first if needed. See `AsyncConnection::consume`. If the connection fails, then
+    /// the consumer is restarted on a new connection, after the last event that was received.
+    pub fn consume<N: Into<String>>(self, namespace: N, version_vector: &VersionVector, event_limit: Option<u64>, await_new: bool) -> ReconnectingConsume<D> {
+        ReconnectingConsume {
+            namespace: namespace.into(),
+            version_vector: version_vector.clone(),
+            events_remaining: event_limit,
+            await_new: await_new,
+            connection: Some(self),
+            consume: None,
+            finished: false,
+        }
+    }
+
+    /// Drives the current connection attempt, if there's no live connection. Resolves once `self.connection` is `Some`
+    fn poll_connected(&mut self) -> Poll<(), ErrorType> {
+        if self.connection.is_some() {
+            return Ok(Async::Ready(()));
+        }
+        if self.attempt.is_none() {
+            self.attempt = Some(ConnectAttempt::new(&self.connector, &self.options));
+        }
+
+        let result = self.attempt.as_mut().unwrap().poll(&self.connector, &self.options, &self.handle);
+        match result {
+            Ok(Async::Ready(connection)) => {
+                self.attempt = None;
+                if self.has_connected {
+                    self.reconnect_count += 1;
+                    info!("Reconnected to the server, reconnect_count: {}", self.reconnect_count);
+                }
+                self.has_connected = true;
+                self.connection = Some(connection);
+                Ok(Async::Ready(()))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(err) => {
+                self.attempt = None;
+                Err(err)
+            }
+        }
+    }
+
+    /// Called when an operation fails. The connection is only kept if the error was something other than I/O
+    fn operation_failed(&mut self, connection: AsyncConnection<D>, error: &ErrorType) {
+        if let ErrorType::Io(ref io_err) = *error {
+            warn!("Closing connection due to I/O error: {}, will reconnect on next use", io_err);
+        } else {
+            self.connection = Some(connection);
+        }
+    }
+}
+
+impl <D: Debug> Debug for ReconnectingConnection<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ReconnectingConnection{{ connection: {:?}, reconnect_count: {}, options: {:?} }}", self.connection, self.reconnect_count, self.options)
+    }
+}
+
+
+/// Keeps trying to connect to the server until it either succeeds, gets a non-I/O error, or runs out of attempts
+struct ConnectAttempt<D: Debug> {
+    failed_attempts: u32,
+    backoff: Duration,
+    state: AttemptState<D>,
+}
+
+enum AttemptState<D: Debug> {
+    Connecting(Box<Future<Item=AsyncConnection<D>, Error=HandshakeError>>),
+    Waiting(Timeout),
+}
+
+impl <D: Debug> ConnectAttempt<D> {
+    fn new(connector: &Connector<D>, options: &ReconnectOptions) -> ConnectAttempt<D> {
+        ConnectAttempt {
+            failed_attempts: 0,
+            backoff: options.initial_backoff,
+            state: AttemptState::Connecting(connector()),
+        }
+    }
+
+    fn poll(&mut self, connector: &Connector<D>, options: &ReconnectOptions, handle: &Handle) -> Poll<AsyncConnection<D>, ErrorType> {
+        loop {
+            let next_state = match self.state {
+                AttemptState::Connecting(ref mut connect) => {
+                    match connect.poll() {
+                        Ok(Async::Ready(connection)) => return Ok(Async::Ready(connection)),
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Err(HandshakeError{message, error_type: ErrorType::Io(io_err)}) => {
+                            self.failed_attempts += 1;
+                            if options.max_attempts.map(|max| self.failed_attempts >= max).unwrap_or(false) {
+                                error!("Giving up connecting after {} failed attempts: {}: {}", self.failed_attempts, message, io_err);
+                                return Err(ErrorType::Io(io_err));
+                            }
+                            warn!("Failed to connect on attempt: {}: {}: {}, retrying in {:?}", self.failed_attempts, message, io_err, self.backoff);
+                            let timeout = Timeout::new(self.backoff, handle)?;
+                            self.backoff = ::std::cmp::min(self.backoff * 2, options.max_backoff);
+                            AttemptState::Waiting(timeout)
+                        }
+                        Err(handshake_err) => {
+                            error!("Failed to connect: {:?}", handshake_err);
+                            return Err(handshake_err.error_type);
+                        }
+                    }
+                }
+                AttemptState::Waiting(ref mut timeout) => {
+                    try_ready!(timeout.poll());
+                    AttemptState::Connecting(connector())
+                }
+            };
+            self.state = next_state;
+        }
+    }
+}
+
+
+/// An error from an operation on a `ReconnectingConnection`. The connection is always returned so that it can be reused.
+/// If the error was an I/O error, then the connection will reconnect the next time it's used.
+#[derive(Debug)]
+pub struct ReconnectError<D: Debug> {
+    pub connection: ReconnectingConnection<D>,
+    pub error: ErrorType,
+}
+
+/// A `Future` that resolves to a `ReconnectingConnection` with a live connection to the server
+#[must_use = "futures do nothing unless polled"]
+pub struct ReconnectingConnect<D: Debug>(Option<ReconnectingConnection<D>>);
+
+impl <D: Debug> Future for ReconnectingConnect<D> {
+    type Item = ReconnectingConnection<D>;
+    type Error = ReconnectError<D>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = self.0.as_mut().expect("Attempted to poll ReconnectingConnect after completion").poll_connected();
+        match result {
+            Ok(Async::Ready(())) => Ok(Async::Ready(self.0.take().unwrap())),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(err) => {
+                Err(ReconnectError {
+                    connection: self.0.take().unwrap(),
+                    error: err,
+                })
+            }
+        }
+    }
+}
+
+
+/// A `Future` that produces a single event using a `ReconnectingConnection`. It resolves to the id of the new event
+/// along with the connection.
+#[must_use = "futures do nothing unless polled"]
+pub struct ReconnectingProduce<D: Debug> {
+    connection: Option<ReconnectingConnection<D>>,
+    to_produce: Option<EventToProduce<D>>,
+    produce: Option<ProduceOne<D>>,
+}
+
+impl <D: Debug> Future for ReconnectingProduce<D> {
+    type Item = (FloEventId, ReconnectingConnection<D>);
+    type Error = ReconnectError<D>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if self.produce.is_none() {
+            let connect_result = self.connection.as_mut().expect("Attempted to poll ReconnectingProduce after completion").poll_connected();
+            match connect_result {
+                Ok(Async::Ready(())) => {
+                    let connection = self.connection.as_mut().unwrap().connection.take().unwrap();
+                    let EventToProduce{partition, namespace, parent_id, data} = self.to_produce.take().unwrap();
+                    self.produce = Some(ProduceOne::new(connection, partition, namespace, parent_id, data));
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => {
+                    return Err(ReconnectError {
+                        connection: self.connection.take().unwrap(),
+                        error: err,
+                    });
+                }
+            }
+        }
+
+        match self.produce.as_mut().unwrap().poll() {
+            Ok(Async::Ready((event_id, async_connection))) => {
+                let mut connection = self.connection.take().unwrap();
+                connection.connection = Some(async_connection);
+                Ok(Async::Ready((event_id, connection)))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(ProduceErr{connection: async_connection, err}) => {
+                let mut connection = self.connection.take().unwrap();
+                connection.operation_failed(async_connection, &err);
+                Err(ReconnectError {
+                    connection: connection,
+                    error: err,
+                })
+            }
+        }
+    }
+}
+
+
+/// A `Stream` of events from a consumer that's restarted on a new connection whenever the connection fails with an I/O
+/// error. The version vector is updated as each event is received, so that a restarted consumer starts right after the
+/// last event, and the `event_limit` applies to all the events received across every connection. Consumers that are
+/// stopped by the server are also restarted, on the same connection. Any other error ends the stream, and returns the
+/// `ReconnectingConnection`.
+pub struct ReconnectingConsume<D: Debug> {
+    namespace: String,
+    version_vector: VersionVector,
+    events_remaining: Option<u64>,
+    await_new: bool,
+    connection: Option<ReconnectingConnection<D>>,
+    consume: Option<Consume<D>>,
+    finished: bool,
+}
+
+impl <D: Debug> ReconnectingConsume<D> {
+    /// The version vector of the last event received from each partition. This can be saved by the application in order
+    /// to resume consuming later
+    pub fn version_vector(&self) -> &VersionVector {
+        &self.version_vector
+    }
+
+    pub fn get_events_remaining(&self) -> Option<u64> {
+        self.events_remaining
+    }
+
+    /// Returns the connection, or `None` if it was already returned as part of an error. If the consumer is still active,
+    /// then its connection is closed rather than returned, since the server may still be sending it events, and the
+    /// `ReconnectingConnection` will reconnect the next time that it's used
+    pub fn into_connection(self) -> Option<ReconnectingConnection<D>> {
+        let ReconnectingConsume {connection, consume, finished, ..} = self;
+        connection.map(|mut connection| {
+            if let Some(consume) = consume {
+                let async_connection: AsyncConnection<D> = consume.into();
+                if finished {
+                    connection.connection = Some(async_connection);
+                }
+            }
+            connection
+        })
+    }
+
+    fn start_consume(&mut self) -> Poll<(), ReconnectError<D>> {
+        let connect_result = self.connection.as_mut().unwrap().poll_connected();
+        match connect_result {
+            Ok(Async::Ready(())) => {
+                let async_connection = self.connection.as_mut().unwrap().connection.take().unwrap();
+                debug!("Starting consumer for namespace: '{}' with version_vector: {:?}", self.namespace, self.version_vector);
+                let consume = async_connection.consume(self.namespace.clone(), &self.version_vector, self.events_remaining, self.await_new);
+                self.consume = Some(consume);
+                Ok(Async::Ready(()))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(err) => {
+                self.finished = true;
+                Err(ReconnectError {
+                    connection: self.connection.take().unwrap(),
+                    error: err,
+                })
+            }
+        }
+    }
+}
+
+impl <D: Debug> Stream for ReconnectingConsume<D> {
+    type Item = Event<D>;
+    type Error = ReconnectError<D>;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if self.finished {
+                return Ok(Async::Ready(None));
+            }
+            if self.consume.is_none() {
+                try_ready!(self.start_consume());
+            }
+
+            match self.consume.as_mut().unwrap().poll() {
+                Ok(Async::Ready(Some(event))) => {
+                    self.version_vector.update_if_greater(event.id);
+                    if let Some(remaining) = self.events_remaining.as_mut() {
+                        *remaining -= 1;
+                    }
+                    return Ok(Async::Ready(Some(event)));
+                }
+                Ok(Async::Ready(None)) => {
+                    let stop_result = self.consume.as_ref().unwrap().stop_result();
+                    if stop_result == Some(StopResult::StoppedByServer) {
+                        debug!("Consumer for namespace: '{}' was stopped by the server, restarting it", self.namespace);
+                        let async_connection = self.consume.take().unwrap().into();
+                        self.connection.as_mut().unwrap().connection = Some(async_connection);
+                    } else {
+                        self.finished = true;
+                    }
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(ConsumeError{connection: async_connection, error}) => {
+                    self.consume = None;
+                    let mut connection = self.connection.take().unwrap();
+                    connection.operation_failed(async_connection, &error);
+                    if let ErrorType::Io(_) = error {
+                        info!("Restarting consumer for namespace: '{}' on a new connection", self.namespace);
+                        self.connection = Some(connection);
+                    } else {
+                        self.finished = true;
+                        return Err(ReconnectError {
+                            connection: connection,
+                            error: error,
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl <D: Debug> Debug for ReconnectingConsume<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ReconnectingConsume{{ namespace: '{}', version_vector: {:?}, events_remaining: {:?}, consume: {:?} }}",
+               self.namespace, self.version_vector, self.events_remaining, self.consume)
+    }
+}
@@ -1,5 +1,6 @@
 #[cfg(feature = "serde-json-codec")]
 mod serde;
+mod multi;
 
 use std::error::Error;
 
@@ -8,6 +9,7 @@ use ::Event;
 
 #[cfg(feature = "serde-json-codec")]
 pub use self::serde::{SerdeJsonCodec, SerdePrettyJsonCodec};
+pub use self::multi::{MultiCodec, MultiCodecData, MultiCodecError, MultiCodecConnection};
 
 /// Trait that allows events to be converted to application-specific types. An `EventCodec` is associated with a
 /// connection, and is used to convert all incoming and outgoing events. Note that the types that are produced and consumed
@@ -81,3 +83,27 @@ impl EventCodec for LossyStringCodec {
         Ok(output.into_bytes())
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn string_codec_converts_strings_to_and_from_utf8_bytes() {
+        let produced = StringCodec.convert_produced("/foo", "héllo wörld".to_owned()).unwrap();
+        assert_eq!("héllo wörld".as_bytes().to_vec(), produced);
+
+        let received = StringCodec.convert_received("/foo", produced).unwrap();
+        assert_eq!("héllo wörld", received);
+    }
+
+    #[test]
+    fn string_codec_returns_error_when_received_data_is_not_valid_utf8() {
+        let err = StringCodec.convert_received("/foo", vec![0x66, 0x6f, 0xff, 0xfe]).expect_err("expected invalid utf8 to be an error");
+        assert!(err.to_string().contains("utf-8"), "unexpected error: {}", err);
+
+        let lossy = LossyStringCodec.convert_received("/foo", vec![0x66, 0x6f, 0xff]).unwrap();
+        assert_eq!("fo\u{FFFD}", lossy);
+    }
+}
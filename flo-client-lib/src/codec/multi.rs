@@ -0,0 +1,184 @@
+use super::EventCodec;
+
+use std::any::Any;
+use std::error::Error;
+use std::fmt::{self, Debug, Display};
+
+use async::AsyncConnection;
+
+/// The type of data that's received and produced through a `MultiCodec`. Each successfully decoded body is boxed, and the
+/// caller downcasts it to the `EventData` type of the codec that's mapped to the event's namespace. Events that cannot be
+/// decoded are yielded as an `Err` instead of failing the whole consumer.
+pub type MultiCodecData = Result<Box<Any + Send>, MultiCodecError>;
+
+/// A connection that uses a `MultiCodec` to handle different event types in different namespaces
+pub type MultiCodecConnection = AsyncConnection<MultiCodecData>;
+
+/// Describes why the body of a single event could not be converted by a `MultiCodec`
+#[derive(Debug, Clone, PartialEq)]
+pub enum MultiCodecError {
+    /// No codec was mapped to a prefix of the event's namespace
+    UnmappedNamespace(String),
+    /// The codec that was mapped to the event's namespace returned an error
+    Codec {
+        namespace: String,
+        description: String,
+    },
+    /// The data given for a produced event was not the `EventData` type of the codec mapped to its namespace
+    WrongType(String),
+}
+
+impl Display for MultiCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MultiCodecError::UnmappedNamespace(ref namespace) => write!(f, "No codec is mapped for namespace: '{}'", namespace),
+            MultiCodecError::Codec{ref namespace, ref description} => write!(f, "Codec error for namespace: '{}': {}", namespace, description),
+            MultiCodecError::WrongType(ref namespace) => write!(f, "Event data has the wrong type for the codec mapped to namespace: '{}'", namespace),
+        }
+    }
+}
+
+impl Error for MultiCodecError {
+    fn description(&self) -> &str {
+        match *self {
+            MultiCodecError::UnmappedNamespace(_) => "no codec is mapped for namespace",
+            MultiCodecError::Codec{..} => "codec error",
+            MultiCodecError::WrongType(_) => "event data has the wrong type",
+        }
+    }
+}
+
+/// Erases the `EventData` type of a codec so that codecs with different types can be kept in the same `MultiCodec`
+trait AnyCodec {
+    fn convert_received(&self, namespace: &str, data: Vec<u8>) -> Result<Box<Any + Send>, Box<Error>>;
+    fn convert_produced(&self, namespace: &str, data: Box<Any + Send>) -> Result<Vec<u8>, MultiCodecError>;
+}
+
+struct TypedCodec<C: EventCodec>(C);
+
+impl <C> AnyCodec for TypedCodec<C> where C: EventCodec, C::EventData: Any + Send {
+    fn convert_received(&self, namespace: &str, data: Vec<u8>) -> Result<Box<Any + Send>, Box<Error>> {
+        self.0.convert_received(namespace, data).map(|body| Box::new(body) as Box<Any + Send>)
+    }
+
+    fn convert_produced(&self, namespace: &str, data: Box<Any + Send>) -> Result<Vec<u8>, MultiCodecError> {
+        let typed = data.downcast::<C::EventData>().map_err(|_| MultiCodecError::WrongType(namespace.to_owned()))?;
+        self.0.convert_produced(namespace, *typed).map_err(|err| {
+            MultiCodecError::Codec {
+                namespace: namespace.to_owned(),
+                description: err.to_string(),
+            }
+        })
+    }
+}
+
+/// A codec that dispatches to a different codec depending on the namespace of each event. Codecs are mapped to namespace
+/// prefixes, and the longest matching prefix wins. This allows a single connection to handle several event types.
+pub struct MultiCodec {
+    codecs: Vec<(String, Box<AnyCodec>)>,
+}
+
+impl MultiCodec {
+    pub fn new() -> MultiCodec {
+        MultiCodec {
+            codecs: Vec::new(),
+        }
+    }
+
+    /// Maps the given codec to all namespaces that start with `namespace_prefix`
+    pub fn with_codec<P, C>(mut self, namespace_prefix: P, codec: C) -> MultiCodec where P: Into<String>, C: EventCodec + 'static, C::EventData: Any + Send {
+        self.codecs.push((namespace_prefix.into(), Box::new(TypedCodec(codec)) as Box<AnyCodec>));
+        self
+    }
+
+    fn codec_for(&self, namespace: &str) -> Option<&AnyCodec> {
+        self.codecs.iter()
+                .filter(|&&(ref prefix, _)| namespace.starts_with(prefix.as_str()))
+                .max_by_key(|&&(ref prefix, _)| prefix.len())
+                .map(|&(_, ref codec)| codec.as_ref())
+    }
+}
+
+impl Debug for MultiCodec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let prefixes = self.codecs.iter().map(|&(ref prefix, _)| prefix.as_str()).collect::<Vec<&str>>();
+        write!(f, "MultiCodec{{ namespace_prefixes: {:?} }}", prefixes)
+    }
+}
+
+impl EventCodec for MultiCodec {
+    type EventData = MultiCodecData;
+
+    fn convert_received(&self, namespace: &str, data: Vec<u8>) -> Result<MultiCodecData, Box<Error>> {
+        let result = match self.codec_for(namespace) {
+            Some(codec) => {
+                codec.convert_received(namespace, data).map_err(|err| {
+                    MultiCodecError::Codec {
+                        namespace: namespace.to_owned(),
+                        description: err.to_string(),
+                    }
+                })
+            }
+            None => Err(MultiCodecError::UnmappedNamespace(namespace.to_owned()))
+        };
+        // errors are returned as the event data so that a single bad event does not stop the consumer
+        Ok(result)
+    }
+
+    fn convert_produced(&self, namespace: &str, data: MultiCodecData) -> Result<Vec<u8>, Box<Error>> {
+        let body = data.map_err(|err| Box::new(err) as Box<Error>)?;
+        let codec = self.codec_for(namespace).ok_or_else(|| {
+            Box::new(MultiCodecError::UnmappedNamespace(namespace.to_owned())) as Box<Error>
+        })?;
+        codec.convert_produced(namespace, body).map_err(|err| Box::new(err) as Box<Error>)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use codec::{StringCodec, RawCodec};
+
+    fn subject() -> MultiCodec {
+        MultiCodec::new()
+                .with_codec("/strings", StringCodec)
+                .with_codec("/strings/raw", RawCodec)
+    }
+
+    #[test]
+    fn received_events_are_decoded_with_codec_for_longest_matching_prefix() {
+        let codec = subject();
+
+        let result = codec.convert_received("/strings/foo", b"hello".to_vec()).unwrap().unwrap();
+        assert_eq!("hello", result.downcast_ref::<String>().unwrap());
+
+        let result = codec.convert_received("/strings/raw/foo", b"hello".to_vec()).unwrap().unwrap();
+        assert_eq!(&b"hello".to_vec(), result.downcast_ref::<Vec<u8>>().unwrap());
+    }
+
+    #[test]
+    fn unmapped_and_undecodable_events_are_returned_as_errors_in_the_event_data() {
+        let codec = subject();
+
+        let result = codec.convert_received("/other", b"hello".to_vec()).unwrap();
+        assert_eq!(Some(MultiCodecError::UnmappedNamespace("/other".to_owned())), result.err());
+
+        let result = codec.convert_received("/strings/foo", vec![0xff, 0xfe]).unwrap();
+        match result {
+            Err(MultiCodecError::Codec{namespace, ..}) => assert_eq!("/strings/foo", namespace),
+            other @ _ => panic!("expected codec error, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn produced_data_must_match_the_type_of_the_mapped_codec() {
+        let codec = subject();
+
+        let result = codec.convert_produced("/strings/foo", Ok(Box::new("hello".to_owned()))).unwrap();
+        assert_eq!(b"hello".to_vec(), result);
+
+        assert!(codec.convert_produced("/strings/foo", Ok(Box::new(5u32))).is_err());
+        assert!(codec.convert_produced("/other", Ok(Box::new("hello".to_owned()))).is_err());
+    }
+}
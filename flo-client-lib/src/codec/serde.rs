@@ -4,6 +4,7 @@ use serde::{Serialize, Deserialize};
 use serde_json::{Serializer as JsonSerializer, Deserializer as JsonDeserializer};
 use std::marker::PhantomData;
 use std::error::Error;
+use std::io;
 
 /// A codec that uses the serde_json library to automatically convert types that implement `serde::Serialize`
 /// and `serde::Deserialize`. It's very common for those traits to simply be derived, making it really easy to
@@ -23,9 +24,8 @@ impl <T: Serialize + Deserialize> SerdeJsonCodec<T> {
 impl <T> EventCodec for SerdeJsonCodec<T> where T: Serialize + Deserialize {
     type EventData = T;
 
-    fn convert_received(&self, _namespace: &str, data: Vec<u8>) -> Result<T, Box<Error>> {
-        let mut deser = JsonDeserializer::from_slice(&data);
-        T::deserialize(&mut deser).map_err(|e| Box::new(e) as Box<Error>)
+    fn convert_received(&self, namespace: &str, data: Vec<u8>) -> Result<T, Box<Error>> {
+        deserialize_json(namespace, &data)
     }
 
     fn convert_produced(&self, _namespace: &str, data: T) -> Result<Vec<u8>, Box<Error>> {
@@ -50,9 +50,8 @@ impl <T: Serialize + Deserialize> SerdePrettyJsonCodec<T> {
 impl <T> EventCodec for SerdePrettyJsonCodec<T> where T: Serialize + Deserialize {
     type EventData = T;
 
-    fn convert_received(&self, _namespace: &str, data: Vec<u8>) -> Result<T, Box<Error>> {
-        let mut deser = JsonDeserializer::from_slice(&data);
-        T::deserialize(&mut deser).map_err(|e| Box::new(e) as Box<Error>)
+    fn convert_received(&self, namespace: &str, data: Vec<u8>) -> Result<T, Box<Error>> {
+        deserialize_json(namespace, &data)
     }
 
     fn convert_produced(&self, _namespace: &str, data: T) -> Result<Vec<u8>, Box<Error>> {
@@ -61,3 +60,55 @@ impl <T> EventCodec for SerdePrettyJsonCodec<T> where T: Serialize + Deserialize
     }
 }
 
+
+/// Deserializes a received event body. Errors are returned as `InvalidData` and include the event's namespace, since the
+/// errors from serde_json on their own only describe the position in the body where parsing failed.
+fn deserialize_json<T: Deserialize>(namespace: &str, data: &[u8]) -> Result<T, Box<Error>> {
+    let mut deser = JsonDeserializer::from_slice(data);
+    T::deserialize(&mut deser).map_err(|e| {
+        let message = format!("Failed to deserialize JSON event body in namespace: '{}': {}", namespace, e);
+        Box::new(io::Error::new(io::ErrorKind::InvalidData, message)) as Box<Error>
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn serde_json_codec_round_trips_a_map() {
+        let codec = SerdeJsonCodec::<BTreeMap<String, Vec<u32>>>::new();
+        let mut input = BTreeMap::new();
+        input.insert("foo".to_owned(), vec![1, 2, 3]);
+        input.insert("bar".to_owned(), Vec::new());
+
+        let bytes = codec.convert_produced("/things", input.clone()).expect("failed to serialize");
+        assert_eq!(&b"{\"bar\":[],\"foo\":[1,2,3]}"[..], &bytes[..]);
+
+        let result = codec.convert_received("/things", bytes).expect("failed to deserialize");
+        assert_eq!(input, result);
+    }
+
+    #[test]
+    fn serde_pretty_json_codec_round_trips_a_map() {
+        let codec = SerdePrettyJsonCodec::<BTreeMap<String, u32>>::new();
+        let mut input = BTreeMap::new();
+        input.insert("foo".to_owned(), 7);
+
+        let bytes = codec.convert_produced("/things", input.clone()).expect("failed to serialize");
+        assert!(bytes.contains(&b'\n'));
+
+        let result = codec.convert_received("/things", bytes).expect("failed to deserialize");
+        assert_eq!(input, result);
+    }
+
+    #[test]
+    fn serde_json_codec_returns_error_when_received_json_is_malformed() {
+        let codec = SerdeJsonCodec::<BTreeMap<String, u32>>::new();
+
+        let err = codec.convert_received("/things", b"{\"foo\": 7".to_vec()).unwrap_err();
+        let message = err.to_string();
+        assert!(message.starts_with("Failed to deserialize JSON event body in namespace: '/things': "), "message was: {}", message);
+    }
+}
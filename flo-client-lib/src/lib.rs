@@ -35,8 +35,9 @@ pub use event::{
 pub const ALL_EVENTS_GLOB: &'static str = "/**/*";
 
 /// An event that can be received by a `Consumer`. It is parameterized on the type of the body, which will be determined
-/// by the `EventCodec` used.
-#[derive(Debug, PartialEq, Clone)]
+/// by the `EventCodec` used. Like `OwnedFloEvent`, events are hashed by their `id` alone, regardless of the type of body,
+/// so redelivered events from a single stream can be deduped with a `HashSet`.
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Event<T> {
     pub id: FloEventId,
     pub parent_id: Option<FloEventId>,
@@ -44,3 +45,9 @@ pub struct Event<T> {
     pub namespace: String,
     pub data: T
 }
+
+impl <T> ::std::hash::Hash for Event<T> {
+    fn hash<H: ::std::hash::Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
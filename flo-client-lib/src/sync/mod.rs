@@ -8,7 +8,7 @@ use futures::{Future, Stream};
 
 use event::{FloEventId, ActorId, VersionVector};
 use async::{AsyncConnection, tcp_connect_with};
-use async::ops::{ProduceErr, Consume, ConsumeError};
+use async::ops::{ProduceErr, Consume, ConsumeError, BatchProduceResult};
 use codec::EventCodec;
 use ::Event;
 
@@ -113,6 +113,25 @@ impl <D: Debug + 'static> SyncConnection<D> {
         self.produce(to_produce)
     }
 
+    /// Produces all of the given events, sending them to the server back-to-back instead of waiting for each one to be
+    /// acknowledged before sending the next. Returns the result of producing each event, in the same order as `events`. An
+    /// error producing one event does not prevent the others from being produced, so the outer `Result` is only an `Err`
+    /// if there was a problem with the connection itself.
+    pub fn produce_batch(&mut self, events: Vec<EventToProduce<D>>) -> Result<Vec<BatchProduceResult>, ErrorType> {
+        let conn = self.async_connection.take().unwrap();
+        let result = run_future(conn.produce_batch(events));
+        match result {
+            Ok((results, conn)) => {
+                self.async_connection = Some(conn);
+                Ok(results)
+            }
+            Err(ProduceErr {connection, err}) => {
+                self.async_connection = Some(connection);
+                Err(err)
+            }
+        }
+    }
+
     /// Use this connection to consume events from the server. The returned value implements `Iterator`
     /// where the associated `Item` is `Result<Event<D>, ErrorType>`.
     ///
@@ -45,6 +45,7 @@ pub use version_vec::VersionVector;
 use std::cmp::{Ord, PartialOrd, Ordering};
 use std::fmt::{self, Display, Debug};
 use std::str::FromStr;
+use std::hash::{Hash, Hasher};
 
 use chrono::{DateTime, UTC};
 
@@ -76,26 +77,30 @@ impl Display for FloEventId {
     }
 }
 
+/// Parses the same "counter.actor" form that `Display` produces. Both parts must be plain decimal numbers, so inputs like
+/// "+5.3" or " 5.3" are rejected even though the numbers themselves could be parsed
 impl FromStr for FloEventId {
     type Err = &'static str;
 
     fn from_str(input: &str) -> Result<Self, Self::Err> {
-        let err_message = "FloEventId must be an event counter and actor id separated by a single '.'";
-
-        if let Some(dot_index) = input.find('.') {
-            println!("dot index: {}", dot_index);
-            let (counter, actor) = input.split_at(dot_index);
-            counter.parse::<EventCounter>().and_then(|c| {
-                (&actor[1..]).parse::<ActorId>().map(|a| {
-                    FloEventId::new(a, c)
-                })
-            }).map_err(|_| err_message)
-        } else {
-            Err(err_message)
+        let err_message = "FloEventId must be an event counter and actor id separated by a single '.', for example: '5.3'";
+
+        let dot_index = input.find('.').ok_or(err_message)?;
+        let (counter, actor) = (&input[..dot_index], &input[(dot_index + 1)..]);
+        if !is_decimal(counter) || !is_decimal(actor) {
+            return Err(err_message);
         }
+        // the digits may still be out of range for their types
+        let counter = counter.parse::<EventCounter>().map_err(|_| err_message)?;
+        let actor = actor.parse::<ActorId>().map_err(|_| err_message)?;
+        Ok(FloEventId::new(actor, counter))
     }
 }
 
+fn is_decimal(input: &str) -> bool {
+    !input.is_empty() && input.bytes().all(|b| b.is_ascii_digit())
+}
+
 #[cfg(test)]
 mod event_id_test {
     use super::*;
@@ -129,6 +134,35 @@ mod event_id_test {
         let result = FloEventId::from_str(input).unwrap();
         assert_eq!(FloEventId::new(2, 8), result);
     }
+
+    #[test]
+    fn counter_comes_before_actor_when_parsing() {
+        let result: FloEventId = "5.3".parse().unwrap();
+        assert_eq!(FloEventId::new(3, 5), result);
+        assert_eq!(3, result.actor);
+        assert_eq!(5, result.event_counter);
+    }
+
+    #[test]
+    fn flo_event_id_round_trips_through_its_string_form() {
+        let ids = vec![
+            FloEventId::new(3, 5),
+            FloEventId::new(1, 1),
+            ZERO_EVENT_ID,
+            MAX_EVENT_ID,
+        ];
+        for id in ids {
+            assert_eq!(Ok(id), id.to_string().parse::<FloEventId>());
+        }
+    }
+
+    #[test]
+    fn from_str_rejects_malformed_input() {
+        let inputs = vec!["", ".", "5..3", "5.3.1", "a.3", "5.b", "+5.3", "5.-3", " 5.3", "5.3 ", "5.65536", "18446744073709551616.1"];
+        for input in inputs {
+            assert!(FloEventId::from_str(input).is_err(), "expected '{}' to be rejected", input);
+        }
+    }
 }
 
 pub const ZERO_EVENT_ID: FloEventId = FloEventId{event_counter: 0, actor: 0};
@@ -202,6 +236,12 @@ pub trait FloEvent: Debug {
     fn data_len(&self) -> u32;
     /// Returns the arbitrary binary data associated with this event.
     fn data(&self) -> &[u8];
+    /// Events may optionally be given a time to live when they're produced, in which case this returns the time after which
+    /// the event will no longer be delivered to consumers. Most events never expire on their own, and are only removed
+    /// once the event stream's retention period has passed.
+    fn expiration(&self) -> Option<Timestamp> {
+        None
+    }
     /// Converts this event into an `OwnedFloEvent`, cloning it in the process.
     fn to_owned(&self) -> OwnedFloEvent {
         let id = *self.id();
@@ -248,7 +288,11 @@ impl <T> FloEvent for T where T: AsRef<OwnedFloEvent> + Debug {
 
 /// This is the main `FloEvent` implementation that clients will deal with. All of the fields returned by the `FloEvent`
 /// methods are simply owned fields in this struct.
-#[derive(Debug, PartialEq, Clone)]
+///
+/// Events are hashed by their `id` alone. Ids are unique within an event stream, so two copies of the same event are
+/// always equal and have the same hash, which lets consumers dedup events that were delivered more than once by using a
+/// `HashSet` or `HashMap`. Events from different streams may share ids, though, so they shouldn't be mixed in one set.
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct OwnedFloEvent {
     pub id: FloEventId,
     pub timestamp: Timestamp,
@@ -269,6 +313,12 @@ impl OwnedFloEvent {
     }
 }
 
+impl Hash for OwnedFloEvent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
 impl FloEvent for OwnedFloEvent {
     fn id(&self) -> &FloEventId {
         &self.id
@@ -297,3 +347,48 @@ impl FloEvent for OwnedFloEvent {
         self.timestamp
     }
 }
+
+#[cfg(test)]
+mod owned_event_test {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    fn event(actor: ActorId, counter: EventCounter) -> OwnedFloEvent {
+        OwnedFloEvent::new(FloEventId::new(actor, counter), None, time::from_millis_since_epoch(1_000), "/foo".to_owned(), vec![1, 2, 3])
+    }
+
+    #[test]
+    fn events_delivered_more_than_once_are_deduped_by_a_hash_set() {
+        let mut set = HashSet::new();
+        assert!(set.insert(event(1, 1)));
+        assert!(set.insert(event(1, 2)));
+        assert!(set.insert(event(2, 2)));
+        assert!(!set.insert(event(1, 1)));
+        assert!(!set.insert(event(1, 2).clone()));
+        assert_eq!(3, set.len());
+
+        // shared references to events can be used the same way
+        let mut shared = HashSet::new();
+        assert!(shared.insert(Arc::new(event(1, 1))));
+        assert!(!shared.insert(Arc::new(event(1, 1))));
+        assert!(shared.contains(&Arc::new(event(1, 1))));
+    }
+
+    #[test]
+    fn events_with_the_same_id_have_the_same_hash() {
+        use std::collections::hash_map::DefaultHasher;
+
+        fn hash_of<T: Hash>(value: &T) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            value.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        let original = event(3, 4);
+        let mut copy = original.clone();
+        copy.data = Vec::new();
+        assert_eq!(hash_of(&original), hash_of(&copy));
+        assert_eq!(hash_of(&original), hash_of(&Arc::new(original.clone())));
+    }
+}
@@ -0,0 +1,1191 @@
+//! An alternative framing for the protocol, where each `ProtocolMessage` is encoded as a single CBOR (RFC 7049) map. This
+//! lets clients in other languages use any off-the-shelf CBOR library instead of reimplementing the binary format and its
+//! parsers. It's a parallel serialization path, not a replacement. The binary format is still the default, and it's the
+//! only one used by the rust client.
+//!
+//! A client selects CBOR framing by wrapping its `announce` message in the self-describe CBOR tag (55799), so the first
+//! three bytes on the connection are `0xd9 0xd9 0xf7`, which can never be the start of a binary message. The server then
+//! uses CBOR for every message in both directions for the rest of the connection. Only the first message needs the tag,
+//! but tags are ignored wherever they appear.
+//!
+//! Every message is a map with a `"type"` key that holds the name of the message, as returned by `message_type`. The other
+//! keys are the fields of the corresponding struct, using the same names. Keys that aren't recognized are ignored.
+//!
+//! - Event ids are a two element array of `[actor, counter]`, and the zero id is `[0, 0]`
+//! - Optional values are `null` when they're absent
+//! - Timestamps are milliseconds since the unix epoch, and a `ttl` is a number of milliseconds
+//! - Event data is a byte string. All other strings are text strings
+//! - `ErrorKind`, `IntegrityProblemKind`, `Compression`, and `ReadConsistency` are encoded as their u8 values. A missing
+//!   `compression` means none, and a missing `read_consistency` means local. A missing `cursor_op_ids`, `start_at_tail`,
+//!   `snapshot`, or `trace` means false, a missing `see_own_writes` means true, and a missing `idle_signal_interval`
+//!   means none
+//! - The `stages` of a `trace` are an array of maps with the keys `stage`, which is the u8 value of the `TraceStage`, and
+//!   `elapsed_micros`
+//! - Events in `receive_event` and `receive_event_prefix` messages are a map under the `"event"` key, with the keys `id`,
+//!   `parent_id`, `timestamp`, `namespace`, and `data`
+//! - Messages that only have an op_id, like `list_streams`, have just the `op_id` key. `set_batch_size` has `batch_size`,
+//!   and `next_batch`, `end_of_batch`, and `awaiting_events` have no other keys
+//! - A `cursor_message` has the cursor's `op_id`, and the wrapped message as a nested map under the `"message"` key
+//!
+//! Only definite lengths are supported, and floating point values are rejected, since no message uses them.
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+use event::{time, OwnedFloEvent, FloEvent, FloEventId};
+use client::*;
+
+/// The self-describe CBOR tag, as it's encoded at the start of a CBOR connection
+pub const SELF_DESCRIBE_PREFIX: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+/// Limits how deeply arrays and maps may be nested, so that a malicious message can't overflow the stack
+const MAX_NESTING_DEPTH: usize = 16;
+
+const MAJOR_UNSIGNED: u8 = 0;
+const MAJOR_NEGATIVE: u8 = 1;
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_ARRAY: u8 = 4;
+const MAJOR_MAP: u8 = 5;
+const MAJOR_TAG: u8 = 6;
+const MAJOR_SIMPLE: u8 = 7;
+
+const SIMPLE_FALSE: u8 = 20;
+const SIMPLE_TRUE: u8 = 21;
+const SIMPLE_NULL: u8 = 22;
+const SIMPLE_UNDEFINED: u8 = 23;
+
+/// The subset of the CBOR data model that's used by the protocol
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    Unsigned(u64),
+    /// A negative integer, holding the CBOR argument `n`, which represents the value `-1 - n`
+    Negative(u64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Value>),
+    /// Entries are kept in the order they were encoded in
+    Map(Vec<(Value, Value)>),
+    Bool(bool),
+    /// Both `null` and `undefined` are decoded as `Null`
+    Null,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum CborError {
+    /// More bytes are required in order to decode the complete value
+    Incomplete,
+    /// The bytes are not valid CBOR, or they use a feature that isn't supported, like indefinite lengths
+    Invalid(String),
+    /// The bytes are valid CBOR, but don't match the schema of any `ProtocolMessage`
+    Schema(String),
+}
+
+/// Returns the name of the message, as used for the `"type"` key
+pub fn message_type<E: FloEvent>(message: &ProtocolMessage<E>) -> &'static str {
+    match *message {
+        ProtocolMessage::Announce(_) => "announce",
+        ProtocolMessage::StreamStatus(_) => "stream_status",
+        ProtocolMessage::SetEventStream(_) => "set_event_stream",
+        ProtocolMessage::ProduceEvent(_) => "produce_event",
+        ProtocolMessage::ReceiveEvent(_) => "receive_event",
+        ProtocolMessage::ReceiveEventPrefix(_, _) => "receive_event_prefix",
+        ProtocolMessage::AckEvent(_) => "ack_event",
+        ProtocolMessage::NewStartConsuming(_) => "new_start_consuming",
+        ProtocolMessage::CursorCreated(_) => "cursor_created",
+        ProtocolMessage::StopConsuming(_) => "stop_consuming",
+        ProtocolMessage::SetBatchSize(_) => "set_batch_size",
+        ProtocolMessage::NextBatch => "next_batch",
+        ProtocolMessage::EndOfBatch => "end_of_batch",
+        ProtocolMessage::AwaitingEvents => "awaiting_events",
+        ProtocolMessage::Error(_) => "error",
+        ProtocolMessage::TruncateStream(_) => "truncate_stream",
+        ProtocolMessage::ListStreams(_) => "list_streams",
+        ProtocolMessage::StreamList(_) => "stream_list",
+        ProtocolMessage::PauseWrites(_) => "pause_writes",
+        ProtocolMessage::ResumeWrites(_) => "resume_writes",
+        ProtocolMessage::GetCapabilities(_) => "get_capabilities",
+        ProtocolMessage::Capabilities(_) => "capabilities",
+        ProtocolMessage::ListTags(_) => "list_tags",
+        ProtocolMessage::GetTag(_) => "get_tag",
+        ProtocolMessage::TagList(_) => "tag_list",
+        ProtocolMessage::VerifyStream(_) => "verify_stream",
+        ProtocolMessage::IntegrityReport(_) => "integrity_report",
+        ProtocolMessage::GetAncestry(_) => "get_ancestry",
+        ProtocolMessage::Ancestry(_) => "ancestry",
+        ProtocolMessage::CountEvents(_) => "count_events",
+        ProtocolMessage::GetConsumerLag(_) => "get_consumer_lag",
+        ProtocolMessage::CountResult(_) => "count_result",
+        ProtocolMessage::Heartbeat(_) => "heartbeat",
+        ProtocolMessage::GetStreamStatus(_) => "get_stream_status",
+        ProtocolMessage::CursorMessage(_, _) => "cursor_message",
+        ProtocolMessage::Trace(_) => "trace",
+        ProtocolMessage::StreamIdle(_) => "stream_idle",
+    }
+}
+
+/// Encodes the complete message, including any event data, as a single CBOR map
+pub fn encode<E: FloEvent>(message: &ProtocolMessage<E>) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(64 + message.get_body().map(|body| body.len()).unwrap_or(0));
+    write_value(&to_value(message), &mut buffer);
+    buffer
+}
+
+/// Decodes a single message from the start of `bytes`, returning the message along with the number of bytes it used.
+/// Returns `CborError::Incomplete` if `bytes` doesn't yet hold the entire message.
+pub fn decode(bytes: &[u8]) -> Result<(ProtocolMessage<OwnedFloEvent>, usize), CborError> {
+    let (value, len) = read_value(bytes)?;
+    from_value(&value).map(|message| (message, len))
+}
+
+/// Appends the encoded value to the buffer. Integers always use the shortest possible encoding
+pub fn write_value(value: &Value, buffer: &mut Vec<u8>) {
+    match *value {
+        Value::Unsigned(n) => write_head(MAJOR_UNSIGNED, n, buffer),
+        Value::Negative(n) => write_head(MAJOR_NEGATIVE, n, buffer),
+        Value::Bytes(ref bytes) => {
+            write_head(MAJOR_BYTES, bytes.len() as u64, buffer);
+            buffer.extend_from_slice(bytes);
+        }
+        Value::Text(ref text) => {
+            write_head(MAJOR_TEXT, text.len() as u64, buffer);
+            buffer.extend_from_slice(text.as_bytes());
+        }
+        Value::Array(ref items) => {
+            write_head(MAJOR_ARRAY, items.len() as u64, buffer);
+            for item in items.iter() {
+                write_value(item, buffer);
+            }
+        }
+        Value::Map(ref entries) => {
+            write_head(MAJOR_MAP, entries.len() as u64, buffer);
+            for &(ref key, ref value) in entries.iter() {
+                write_value(key, buffer);
+                write_value(value, buffer);
+            }
+        }
+        Value::Bool(false) => write_head(MAJOR_SIMPLE, SIMPLE_FALSE as u64, buffer),
+        Value::Bool(true) => write_head(MAJOR_SIMPLE, SIMPLE_TRUE as u64, buffer),
+        Value::Null => write_head(MAJOR_SIMPLE, SIMPLE_NULL as u64, buffer),
+    }
+}
+
+/// Reads a single value from the start of `bytes`, returning the value along with the number of bytes it used
+pub fn read_value(bytes: &[u8]) -> Result<(Value, usize), CborError> {
+    read_nested_value(bytes, 0)
+}
+
+fn write_head(major: u8, argument: u64, buffer: &mut Vec<u8>) {
+    let major = major << 5;
+    // writes to a Vec can't fail
+    if argument < 24 {
+        buffer.push(major | argument as u8);
+    } else if argument <= ::std::u8::MAX as u64 {
+        buffer.push(major | 24);
+        buffer.push(argument as u8);
+    } else if argument <= ::std::u16::MAX as u64 {
+        buffer.push(major | 25);
+        buffer.write_u16::<BigEndian>(argument as u16).unwrap();
+    } else if argument <= ::std::u32::MAX as u64 {
+        buffer.push(major | 26);
+        buffer.write_u32::<BigEndian>(argument as u32).unwrap();
+    } else {
+        buffer.push(major | 27);
+        buffer.write_u64::<BigEndian>(argument).unwrap();
+    }
+}
+
+/// Reads the initial byte and argument of a data item, returning the major type, the argument, and the number of bytes used
+fn read_head(bytes: &[u8]) -> Result<(u8, u64, usize), CborError> {
+    let initial = *bytes.first().ok_or(CborError::Incomplete)?;
+    let major = initial >> 5;
+    let info = initial & 0x1f;
+
+    let argument_len = match info {
+        n if n < 24 => return Ok((major, n as u64, 1)),
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        other => return Err(CborError::Invalid(format!("Unsupported additional info: {} for major type: {}", other, major)))
+    };
+    if bytes.len() < 1 + argument_len {
+        return Err(CborError::Incomplete);
+    }
+    let argument = BigEndian::read_uint(&bytes[1..(1 + argument_len)], argument_len);
+    Ok((major, argument, 1 + argument_len))
+}
+
+fn read_nested_value(bytes: &[u8], depth: usize) -> Result<(Value, usize), CborError> {
+    if depth > MAX_NESTING_DEPTH {
+        return Err(CborError::Invalid(format!("Values may not be nested more than {} levels deep", MAX_NESTING_DEPTH)));
+    }
+
+    let (major, argument, head_len) = read_head(bytes)?;
+    match major {
+        MAJOR_UNSIGNED => Ok((Value::Unsigned(argument), head_len)),
+        MAJOR_NEGATIVE => Ok((Value::Negative(argument), head_len)),
+        MAJOR_BYTES => {
+            let data = read_data(bytes, head_len, argument)?;
+            Ok((Value::Bytes(data.to_vec()), head_len + data.len()))
+        }
+        MAJOR_TEXT => {
+            let data = read_data(bytes, head_len, argument)?;
+            let text = ::std::str::from_utf8(data).map_err(|err| {
+                CborError::Invalid(format!("Text string is not valid utf-8: {}", err))
+            })?;
+            Ok((Value::Text(text.to_owned()), head_len + data.len()))
+        }
+        MAJOR_ARRAY => {
+            // the capacity isn't taken from the argument, since it hasn't been checked against the number of bytes yet
+            let mut items = Vec::new();
+            let mut position = head_len;
+            for _ in 0..argument {
+                let (item, len) = read_nested_value(&bytes[position..], depth + 1)?;
+                items.push(item);
+                position += len;
+            }
+            Ok((Value::Array(items), position))
+        }
+        MAJOR_MAP => {
+            let mut entries = Vec::new();
+            let mut position = head_len;
+            for _ in 0..argument {
+                let (key, key_len) = read_nested_value(&bytes[position..], depth + 1)?;
+                position += key_len;
+                let (value, value_len) = read_nested_value(&bytes[position..], depth + 1)?;
+                position += value_len;
+                entries.push((key, value));
+            }
+            Ok((Value::Map(entries), position))
+        }
+        MAJOR_TAG => {
+            // tags, including the self-describe tag, don't change the meaning of any values in the protocol
+            let (value, len) = read_nested_value(&bytes[head_len..], depth + 1)?;
+            Ok((value, head_len + len))
+        }
+        _ => {
+            match argument {
+                n if n == SIMPLE_FALSE as u64 && head_len == 1 => Ok((Value::Bool(false), head_len)),
+                n if n == SIMPLE_TRUE as u64 && head_len == 1 => Ok((Value::Bool(true), head_len)),
+                n if (n == SIMPLE_NULL as u64 || n == SIMPLE_UNDEFINED as u64) && head_len == 1 => Ok((Value::Null, head_len)),
+                other => Err(CborError::Invalid(format!("Unsupported simple or floating point value: {}", other)))
+            }
+        }
+    }
+}
+
+fn read_data(bytes: &[u8], head_len: usize, data_len: u64) -> Result<&[u8], CborError> {
+    let available = (bytes.len() - head_len) as u64;
+    if data_len > available {
+        Err(CborError::Incomplete)
+    } else {
+        Ok(&bytes[head_len..(head_len + data_len as usize)])
+    }
+}
+
+
+fn to_value<E: FloEvent>(message: &ProtocolMessage<E>) -> Value {
+    let fields = match *message {
+        ProtocolMessage::Announce(ref announce) => vec![
+            ("protocol_version", uint(announce.protocol_version)),
+            ("op_id", uint(announce.op_id)),
+            ("client_name", text(&announce.client_name)),
+            ("consume_batch_size", optional(announce.consume_batch_size.map(uint))),
+        ],
+        ProtocolMessage::StreamStatus(ref status) => vec![
+            ("op_id", uint(status.op_id)),
+            ("name", text(&status.name)),
+            ("partitions", Value::Array(status.partitions.iter().map(|partition| {
+                map(vec![
+                    ("partition_num", uint(partition.partition_num)),
+                    ("head", uint(partition.head)),
+                    ("primary", Value::Bool(partition.primary)),
+                ])
+            }).collect())),
+        ],
+        ProtocolMessage::SetEventStream(ref set_stream) => vec![
+            ("op_id", uint(set_stream.op_id)),
+            ("name", text(&set_stream.name)),
+        ],
+        ProtocolMessage::ProduceEvent(ref produce) => vec![
+            ("op_id", uint(produce.op_id)),
+            ("partition", uint(produce.partition)),
+            ("namespace", text(&produce.namespace)),
+            ("parent_id", optional(produce.parent_id.map(event_id))),
+            ("ttl", optional(produce.ttl.map(|ttl| uint(duration_millis(ttl))))),
+            ("compression", uint(produce.compression.u8_value())),
+            ("trace", Value::Bool(produce.trace)),
+            ("data", Value::Bytes(produce.data.clone())),
+        ],
+        ProtocolMessage::ReceiveEvent(ref event) => vec![
+            ("event", event_value(event)),
+        ],
+        ProtocolMessage::ReceiveEventPrefix(ref event, total_data_len) => vec![
+            ("event", event_value(event)),
+            ("total_data_len", uint(total_data_len)),
+        ],
+        ProtocolMessage::AckEvent(ref ack) => vec![
+            ("op_id", uint(ack.op_id)),
+            ("event_id", event_id(ack.event_id)),
+        ],
+        ProtocolMessage::NewStartConsuming(ref start) => vec![
+            ("op_id", uint(start.op_id)),
+            ("version_vector", event_ids(&start.version_vector)),
+            ("max_events", uint(start.max_events)),
+            ("namespace", text(&start.namespace)),
+            ("body_prefix_bytes", optional(start.body_prefix_bytes.map(uint))),
+            ("start_tag", optional(start.start_tag.as_ref().map(|tag| text(tag)))),
+            ("max_delivery_rate", optional(start.max_delivery_rate.map(uint))),
+            ("namespace_regex", optional(start.namespace_regex.as_ref().map(|regex| text(regex)))),
+            ("error_on_empty", Value::Bool(start.error_on_empty)),
+            ("unlimited_lifetime", Value::Bool(start.unlimited_lifetime)),
+            ("read_consistency", uint(start.read_consistency.u8_value())),
+            ("cursor_op_ids", Value::Bool(start.cursor_op_ids)),
+            ("start_at_tail", Value::Bool(start.start_at_tail)),
+            ("snapshot", Value::Bool(start.snapshot)),
+            ("idle_signal_interval", optional(start.idle_signal_interval.map(uint))),
+            ("see_own_writes", Value::Bool(start.see_own_writes)),
+            ("trace", Value::Bool(start.trace)),
+        ],
+        ProtocolMessage::CursorCreated(ref info) => vec![
+            ("op_id", uint(info.op_id)),
+            ("batch_size", uint(info.batch_size)),
+        ],
+        ProtocolMessage::SetBatchSize(batch_size) => vec![
+            ("batch_size", uint(batch_size)),
+        ],
+        ProtocolMessage::NextBatch | ProtocolMessage::EndOfBatch | ProtocolMessage::AwaitingEvents => Vec::new(),
+        ProtocolMessage::Error(ref error) => vec![
+            ("op_id", uint(error.op_id)),
+            ("kind", uint(error.kind.u8_value())),
+            ("description", text(&error.description)),
+        ],
+        ProtocolMessage::TruncateStream(ref truncate) => vec![
+            ("op_id", uint(truncate.op_id)),
+            ("name", text(&truncate.name)),
+            ("up_to", event_id(truncate.up_to)),
+        ],
+        ProtocolMessage::StopConsuming(op_id) |
+        ProtocolMessage::ListStreams(op_id) |
+        ProtocolMessage::PauseWrites(op_id) |
+        ProtocolMessage::ResumeWrites(op_id) |
+        ProtocolMessage::GetCapabilities(op_id) |
+        ProtocolMessage::ListTags(op_id) => vec![
+            ("op_id", uint(op_id)),
+        ],
+        ProtocolMessage::StreamList(ref list) => vec![
+            ("op_id", uint(list.op_id)),
+            ("streams", Value::Array(list.streams.iter().map(|stream| {
+                map(vec![
+                    ("name", text(&stream.name)),
+                    ("event_count", uint(stream.event_count)),
+                    ("head", event_id(stream.head)),
+                    ("tail", event_id(stream.tail)),
+                    ("writable", Value::Bool(stream.writable)),
+                    ("stored_bytes", uint(stream.stored_bytes)),
+                ])
+            }).collect())),
+        ],
+        ProtocolMessage::Capabilities(ref capabilities) => vec![
+            ("op_id", uint(capabilities.op_id)),
+            ("flags", uint(capabilities.flags)),
+            ("features", Value::Array(capabilities.features.iter().map(|feature| text(feature)).collect())),
+        ],
+        ProtocolMessage::GetTag(ref get_tag) => vec![
+            ("op_id", uint(get_tag.op_id)),
+            ("name", text(&get_tag.name)),
+        ],
+        ProtocolMessage::TagList(ref list) => vec![
+            ("op_id", uint(list.op_id)),
+            ("tags", Value::Array(list.tags.iter().map(|tag| {
+                map(vec![
+                    ("name", text(&tag.name)),
+                    ("event_id", event_id(tag.event_id)),
+                ])
+            }).collect())),
+        ],
+        ProtocolMessage::VerifyStream(ref verify) => vec![
+            ("op_id", uint(verify.op_id)),
+            ("name", text(&verify.name)),
+            ("version_vector", event_ids(&verify.version_vector)),
+            ("max_events", uint(verify.max_events)),
+            ("check_parents", Value::Bool(verify.check_parents)),
+        ],
+        ProtocolMessage::IntegrityReport(ref report) => vec![
+            ("op_id", uint(report.op_id)),
+            ("stream", text(&report.stream)),
+            ("partitions", Value::Array(report.partitions.iter().map(|partition| {
+                map(vec![
+                    ("partition_num", uint(partition.partition_num)),
+                    ("events_checked", uint(partition.events_checked)),
+                    ("last_verified", event_id(partition.last_verified)),
+                    ("complete", Value::Bool(partition.complete)),
+                    ("problems", Value::Array(partition.problems.iter().map(|problem| {
+                        map(vec![
+                            ("kind", uint(problem.kind.u8_value())),
+                            ("event_id", event_id(problem.event_id)),
+                            ("description", text(&problem.description)),
+                        ])
+                    }).collect())),
+                ])
+            }).collect())),
+        ],
+        ProtocolMessage::GetAncestry(ref get_ancestry) => vec![
+            ("op_id", uint(get_ancestry.op_id)),
+            ("event_id", event_id(get_ancestry.event_id)),
+        ],
+        ProtocolMessage::Ancestry(ref info) => vec![
+            ("op_id", uint(info.op_id)),
+            ("event_count", uint(info.event_count)),
+        ],
+        ProtocolMessage::CountEvents(ref count) => vec![
+            ("op_id", uint(count.op_id)),
+            ("namespace", text(&count.namespace)),
+            ("since", optional(count.since.map(event_id))),
+        ],
+        ProtocolMessage::GetConsumerLag(ref get_lag) => vec![
+            ("op_id", uint(get_lag.op_id)),
+            ("namespace", text(&get_lag.namespace)),
+            ("version_vector", event_ids(&get_lag.version_vector)),
+        ],
+        ProtocolMessage::CountResult(ref result) => vec![
+            ("op_id", uint(result.op_id)),
+            ("count", uint(result.count)),
+        ],
+        ProtocolMessage::Heartbeat(ref heartbeat) => vec![
+            ("op_id", uint(heartbeat.op_id)),
+            ("timestamp", uint(time::millis_since_epoch(heartbeat.timestamp))),
+        ],
+        ProtocolMessage::GetStreamStatus(ref get_status) => vec![
+            ("op_id", uint(get_status.op_id)),
+            ("name", text(&get_status.name)),
+        ],
+        ProtocolMessage::CursorMessage(op_id, ref message) => vec![
+            ("op_id", uint(op_id)),
+            ("message", to_value(message)),
+        ],
+        ProtocolMessage::Trace(ref trace) => vec![
+            ("op_id", uint(trace.op_id)),
+            ("stages", Value::Array(trace.stages.iter().map(|timing| {
+                map(vec![
+                    ("stage", uint(timing.stage.u8_value())),
+                    ("elapsed_micros", uint(timing.elapsed_micros)),
+                ])
+            }).collect())),
+        ],
+        ProtocolMessage::StreamIdle(ref idle) => vec![
+            ("op_id", uint(idle.op_id)),
+            ("tail_id", event_id(idle.tail_id)),
+            ("server_time", uint(time::millis_since_epoch(idle.server_time))),
+        ],
+    };
+
+    let mut entries = Vec::with_capacity(fields.len() + 1);
+    entries.push((text("type"), text(message_type(message))));
+    entries.extend(fields.into_iter().map(|(key, value)| (text(key), value)));
+    Value::Map(entries)
+}
+
+fn from_value(value: &Value) -> Result<ProtocolMessage<OwnedFloEvent>, CborError> {
+    let fields = Fields::from_value(value, "message")?;
+    let message_type = fields.string("type")?;
+
+    let message = match message_type.as_str() {
+        "announce" => ProtocolMessage::Announce(ClientAnnounce {
+            protocol_version: fields.u32("protocol_version")?,
+            op_id: fields.u32("op_id")?,
+            client_name: fields.string("client_name")?,
+            consume_batch_size: fields.optional("consume_batch_size", as_u32)?,
+        }),
+        "stream_status" => ProtocolMessage::StreamStatus(EventStreamStatus {
+            op_id: fields.u32("op_id")?,
+            name: fields.string("name")?,
+            partitions: fields.array("partitions", |value, key| {
+                let partition = Fields::from_value(value, key)?;
+                Ok(PartitionStatus {
+                    partition_num: partition.u16("partition_num")?,
+                    head: partition.u64("head")?,
+                    primary: partition.bool("primary")?,
+                })
+            })?,
+        }),
+        "set_event_stream" => ProtocolMessage::SetEventStream(SetEventStream {
+            op_id: fields.u32("op_id")?,
+            name: fields.string("name")?,
+        }),
+        "produce_event" => ProtocolMessage::ProduceEvent(ProduceEvent {
+            op_id: fields.u32("op_id")?,
+            partition: fields.u16("partition")?,
+            namespace: fields.string("namespace")?,
+            parent_id: fields.optional("parent_id", as_event_id)?,
+            ttl: fields.optional("ttl", as_u64)?.and_then(ttl_from_millis),
+            compression: fields.optional("compression", as_compression)?.unwrap_or(Compression::None),
+            trace: fields.optional("trace", as_bool)?.unwrap_or(false),
+            data: fields.bytes("data")?,
+        }),
+        "receive_event" => ProtocolMessage::ReceiveEvent(fields.event("event")?),
+        "receive_event_prefix" => ProtocolMessage::ReceiveEventPrefix(fields.event("event")?, fields.u32("total_data_len")?),
+        "ack_event" => ProtocolMessage::AckEvent(EventAck {
+            op_id: fields.u32("op_id")?,
+            event_id: fields.event_id("event_id")?,
+        }),
+        "new_start_consuming" => ProtocolMessage::NewStartConsuming(NewConsumerStart {
+            op_id: fields.u32("op_id")?,
+            version_vector: fields.array("version_vector", as_event_id)?,
+            max_events: fields.u64("max_events")?,
+            namespace: fields.string("namespace")?,
+            body_prefix_bytes: fields.optional("body_prefix_bytes", as_u32)?,
+            start_tag: fields.optional("start_tag", as_string)?,
+            max_delivery_rate: fields.optional("max_delivery_rate", as_u32)?,
+            namespace_regex: fields.optional("namespace_regex", as_string)?,
+            error_on_empty: fields.bool("error_on_empty")?,
+            unlimited_lifetime: fields.bool("unlimited_lifetime")?,
+            read_consistency: fields.optional("read_consistency", as_read_consistency)?.unwrap_or(ReadConsistency::Local),
+            cursor_op_ids: fields.optional("cursor_op_ids", as_bool)?.unwrap_or(false),
+            start_at_tail: fields.optional("start_at_tail", as_bool)?.unwrap_or(false),
+            snapshot: fields.optional("snapshot", as_bool)?.unwrap_or(false),
+            idle_signal_interval: fields.optional("idle_signal_interval", as_u32)?,
+            see_own_writes: fields.optional("see_own_writes", as_bool)?.unwrap_or(true),
+            trace: fields.optional("trace", as_bool)?.unwrap_or(false),
+        }),
+        "cursor_created" => ProtocolMessage::CursorCreated(CursorInfo {
+            op_id: fields.u32("op_id")?,
+            batch_size: fields.u32("batch_size")?,
+        }),
+        "stop_consuming" => ProtocolMessage::StopConsuming(fields.u32("op_id")?),
+        "set_batch_size" => ProtocolMessage::SetBatchSize(fields.u32("batch_size")?),
+        "next_batch" => ProtocolMessage::NextBatch,
+        "end_of_batch" => ProtocolMessage::EndOfBatch,
+        "awaiting_events" => ProtocolMessage::AwaitingEvents,
+        "error" => ProtocolMessage::Error(ErrorMessage {
+            op_id: fields.u32("op_id")?,
+            kind: ErrorKind::from_u8(fields.u8("kind")?).map_err(|kind| {
+                CborError::Schema(format!("Unknown error kind: {}", kind))
+            })?,
+            description: fields.string("description")?,
+        }),
+        "truncate_stream" => ProtocolMessage::TruncateStream(TruncateStream {
+            op_id: fields.u32("op_id")?,
+            name: fields.string("name")?,
+            up_to: fields.event_id("up_to")?,
+        }),
+        "list_streams" => ProtocolMessage::ListStreams(fields.u32("op_id")?),
+        "stream_list" => ProtocolMessage::StreamList(StreamList {
+            op_id: fields.u32("op_id")?,
+            streams: fields.array("streams", |value, key| {
+                let stream = Fields::from_value(value, key)?;
+                Ok(StreamDescriptor {
+                    name: stream.string("name")?,
+                    event_count: stream.u64("event_count")?,
+                    head: stream.event_id("head")?,
+                    tail: stream.event_id("tail")?,
+                    writable: stream.bool("writable")?,
+                    stored_bytes: stream.u64("stored_bytes")?,
+                })
+            })?,
+        }),
+        "pause_writes" => ProtocolMessage::PauseWrites(fields.u32("op_id")?),
+        "resume_writes" => ProtocolMessage::ResumeWrites(fields.u32("op_id")?),
+        "get_capabilities" => ProtocolMessage::GetCapabilities(fields.u32("op_id")?),
+        "capabilities" => ProtocolMessage::Capabilities(Capabilities {
+            op_id: fields.u32("op_id")?,
+            flags: fields.u64("flags")?,
+            features: fields.array("features", as_string)?,
+        }),
+        "list_tags" => ProtocolMessage::ListTags(fields.u32("op_id")?),
+        "get_tag" => ProtocolMessage::GetTag(GetTag {
+            op_id: fields.u32("op_id")?,
+            name: fields.string("name")?,
+        }),
+        "tag_list" => ProtocolMessage::TagList(TagList {
+            op_id: fields.u32("op_id")?,
+            tags: fields.array("tags", |value, key| {
+                let tag = Fields::from_value(value, key)?;
+                Ok(StreamTag {
+                    name: tag.string("name")?,
+                    event_id: tag.event_id("event_id")?,
+                })
+            })?,
+        }),
+        "verify_stream" => ProtocolMessage::VerifyStream(VerifyStream {
+            op_id: fields.u32("op_id")?,
+            name: fields.string("name")?,
+            version_vector: fields.array("version_vector", as_event_id)?,
+            max_events: fields.u64("max_events")?,
+            check_parents: fields.bool("check_parents")?,
+        }),
+        "integrity_report" => ProtocolMessage::IntegrityReport(IntegrityReport {
+            op_id: fields.u32("op_id")?,
+            stream: fields.string("stream")?,
+            partitions: fields.array("partitions", |value, key| {
+                let partition = Fields::from_value(value, key)?;
+                Ok(PartitionIntegrity {
+                    partition_num: partition.u16("partition_num")?,
+                    events_checked: partition.u64("events_checked")?,
+                    last_verified: partition.event_id("last_verified")?,
+                    complete: partition.bool("complete")?,
+                    problems: partition.array("problems", |value, key| {
+                        let problem = Fields::from_value(value, key)?;
+                        let kind = problem.u8("kind")?;
+                        Ok(IntegrityProblem {
+                            kind: IntegrityProblemKind::from_u8(kind).map_err(|kind| {
+                                CborError::Schema(format!("Unknown integrity problem kind: {}", kind))
+                            })?,
+                            event_id: problem.event_id("event_id")?,
+                            description: problem.string("description")?,
+                        })
+                    })?,
+                })
+            })?,
+        }),
+        "get_ancestry" => ProtocolMessage::GetAncestry(GetAncestry {
+            op_id: fields.u32("op_id")?,
+            event_id: fields.event_id("event_id")?,
+        }),
+        "ancestry" => ProtocolMessage::Ancestry(AncestryInfo {
+            op_id: fields.u32("op_id")?,
+            event_count: fields.u32("event_count")?,
+        }),
+        "count_events" => ProtocolMessage::CountEvents(CountEvents {
+            op_id: fields.u32("op_id")?,
+            namespace: fields.string("namespace")?,
+            since: fields.optional("since", as_event_id)?,
+        }),
+        "get_consumer_lag" => ProtocolMessage::GetConsumerLag(GetConsumerLag {
+            op_id: fields.u32("op_id")?,
+            namespace: fields.string("namespace")?,
+            version_vector: fields.array("version_vector", as_event_id)?,
+        }),
+        "count_result" => ProtocolMessage::CountResult(CountResult {
+            op_id: fields.u32("op_id")?,
+            count: fields.u64("count")?,
+        }),
+        "heartbeat" => ProtocolMessage::Heartbeat(Heartbeat {
+            op_id: fields.u32("op_id")?,
+            timestamp: time::from_millis_since_epoch(fields.u64("timestamp")?),
+        }),
+        "get_stream_status" => ProtocolMessage::GetStreamStatus(GetStreamStatus {
+            op_id: fields.u32("op_id")?,
+            name: fields.string("name")?,
+        }),
+        "cursor_message" => {
+            let message = fields.field("message", |value, _| from_value(value))?;
+            match message {
+                ProtocolMessage::ReceiveEvent(_) | ProtocolMessage::ReceiveEventPrefix(_, _) |
+                ProtocolMessage::EndOfBatch | ProtocolMessage::AwaitingEvents => {}
+                other @ _ => {
+                    return Err(CborError::Schema(format!("A cursor_message cannot contain a '{}' message", self::message_type(&other))));
+                }
+            }
+            ProtocolMessage::CursorMessage(fields.u32("op_id")?, Box::new(message))
+        }
+        "trace" => ProtocolMessage::Trace(OperationTrace {
+            op_id: fields.u32("op_id")?,
+            stages: fields.array("stages", |value, key| {
+                let timing = Fields::from_value(value, key)?;
+                let stage = timing.u8("stage")?;
+                Ok(StageTiming {
+                    stage: TraceStage::from_u8(stage).map_err(|stage| {
+                        CborError::Schema(format!("Unknown trace stage: {}", stage))
+                    })?,
+                    elapsed_micros: timing.u64("elapsed_micros")?,
+                })
+            })?,
+        }),
+        "stream_idle" => ProtocolMessage::StreamIdle(StreamIdle {
+            op_id: fields.u32("op_id")?,
+            tail_id: fields.event_id("tail_id")?,
+            server_time: time::from_millis_since_epoch(fields.u64("server_time")?),
+        }),
+        other => return Err(CborError::Schema(format!("Unknown message type: '{}'", other)))
+    };
+    Ok(message)
+}
+
+/// The entries of a map, with conversions for each type of field
+struct Fields<'a>(&'a [(Value, Value)]);
+
+impl <'a> Fields<'a> {
+    fn from_value(value: &'a Value, key: &str) -> Result<Fields<'a>, CborError> {
+        match *value {
+            Value::Map(ref entries) => Ok(Fields(entries)),
+            ref other => Err(wrong_type(key, "a map", other))
+        }
+    }
+
+    fn get(&self, key: &str) -> Result<&'a Value, CborError> {
+        self.0.iter().find(|&&(ref entry_key, _)| {
+            match *entry_key {
+                Value::Text(ref text) => text == key,
+                _ => false
+            }
+        }).map(|&(_, ref value)| value).ok_or_else(|| {
+            CborError::Schema(format!("Missing field: '{}'", key))
+        })
+    }
+
+    fn field<T, F>(&self, key: &str, convert: F) -> Result<T, CborError> where F: Fn(&'a Value, &str) -> Result<T, CborError> {
+        self.get(key).and_then(|value| convert(value, key))
+    }
+
+    /// A missing field is treated the same as one that's `null`
+    fn optional<T, F>(&self, key: &str, convert: F) -> Result<Option<T>, CborError> where F: Fn(&'a Value, &str) -> Result<T, CborError> {
+        match self.get(key) {
+            Ok(&Value::Null) | Err(_) => Ok(None),
+            Ok(value) => convert(value, key).map(Some)
+        }
+    }
+
+    fn array<T, F>(&self, key: &str, convert: F) -> Result<Vec<T>, CborError> where F: Fn(&'a Value, &str) -> Result<T, CborError> {
+        match *self.get(key)? {
+            Value::Array(ref items) => items.iter().map(|item| convert(item, key)).collect(),
+            ref other => Err(wrong_type(key, "an array", other))
+        }
+    }
+
+    fn u64(&self, key: &str) -> Result<u64, CborError> {
+        self.field(key, as_u64)
+    }
+
+    fn u32(&self, key: &str) -> Result<u32, CborError> {
+        self.field(key, as_u32)
+    }
+
+    fn u16(&self, key: &str) -> Result<u16, CborError> {
+        self.field(key, |value, key| narrow(value, key, ::std::u16::MAX as u64).map(|n| n as u16))
+    }
+
+    fn u8(&self, key: &str) -> Result<u8, CborError> {
+        self.field(key, |value, key| narrow(value, key, ::std::u8::MAX as u64).map(|n| n as u8))
+    }
+
+    fn bool(&self, key: &str) -> Result<bool, CborError> {
+        self.field(key, as_bool)
+    }
+
+    fn string(&self, key: &str) -> Result<String, CborError> {
+        self.field(key, as_string)
+    }
+
+    fn bytes(&self, key: &str) -> Result<Vec<u8>, CborError> {
+        self.field(key, |value, key| {
+            match *value {
+                Value::Bytes(ref bytes) => Ok(bytes.clone()),
+                ref other => Err(wrong_type(key, "a byte string", other))
+            }
+        })
+    }
+
+    fn event_id(&self, key: &str) -> Result<FloEventId, CborError> {
+        self.field(key, as_event_id)
+    }
+
+    fn event(&self, key: &str) -> Result<OwnedFloEvent, CborError> {
+        let event = Fields::from_value(self.get(key)?, key)?;
+        Ok(OwnedFloEvent {
+            id: event.event_id("id")?,
+            parent_id: event.optional("parent_id", as_event_id)?,
+            timestamp: time::from_millis_since_epoch(event.u64("timestamp")?),
+            namespace: event.string("namespace")?,
+            data: event.bytes("data")?,
+        })
+    }
+}
+
+fn wrong_type(key: &str, expected: &str, actual: &Value) -> CborError {
+    CborError::Schema(format!("Expected field: '{}' to be {}, but it was: {:?}", key, expected, actual))
+}
+
+fn as_u64(value: &Value, key: &str) -> Result<u64, CborError> {
+    match *value {
+        Value::Unsigned(n) => Ok(n),
+        ref other => Err(wrong_type(key, "an unsigned integer", other))
+    }
+}
+
+fn as_bool(value: &Value, key: &str) -> Result<bool, CborError> {
+    match *value {
+        Value::Bool(b) => Ok(b),
+        ref other => Err(wrong_type(key, "a bool", other))
+    }
+}
+
+fn as_u32(value: &Value, key: &str) -> Result<u32, CborError> {
+    narrow(value, key, ::std::u32::MAX as u64).map(|n| n as u32)
+}
+
+fn narrow(value: &Value, key: &str, max: u64) -> Result<u64, CborError> {
+    let n = as_u64(value, key)?;
+    if n > max {
+        Err(CborError::Schema(format!("Field: '{}' has value: {}, which is larger than the maximum of: {}", key, n, max)))
+    } else {
+        Ok(n)
+    }
+}
+
+fn as_string(value: &Value, key: &str) -> Result<String, CborError> {
+    match *value {
+        Value::Text(ref text) => Ok(text.clone()),
+        ref other => Err(wrong_type(key, "a text string", other))
+    }
+}
+
+fn as_compression(value: &Value, key: &str) -> Result<Compression, CborError> {
+    let n = narrow(value, key, ::std::u8::MAX as u64)?;
+    Compression::from_u8(n as u8).map_err(|n| {
+        CborError::Schema(format!("Unknown compression: {}", n))
+    })
+}
+
+fn as_read_consistency(value: &Value, key: &str) -> Result<ReadConsistency, CborError> {
+    let n = narrow(value, key, ::std::u8::MAX as u64)?;
+    ReadConsistency::from_u8(n as u8).map_err(|n| {
+        CborError::Schema(format!("Unknown read consistency: {}", n))
+    })
+}
+
+fn as_event_id(value: &Value, key: &str) -> Result<FloEventId, CborError> {
+    match *value {
+        Value::Array(ref parts) if parts.len() == 2 => {
+            let actor = narrow(&parts[0], key, ::std::u16::MAX as u64)?;
+            let counter = as_u64(&parts[1], key)?;
+            Ok(FloEventId::new(actor as u16, counter))
+        }
+        ref other => Err(wrong_type(key, "an event id array of [actor, counter]", other))
+    }
+}
+
+fn uint<N: Into<u64>>(n: N) -> Value {
+    Value::Unsigned(n.into())
+}
+
+fn text(value: &str) -> Value {
+    Value::Text(value.to_owned())
+}
+
+fn optional(value: Option<Value>) -> Value {
+    value.unwrap_or(Value::Null)
+}
+
+fn map(fields: Vec<(&str, Value)>) -> Value {
+    Value::Map(fields.into_iter().map(|(key, value)| (text(key), value)).collect())
+}
+
+fn event_id(id: FloEventId) -> Value {
+    Value::Array(vec![uint(id.actor), uint(id.event_counter)])
+}
+
+fn event_ids(ids: &[FloEventId]) -> Value {
+    Value::Array(ids.iter().map(|id| event_id(*id)).collect())
+}
+
+fn event_value<E: FloEvent>(event: &E) -> Value {
+    map(vec![
+        ("id", event_id(*event.id())),
+        ("parent_id", optional(event.parent_id().map(event_id))),
+        ("timestamp", uint(time::millis_since_epoch(event.timestamp()))),
+        ("namespace", text(event.namespace())),
+        ("data", Value::Bytes(event.data().to_vec())),
+    ])
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+    use std::time::Duration;
+    use std::collections::BTreeMap;
+    use serde_cbor;
+    use {MessageStream, MessageWriter, Framing};
+
+    fn one_of_every_message() -> Vec<ProtocolMessage<OwnedFloEvent>> {
+        let event = OwnedFloEvent {
+            id: FloEventId::new(2, 44),
+            timestamp: time::from_millis_since_epoch(1_500_000_000_123),
+            parent_id: Some(FloEventId::new(1, 43)),
+            namespace: "/foo/bar".to_owned(),
+            data: vec![0, 1, 2, 255],
+        };
+        vec![
+            ProtocolMessage::Announce(ClientAnnounce {
+                protocol_version: 1,
+                op_id: 2,
+                client_name: "polyglot".to_owned(),
+                consume_batch_size: Some(300),
+            }),
+            ProtocolMessage::Announce(ClientAnnounce {
+                protocol_version: 1,
+                op_id: 3,
+                client_name: "".to_owned(),
+                consume_batch_size: None,
+            }),
+            ProtocolMessage::StreamStatus(EventStreamStatus {
+                op_id: 4,
+                name: "default".to_owned(),
+                partitions: vec![
+                    PartitionStatus { partition_num: 1, head: 0, primary: true },
+                    PartitionStatus { partition_num: 2, head: ::std::u64::MAX, primary: false },
+                ],
+            }),
+            ProtocolMessage::SetEventStream(SetEventStream { op_id: 5, name: "other".to_owned() }),
+            ProtocolMessage::ProduceEvent(ProduceEvent {
+                op_id: 6,
+                partition: 2,
+                namespace: "/foo".to_owned(),
+                parent_id: Some(FloEventId::new(1, 12)),
+                ttl: Some(Duration::from_millis(90_000)),
+                compression: Compression::Gzip,
+                trace: false,
+                data: vec![9; 300],
+            }),
+            ProtocolMessage::ProduceEvent(ProduceEvent {
+                op_id: 7,
+                partition: 1,
+                namespace: "/foo".to_owned(),
+                parent_id: None,
+                ttl: None,
+                compression: Compression::None,
+                trace: true,
+                data: Vec::new(),
+            }),
+            ProtocolMessage::ReceiveEvent(event.clone()),
+            ProtocolMessage::ReceiveEventPrefix(event, 1024),
+            ProtocolMessage::AckEvent(EventAck { op_id: 8, event_id: FloEventId::new(3, 70_000) }),
+            ProtocolMessage::NewStartConsuming(NewConsumerStart {
+                op_id: 9,
+                version_vector: vec![FloEventId::new(1, 5), FloEventId::new(2, 0)],
+                max_events: 100,
+                body_prefix_bytes: Some(16),
+                start_tag: Some("release-1".to_owned()),
+                max_delivery_rate: Some(250),
+                namespace_regex: Some(r"/orders/\d+/shipped".to_owned()),
+                error_on_empty: true,
+                unlimited_lifetime: true,
+                ..Default::default()
+            }),
+            ProtocolMessage::NewStartConsuming(NewConsumerStart {
+                op_id: 10,
+                namespace: "/foo".to_owned(),
+                read_consistency: ReadConsistency::Quorum,
+                start_at_tail: true,
+                ..Default::default()
+            }),
+            ProtocolMessage::NewStartConsuming(NewConsumerStart {
+                op_id: 10,
+                version_vector: vec![FloEventId::new(1, 20)],
+                namespace: "/foo".to_owned(),
+                snapshot: true,
+                idle_signal_interval: Some(2500),
+                see_own_writes: false,
+                trace: true,
+                ..Default::default()
+            }),
+            ProtocolMessage::CursorCreated(CursorInfo { op_id: 11, batch_size: 500 }),
+            ProtocolMessage::StopConsuming(12),
+            ProtocolMessage::SetBatchSize(13),
+            ProtocolMessage::NextBatch,
+            ProtocolMessage::EndOfBatch,
+            ProtocolMessage::AwaitingEvents,
+            ProtocolMessage::Error(ErrorMessage {
+                op_id: 14,
+                kind: ErrorKind::NoSuchTag,
+                description: "no such tag: 'nope'".to_owned(),
+            }),
+            ProtocolMessage::TruncateStream(TruncateStream { op_id: 15, name: "default".to_owned(), up_to: FloEventId::new(1, 8) }),
+            ProtocolMessage::ListStreams(16),
+            ProtocolMessage::StreamList(StreamList {
+                op_id: 17,
+                streams: vec![StreamDescriptor {
+                    name: "default".to_owned(),
+                    event_count: 3,
+                    head: FloEventId::new(1, 3),
+                    tail: FloEventId::zero(),
+                    writable: true,
+                    stored_bytes: 4096,
+                }],
+            }),
+            ProtocolMessage::PauseWrites(18),
+            ProtocolMessage::ResumeWrites(19),
+            ProtocolMessage::GetCapabilities(20),
+            ProtocolMessage::Capabilities(Capabilities {
+                op_id: 21,
+                flags: features::EVENT_TTL | features::CBOR_FRAMING,
+                features: vec!["event_ttl".to_owned(), "cbor_framing".to_owned()],
+            }),
+            ProtocolMessage::ListTags(22),
+            ProtocolMessage::GetTag(GetTag { op_id: 23, name: "release-1".to_owned() }),
+            ProtocolMessage::TagList(TagList {
+                op_id: 24,
+                tags: vec![StreamTag { name: "release-1".to_owned(), event_id: FloEventId::new(1, 2) }],
+            }),
+            ProtocolMessage::VerifyStream(VerifyStream {
+                op_id: 25,
+                name: "default".to_owned(),
+                version_vector: vec![FloEventId::new(1, 2)],
+                max_events: 0,
+                check_parents: true,
+            }),
+            ProtocolMessage::IntegrityReport(IntegrityReport {
+                op_id: 26,
+                stream: "default".to_owned(),
+                partitions: vec![PartitionIntegrity {
+                    partition_num: 1,
+                    events_checked: 2,
+                    last_verified: FloEventId::new(1, 2),
+                    complete: false,
+                    problems: vec![IntegrityProblem {
+                        kind: IntegrityProblemKind::OutOfOrder,
+                        event_id: FloEventId::new(1, 2),
+                        description: "out of order".to_owned(),
+                    }],
+                }],
+            }),
+            ProtocolMessage::GetAncestry(GetAncestry { op_id: 27, event_id: FloEventId::new(1, 2) }),
+            ProtocolMessage::Ancestry(AncestryInfo { op_id: 27, event_count: 2 }),
+            ProtocolMessage::CountEvents(CountEvents { op_id: 28, namespace: "/foo/*".to_owned(), since: Some(FloEventId::new(1, 2)) }),
+            ProtocolMessage::GetConsumerLag(GetConsumerLag { op_id: 28, namespace: "/foo/*".to_owned(), version_vector: vec![FloEventId::new(1, 2)] }),
+            ProtocolMessage::CountResult(CountResult { op_id: 28, count: 1 << 40 }),
+            ProtocolMessage::Heartbeat(Heartbeat { op_id: 29, timestamp: time::from_millis_since_epoch(1_500_000_000_456) }),
+            ProtocolMessage::GetStreamStatus(GetStreamStatus { op_id: 30, name: "other-stream".to_owned() }),
+            ProtocolMessage::CursorMessage(31, Box::new(ProtocolMessage::AwaitingEvents)),
+            ProtocolMessage::Trace(OperationTrace {
+                op_id: 32,
+                stages: vec![
+                    StageTiming { stage: TraceStage::Received, elapsed_micros: 0 },
+                    StageTiming { stage: TraceStage::Acked, elapsed_micros: 1 << 33 },
+                ],
+            }),
+            ProtocolMessage::StreamIdle(StreamIdle { op_id: 33, tail_id: FloEventId::new(2, 9), server_time: time::from_millis_since_epoch(1_500_000_000_789) }),
+        ]
+    }
+
+    #[test]
+    fn every_message_type_round_trips() {
+        let messages = one_of_every_message();
+        let mut types = messages.iter().map(|message| message_type(message)).collect::<Vec<_>>();
+        types.dedup();
+        assert_eq!(37, types.len(), "expected one of each message type, got: {:?}", types);
+
+        for message in messages {
+            let mut encoded = encode(&message);
+            let len = encoded.len();
+            encoded.extend_from_slice(&[4, 3, 2, 1]); // extra bytes after the message
+            let (result, result_len) = decode(&encoded).expect("failed to decode message");
+            assert_eq!(message, result);
+            assert_eq!(len, result_len);
+        }
+    }
+
+    #[test]
+    fn decode_returns_incomplete_until_the_whole_message_is_available() {
+        let message = one_of_every_message().remove(6); // receive_event
+        let encoded = encode(&message);
+        for end in 0..encoded.len() {
+            assert_eq!(Err(CborError::Incomplete), decode(&encoded[..end]), "with {} bytes", end);
+        }
+        assert_eq!(message, decode(&encoded).unwrap().0);
+    }
+
+    #[test]
+    fn decode_returns_error_for_values_that_are_not_messages() {
+        let mut unknown_type = Vec::new();
+        write_value(&map(vec![("type", text("explode"))]), &mut unknown_type);
+        assert_eq!(Err(CborError::Schema("Unknown message type: 'explode'".to_owned())), decode(&unknown_type));
+
+        let mut missing_field = Vec::new();
+        write_value(&map(vec![("type", text("list_streams"))]), &mut missing_field);
+        assert_eq!(Err(CborError::Schema("Missing field: 'op_id'".to_owned())), decode(&missing_field));
+
+        let mut wrong_size = Vec::new();
+        write_value(&map(vec![("type", text("set_batch_size")), ("batch_size", uint(::std::u64::MAX))]), &mut wrong_size);
+        assert!(decode(&wrong_size).is_err());
+
+        let mut wrong_wrapped_type = Vec::new();
+        let list_streams = map(vec![("type", text("list_streams")), ("op_id", uint(3u32))]);
+        write_value(&map(vec![("type", text("cursor_message")), ("op_id", uint(3u32)), ("message", list_streams)]), &mut wrong_wrapped_type);
+        assert_eq!(Err(CborError::Schema("A cursor_message cannot contain a 'list_streams' message".to_owned())), decode(&wrong_wrapped_type));
+
+        let mut too_deep = vec![0x81; MAX_NESTING_DEPTH + 2]; // arrays of one element
+        too_deep.push(0x00);
+        assert!(match read_value(&too_deep) { Err(CborError::Invalid(_)) => true, _ => false });
+
+        let indefinite_map = [0xbf, 0xff];
+        assert!(match decode(&indefinite_map) { Err(CborError::Invalid(_)) => true, _ => false });
+    }
+
+    #[test]
+    fn frame_encoded_by_a_generic_cbor_library_is_decoded() {
+        use serde_cbor::Value as Generic;
+
+        let mut map = BTreeMap::new();
+        map.insert(Generic::Text("type".to_owned()), Generic::Text("produce_event".to_owned()));
+        map.insert(Generic::Text("op_id".to_owned()), Generic::Integer(7));
+        map.insert(Generic::Text("partition".to_owned()), Generic::Integer(1));
+        map.insert(Generic::Text("namespace".to_owned()), Generic::Text("/orders".to_owned()));
+        map.insert(Generic::Text("parent_id".to_owned()), Generic::Array(vec![Generic::Integer(1), Generic::Integer(70_000)]));
+        map.insert(Generic::Text("ttl".to_owned()), Generic::Null);
+        map.insert(Generic::Text("data".to_owned()), Generic::Bytes(b"order placed".to_vec()));
+        map.insert(Generic::Text("unknown_key".to_owned()), Generic::Bool(true));
+
+        let mut frame = SELF_DESCRIBE_PREFIX.to_vec();
+        frame.extend(serde_cbor::to_vec(&Generic::Map(map)).unwrap());
+
+        let (message, len) = decode(&frame).expect("failed to decode frame");
+        let expected = ProtocolMessage::ProduceEvent(ProduceEvent {
+            op_id: 7,
+            partition: 1,
+            namespace: "/orders".to_owned(),
+            parent_id: Some(FloEventId::new(1, 70_000)),
+            ttl: None,
+            compression: Compression::None,
+            trace: false,
+            data: b"order placed".to_vec(),
+        });
+        assert_eq!(expected, message);
+        assert_eq!(frame.len(), len);
+    }
+
+    #[test]
+    fn encoded_message_is_decoded_by_a_generic_cbor_library() {
+        use serde_cbor::Value as Generic;
+
+        let message = ProtocolMessage::<OwnedFloEvent>::AckEvent(EventAck { op_id: 8, event_id: FloEventId::new(3, 70_000) });
+        let decoded: Generic = serde_cbor::from_slice(&encode(&message)).expect("failed to decode with serde_cbor");
+
+        let mut expected = BTreeMap::new();
+        expected.insert(Generic::Text("type".to_owned()), Generic::Text("ack_event".to_owned()));
+        expected.insert(Generic::Text("op_id".to_owned()), Generic::Integer(8));
+        expected.insert(Generic::Text("event_id".to_owned()), Generic::Array(vec![Generic::Integer(3), Generic::Integer(70_000)]));
+        assert_eq!(Generic::Map(expected), decoded);
+    }
+
+    #[test]
+    fn message_stream_detects_cbor_framing_from_the_tagged_announce() {
+        let announce = ProtocolMessage::Announce(ClientAnnounce {
+            protocol_version: 1,
+            op_id: 1,
+            client_name: "polyglot".to_owned(),
+            consume_batch_size: None,
+        });
+        let list_streams = ProtocolMessage::ListStreams(2);
+        let mut bytes = SELF_DESCRIBE_PREFIX.to_vec();
+        bytes.extend(encode(&announce));
+        bytes.extend(encode(&list_streams));
+
+        let mut stream = MessageStream::new(Cursor::new(bytes));
+        assert_eq!(None, stream.framing());
+        assert_eq!(announce, stream.read_next().unwrap());
+        assert_eq!(Some(Framing::Cbor), stream.framing());
+        assert_eq!(list_streams, stream.read_next().unwrap());
+    }
+
+    #[test]
+    fn message_writer_writes_the_whole_message_as_cbor() {
+        let message = one_of_every_message().remove(6); // receive_event
+        let mut writer = MessageWriter::with_framing(message.clone(), Framing::Cbor);
+        let mut output = Vec::new();
+        writer.write(&mut output).unwrap();
+        assert!(writer.is_done());
+        assert_eq!(encode(&message), output);
+    }
+}
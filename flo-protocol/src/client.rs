@@ -9,11 +9,16 @@
 //! header and then reading however many bytes are indicated by the header for the body of the event.
 //!
 //! All numbers use big endian byte order.
-//! All Strings are newline terminated.
-use nom::{be_u64, be_u32, be_u16};
+//! All Strings are prefixed with their length in bytes as a u16, and are NOT terminated. They may contain any valid utf-8,
+//! including newlines, and are always written with `Serializer::write_string` and read with `parse_str`.
+use nom::{be_u64, be_u32, be_u16, be_u8};
 use event::{time, OwnedFloEvent, FloEvent, FloEventId, ActorId, EventCounter, Timestamp};
 use serializer::Serializer;
 use std::net::SocketAddr;
+use std::time::Duration;
+use std::io::{self, Read, Write};
+
+use flate2;
 
 pub mod headers {
     pub const CLIENT_AUTH: u8 = 1;
@@ -35,16 +40,131 @@ pub mod headers {
     pub const NEW_START_CONSUMING: u8 = 17;
     pub const SET_EVENT_STREAM: u8 = 18;
     pub const EVENT_STREAM_STATUS: u8 = 19;
+    pub const TRUNCATE_STREAM: u8 = 20;
+    pub const LIST_STREAMS: u8 = 21;
+    pub const STREAM_LIST: u8 = 22;
+    pub const PAUSE_WRITES: u8 = 23;
+    pub const RESUME_WRITES: u8 = 24;
+    pub const RECEIVE_EVENT_PREFIX: u8 = 25;
+    pub const GET_CAPABILITIES: u8 = 26;
+    pub const CAPABILITIES: u8 = 27;
+    pub const LIST_TAGS: u8 = 28;
+    pub const GET_TAG: u8 = 29;
+    pub const TAG_LIST: u8 = 30;
+    pub const VERIFY_STREAM: u8 = 31;
+    pub const INTEGRITY_REPORT: u8 = 32;
+    pub const GET_ANCESTRY: u8 = 33;
+    pub const ANCESTRY: u8 = 34;
+    pub const COUNT_EVENTS: u8 = 35;
+    pub const COUNT_RESULT: u8 = 36;
+    pub const HEARTBEAT: u8 = 37;
+    pub const GET_STREAM_STATUS: u8 = 38;
+    pub const CURSOR_MESSAGE: u8 = 39;
+    pub const TRACE: u8 = 40;
+    pub const GET_CONSUMER_LAG: u8 = 41;
+    pub const STREAM_IDLE: u8 = 42;
     pub const CLIENT_ANNOUNCE: u8 = 170;
 }
 
 use self::headers::*;
 
+/// Flags for the optional protocol features that a server may support, as advertised in a `Capabilities` message.
+/// Clients should only make use of a feature if the server has advertised it.
+pub mod features {
+    /// `ProduceEvent` messages may have a `ttl`
+    pub const EVENT_TTL: u64 = 1;
+    /// `NewConsumerStart` messages may set `body_prefix_bytes`
+    pub const BODY_PREFIX: u64 = 1 << 1;
+    /// `PauseWrites` and `ResumeWrites` messages are handled
+    pub const PAUSE_WRITES: u64 = 1 << 2;
+    /// `ListStreams` messages are handled
+    pub const LIST_STREAMS: u64 = 1 << 3;
+    /// `ListTags` and `GetTag` messages are handled, and `NewConsumerStart` messages may set a `start_tag`
+    pub const STREAM_TAGS: u64 = 1 << 4;
+    /// `VerifyStream` messages are handled
+    pub const VERIFY_STREAM: u64 = 1 << 5;
+    /// Connections may use CBOR framing instead of the binary format. See the `cbor` module
+    pub const CBOR_FRAMING: u64 = 1 << 6;
+    /// `NewConsumerStart` messages may set `max_delivery_rate`
+    pub const DELIVERY_RATE: u64 = 1 << 7;
+    /// `NewConsumerStart` messages may set a `namespace_regex`
+    pub const NAMESPACE_REGEX: u64 = 1 << 8;
+    /// `GetAncestry` messages are handled
+    pub const ANCESTRY: u64 = 1 << 9;
+    /// `NewConsumerStart` messages may set `error_on_empty`
+    pub const ERROR_ON_EMPTY: u64 = 1 << 10;
+    /// `CountEvents` messages are handled
+    pub const COUNT_EVENTS: u64 = 1 << 11;
+    /// The server may stop cursors that outlive their stream's `max_cursor_lifetime` by sending `StopConsuming`, and
+    /// `NewConsumerStart` messages may set `unlimited_lifetime` to opt out
+    pub const CURSOR_LIFETIME: u64 = 1 << 12;
+    /// `Heartbeat` messages are echoed back to the client
+    pub const HEARTBEAT: u64 = 1 << 13;
+    /// `ProduceEvent` messages may have gzip `compression`
+    pub const COMPRESSION: u64 = 1 << 14;
+    /// `NewConsumerStart` messages may set a `read_consistency`
+    pub const READ_CONSISTENCY: u64 = 1 << 15;
+    /// `GetStreamStatus` messages are handled
+    pub const STREAM_STATUS: u64 = 1 << 16;
+    /// `NewConsumerStart` messages may set `cursor_op_ids`
+    pub const CURSOR_OP_IDS: u64 = 1 << 17;
+    /// `NewConsumerStart` messages may set `start_at_tail`
+    pub const START_AT_TAIL: u64 = 1 << 18;
+    /// `ProduceEvent` and `NewConsumerStart` messages may set `trace`, in which case the server sends a `Trace` after the
+    /// `EventAck` or `CursorCreated`
+    pub const OPERATION_TRACE: u64 = 1 << 19;
+    /// `GetConsumerLag` messages are handled
+    pub const CONSUMER_LAG: u64 = 1 << 20;
+    /// `NewConsumerStart` messages may set `snapshot`
+    pub const SNAPSHOT: u64 = 1 << 21;
+    /// `NewConsumerStart` messages may set an `idle_signal_interval`, in which case the server sends `StreamIdle` messages
+    pub const IDLE_SIGNAL: u64 = 1 << 22;
+    /// `NewConsumerStart` messages may clear `see_own_writes`
+    pub const SEE_OWN_WRITES: u64 = 1 << 23;
+
+    /// The name of each feature, as it appears in `Capabilities::features`
+    pub const NAMES: &'static [(u64, &'static str)] = &[
+        (EVENT_TTL, "event_ttl"),
+        (BODY_PREFIX, "body_prefix"),
+        (PAUSE_WRITES, "pause_writes"),
+        (LIST_STREAMS, "list_streams"),
+        (STREAM_TAGS, "stream_tags"),
+        (VERIFY_STREAM, "verify_stream"),
+        (CBOR_FRAMING, "cbor_framing"),
+        (DELIVERY_RATE, "delivery_rate"),
+        (NAMESPACE_REGEX, "namespace_regex"),
+        (ANCESTRY, "ancestry"),
+        (ERROR_ON_EMPTY, "error_on_empty"),
+        (COUNT_EVENTS, "count_events"),
+        (CURSOR_LIFETIME, "cursor_lifetime"),
+        (HEARTBEAT, "heartbeat"),
+        (COMPRESSION, "compression"),
+        (READ_CONSISTENCY, "read_consistency"),
+        (STREAM_STATUS, "stream_status"),
+        (CURSOR_OP_IDS, "cursor_op_ids"),
+        (START_AT_TAIL, "start_at_tail"),
+        (OPERATION_TRACE, "operation_trace"),
+        (CONSUMER_LAG, "consumer_lag"),
+        (SNAPSHOT, "snapshot"),
+        (IDLE_SIGNAL, "idle_signal"),
+        (SEE_OWN_WRITES, "see_own_writes"),
+    ];
+}
+
 pub const ERROR_INVALID_NAMESPACE: u8 = 15;
 pub const ERROR_INVALID_CONSUMER_STATE: u8 = 16;
 pub const ERROR_INVALID_VERSION_VECTOR: u8 = 17;
 pub const ERROR_STORAGE_ENGINE_IO: u8 = 18;
 pub const ERROR_NO_STREAM: u8 = 19;
+pub const ERROR_TOO_MANY_CURSORS: u8 = 20;
+pub const ERROR_SERVER_BUSY: u8 = 21;
+pub const ERROR_INVALID_EVENT_ID: u8 = 22;
+pub const ERROR_NO_SUCH_TAG: u8 = 23;
+pub const ERROR_NO_MATCHING_EVENTS: u8 = 24;
+pub const ERROR_INVALID_EVENT_DATA: u8 = 25;
+pub const ERROR_PARTITION_BUSY: u8 = 26;
+pub const ERROR_INVALID_NAMESPACE_REGEX: u8 = 27;
+pub const ERROR_UNEXPECTED_MESSAGE: u8 = 28;
 
 /// Describes the type of error. This gets serialized a u8
 #[derive(Debug, PartialEq, Clone)]
@@ -59,6 +179,25 @@ pub enum ErrorKind {
     StorageEngineError,
     /// Requested event stream does not exist
     NoSuchStream,
+    /// The connection already has the maximum number of active cursors, so no more can be started until one finishes
+    TooManyCursors,
+    /// The server is temporarily not accepting writes, or new connections. The request may be retried later
+    ServerBusy,
+    /// A produced event's `parent_id`, or the event in a `GetAncestry` message, does not refer to an event that exists in the stream
+    InvalidEventId,
+    /// Requested tag does not exist in the event stream
+    NoSuchTag,
+    /// A consumer that set `error_on_empty` started where no events currently match its namespace
+    NoMatchingEvents,
+    /// A produced event's `data` could not be decompressed
+    InvalidEventData,
+    /// The partition already has the maximum number of operations waiting to be written. The request may be retried once
+    /// the partition catches up
+    PartitionBusy,
+    /// Indicates that the `namespace_regex` provided by a consumer was not a valid regular expression
+    InvalidNamespaceRegex,
+    /// The client sent a message that only the server may send. The server closes the connection after sending this
+    UnexpectedMessage,
 }
 
 /// Represents a response to any request that results in an error
@@ -83,6 +222,15 @@ impl ErrorKind {
             ERROR_INVALID_VERSION_VECTOR => Ok(ErrorKind::InvalidVersionVector),
             ERROR_STORAGE_ENGINE_IO => Ok(ErrorKind::StorageEngineError),
             ERROR_NO_STREAM => Ok(ErrorKind::NoSuchStream),
+            ERROR_TOO_MANY_CURSORS => Ok(ErrorKind::TooManyCursors),
+            ERROR_SERVER_BUSY => Ok(ErrorKind::ServerBusy),
+            ERROR_INVALID_EVENT_ID => Ok(ErrorKind::InvalidEventId),
+            ERROR_NO_SUCH_TAG => Ok(ErrorKind::NoSuchTag),
+            ERROR_NO_MATCHING_EVENTS => Ok(ErrorKind::NoMatchingEvents),
+            ERROR_INVALID_EVENT_DATA => Ok(ErrorKind::InvalidEventData),
+            ERROR_PARTITION_BUSY => Ok(ErrorKind::PartitionBusy),
+            ERROR_INVALID_NAMESPACE_REGEX => Ok(ErrorKind::InvalidNamespaceRegex),
+            ERROR_UNEXPECTED_MESSAGE => Ok(ErrorKind::UnexpectedMessage),
             other => Err(other)
         }
     }
@@ -95,6 +243,15 @@ impl ErrorKind {
             &ErrorKind::InvalidVersionVector => ERROR_INVALID_VERSION_VECTOR,
             &ErrorKind::StorageEngineError => ERROR_STORAGE_ENGINE_IO,
             &ErrorKind::NoSuchStream => ERROR_NO_STREAM,
+            &ErrorKind::TooManyCursors => ERROR_TOO_MANY_CURSORS,
+            &ErrorKind::ServerBusy => ERROR_SERVER_BUSY,
+            &ErrorKind::InvalidEventId => ERROR_INVALID_EVENT_ID,
+            &ErrorKind::NoSuchTag => ERROR_NO_SUCH_TAG,
+            &ErrorKind::NoMatchingEvents => ERROR_NO_MATCHING_EVENTS,
+            &ErrorKind::InvalidEventData => ERROR_INVALID_EVENT_DATA,
+            &ErrorKind::PartitionBusy => ERROR_PARTITION_BUSY,
+            &ErrorKind::InvalidNamespaceRegex => ERROR_INVALID_NAMESPACE_REGEX,
+            &ErrorKind::UnexpectedMessage => ERROR_UNEXPECTED_MESSAGE,
         }
     }
 }
@@ -102,6 +259,9 @@ impl ErrorKind {
 /// The body of a ProduceEvent `ProtocolMessage`. This is sent from a client producer to the server, and the server will
 /// respond with either an `EventAck` or an `ErrorMessage` to indicate success or failure respectively. Although the flo
 /// protocol is pipelined, this message includes an `op_id` field to aid in correlation of requests and responses.
+///
+/// Events produced on a single connection are always assigned strictly increasing event counters in the order that the
+/// `ProduceEvent` messages were sent, regardless of their namespaces.
 #[derive(Debug, PartialEq, Clone)]
 pub struct ProduceEvent {
     /// This is an arbritrary number, assigned by the client, to aid in correlation of requests and responses. Clients may
@@ -116,11 +276,96 @@ pub struct ProduceEvent {
     /// The parent id is optional. On the wire, a null parent_id is serialized as an event id where both the counter and the
     /// actor are set to 0.
     pub parent_id: Option<FloEventId>,
+    /// An optional time to live for the event. Once it's passed, the event will no longer be delivered to any consumers,
+    /// even if the event stream's retention period is longer. On the wire, this is serialized as a number of milliseconds,
+    /// with 0 meaning that the event does not expire.
+    pub ttl: Option<Duration>,
+    /// How the `data` is compressed. The server decompresses the data before persisting the event, so this only affects
+    /// the size of the message on the wire, and consumers always receive the original data.
+    pub compression: Compression,
+    /// If set, then the server follows the `EventAck` for this event with a `Trace` message describing how long each stage
+    /// of persisting the event took. This is meant for debugging slow produces, and has no effect on the event itself
+    pub trace: bool,
     /// The event payload. As far as the flo server is concerned, this is just an opaque byte array. Note that events with
-    /// 0-length bodies are perfectly fine.
+    /// 0-length bodies are perfectly fine. If the event is compressed, then this is the compressed data, and its length is
+    /// the length that's sent on the wire.
     pub data: Vec<u8>,
 }
 
+/// An empty, uncompressed event for the first partition, with no parent or time to live
+impl Default for ProduceEvent {
+    fn default() -> Self {
+        ProduceEvent {
+            op_id: 0,
+            partition: 1,
+            namespace: String::new(),
+            parent_id: None,
+            ttl: None,
+            compression: Compression::None,
+            trace: false,
+            data: Vec::new(),
+        }
+    }
+}
+
+impl ProduceEvent {
+    /// Compresses the `data` using gzip, unless it's already compressed
+    pub fn compress_gzip(&mut self) -> io::Result<()> {
+        if self.compression == Compression::Gzip {
+            return Ok(());
+        }
+        let mut encoder = flate2::write::GzEncoder::new(Vec::with_capacity(self.data.len() / 2), flate2::Compression::default());
+        encoder.write_all(&self.data)?;
+        self.data = encoder.finish()?;
+        self.compression = Compression::Gzip;
+        Ok(())
+    }
+
+    /// Replaces compressed `data` with the original, uncompressed data. Does nothing if the data isn't compressed.
+    /// Returns an error without reading any further if the uncompressed data would be larger than `max_len` bytes, since
+    /// a tiny compressed body can expand to an arbitrary amount of data
+    pub fn decompress(&mut self, max_len: usize) -> io::Result<()> {
+        if self.compression == Compression::Gzip {
+            let mut decompressed = Vec::with_capacity(::std::cmp::min(self.data.len() * 2, max_len));
+            flate2::read::GzDecoder::new(&self.data[..]).take(max_len as u64 + 1).read_to_end(&mut decompressed)?;
+            if decompressed.len() > max_len {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("uncompressed data is larger than the limit of {} bytes", max_len)));
+            }
+            self.data = decompressed;
+            self.compression = Compression::None;
+        }
+        Ok(())
+    }
+}
+
+pub const COMPRESSION_NONE: u8 = 0;
+pub const COMPRESSION_GZIP: u8 = 1;
+
+/// How the data of a `ProduceEvent` is compressed on the wire
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Compression {
+    None,
+    Gzip,
+}
+
+impl Compression {
+    pub fn from_u8(byte: u8) -> Result<Compression, u8> {
+        match byte {
+            COMPRESSION_NONE => Ok(Compression::None),
+            COMPRESSION_GZIP => Ok(Compression::Gzip),
+            other => Err(other)
+        }
+    }
+
+    pub fn u8_value(&self) -> u8 {
+        match *self {
+            Compression::None => COMPRESSION_NONE,
+            Compression::Gzip => COMPRESSION_GZIP,
+        }
+    }
+}
+
 /// Sent by the server to the producer of an event to acknowledge that the event was successfully persisted to the stream.
 #[derive(Debug, PartialEq, Clone)]
 pub struct EventAck {
@@ -141,6 +386,7 @@ pub struct ConsumerStart {
     pub max_events: u64,
 
     /// The namespace to consume from. This can be any valid glob pattern, to allow reading from multiple namespaces.
+    /// Patterns can be checked with `validate_namespace_glob` before they're sent.
     pub namespace: String,
 }
 
@@ -153,6 +399,106 @@ pub struct NewConsumerStart {
     pub version_vector: Vec<FloEventId>,
     pub max_events: u64,
     pub namespace: String,
+    /// If set, then the server will send only the first `n` bytes of each event body, as `ReceiveEventPrefix` messages,
+    /// instead of the complete events. Useful for consumers that only need an envelope at the start of each body
+    pub body_prefix_bytes: Option<u32>,
+    /// If set, then the `version_vector` is ignored, and the consumer starts just after the event with this tag, as if it
+    /// had already received every event up to and including the tagged one. On the wire, an empty tag means none
+    pub start_tag: Option<String>,
+    /// If set, then the server paces the events it sends to this consumer so that it never exceeds this many events per
+    /// second, instead of sending them as fast as possible. On the wire, `0` means unlimited
+    pub max_delivery_rate: Option<u32>,
+    /// If set, then the `namespace` glob is ignored, and the consumer receives only events whose namespace matches this
+    /// regular expression. The regex must match the whole namespace. On the wire, an empty regex means none. An invalid
+    /// regex is rejected with an `InvalidNamespaceRegex` error
+    pub namespace_regex: Option<String>,
+    /// Consumers that start where no events match their namespace normally receive `CursorCreated` followed by
+    /// `AwaitingEvents`, and then wait for matching events to be produced. If this is set, then they instead receive
+    /// `CursorCreated` followed by a `NoMatchingEvents` error, and the consumer is finished
+    pub error_on_empty: bool,
+    /// Streams may limit how long a cursor stays open, after which the server sends `StopConsuming` with the cursor's
+    /// op_id. If this is set, then the cursor is exempt from that limit, which is appropriate for live tailing
+    pub unlimited_lifetime: bool,
+    /// Which events the consumer may read. A single server has no other members to wait for, so `Quorum` currently reads
+    /// the same events as `Local`
+    pub read_consistency: ReadConsistency,
+    /// If set, then every `ReceiveEvent`, `ReceiveEventPrefix`, `EndOfBatch`, and `AwaitingEvents` message for this
+    /// cursor is wrapped in a `CursorMessage` with the cursor's op_id, so that clients with several active cursors can
+    /// tell which one each message is for
+    pub cursor_op_ids: bool,
+    /// If set, then the `version_vector` and `start_tag` are ignored, and the consumer starts after the newest event in
+    /// each partition as of when the server starts the cursor. The consumer receives only events that are produced after
+    /// that point, including on an empty stream
+    pub start_at_tail: bool,
+    /// If set, then the consumer only receives events that had already been written to each partition when the server
+    /// started the cursor. Once it has received all of those, it gets a `StopConsuming` with its op_id instead of
+    /// `AwaitingEvents`, and the cursor is finished. This gives a stable view of the stream for batch processing
+    pub snapshot: bool,
+    /// If set, then once the consumer has caught up, the server sends it a `StreamIdle` each time this many milliseconds
+    /// pass without it receiving an event. On the wire, `0` means none, and the server rejects a consumer start with an
+    /// interval of `Some(0)`
+    pub idle_signal_interval: Option<u32>,
+    /// If cleared, then the consumer doesn't receive events that were produced on its own connection, which avoids
+    /// feedback loops for clients that consume the same namespaces they produce to. Only events produced after the
+    /// connection's first such consumer was started are known to be its own. This is normally set
+    pub see_own_writes: bool,
+    /// If set, then the server follows the `CursorCreated` for this consumer with a `Trace` message describing how long
+    /// each stage of starting the cursor took. This is meant for debugging slow consumer starts
+    pub trace: bool,
+}
+
+/// Consumes every event in every namespace, starting from the beginning of the stream, with none of the optional behavior
+impl Default for NewConsumerStart {
+    fn default() -> Self {
+        NewConsumerStart {
+            op_id: 0,
+            version_vector: Vec::new(),
+            max_events: CONSUME_UNLIMITED,
+            namespace: "/**/*".to_owned(),
+            body_prefix_bytes: None,
+            start_tag: None,
+            max_delivery_rate: None,
+            namespace_regex: None,
+            error_on_empty: false,
+            unlimited_lifetime: false,
+            read_consistency: ReadConsistency::Local,
+            cursor_op_ids: false,
+            start_at_tail: false,
+            snapshot: false,
+            idle_signal_interval: None,
+            see_own_writes: true,
+            trace: false,
+        }
+    }
+}
+
+pub const READ_CONSISTENCY_LOCAL: u8 = 0;
+pub const READ_CONSISTENCY_QUORUM: u8 = 1;
+
+/// Determines which events a consumer may read, in a cluster where events may not yet be acknowledged by every member
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ReadConsistency {
+    /// Read every event that's known to the server the consumer is connected to. This gives the lowest latency
+    Local,
+    /// Read only events that have been acknowledged by a quorum of the cluster, so that they can never be lost
+    Quorum,
+}
+
+impl ReadConsistency {
+    pub fn from_u8(byte: u8) -> Result<ReadConsistency, u8> {
+        match byte {
+            READ_CONSISTENCY_LOCAL => Ok(ReadConsistency::Local),
+            READ_CONSISTENCY_QUORUM => Ok(ReadConsistency::Quorum),
+            other => Err(other)
+        }
+    }
+
+    pub fn u8_value(&self) -> u8 {
+        match *self {
+            ReadConsistency::Local => READ_CONSISTENCY_LOCAL,
+            ReadConsistency::Quorum => READ_CONSISTENCY_QUORUM,
+        }
+    }
 }
 
 
@@ -190,6 +536,19 @@ pub struct ClusterState {
     pub other_members: Vec<ClusterMember>,
 }
 
+impl ClusterState {
+    /// Returns the lowest actor id out of this actor and all the other members. Actor ids are unique within a cluster, so
+    /// every member with the same view of the cluster picks the same one, which makes it a simple choice of coordinator
+    pub fn lowest_actor_id(&self) -> ActorId {
+        self.other_members.iter().map(|member| member.actor_id).fold(self.actor_id, ::std::cmp::min)
+    }
+
+    /// Returns true if the actor that sent this state has the lowest actor id in the cluster
+    pub fn is_self_lowest(&self) -> bool {
+        self.lowest_actor_id() == self.actor_id
+    }
+}
+
 /// Sent in a CursorCreated message from the server to a client to indicate that a cursor was successfully created.
 /// Currently, this message only contains the batch size, but more fields may be added as they become necessary.
 #[derive(Debug, PartialEq, Clone)]
@@ -227,6 +586,305 @@ pub struct SetEventStream{
     pub name: String,
 }
 
+/// Sent by a client to get the status of the named event stream, without changing the stream that the connection uses. The
+/// server responds with an `EventStreamStatus`, or an `ErrorMessage` with `ErrorKind::NoSuchStream`
+#[derive(Debug, PartialEq, Clone)]
+pub struct GetStreamStatus {
+    pub op_id: u32,
+    pub name: String,
+}
+
+/// Sent by a client to remove all events with ids greater than `up_to` from the named event stream. The server responds
+/// with an `EventStreamStatus` for the stream once every partition has been truncated, or an `ErrorMessage` if the truncation
+/// was rejected
+#[derive(Debug, PartialEq, Clone)]
+pub struct TruncateStream {
+    pub op_id: u32,
+    pub name: String,
+    pub up_to: FloEventId,
+}
+
+/// Basic information on a single event stream. Included as part of a `StreamList`
+#[derive(Debug, PartialEq, Clone)]
+pub struct StreamDescriptor {
+    pub name: String,
+    /// The total number of events currently stored in the stream, across all partitions
+    pub event_count: u64,
+    /// The id of the newest event in the stream, or zero if the stream is empty
+    pub head: FloEventId,
+    /// The id of the oldest event in the stream, or zero if the stream is empty
+    pub tail: FloEventId,
+    /// Whether this server will currently accept new events for the stream
+    pub writable: bool,
+    /// The total number of bytes that the stream occupies on disk
+    pub stored_bytes: u64,
+}
+
+/// Sent by the server in response to a `ListStreams` message
+#[derive(Debug, PartialEq, Clone)]
+pub struct StreamList {
+    pub op_id: u32,
+    pub streams: Vec<StreamDescriptor>,
+}
+
+/// A name given to a specific event in an event stream. Included as part of a `TagList`
+#[derive(Debug, PartialEq, Clone)]
+pub struct StreamTag {
+    pub name: String,
+    pub event_id: FloEventId,
+}
+
+/// Sent by a client to look up a single tag in its current event stream. The server responds with a `TagList` that contains
+/// only that tag, or an `ErrorMessage` with `ErrorKind::NoSuchTag`
+#[derive(Debug, PartialEq, Clone)]
+pub struct GetTag {
+    pub op_id: u32,
+    pub name: String,
+}
+
+/// Sent by the server in response to a `ListTags` or `GetTag` message. Tags are sorted by name
+#[derive(Debug, PartialEq, Clone)]
+pub struct TagList {
+    pub op_id: u32,
+    pub tags: Vec<StreamTag>,
+}
+
+/// Sent by a client to check the integrity of the events in the named stream. The server reads through every partition and
+/// responds with an `IntegrityReport`. Nothing in the stream is modified.
+#[derive(Debug, PartialEq, Clone)]
+pub struct VerifyStream {
+    pub op_id: u32,
+    pub name: String,
+    /// The exclusive starting point in each partition. Partitions that aren't included are verified from the beginning,
+    /// so verification of a large stream can be resumed by sending the `last_verified` ids from the previous report
+    pub version_vector: Vec<FloEventId>,
+    /// The maximum number of events to verify in each partition, or `0` for no limit
+    pub max_events: u64,
+    /// Whether to check that the `parent_id` of each event refers to an earlier event that exists in one of the stream's
+    /// partitions
+    pub check_parents: bool,
+}
+
+/// The types of problems that can be found when verifying an event stream. This gets serialized as a u8
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum IntegrityProblemKind {
+    /// An event could not be read because its framing or lengths are corrupt, or because its body failed authentication
+    /// when decrypting it. Nothing after it in the partition can be read
+    Unreadable,
+    /// An event's counter was not greater than the one before it, or it belongs to a different partition
+    OutOfOrder,
+    /// An event's `parent_id` does not refer to an earlier event that exists in any of the stream's partitions
+    InvalidParent,
+}
+
+impl IntegrityProblemKind {
+    pub fn from_u8(byte: u8) -> Result<IntegrityProblemKind, u8> {
+        match byte {
+            1 => Ok(IntegrityProblemKind::Unreadable),
+            2 => Ok(IntegrityProblemKind::OutOfOrder),
+            3 => Ok(IntegrityProblemKind::InvalidParent),
+            other => Err(other)
+        }
+    }
+
+    pub fn u8_value(&self) -> u8 {
+        match *self {
+            IntegrityProblemKind::Unreadable => 1,
+            IntegrityProblemKind::OutOfOrder => 2,
+            IntegrityProblemKind::InvalidParent => 3,
+        }
+    }
+}
+
+/// A single problem found when verifying an event stream. Included as part of a `PartitionIntegrity`
+#[derive(Debug, PartialEq, Clone)]
+pub struct IntegrityProblem {
+    pub kind: IntegrityProblemKind,
+    /// The id of the event with the problem. For `Unreadable` events, the id isn't known, so this is the id of the last
+    /// event that was read successfully
+    pub event_id: FloEventId,
+    pub description: String,
+}
+
+/// The result of verifying a single partition. Included as part of an `IntegrityReport`
+#[derive(Debug, PartialEq, Clone)]
+pub struct PartitionIntegrity {
+    pub partition_num: ActorId,
+    pub events_checked: u64,
+    /// The id of the last event that was read, or the starting point if there were none
+    pub last_verified: FloEventId,
+    /// False if `max_events` were verified before reaching the end of the partition
+    pub complete: bool,
+    /// At most `MAX_REPORTED_INTEGRITY_PROBLEMS` are included for each partition
+    pub problems: Vec<IntegrityProblem>,
+}
+
+pub const MAX_REPORTED_INTEGRITY_PROBLEMS: usize = 16;
+
+/// Sent by the server in response to a `VerifyStream` message
+#[derive(Debug, PartialEq, Clone)]
+pub struct IntegrityReport {
+    pub op_id: u32,
+    pub stream: String,
+    pub partitions: Vec<PartitionIntegrity>,
+}
+
+impl IntegrityReport {
+    /// Returns true if no problems were found in any partition
+    pub fn is_clean(&self) -> bool {
+        self.partitions.iter().all(|p| p.problems.is_empty())
+    }
+
+    /// Returns true if every partition was verified to the end
+    pub fn is_complete(&self) -> bool {
+        self.partitions.iter().all(|p| p.complete)
+    }
+}
+
+/// Sent by a client to read an event along with its chain of ancestors, following each `parent_id` back to the root. Only
+/// ancestors in the connection's current event stream are found
+#[derive(Debug, PartialEq, Clone)]
+pub struct GetAncestry {
+    pub op_id: u32,
+    pub event_id: FloEventId,
+}
+
+/// Sent by the server in response to a `GetAncestry` message. It's immediately followed by `event_count` `ReceiveEvent`
+/// messages, starting with the requested event and then each of its ancestors in turn. The chain ends early if an
+/// ancestor no longer exists, if it refers back to an event that's already in the chain, or if it reaches the server's
+/// depth limit
+#[derive(Debug, PartialEq, Clone)]
+pub struct AncestryInfo {
+    pub op_id: u32,
+    pub event_count: u32,
+}
+
+/// Sent by a client to count the events in its current event stream that match a namespace, without receiving them. The
+/// server reads through the matching events and responds with a `CountResult`
+#[derive(Debug, PartialEq, Clone)]
+pub struct CountEvents {
+    pub op_id: u32,
+    /// Any valid glob pattern, just like the namespace of a `NewConsumerStart`
+    pub namespace: String,
+    /// If set, then only events after this one are counted. Like a `start_tag`, this applies to every partition, since
+    /// event counters are assigned in order across all the partitions in a stream. On the wire, a zero id means none
+    pub since: Option<FloEventId>,
+}
+
+/// Sent by a client to find out how far behind the head of its current event stream a consumer is. The server counts the
+/// events that match the namespace and aren't covered by the version vector, and responds with a `CountResult`
+#[derive(Debug, PartialEq, Clone)]
+pub struct GetConsumerLag {
+    pub op_id: u32,
+    /// Any valid glob pattern, just like the namespace of a `NewConsumerStart`
+    pub namespace: String,
+    /// The consumer's current position, as the exclusive starting counter for each partition. Partitions that aren't
+    /// included are counted from the beginning
+    pub version_vector: Vec<FloEventId>,
+}
+
+/// Sent by the server in response to a `CountEvents` or `GetConsumerLag` message
+#[derive(Debug, PartialEq, Clone)]
+pub struct CountResult {
+    pub op_id: u32,
+    pub count: u64,
+}
+
+/// Sent by a client to check that an otherwise idle connection is still alive. The server responds with an identical
+/// `Heartbeat`, so the client can measure the round trip time by comparing the echoed `timestamp` to the current time
+#[derive(Debug, PartialEq, Clone)]
+pub struct Heartbeat {
+    pub op_id: u32,
+    pub timestamp: Timestamp,
+}
+
+/// The stages of persisting a produced event, or of starting a consumer, that are included in a `Trace`. This gets
+/// serialized as a u8
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TraceStage {
+    /// The server received the complete `ProduceEvent` or `NewConsumerStart`. Every other stage is timed relative to this one
+    Received,
+    /// The event was handed off to its partition. For events with a `parent_id`, this includes checking that the parent
+    /// exists. For a consumer, the request for a reader was sent to every partition that it reads from
+    Queued,
+    /// The partition finished writing the event to its segment, including syncing it to disk if the stream's fsync policy
+    /// required that before the ack, so there's no separate stage for syncing
+    Written,
+    /// The server sent the `EventAck`, or the `CursorCreated` for a consumer
+    Acked,
+    /// Every partition that a consumer reads from created its reader. Consumers have no `Written` stage
+    Opened,
+}
+
+impl TraceStage {
+    pub fn from_u8(byte: u8) -> Result<TraceStage, u8> {
+        match byte {
+            1 => Ok(TraceStage::Received),
+            2 => Ok(TraceStage::Queued),
+            3 => Ok(TraceStage::Written),
+            4 => Ok(TraceStage::Acked),
+            5 => Ok(TraceStage::Opened),
+            other => Err(other)
+        }
+    }
+
+    pub fn u8_value(&self) -> u8 {
+        match *self {
+            TraceStage::Received => 1,
+            TraceStage::Queued => 2,
+            TraceStage::Written => 3,
+            TraceStage::Acked => 4,
+            TraceStage::Opened => 5,
+        }
+    }
+}
+
+/// When a single stage of an operation was reached. Included as part of an `OperationTrace`
+#[derive(Debug, PartialEq, Clone)]
+pub struct StageTiming {
+    pub stage: TraceStage,
+    /// Microseconds since the operation was received by the server
+    pub elapsed_micros: u64,
+}
+
+/// Sent by the server to a caught up consumer that set an `idle_signal_interval`, so that the client can tell an idle cursor
+/// apart from a dead connection
+#[derive(Debug, PartialEq, Clone)]
+pub struct StreamIdle {
+    /// The op_id of the consumer
+    pub op_id: u32,
+    /// The newest event in the consumer's event stream, whether or not it matches the consumer's namespace. This is the
+    /// zero id if the stream is empty
+    pub tail_id: FloEventId,
+    /// When the server sent the message
+    pub server_time: Timestamp,
+}
+
+/// Sent by the server after the `EventAck` for a `ProduceEvent` that set `trace`, or after the `CursorCreated` for a
+/// `NewConsumerStart` that set `trace`. The stages are in the order they were reached, and are timed using a monotonic
+/// clock
+#[derive(Debug, PartialEq, Clone)]
+pub struct OperationTrace {
+    pub op_id: u32,
+    pub stages: Vec<StageTiming>,
+}
+
+/// Sent by the server in response to a `GetCapabilities` message to describe the optional protocol features that it supports.
+/// `flags` is made up of the constants in the `features` module, and `features` has the name of each one
+#[derive(Debug, PartialEq, Clone)]
+pub struct Capabilities {
+    pub op_id: u32,
+    pub flags: u64,
+    pub features: Vec<String>,
+}
+
+impl Capabilities {
+    /// Returns true if every one of the given feature flags is supported
+    pub fn supports(&self, feature_flags: u64) -> bool {
+        self.flags & feature_flags == feature_flags
+    }
+}
+
 /// Sent by the client as the very first message to the server. The server will respond with an `EventStreamStatus` for the current (default) stream
 #[derive(Debug, PartialEq, Clone)]
 pub struct ClientAnnounce {
@@ -250,13 +908,17 @@ pub enum ProtocolMessage<E: FloEvent> {
     ProduceEvent(ProduceEvent),
     /// This is a complete event as serialized over the wire. This message is sent to to both consumers as well as other servers
     ReceiveEvent(E),
+    /// Sent instead of `ReceiveEvent` to consumers that asked for only a prefix of each event body. The event's data is
+    /// just the requested prefix, and the `u32` is the length of the complete body
+    ReceiveEventPrefix(E, u32),
     /// Sent from the server to client to acknowledge that an event was persisted successfully.
     AckEvent(EventAck),
     /// New message sent by a client to start reading events from the stream
     NewStartConsuming(NewConsumerStart),
     /// send by the server to a client in response to a StartConsuming message to indicate the start of a series of events
     CursorCreated(CursorInfo),
-    /// sent by a client to a server to tell the server to stop sending events. This is required in order to reuse the connection for multiple queries
+    /// sent by a client to a server to tell the server to stop sending events. This is required in order to reuse the connection for multiple queries.
+    /// Also sent by the server to a client when a cursor is stopped because it reached its stream's `max_cursor_lifetime`
     StopConsuming(u32),
     /// Sent by the client to set the batch size to use for consuming. It is an error to send this message while consuming.
     SetBatchSize(u32),
@@ -267,10 +929,60 @@ pub enum ProtocolMessage<E: FloEvent> {
     EndOfBatch,
     /// Sent by the server to an active consumer to indicate that it has reached the end of the stream. The server will
     /// continue to send events as more come in, but this just lets the client know that it may be some time before more
-    /// events are available. This message will only be sent at most once to a given consumer.
+    /// events are available. This message will only be sent at most once to a given consumer. A consumer that starts
+    /// with no matching events at all always receives `CursorCreated` immediately followed by `AwaitingEvents`.
     AwaitingEvents,
     /// Represents an error response to any other message
     Error(ErrorMessage),
+    /// Sent by a client to discard all events after a given id from an event stream
+    TruncateStream(TruncateStream),
+    /// Sent by a client to request a `StreamList` describing all of the event streams on the server. Contains only the op_id
+    ListStreams(u32),
+    /// Sent by the server in response to a `ListStreams` message
+    StreamList(StreamList),
+    /// Sent by a client to make the server reject all produces with a `ServerBusy` error until writes are resumed.
+    /// Consumers are unaffected. The server responds with the status of the connection's current event stream
+    PauseWrites(u32),
+    /// Sent by a client to resume accepting writes after a `PauseWrites`. The server responds with the status of the
+    /// connection's current event stream
+    ResumeWrites(u32),
+    /// Sent by a client at any point after the handshake to ask which optional features the server supports. Contains
+    /// only the op_id
+    GetCapabilities(u32),
+    /// Sent by the server in response to a `GetCapabilities` message
+    Capabilities(Capabilities),
+    /// Sent by a client to request a `TagList` with every tag in its current event stream. Contains only the op_id
+    ListTags(u32),
+    /// Sent by a client to look up a single tag in its current event stream
+    GetTag(GetTag),
+    /// Sent by the server in response to a `ListTags` or `GetTag` message
+    TagList(TagList),
+    /// Sent by a client to check the integrity of an event stream
+    VerifyStream(VerifyStream),
+    /// Sent by the server in response to a `VerifyStream` message
+    IntegrityReport(IntegrityReport),
+    /// Sent by a client to read an event and its ancestors from its current event stream
+    GetAncestry(GetAncestry),
+    /// Sent by the server in response to a `GetAncestry` message, just before the events themselves
+    Ancestry(AncestryInfo),
+    /// Sent by a client to count the matching events in its current event stream
+    CountEvents(CountEvents),
+    /// Sent by a client to count the matching events that a consumer hasn't yet received
+    GetConsumerLag(GetConsumerLag),
+    /// Sent by the server in response to a `CountEvents` or `GetConsumerLag` message
+    CountResult(CountResult),
+    /// Sent by a client to check that the connection is alive, and echoed back by the server
+    Heartbeat(Heartbeat),
+    /// Sent by a client to get the `StreamStatus` of any event stream, including the head of each of its partitions
+    GetStreamStatus(GetStreamStatus),
+    /// Sent by the server instead of a bare `ReceiveEvent`, `ReceiveEventPrefix`, `EndOfBatch`, or `AwaitingEvents` to
+    /// consumers that set `cursor_op_ids`. The `u32` is the op_id of the cursor that the wrapped message is for, and it's
+    /// what `get_op_id` returns
+    CursorMessage(u32, Box<ProtocolMessage<E>>),
+    /// Sent by the server after acknowledging a `ProduceEvent` that set `trace`
+    Trace(OperationTrace),
+    /// Sent by the server to a caught up consumer that set an `idle_signal_interval`
+    StreamIdle(StreamIdle),
 }
 
 named!{pub parse_str<String>,
@@ -347,25 +1059,52 @@ named!{pub parse_event_id<Option<FloEventId>>,
 }
 
 named!{pub parse_new_producer_event<ProtocolMessage<OwnedFloEvent>>,
+    map!(parse_produce_event_header, |(mut produce, data_len): (ProduceEvent, usize)| {
+        produce.data = Vec::with_capacity(data_len);
+        ProtocolMessage::ProduceEvent(produce)
+    })
+}
+
+// Parses the header of a `ProduceEvent`, returning it with empty `data` along with the length of its body
+named!{parse_produce_event_header<(ProduceEvent, usize)>,
     chain!(
         _tag: tag!(&[PRODUCE_EVENT]) ~
         namespace: parse_str ~
         parent_id: parse_event_id ~
+        ttl: parse_ttl ~
         op_id: be_u32 ~
         partition: be_u16 ~
-        data_len: be_u32,
+        data_len: be_u32 ~
+        compression: map_res!(be_u8, Compression::from_u8) ~
+        trace: be_u8,
         || {
-            ProtocolMessage::ProduceEvent(ProduceEvent{
+            (ProduceEvent{
                 namespace: namespace.to_owned(),
                 parent_id: parent_id,
                 op_id: op_id,
                 partition: partition,
-                data: Vec::with_capacity(data_len as usize),
-            })
+                ttl: ttl,
+                compression: compression,
+                trace: trace == 1,
+                data: Vec::new(),
+            }, data_len as usize)
         }
     )
 }
 
+named!{parse_ttl<Option<Duration>>,
+    map!(be_u64, ttl_from_millis)
+}
+
+/// Converts a ttl that was sent as milliseconds. A ttl of 0 means that the event doesn't expire
+pub fn ttl_from_millis(millis: u64) -> Option<Duration> {
+    if millis > 0 {
+        Some(Duration::from_millis(millis))
+    } else {
+        None
+    }
+}
+
 named!{parse_timestamp<Timestamp>,
     map!(be_u64, time::from_millis_since_epoch)
 }
@@ -390,6 +1129,27 @@ named!{parse_receive_event_header<ProtocolMessage<OwnedFloEvent>>,
     )
 }
 
+named!{parse_receive_event_prefix<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[RECEIVE_EVENT_PREFIX]) ~
+        id: parse_non_zero_event_id ~
+        parent_id: parse_event_id ~
+        timestamp: parse_timestamp ~
+        namespace: parse_str ~
+        total_data_len: be_u32 ~
+        data: length_data!(be_u32),
+        || {
+           ProtocolMessage::ReceiveEventPrefix(OwnedFloEvent {
+                id: id,
+                parent_id: parent_id,
+                namespace: namespace,
+                timestamp: timestamp,
+                data: data.to_vec(),
+            }, total_data_len)
+        }
+    )
+}
+
 named!{parse_event_ack<ProtocolMessage<OwnedFloEvent>>,
     chain!(
         _tag: tag!(&[ACK_HEADER]) ~
@@ -411,18 +1171,78 @@ named!{parse_new_start_consuming<ProtocolMessage<OwnedFloEvent>>,
         op_id: be_u32 ~
         version_vec: parse_version_vec ~
         max_events: be_u64 ~
-        namespace: parse_str,
+        namespace: parse_str ~
+        body_prefix_bytes: parse_body_prefix_bytes ~
+        start_tag: parse_optional_str ~
+        max_delivery_rate: parse_max_delivery_rate ~
+        namespace_regex: parse_optional_str ~
+        error_on_empty: be_u8 ~
+        unlimited_lifetime: be_u8 ~
+        read_consistency: map_res!(be_u8, ReadConsistency::from_u8) ~
+        cursor_op_ids: be_u8 ~
+        start_at_tail: be_u8 ~
+        snapshot: be_u8 ~
+        idle_signal_interval: parse_idle_signal_interval ~
+        see_own_writes: be_u8 ~
+        trace: be_u8,
         || {
             ProtocolMessage::NewStartConsuming(NewConsumerStart {
                 op_id: op_id,
                 version_vector: version_vec,
                 max_events: max_events,
                 namespace: namespace,
+                body_prefix_bytes: body_prefix_bytes,
+                start_tag: start_tag,
+                max_delivery_rate: max_delivery_rate,
+                namespace_regex: namespace_regex,
+                error_on_empty: error_on_empty == 1,
+                unlimited_lifetime: unlimited_lifetime == 1,
+                read_consistency: read_consistency,
+                cursor_op_ids: cursor_op_ids == 1,
+                start_at_tail: start_at_tail == 1,
+                snapshot: snapshot == 1,
+                idle_signal_interval: idle_signal_interval,
+                see_own_writes: see_own_writes == 1,
+                trace: trace == 1,
             })
         }
     )
 }
 
+named!{parse_max_delivery_rate<Option<u32>>,
+    map!(be_u32, |rate| if rate > 0 { Some(rate) } else { None })
+}
+
+named!{parse_idle_signal_interval<Option<u32>>,
+    map!(be_u32, |millis| if millis > 0 { Some(millis) } else { None })
+}
+
+named!{parse_body_prefix_bytes<Option<u32>>,
+    chain!(
+        present: be_u8 ~
+        prefix_bytes: be_u32,
+        || {
+            if present == 1 {
+                Some(prefix_bytes)
+            } else {
+                None
+            }
+        }
+    )
+}
+
+named!{parse_optional_str<Option<String>>,
+    map!(parse_str, non_empty)
+}
+
+fn non_empty(value: String) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
 named!{parse_set_event_stream<ProtocolMessage<OwnedFloEvent>>,
     chain!(
         _tag: tag!(&[SET_EVENT_STREAM]) ~
@@ -437,50 +1257,381 @@ named!{parse_set_event_stream<ProtocolMessage<OwnedFloEvent>>,
     )
 }
 
-named!{parse_version_vec<Vec<FloEventId>>,
-    length_count!(be_u16, parse_zeroable_event_id)
-}
-
-
-named!{parse_error_message<ProtocolMessage<OwnedFloEvent>>,
+named!{parse_truncate_stream<ProtocolMessage<OwnedFloEvent>>,
     chain!(
-        _tag: tag!(&[ERROR_HEADER]) ~
+        _tag: tag!(&[TRUNCATE_STREAM]) ~
         op_id: be_u32 ~
-        kind: map_res!(take!(1), |res: &[u8]| {
-            ErrorKind::from_u8(res[0])
-        }) ~
-        description: parse_str,
+        name: parse_str ~
+        up_to: parse_zeroable_event_id,
         || {
-            ProtocolMessage::Error(ErrorMessage {
+            ProtocolMessage::TruncateStream(TruncateStream {
                 op_id: op_id,
-                kind: kind,
-                description: description,
+                name: name,
+                up_to: up_to,
             })
         }
     )
 }
 
-named!{parse_awaiting_events<ProtocolMessage<OwnedFloEvent>>, map!(tag!(&[AWAITING_EVENTS]), |_| {ProtocolMessage::AwaitingEvents})}
-
-named!{parse_set_batch_size<ProtocolMessage<OwnedFloEvent>>, chain!(
-    _tag: tag!(&[SET_BATCH_SIZE]) ~
-    batch_size: be_u32,
+named!{parse_list_streams<ProtocolMessage<OwnedFloEvent>>, chain!(
+    _tag: tag!(&[LIST_STREAMS]) ~
+    op_id: be_u32,
     || {
-        ProtocolMessage::SetBatchSize(batch_size)
+        ProtocolMessage::ListStreams(op_id)
     }
 )}
 
-named!{parse_next_batch<ProtocolMessage<OwnedFloEvent>>, map!(tag!(&[NEXT_BATCH]), |_| {ProtocolMessage::NextBatch})}
-named!{parse_end_of_batch<ProtocolMessage<OwnedFloEvent>>, map!(tag!(&[END_OF_BATCH]), |_| {ProtocolMessage::EndOfBatch})}
-named!{parse_stop_consuming<ProtocolMessage<OwnedFloEvent>>, chain!(
-    _tag: tag!(&[STOP_CONSUMING]) ~
+named!{parse_pause_writes<ProtocolMessage<OwnedFloEvent>>, chain!(
+    _tag: tag!(&[PAUSE_WRITES]) ~
     op_id: be_u32,
     || {
-        ProtocolMessage::StopConsuming(op_id)
+        ProtocolMessage::PauseWrites(op_id)
     }
 )}
 
-named!{parse_cursor_created<ProtocolMessage<OwnedFloEvent>>, chain!(
+named!{parse_resume_writes<ProtocolMessage<OwnedFloEvent>>, chain!(
+    _tag: tag!(&[RESUME_WRITES]) ~
+    op_id: be_u32,
+    || {
+        ProtocolMessage::ResumeWrites(op_id)
+    }
+)}
+
+named!{parse_get_capabilities<ProtocolMessage<OwnedFloEvent>>, chain!(
+    _tag: tag!(&[GET_CAPABILITIES]) ~
+    op_id: be_u32,
+    || {
+        ProtocolMessage::GetCapabilities(op_id)
+    }
+)}
+
+named!{parse_capabilities<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[CAPABILITIES]) ~
+        op_id: be_u32 ~
+        flags: be_u64 ~
+        features: length_count!(be_u16, parse_str),
+        || {
+            ProtocolMessage::Capabilities(Capabilities {
+                op_id: op_id,
+                flags: flags,
+                features: features,
+            })
+        }
+    )
+}
+
+named!{parse_list_tags<ProtocolMessage<OwnedFloEvent>>, chain!(
+    _tag: tag!(&[LIST_TAGS]) ~
+    op_id: be_u32,
+    || {
+        ProtocolMessage::ListTags(op_id)
+    }
+)}
+
+named!{parse_get_tag<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[GET_TAG]) ~
+        op_id: be_u32 ~
+        name: parse_str,
+        || {
+            ProtocolMessage::GetTag(GetTag {
+                op_id: op_id,
+                name: name,
+            })
+        }
+    )
+}
+
+named!{parse_stream_tag<StreamTag>,
+    chain!(
+        name: parse_str ~
+        event_id: parse_zeroable_event_id,
+        || {
+            StreamTag {
+                name: name,
+                event_id: event_id,
+            }
+        }
+    )
+}
+
+named!{parse_tag_list<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[TAG_LIST]) ~
+        op_id: be_u32 ~
+        tags: length_count!(be_u16, parse_stream_tag),
+        || {
+            ProtocolMessage::TagList(TagList {
+                op_id: op_id,
+                tags: tags,
+            })
+        }
+    )
+}
+
+named!{parse_verify_stream<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[VERIFY_STREAM]) ~
+        op_id: be_u32 ~
+        name: parse_str ~
+        version_vector: parse_version_vec ~
+        max_events: be_u64 ~
+        check_parents: be_u8,
+        || {
+            ProtocolMessage::VerifyStream(VerifyStream {
+                op_id: op_id,
+                name: name,
+                version_vector: version_vector,
+                max_events: max_events,
+                check_parents: check_parents == 1,
+            })
+        }
+    )
+}
+
+named!{parse_integrity_problem<IntegrityProblem>,
+    chain!(
+        kind: map_res!(be_u8, IntegrityProblemKind::from_u8) ~
+        event_id: parse_zeroable_event_id ~
+        description: parse_str,
+        || {
+            IntegrityProblem {
+                kind: kind,
+                event_id: event_id,
+                description: description,
+            }
+        }
+    )
+}
+
+named!{parse_partition_integrity<PartitionIntegrity>,
+    chain!(
+        partition_num: be_u16 ~
+        events_checked: be_u64 ~
+        last_verified: parse_zeroable_event_id ~
+        complete: be_u8 ~
+        problems: length_count!(be_u16, parse_integrity_problem),
+        || {
+            PartitionIntegrity {
+                partition_num: partition_num,
+                events_checked: events_checked,
+                last_verified: last_verified,
+                complete: complete == 1,
+                problems: problems,
+            }
+        }
+    )
+}
+
+named!{parse_integrity_report<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[INTEGRITY_REPORT]) ~
+        op_id: be_u32 ~
+        stream: parse_str ~
+        partitions: length_count!(be_u16, parse_partition_integrity),
+        || {
+            ProtocolMessage::IntegrityReport(IntegrityReport {
+                op_id: op_id,
+                stream: stream,
+                partitions: partitions,
+            })
+        }
+    )
+}
+
+named!{parse_get_ancestry<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[GET_ANCESTRY]) ~
+        op_id: be_u32 ~
+        event_id: parse_zeroable_event_id,
+        || {
+            ProtocolMessage::GetAncestry(GetAncestry {
+                op_id: op_id,
+                event_id: event_id,
+            })
+        }
+    )
+}
+
+named!{parse_ancestry<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[ANCESTRY]) ~
+        op_id: be_u32 ~
+        event_count: be_u32,
+        || {
+            ProtocolMessage::Ancestry(AncestryInfo {
+                op_id: op_id,
+                event_count: event_count,
+            })
+        }
+    )
+}
+
+named!{parse_count_events<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[COUNT_EVENTS]) ~
+        op_id: be_u32 ~
+        namespace: parse_str ~
+        since: parse_zeroable_event_id,
+        || {
+            ProtocolMessage::CountEvents(CountEvents {
+                op_id: op_id,
+                namespace: namespace,
+                since: if since.is_zero() { None } else { Some(since) },
+            })
+        }
+    )
+}
+
+named!{parse_get_consumer_lag<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[GET_CONSUMER_LAG]) ~
+        op_id: be_u32 ~
+        namespace: parse_str ~
+        version_vector: parse_version_vec,
+        || {
+            ProtocolMessage::GetConsumerLag(GetConsumerLag {
+                op_id: op_id,
+                namespace: namespace,
+                version_vector: version_vector,
+            })
+        }
+    )
+}
+
+named!{parse_count_result<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[COUNT_RESULT]) ~
+        op_id: be_u32 ~
+        count: be_u64,
+        || {
+            ProtocolMessage::CountResult(CountResult {
+                op_id: op_id,
+                count: count,
+            })
+        }
+    )
+}
+
+named!{parse_heartbeat<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[HEARTBEAT]) ~
+        op_id: be_u32 ~
+        timestamp: parse_timestamp,
+        || {
+            ProtocolMessage::Heartbeat(Heartbeat {
+                op_id: op_id,
+                timestamp: timestamp,
+            })
+        }
+    )
+}
+
+named!{parse_get_stream_status<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[GET_STREAM_STATUS]) ~
+        op_id: be_u32 ~
+        name: parse_str,
+        || {
+            ProtocolMessage::GetStreamStatus(GetStreamStatus {
+                op_id: op_id,
+                name: name,
+            })
+        }
+    )
+}
+
+named!{parse_cursor_message<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[CURSOR_MESSAGE]) ~
+        op_id: be_u32 ~
+        message: alt!(parse_receive_event_header | parse_receive_event_prefix | parse_end_of_batch | parse_awaiting_events),
+        || {
+            ProtocolMessage::CursorMessage(op_id, Box::new(message))
+        }
+    )
+}
+
+named!{parse_stream_descriptor<StreamDescriptor>,
+    chain!(
+        name: parse_str ~
+        event_count: be_u64 ~
+        head: parse_zeroable_event_id ~
+        tail: parse_zeroable_event_id ~
+        writable: be_u8 ~
+        stored_bytes: be_u64,
+        || {
+            StreamDescriptor {
+                name: name,
+                event_count: event_count,
+                head: head,
+                tail: tail,
+                writable: writable == 1,
+                stored_bytes: stored_bytes,
+            }
+        }
+    )
+}
+
+named!{parse_stream_list<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[STREAM_LIST]) ~
+        op_id: be_u32 ~
+        streams: length_count!(be_u16, parse_stream_descriptor),
+        || {
+            ProtocolMessage::StreamList(StreamList {
+                op_id: op_id,
+                streams: streams,
+            })
+        }
+    )
+}
+
+named!{parse_version_vec<Vec<FloEventId>>,
+    length_count!(be_u16, parse_zeroable_event_id)
+}
+
+
+named!{parse_error_message<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[ERROR_HEADER]) ~
+        op_id: be_u32 ~
+        kind: map_res!(take!(1), |res: &[u8]| {
+            ErrorKind::from_u8(res[0])
+        }) ~
+        description: parse_str,
+        || {
+            ProtocolMessage::Error(ErrorMessage {
+                op_id: op_id,
+                kind: kind,
+                description: description,
+            })
+        }
+    )
+}
+
+named!{parse_awaiting_events<ProtocolMessage<OwnedFloEvent>>, map!(tag!(&[AWAITING_EVENTS]), |_| {ProtocolMessage::AwaitingEvents})}
+
+named!{parse_set_batch_size<ProtocolMessage<OwnedFloEvent>>, chain!(
+    _tag: tag!(&[SET_BATCH_SIZE]) ~
+    batch_size: be_u32,
+    || {
+        ProtocolMessage::SetBatchSize(batch_size)
+    }
+)}
+
+named!{parse_next_batch<ProtocolMessage<OwnedFloEvent>>, map!(tag!(&[NEXT_BATCH]), |_| {ProtocolMessage::NextBatch})}
+named!{parse_end_of_batch<ProtocolMessage<OwnedFloEvent>>, map!(tag!(&[END_OF_BATCH]), |_| {ProtocolMessage::EndOfBatch})}
+named!{parse_stop_consuming<ProtocolMessage<OwnedFloEvent>>, chain!(
+    _tag: tag!(&[STOP_CONSUMING]) ~
+    op_id: be_u32,
+    || {
+        ProtocolMessage::StopConsuming(op_id)
+    }
+)}
+
+named!{parse_cursor_created<ProtocolMessage<OwnedFloEvent>>, chain!(
     _tag: tag!(&[headers::CURSOR_CREATED]) ~
     op_id: be_u32 ~
     batch_size: be_u32,
@@ -510,9 +1661,61 @@ named!{parse_client_announce<ProtocolMessage<OwnedFloEvent>>, chain!(
     }
 )}
 
+named!{parse_stage_timing<StageTiming>,
+    chain!(
+        stage: map_res!(be_u8, TraceStage::from_u8) ~
+        elapsed_micros: be_u64,
+        || {
+            StageTiming {
+                stage: stage,
+                elapsed_micros: elapsed_micros,
+            }
+        }
+    )
+}
+
+named!{parse_trace<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[TRACE]) ~
+        op_id: be_u32 ~
+        stages: length_count!(be_u16, parse_stage_timing),
+        || {
+            ProtocolMessage::Trace(OperationTrace {
+                op_id: op_id,
+                stages: stages,
+            })
+        }
+    )
+}
+
+named!{parse_stream_idle<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[STREAM_IDLE]) ~
+        op_id: be_u32 ~
+        tail_id: parse_zeroable_event_id ~
+        server_time: parse_timestamp,
+        || {
+            ProtocolMessage::StreamIdle(StreamIdle {
+                op_id: op_id,
+                tail_id: tail_id,
+                server_time: server_time,
+            })
+        }
+    )
+}
+
+// Parses any message the same as `parse_any`, except that a `ProduceEvent` is returned with empty `data` instead of having
+// room allocated for its whole body, along with the length of the body that follows it. The length of the body is
+// always 0 for other messages
+named!{pub parse_any_header<(ProtocolMessage<OwnedFloEvent>, usize)>, alt!(
+        map!(parse_produce_event_header, |(produce, data_len)| (ProtocolMessage::ProduceEvent(produce), data_len)) |
+        map!(parse_any, |message| (message, 0))
+)}
+
 named!{pub parse_any<ProtocolMessage<OwnedFloEvent>>, alt!(
         parse_event_ack |
         parse_receive_event_header |
+        parse_receive_event_prefix |
         parse_error_message |
         parse_awaiting_events |
         parse_new_producer_event |
@@ -524,7 +1727,29 @@ named!{pub parse_any<ProtocolMessage<OwnedFloEvent>>, alt!(
         parse_new_start_consuming |
         parse_set_event_stream |
         parse_event_stream_status |
-        parse_client_announce
+        parse_client_announce |
+        parse_truncate_stream |
+        parse_list_streams |
+        parse_stream_list |
+        parse_pause_writes |
+        parse_resume_writes |
+        parse_get_capabilities |
+        parse_capabilities |
+        parse_list_tags |
+        parse_get_tag |
+        parse_tag_list |
+        parse_verify_stream |
+        parse_integrity_report |
+        parse_get_ancestry |
+        parse_ancestry |
+        parse_count_events |
+        parse_get_consumer_lag |
+        parse_count_result |
+        parse_heartbeat |
+        parse_get_stream_status |
+        parse_cursor_message |
+        parse_trace |
+        parse_stream_idle
 )}
 
 fn serialize_new_produce_header(header: &ProduceEvent, buf: &mut [u8]) -> usize {
@@ -536,12 +1761,20 @@ fn serialize_new_produce_header(header: &ProduceEvent, buf: &mut [u8]) -> usize
                         .write_string(&header.namespace)
                         .write_u64(counter)
                         .write_u16(actor)
+                        .write_u64(header.ttl.map(duration_millis).unwrap_or(0))
                         .write_u32(header.op_id)
                         .write_u16(header.partition)
                         .write_u32(header.data.len() as u32)
+                        .write_u8(header.compression.u8_value())
+                        .write_bool(header.trace)
                         .finish()
 }
 
+/// Converts a duration, such as a ttl, to whole milliseconds, which is how it's sent to and stored by the server
+pub fn duration_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
 fn serialize_event_ack(ack: &EventAck, buf: &mut [u8]) -> usize {
     Serializer::new(buf).write_u8(ACK_HEADER)
             .write_u32(ack.op_id)
@@ -559,16 +1792,27 @@ fn serialize_error_message(err: &ErrorMessage, buf: &mut [u8]) -> usize {
 }
 
 fn serialize_receive_event_header<E: FloEvent>(event: &E, buf: &mut [u8]) -> usize {
+    serialize_event_header_fields(RECEIVE_EVENT, event, buf)
+            .write_u32(event.data_len())
+            .finish()
+}
+
+fn serialize_receive_event_prefix_header<E: FloEvent>(event: &E, total_data_len: u32, buf: &mut [u8]) -> usize {
+    serialize_event_header_fields(RECEIVE_EVENT_PREFIX, event, buf)
+            .write_u32(total_data_len)
+            .write_u32(event.data_len())
+            .finish()
+}
+
+fn serialize_event_header_fields<'a, E: FloEvent>(header: u8, event: &E, buf: &'a mut [u8]) -> Serializer<'a> {
     Serializer::new(buf)
-            .write_u8(::client::headers::RECEIVE_EVENT)
+            .write_u8(header)
             .write_u64(event.id().event_counter)
             .write_u16(event.id().actor)
             .write_u64(event.parent_id().map(|id| id.event_counter).unwrap_or(0))
             .write_u16(event.parent_id().map(|id| id.actor).unwrap_or(0))
             .write_u64(time::millis_since_epoch(event.timestamp()))
             .write_string(event.namespace())
-            .write_u32(event.data_len())
-            .finish()
 }
 
 fn serialize_event_stream_status(status: &EventStreamStatus, buf: &mut [u8]) -> usize {
@@ -586,8 +1830,53 @@ fn serialize_event_stream_status(status: &EventStreamStatus, buf: &mut [u8]) ->
             .finish()
 }
 
+fn serialize_stream_list(list: &StreamList, buf: &mut [u8]) -> usize {
+    Serializer::new(buf)
+            .write_u8(STREAM_LIST)
+            .write_u32(list.op_id)
+            .write_u16(list.streams.len() as u16)
+            .write_many(list.streams.iter(), |ser, stream| {
+                let writable: u8 = if stream.writable { 1 } else { 0 };
+                ser.write_string(&stream.name)
+                        .write_u64(stream.event_count)
+                        .write_u64(stream.head.event_counter)
+                        .write_u16(stream.head.actor)
+                        .write_u64(stream.tail.event_counter)
+                        .write_u16(stream.tail.actor)
+                        .write_u8(writable)
+                        .write_u64(stream.stored_bytes)
+            })
+            .finish()
+}
+
+fn serialize_integrity_report(report: &IntegrityReport, buf: &mut [u8]) -> usize {
+    Serializer::new(buf)
+            .write_u8(INTEGRITY_REPORT)
+            .write_u32(report.op_id)
+            .write_string(&report.stream)
+            .write_u16(report.partitions.len() as u16)
+            .write_many(report.partitions.iter(), |ser, partition| {
+                ser.write_u16(partition.partition_num)
+                        .write_u64(partition.events_checked)
+                        .write_u64(partition.last_verified.event_counter)
+                        .write_u16(partition.last_verified.actor)
+                        .write_bool(partition.complete)
+                        .write_u16(partition.problems.len() as u16)
+                        .write_many(partition.problems.iter(), |ser, problem| {
+                            ser.write_u8(problem.kind.u8_value())
+                                    .write_u64(problem.event_id.event_counter)
+                                    .write_u16(problem.event_id.actor)
+                                    .write_string(&problem.description)
+                        })
+            })
+            .finish()
+}
+
 impl <E: FloEvent> ProtocolMessage<E> {
 
+    /// Serializes the message header into the given buffer and returns the number of bytes that it needs. If that's more
+    /// than the length of the buffer, then the header was not written completely and must be serialized again into a
+    /// buffer that's at least that large.
     pub fn serialize(&self, buf: &mut [u8]) -> usize {
         match *self {
             ProtocolMessage::Announce(ref announce) => {
@@ -612,15 +1901,15 @@ impl <E: FloEvent> ProtocolMessage<E> {
             ProtocolMessage::ReceiveEvent(ref event) => {
                 serialize_receive_event_header(event, buf)
             }
+            ProtocolMessage::ReceiveEventPrefix(ref event, total_data_len) => {
+                serialize_receive_event_prefix_header(event, total_data_len, buf)
+            }
             ProtocolMessage::CursorCreated(ref info) => {
                 Serializer::new(buf).write_u8(headers::CURSOR_CREATED)
                         .write_u32(info.op_id)
                         .write_u32(info.batch_size)
                         .finish()
             }
-            ProtocolMessage::AwaitingEvents => {
-                Serializer::new(buf).write_u8(AWAITING_EVENTS).finish()
-            }
             ProtocolMessage::StopConsuming(op_id) => {
                 Serializer::new(buf)
                         .write_u8(headers::STOP_CONSUMING)
@@ -630,7 +1919,7 @@ impl <E: FloEvent> ProtocolMessage<E> {
             ProtocolMessage::ProduceEvent(ref header) => {
                 serialize_new_produce_header(header, buf)
             }
-            ProtocolMessage::NewStartConsuming(NewConsumerStart{ref op_id, ref version_vector, ref max_events, ref namespace}) => {
+            ProtocolMessage::NewStartConsuming(NewConsumerStart{ref op_id, ref version_vector, ref max_events, ref namespace, ref body_prefix_bytes, ref start_tag, ref max_delivery_rate, ref namespace_regex, ref error_on_empty, ref unlimited_lifetime, ref read_consistency, ref cursor_op_ids, ref start_at_tail, ref snapshot, ref idle_signal_interval, ref see_own_writes, ref trace}) => {
                 let mut serializer = Serializer::new(buf).write_u8(NEW_START_CONSUMING)
                         .write_u32(*op_id)
                         .write_u16(version_vector.len() as u16);
@@ -639,7 +1928,22 @@ impl <E: FloEvent> ProtocolMessage<E> {
                     serializer = serializer.write_u64(id.event_counter).write_u16(id.actor);
                 }
                 serializer.write_u64(*max_events)
-                        .write_string(namespace).finish()
+                        .write_string(namespace)
+                        .write_u8(if body_prefix_bytes.is_some() { 1 } else { 0 })
+                        .write_u32(body_prefix_bytes.unwrap_or(0))
+                        .write_string(start_tag.as_ref().map(|tag| tag.as_str()).unwrap_or(""))
+                        .write_u32(max_delivery_rate.unwrap_or(0))
+                        .write_string(namespace_regex.as_ref().map(|regex| regex.as_str()).unwrap_or(""))
+                        .write_bool(*error_on_empty)
+                        .write_bool(*unlimited_lifetime)
+                        .write_u8(read_consistency.u8_value())
+                        .write_bool(*cursor_op_ids)
+                        .write_bool(*start_at_tail)
+                        .write_bool(*snapshot)
+                        .write_u32(idle_signal_interval.unwrap_or(0))
+                        .write_bool(*see_own_writes)
+                        .write_bool(*trace)
+                        .finish()
             }
             ProtocolMessage::AckEvent(ref ack) => {
                 serialize_event_ack(ack, buf)
@@ -652,25 +1956,203 @@ impl <E: FloEvent> ProtocolMessage<E> {
                                     .write_u32(batch_size)
                                     .finish()
             }
-            ProtocolMessage::NextBatch => {
-                buf[0] = NEXT_BATCH;
-                1
+            ProtocolMessage::NextBatch | ProtocolMessage::EndOfBatch | ProtocolMessage::AwaitingEvents => {
+                Serializer::new(buf).write_u8(self.serialize_control().unwrap()).finish()
+            }
+            ProtocolMessage::TruncateStream(ref truncate) => {
+                Serializer::new(buf)
+                        .write_u8(TRUNCATE_STREAM)
+                        .write_u32(truncate.op_id)
+                        .write_string(&truncate.name)
+                        .write_u64(truncate.up_to.event_counter)
+                        .write_u16(truncate.up_to.actor)
+                        .finish()
+            }
+            ProtocolMessage::ListStreams(op_id) => {
+                Serializer::new(buf)
+                        .write_u8(LIST_STREAMS)
+                        .write_u32(op_id)
+                        .finish()
+            }
+            ProtocolMessage::StreamList(ref list) => {
+                serialize_stream_list(list, buf)
+            }
+            ProtocolMessage::PauseWrites(op_id) => {
+                Serializer::new(buf)
+                        .write_u8(PAUSE_WRITES)
+                        .write_u32(op_id)
+                        .finish()
+            }
+            ProtocolMessage::ResumeWrites(op_id) => {
+                Serializer::new(buf)
+                        .write_u8(RESUME_WRITES)
+                        .write_u32(op_id)
+                        .finish()
+            }
+            ProtocolMessage::GetCapabilities(op_id) => {
+                Serializer::new(buf)
+                        .write_u8(GET_CAPABILITIES)
+                        .write_u32(op_id)
+                        .finish()
+            }
+            ProtocolMessage::Capabilities(ref capabilities) => {
+                Serializer::new(buf)
+                        .write_u8(CAPABILITIES)
+                        .write_u32(capabilities.op_id)
+                        .write_u64(capabilities.flags)
+                        .write_u16(capabilities.features.len() as u16)
+                        .write_many(capabilities.features.iter(), |ser, feature| ser.write_string(feature))
+                        .finish()
+            }
+            ProtocolMessage::ListTags(op_id) => {
+                Serializer::new(buf)
+                        .write_u8(LIST_TAGS)
+                        .write_u32(op_id)
+                        .finish()
+            }
+            ProtocolMessage::GetTag(ref get_tag) => {
+                Serializer::new(buf)
+                        .write_u8(GET_TAG)
+                        .write_u32(get_tag.op_id)
+                        .write_string(&get_tag.name)
+                        .finish()
+            }
+            ProtocolMessage::VerifyStream(ref verify) => {
+                Serializer::new(buf)
+                        .write_u8(VERIFY_STREAM)
+                        .write_u32(verify.op_id)
+                        .write_string(&verify.name)
+                        .write_u16(verify.version_vector.len() as u16)
+                        .write_many(verify.version_vector.iter(), |ser, id| {
+                            ser.write_u64(id.event_counter).write_u16(id.actor)
+                        })
+                        .write_u64(verify.max_events)
+                        .write_bool(verify.check_parents)
+                        .finish()
+            }
+            ProtocolMessage::IntegrityReport(ref report) => {
+                serialize_integrity_report(report, buf)
             }
-            ProtocolMessage::EndOfBatch => {
-                buf[0] = END_OF_BATCH;
-                1
+            ProtocolMessage::GetAncestry(ref get_ancestry) => {
+                Serializer::new(buf)
+                        .write_u8(GET_ANCESTRY)
+                        .write_u32(get_ancestry.op_id)
+                        .write_u64(get_ancestry.event_id.event_counter)
+                        .write_u16(get_ancestry.event_id.actor)
+                        .finish()
+            }
+            ProtocolMessage::Ancestry(ref info) => {
+                Serializer::new(buf)
+                        .write_u8(ANCESTRY)
+                        .write_u32(info.op_id)
+                        .write_u32(info.event_count)
+                        .finish()
+            }
+            ProtocolMessage::CountEvents(ref count) => {
+                let since = count.since.unwrap_or(FloEventId::zero());
+                Serializer::new(buf)
+                        .write_u8(COUNT_EVENTS)
+                        .write_u32(count.op_id)
+                        .write_string(&count.namespace)
+                        .write_u64(since.event_counter)
+                        .write_u16(since.actor)
+                        .finish()
+            }
+            ProtocolMessage::GetConsumerLag(ref get_lag) => {
+                Serializer::new(buf)
+                        .write_u8(GET_CONSUMER_LAG)
+                        .write_u32(get_lag.op_id)
+                        .write_string(&get_lag.namespace)
+                        .write_u16(get_lag.version_vector.len() as u16)
+                        .write_many(get_lag.version_vector.iter(), |ser, id| {
+                            ser.write_u64(id.event_counter).write_u16(id.actor)
+                        })
+                        .finish()
+            }
+            ProtocolMessage::CountResult(ref result) => {
+                Serializer::new(buf)
+                        .write_u8(COUNT_RESULT)
+                        .write_u32(result.op_id)
+                        .write_u64(result.count)
+                        .finish()
+            }
+            ProtocolMessage::Heartbeat(ref heartbeat) => {
+                Serializer::new(buf)
+                        .write_u8(HEARTBEAT)
+                        .write_u32(heartbeat.op_id)
+                        .write_u64(time::millis_since_epoch(heartbeat.timestamp))
+                        .finish()
+            }
+            ProtocolMessage::GetStreamStatus(ref get_status) => {
+                Serializer::new(buf)
+                        .write_u8(GET_STREAM_STATUS)
+                        .write_u32(get_status.op_id)
+                        .write_string(&get_status.name)
+                        .finish()
+            }
+            ProtocolMessage::CursorMessage(op_id, ref message) => {
+                let header_len = Serializer::new(buf)
+                        .write_u8(CURSOR_MESSAGE)
+                        .write_u32(op_id)
+                        .finish();
+                let body_start = ::std::cmp::min(header_len, buf.len());
+                header_len + message.serialize(&mut buf[body_start..])
+            }
+            ProtocolMessage::Trace(ref trace) => {
+                Serializer::new(buf)
+                        .write_u8(TRACE)
+                        .write_u32(trace.op_id)
+                        .write_u16(trace.stages.len() as u16)
+                        .write_many(trace.stages.iter(), |ser, timing| {
+                            ser.write_u8(timing.stage.u8_value()).write_u64(timing.elapsed_micros)
+                        })
+                        .finish()
+            }
+            ProtocolMessage::StreamIdle(ref idle) => {
+                Serializer::new(buf)
+                        .write_u8(STREAM_IDLE)
+                        .write_u32(idle.op_id)
+                        .write_u64(idle.tail_id.event_counter)
+                        .write_u16(idle.tail_id.actor)
+                        .write_u64(time::millis_since_epoch(idle.server_time))
+                        .finish()
+            }
+            ProtocolMessage::TagList(ref list) => {
+                Serializer::new(buf)
+                        .write_u8(TAG_LIST)
+                        .write_u32(list.op_id)
+                        .write_u16(list.tags.len() as u16)
+                        .write_many(list.tags.iter(), |ser, tag| {
+                            ser.write_string(&tag.name)
+                                    .write_u64(tag.event_id.event_counter)
+                                    .write_u16(tag.event_id.actor)
+                        })
+                        .finish()
             }
         }
     }
 
+    /// Returns the single byte that represents this message if it's one of the control messages that has no payload at
+    /// all (`NextBatch`, `EndOfBatch`, or `AwaitingEvents`). Returns `None` for all other messages, including
+    /// `StopConsuming`, since it carries an op_id.
+    pub fn serialize_control(&self) -> Option<u8> {
+        match *self {
+            ProtocolMessage::NextBatch => Some(NEXT_BATCH),
+            ProtocolMessage::EndOfBatch => Some(END_OF_BATCH),
+            ProtocolMessage::AwaitingEvents => Some(AWAITING_EVENTS),
+            _ => None
+        }
+    }
+
     pub fn get_body(&self) -> Option<&[u8]> {
         match *self {
             ProtocolMessage::ProduceEvent(ref produce) => {
                 Some(produce.data.as_slice())
             }
-            ProtocolMessage::ReceiveEvent(ref event) => {
+            ProtocolMessage::ReceiveEvent(ref event) | ProtocolMessage::ReceiveEventPrefix(ref event, _) => {
                 Some(event.data())
             }
+            ProtocolMessage::CursorMessage(_, ref message) => message.get_body(),
             _ => None
         }
     }
@@ -685,6 +2167,28 @@ impl <E: FloEvent> ProtocolMessage<E> {
             ProtocolMessage::StreamStatus(ref status) => status.op_id,
             ProtocolMessage::SetEventStream(ref set) => set.op_id,
             ProtocolMessage::StopConsuming(ref op_id) => *op_id,
+            ProtocolMessage::TruncateStream(ref truncate) => truncate.op_id,
+            ProtocolMessage::ListStreams(op_id) => op_id,
+            ProtocolMessage::StreamList(ref list) => list.op_id,
+            ProtocolMessage::PauseWrites(op_id) => op_id,
+            ProtocolMessage::ResumeWrites(op_id) => op_id,
+            ProtocolMessage::GetCapabilities(op_id) => op_id,
+            ProtocolMessage::Capabilities(ref capabilities) => capabilities.op_id,
+            ProtocolMessage::ListTags(op_id) => op_id,
+            ProtocolMessage::GetTag(ref get_tag) => get_tag.op_id,
+            ProtocolMessage::TagList(ref list) => list.op_id,
+            ProtocolMessage::VerifyStream(ref verify) => verify.op_id,
+            ProtocolMessage::IntegrityReport(ref report) => report.op_id,
+            ProtocolMessage::GetAncestry(ref get_ancestry) => get_ancestry.op_id,
+            ProtocolMessage::Ancestry(ref info) => info.op_id,
+            ProtocolMessage::CountEvents(ref count) => count.op_id,
+            ProtocolMessage::GetConsumerLag(ref get_lag) => get_lag.op_id,
+            ProtocolMessage::CountResult(ref result) => result.op_id,
+            ProtocolMessage::Heartbeat(ref heartbeat) => heartbeat.op_id,
+            ProtocolMessage::GetStreamStatus(ref get_status) => get_status.op_id,
+            ProtocolMessage::CursorMessage(op_id, _) => op_id,
+            ProtocolMessage::Trace(ref trace) => trace.op_id,
+            ProtocolMessage::StreamIdle(ref idle) => idle.op_id,
             _ => 0
         }
     }
@@ -786,6 +2290,317 @@ mod test {
         test_serialize_then_deserialize(&ProtocolMessage::SetEventStream(set_stream));
     }
 
+    #[test]
+    fn serde_truncate_stream() {
+        let truncate = TruncateStream {
+            op_id: 8765,
+            name: "foo".to_owned(),
+            up_to: FloEventId::new(3, 456),
+        };
+        test_serialize_then_deserialize(&ProtocolMessage::TruncateStream(truncate));
+    }
+
+    #[test]
+    fn serde_list_streams() {
+        test_serialize_then_deserialize(&ProtocolMessage::ListStreams(9753));
+    }
+
+    #[test]
+    fn serde_pause_and_resume_writes() {
+        test_serialize_then_deserialize(&ProtocolMessage::PauseWrites(4321));
+        test_serialize_then_deserialize(&ProtocolMessage::ResumeWrites(4322));
+    }
+
+    #[test]
+    fn serde_get_capabilities_and_capabilities() {
+        test_serialize_then_deserialize(&ProtocolMessage::GetCapabilities(55));
+        test_serialize_then_deserialize(&ProtocolMessage::Capabilities(Capabilities {
+            op_id: 55,
+            flags: features::EVENT_TTL | features::LIST_STREAMS,
+            features: vec!["event_ttl".to_owned(), "list_streams".to_owned()],
+        }));
+    }
+
+    #[test]
+    fn serde_new_start_consuming_with_start_tag() {
+        test_serialize_then_deserialize(&ProtocolMessage::NewStartConsuming(NewConsumerStart {
+            op_id: 3,
+            version_vector: vec![FloEventId::new(1, 0)],
+            max_events: 1,
+            namespace: "/foo/*".to_owned(),
+            start_tag: Some("v1.2".to_owned()),
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn serde_new_start_consuming_with_max_delivery_rate() {
+        test_serialize_then_deserialize(&ProtocolMessage::NewStartConsuming(NewConsumerStart {
+            op_id: 4,
+            version_vector: vec![FloEventId::new(1, 0)],
+            namespace: "/foo/*".to_owned(),
+            body_prefix_bytes: Some(4),
+            max_delivery_rate: Some(500),
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn serde_new_start_consuming_with_namespace_regex() {
+        test_serialize_then_deserialize(&ProtocolMessage::NewStartConsuming(NewConsumerStart {
+            op_id: 5,
+            version_vector: vec![FloEventId::new(1, 0)],
+            namespace_regex: Some(r"/orders/\d+/shipped".to_owned()),
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn serde_new_start_consuming_with_error_on_empty() {
+        test_serialize_then_deserialize(&ProtocolMessage::NewStartConsuming(NewConsumerStart {
+            op_id: 6,
+            version_vector: vec![FloEventId::new(1, 0)],
+            namespace: "/future/*".to_owned(),
+            error_on_empty: true,
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn serde_new_start_consuming_with_unlimited_lifetime() {
+        test_serialize_then_deserialize(&ProtocolMessage::NewStartConsuming(NewConsumerStart {
+            op_id: 7,
+            namespace: "/live/**".to_owned(),
+            unlimited_lifetime: true,
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn serde_new_start_consuming_with_each_read_consistency() {
+        for read_consistency in vec![ReadConsistency::Local, ReadConsistency::Quorum] {
+            test_serialize_then_deserialize(&ProtocolMessage::NewStartConsuming(NewConsumerStart {
+                op_id: 8,
+                version_vector: vec![FloEventId::new(1, 5)],
+                max_events: 10,
+                namespace: "/foo/*".to_owned(),
+                read_consistency: read_consistency,
+                ..Default::default()
+            }));
+        }
+    }
+
+    #[test]
+    fn serde_tag_messages() {
+        test_serialize_then_deserialize(&ProtocolMessage::ListTags(8));
+        test_serialize_then_deserialize(&ProtocolMessage::GetTag(GetTag {
+            op_id: 9,
+            name: "v1.2".to_owned(),
+        }));
+        test_serialize_then_deserialize(&ProtocolMessage::TagList(TagList {
+            op_id: 9,
+            tags: vec![
+                StreamTag { name: "v1.1".to_owned(), event_id: FloEventId::new(1, 40) },
+                StreamTag { name: "v1.2".to_owned(), event_id: FloEventId::new(2, 77) },
+            ],
+        }));
+        test_serialize_then_deserialize(&ProtocolMessage::TagList(TagList {
+            op_id: 10,
+            tags: Vec::new(),
+        }));
+    }
+
+    /// Writes the message with a `MessageWriter`, which is how it's sent to a connection, and then parses it again
+    fn write_then_parse(message: &ProtocolMessage<OwnedFloEvent>) -> (usize, ProtocolMessage<OwnedFloEvent>) {
+        let mut written = Vec::new();
+        ::MessageWriter::new_owned(message.clone()).write(&mut written).expect("failed to write message");
+        match parse_any(&written[..]) {
+            IResult::Done(remaining, result) => {
+                assert!(remaining.is_empty());
+                (written.len(), result)
+            }
+            other @ _ => panic!("Expected Done, got: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn tag_list_larger_than_the_default_buffer_is_written_and_parsed_completely() {
+        let tags = (0..1000).map(|i| {
+            StreamTag { name: format!("some-long-tag-name-{}", i), event_id: FloEventId::new(1, i) }
+        }).collect::<Vec<_>>();
+        let message = ProtocolMessage::<OwnedFloEvent>::TagList(TagList { op_id: 3, tags: tags });
+
+        let mut small_buffer = [0; 64];
+        let required_len = message.serialize(&mut small_buffer[..]);
+        assert!(required_len > ::BUFFER_LENGTH);
+
+        let (written_len, result) = write_then_parse(&message);
+        assert_eq!(required_len, written_len);
+        assert_eq!(message, result);
+    }
+
+    #[test]
+    fn serde_ancestry_messages() {
+        test_serialize_then_deserialize(&ProtocolMessage::GetAncestry(GetAncestry {
+            op_id: 13,
+            event_id: FloEventId::new(2, 345),
+        }));
+        test_serialize_then_deserialize(&ProtocolMessage::Ancestry(AncestryInfo {
+            op_id: 13,
+            event_count: 3,
+        }));
+    }
+
+    #[test]
+    fn serde_count_messages() {
+        test_serialize_then_deserialize(&ProtocolMessage::CountEvents(CountEvents {
+            op_id: 14,
+            namespace: "/orders/*".to_owned(),
+            since: Some(FloEventId::new(2, 99)),
+        }));
+        test_serialize_then_deserialize(&ProtocolMessage::CountEvents(CountEvents {
+            op_id: 15,
+            namespace: "/**/*".to_owned(),
+            since: None,
+        }));
+        test_serialize_then_deserialize(&ProtocolMessage::CountResult(CountResult {
+            op_id: 15,
+            count: u64::max_value(),
+        }));
+    }
+
+    #[test]
+    fn serde_get_consumer_lag() {
+        test_serialize_then_deserialize(&ProtocolMessage::GetConsumerLag(GetConsumerLag {
+            op_id: 17,
+            namespace: "/orders/*".to_owned(),
+            version_vector: vec![FloEventId::new(1, 40), FloEventId::new(2, 77)],
+        }));
+        test_serialize_then_deserialize(&ProtocolMessage::GetConsumerLag(GetConsumerLag {
+            op_id: 18,
+            namespace: "/**/*".to_owned(),
+            version_vector: Vec::new(),
+        }));
+    }
+
+    #[test]
+    fn serde_heartbeat() {
+        test_serialize_then_deserialize(&ProtocolMessage::Heartbeat(Heartbeat {
+            op_id: 16,
+            timestamp: time::from_millis_since_epoch(1_500_000_000_123),
+        }));
+    }
+
+    #[test]
+    fn serde_get_stream_status() {
+        test_serialize_then_deserialize(&ProtocolMessage::GetStreamStatus(GetStreamStatus {
+            op_id: 17,
+            name: "other-stream".to_owned(),
+        }));
+    }
+
+    #[test]
+    fn serde_verify_stream_and_integrity_report() {
+        test_serialize_then_deserialize(&ProtocolMessage::VerifyStream(VerifyStream {
+            op_id: 12,
+            name: "foo".to_owned(),
+            version_vector: vec![FloEventId::new(2, 500)],
+            max_events: 1000,
+            check_parents: true,
+        }));
+        test_serialize_then_deserialize(&ProtocolMessage::IntegrityReport(IntegrityReport {
+            op_id: 12,
+            stream: "foo".to_owned(),
+            partitions: vec![
+                PartitionIntegrity {
+                    partition_num: 1,
+                    events_checked: 0,
+                    last_verified: FloEventId::new(1, 0),
+                    complete: true,
+                    problems: Vec::new(),
+                },
+                PartitionIntegrity {
+                    partition_num: 2,
+                    events_checked: 1000,
+                    last_verified: FloEventId::new(2, 1500),
+                    complete: false,
+                    problems: vec![
+                        IntegrityProblem {
+                            kind: IntegrityProblemKind::InvalidParent,
+                            event_id: FloEventId::new(2, 700),
+                            description: "parent: 3.9000 is not an earlier event".to_owned(),
+                        },
+                        IntegrityProblem {
+                            kind: IntegrityProblemKind::Unreadable,
+                            event_id: FloEventId::new(2, 1500),
+                            description: "invalid marker bytes".to_owned(),
+                        },
+                    ],
+                },
+            ],
+        }));
+    }
+
+    #[test]
+    fn integrity_report_with_the_most_problems_in_many_partitions_is_written_and_parsed_completely() {
+        let partitions = (1..9).map(|partition_num| {
+            PartitionIntegrity {
+                partition_num: partition_num,
+                events_checked: 5000,
+                last_verified: FloEventId::new(partition_num, 5000),
+                complete: true,
+                problems: (0..MAX_REPORTED_INTEGRITY_PROBLEMS).map(|i| {
+                    IntegrityProblem {
+                        kind: IntegrityProblemKind::InvalidParent,
+                        event_id: FloEventId::new(partition_num, i as u64 + 1),
+                        description: format!("Parent: {}.{} does not exist in the stream, which is a rather long description", partition_num, i),
+                    }
+                }).collect(),
+            }
+        }).collect();
+        let message = ProtocolMessage::<OwnedFloEvent>::IntegrityReport(IntegrityReport {
+            op_id: 12,
+            stream: "foo".to_owned(),
+            partitions: partitions,
+        });
+
+        let (written_len, result) = write_then_parse(&message);
+        assert!(written_len > ::BUFFER_LENGTH);
+        assert_eq!(message, result);
+    }
+
+    #[test]
+    fn serde_stream_list() {
+        let list = StreamList {
+            op_id: 3579,
+            streams: vec![
+                StreamDescriptor {
+                    name: "foo".to_owned(),
+                    event_count: 42,
+                    head: FloEventId::new(2, 50),
+                    tail: FloEventId::new(1, 9),
+                    writable: true,
+                    stored_bytes: 8192,
+                },
+                StreamDescriptor {
+                    name: "empty".to_owned(),
+                    event_count: 0,
+                    head: FloEventId::zero(),
+                    tail: FloEventId::zero(),
+                    writable: false,
+                    stored_bytes: 0,
+                },
+            ],
+        };
+        test_serialize_then_deserialize(&ProtocolMessage::StreamList(list));
+
+        let empty = StreamList {
+            op_id: 1,
+            streams: Vec::new(),
+        };
+        test_serialize_then_deserialize(&ProtocolMessage::StreamList(empty));
+    }
+
     #[test]
     fn serde_new_start_consuming() {
         let version_vec = vec![
@@ -798,6 +2613,7 @@ mod test {
             version_vector: version_vec,
             max_events: 987,
             namespace: "/foo/bar/*".to_owned(),
+            ..Default::default()
         }));
     }
 
@@ -809,10 +2625,25 @@ mod test {
             version_vector: vv,
             max_events: 1,
             namespace: "/foo/*".to_owned(),
+            ..Default::default()
         });
         test_serialize_then_deserialize(&msg);
     }
 
+    #[test]
+    fn serde_new_start_consuming_with_body_prefix_bytes() {
+        for prefix in vec![0, 16] {
+            test_serialize_then_deserialize(&ProtocolMessage::NewStartConsuming(NewConsumerStart {
+                op_id: 3,
+                version_vector: vec![FloEventId::new(1, 0)],
+                max_events: 1,
+                namespace: "/foo/*".to_owned(),
+                body_prefix_bytes: Some(prefix),
+                ..Default::default()
+            }));
+        }
+    }
+
     #[test]
     fn serde_receive_event() {
         let event = OwnedFloEvent {
@@ -827,6 +2658,20 @@ mod test {
         assert_eq!(message, result);
     }
 
+    #[test]
+    fn serde_receive_event_prefix() {
+        let event = OwnedFloEvent {
+            id: FloEventId::new(4, 5),
+            timestamp: time::from_millis_since_epoch(99),
+            parent_id: None,
+            namespace: "/foo/bar".to_owned(),
+            data: vec![9; 8],
+        };
+        let message = ProtocolMessage::ReceiveEventPrefix(event, 99);
+        let result = serde_with_body(&message, true);
+        assert_eq!(message, result);
+    }
+
     #[test]
     fn stop_consuming_is_serialized_and_parsed() {
         test_serialize_then_deserialize(&ProtocolMessage::StopConsuming(345));
@@ -847,6 +2692,33 @@ mod test {
         test_serialize_then_deserialize(&ProtocolMessage::EndOfBatch);
     }
 
+    #[test]
+    fn control_messages_serialize_to_their_header_byte() {
+        let control_messages = vec![
+            (ProtocolMessage::NextBatch, NEXT_BATCH),
+            (ProtocolMessage::EndOfBatch, END_OF_BATCH),
+            (ProtocolMessage::AwaitingEvents, AWAITING_EVENTS),
+        ];
+        for (message, header) in control_messages {
+            assert_eq!(Some(header), message.serialize_control());
+
+            let mut buffer = [0; 16];
+            assert_eq!(1, message.serialize(&mut buffer[..]));
+            assert_eq!(header, buffer[0]);
+
+            match parse_any(&[header]) {
+                IResult::Done(remaining, parsed) => {
+                    assert!(remaining.is_empty());
+                    assert_eq!(message, parsed);
+                }
+                other @ _ => panic!("failed to parse control message: {:?}, got: {:?}", message, other),
+            }
+        }
+
+        assert_eq!(None, ProtocolMessage::<OwnedFloEvent>::StopConsuming(5).serialize_control());
+        assert_eq!(None, ProtocolMessage::<OwnedFloEvent>::SetBatchSize(5).serialize_control());
+    }
+
     #[test]
     fn set_batch_size_is_serialized_and_parsed() {
         test_serialize_then_deserialize(&ProtocolMessage::SetBatchSize(1234567));
@@ -857,6 +2729,165 @@ mod test {
         test_serialize_then_deserialize(&mut ProtocolMessage::AwaitingEvents);
     }
 
+    #[test]
+    fn cursor_messages_are_serialized_and_parsed_with_their_op_id() {
+        let event = OwnedFloEvent {
+            id: FloEventId::new(4, 5),
+            timestamp: time::from_millis_since_epoch(99),
+            parent_id: Some(FloEventId::new(1, 2)),
+            namespace: "/foo/bar".to_owned(),
+            data: vec![9; 8],
+        };
+        let wrapped_messages = vec![
+            ProtocolMessage::ReceiveEvent(event.clone()),
+            ProtocolMessage::ReceiveEventPrefix(event, 99),
+            ProtocolMessage::EndOfBatch,
+            ProtocolMessage::AwaitingEvents,
+        ];
+        for (i, wrapped) in wrapped_messages.into_iter().enumerate() {
+            let op_id = 70_000 + i as u32;
+            let message = ProtocolMessage::CursorMessage(op_id, Box::new(wrapped));
+            assert_eq!(None, message.serialize_control());
+
+            let result = serde_with_body(&message, true);
+            assert_eq!(message, result);
+            assert_eq!(op_id, result.get_op_id());
+        }
+    }
+
+    #[test]
+    fn cursor_message_cannot_wrap_messages_that_are_not_sent_by_consumers() {
+        let mut buffer = Vec::new();
+        buffer.push(CURSOR_MESSAGE);
+        buffer.extend_from_slice(&[0, 0, 0, 5]);
+        let mut inner = [0; 64];
+        let len = ProtocolMessage::<OwnedFloEvent>::StopConsuming(5).serialize(&mut inner[..]);
+        buffer.extend_from_slice(&inner[..len]);
+
+        assert!(parse_any(&buffer).is_err());
+    }
+
+    #[test]
+    fn trace_is_serialized_and_parsed() {
+        test_serialize_then_deserialize(&ProtocolMessage::Trace(OperationTrace {
+            op_id: 12,
+            stages: vec![
+                StageTiming { stage: TraceStage::Received, elapsed_micros: 0 },
+                StageTiming { stage: TraceStage::Queued, elapsed_micros: 15 },
+                StageTiming { stage: TraceStage::Written, elapsed_micros: 250 },
+                StageTiming { stage: TraceStage::Acked, elapsed_micros: 1 << 40 },
+                StageTiming { stage: TraceStage::Opened, elapsed_micros: 1 << 41 },
+            ],
+        }));
+        test_serialize_then_deserialize(&ProtocolMessage::Trace(OperationTrace { op_id: 13, stages: Vec::new() }));
+    }
+
+    #[test]
+    fn trace_with_unknown_stage_is_rejected() {
+        let trace = ProtocolMessage::<OwnedFloEvent>::Trace(OperationTrace {
+            op_id: 12,
+            stages: vec![StageTiming { stage: TraceStage::Received, elapsed_micros: 0 }],
+        });
+        let mut buffer = [0; 64];
+        let len = trace.serialize(&mut buffer[..]);
+        // tag, op_id, and stage count come before the stage
+        buffer[7] = 99;
+        assert!(parse_any(&buffer[..len]).is_err());
+    }
+
+    #[test]
+    fn produce_event_trace_flag_is_serialized_and_parsed() {
+        let input = ProduceEvent {
+            namespace: "/foo".to_owned(),
+            parent_id: None,
+            ttl: None,
+            compression: Compression::None,
+            trace: true,
+            op_id: 9,
+            partition: 1,
+            data: vec![9; 5]
+        };
+        let message = ProtocolMessage::ProduceEvent(input);
+        let mut buffer = [0; 64];
+        let len = message.serialize(&mut buffer[..]);
+        // the flag is the last byte of the header, so it comes right before the data
+        assert_eq!(1, buffer[len - 1]);
+        match ser_de(&message) {
+            ProtocolMessage::ProduceEvent(result) => assert!(result.trace),
+            other @ _ => panic!("expected ProduceEvent, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn new_start_consuming_trace_flag_is_serialized_and_parsed() {
+        test_serialize_then_deserialize(&ProtocolMessage::NewStartConsuming(NewConsumerStart {
+            op_id: 9,
+            trace: true,
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn new_start_consuming_is_serialized_and_parsed_with_cursor_op_ids() {
+        test_serialize_then_deserialize(&ProtocolMessage::NewStartConsuming(NewConsumerStart {
+            op_id: 6,
+            version_vector: vec![FloEventId::new(1, 2)],
+            max_events: 8,
+            namespace: "/foo/*".to_owned(),
+            cursor_op_ids: true,
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn new_start_consuming_is_serialized_and_parsed_with_start_at_tail() {
+        test_serialize_then_deserialize(&ProtocolMessage::NewStartConsuming(NewConsumerStart {
+            op_id: 7,
+            namespace: "/foo/*".to_owned(),
+            start_at_tail: true,
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn new_start_consuming_is_serialized_and_parsed_with_snapshot() {
+        test_serialize_then_deserialize(&ProtocolMessage::NewStartConsuming(NewConsumerStart {
+            op_id: 8,
+            version_vector: vec![FloEventId::new(1, 20)],
+            namespace: "/foo/*".to_owned(),
+            snapshot: true,
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn new_start_consuming_is_serialized_and_parsed_with_idle_signal_interval() {
+        test_serialize_then_deserialize(&ProtocolMessage::NewStartConsuming(NewConsumerStart {
+            op_id: 6,
+            version_vector: vec![FloEventId::new(1, 2)],
+            namespace: "/foo/*".to_owned(),
+            unlimited_lifetime: true,
+            start_at_tail: true,
+            idle_signal_interval: Some(5000),
+            see_own_writes: false,
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn serde_stream_idle() {
+        test_serialize_then_deserialize(&ProtocolMessage::StreamIdle(StreamIdle {
+            op_id: 14,
+            tail_id: FloEventId::new(2, 456),
+            server_time: time::from_millis_since_epoch(1_500_000_000_789),
+        }));
+        test_serialize_then_deserialize(&ProtocolMessage::StreamIdle(StreamIdle {
+            op_id: 15,
+            tail_id: FloEventId::zero(),
+            server_time: time::from_millis_since_epoch(1_500_000_000_789),
+        }));
+    }
+
     #[test]
     fn error_message_is_parsed() {
         let error = ErrorMessage {
@@ -880,11 +2911,21 @@ mod test {
         let input = ProduceEvent {
             namespace: "/the/namespace".to_owned(),
             parent_id: Some(FloEventId::new(123, 456)),
+            ttl: Some(Duration::from_millis(1500)),
+            compression: Compression::None,
+            trace: false,
             op_id: 9,
             partition: 7,
             data: vec![9; 5]
         };
         let mut message_input = ProtocolMessage::ProduceEvent(input.clone());
+
+        // the ttl comes right after the tag, the namespace, and the parent_id
+        let mut buffer = [0; 64];
+        message_input.serialize(&mut buffer[..]);
+        let ttl_start = 1 + 2 + input.namespace.len() + 10;
+        assert_eq!(&[0, 0, 0, 0, 0, 0, 0x05, 0xdc][..], &buffer[ttl_start..(ttl_start + 8)]);
+
         let message_result = ser_de(&mut message_input);
 
         if let ProtocolMessage::ProduceEvent(result) = message_result {
@@ -892,6 +2933,8 @@ mod test {
             assert_eq!(input.parent_id, result.parent_id);
             assert_eq!(input.op_id, result.op_id);
             assert_eq!(input.partition, result.partition);
+            assert_eq!(input.ttl, result.ttl);
+            assert_eq!(input.compression, result.compression);
 
             // The vector must be allocated with the correct capacity, but we haven't actually read all the data
             assert_eq!(input.data.len(), result.data.capacity());
@@ -900,6 +2943,115 @@ mod test {
         }
     }
 
+    #[test]
+    fn gzip_compressed_produce_event_round_trips_with_the_compressed_length() {
+        let original_data = b"{\"some\": \"json\"}".iter().cycle().take(1000).cloned().collect::<Vec<u8>>();
+        let mut input = ProduceEvent {
+            namespace: "/the/namespace".to_owned(),
+            parent_id: None,
+            ttl: None,
+            compression: Compression::None,
+            trace: false,
+            op_id: 9,
+            partition: 1,
+            data: original_data.clone(),
+        };
+        input.compress_gzip().expect("failed to compress data");
+        assert_eq!(Compression::Gzip, input.compression);
+
+        let message_result = ser_de(&ProtocolMessage::ProduceEvent(input.clone()));
+        let mut result = match message_result {
+            ProtocolMessage::ProduceEvent(result) => result,
+            other => panic!("expected ProduceEvent, got: {:?}", other)
+        };
+        assert_eq!(Compression::Gzip, result.compression);
+        assert_eq!(input.data.len(), result.data.capacity());
+
+        result.data.extend_from_slice(&input.data);
+        result.decompress(original_data.len()).expect("failed to decompress data");
+        assert_eq!(Compression::None, result.compression);
+        assert_eq!(original_data, result.data);
+    }
+
+    #[test]
+    fn compress_gzip_shrinks_a_large_repetitive_body() {
+        let original_data = b"{\"key\": \"value\", \"count\": 1}, ".iter().cycle().take(10 * 1024).cloned().collect::<Vec<u8>>();
+        let mut produce = ProduceEvent {
+            namespace: "/foo".to_owned(),
+            parent_id: None,
+            ttl: None,
+            compression: Compression::None,
+            trace: false,
+            op_id: 1,
+            partition: 1,
+            data: original_data.clone(),
+        };
+        produce.compress_gzip().expect("failed to compress data");
+        assert!(produce.data.len() < original_data.len() / 10, "compressed length was: {}", produce.data.len());
+
+        // compressing again is a no-op
+        let compressed = produce.data.clone();
+        produce.compress_gzip().expect("failed to compress data");
+        assert_eq!(compressed, produce.data);
+
+        produce.decompress(original_data.len()).expect("failed to decompress data");
+        assert_eq!(original_data, produce.data);
+    }
+
+    #[test]
+    fn decompress_returns_error_when_the_uncompressed_data_is_larger_than_the_limit() {
+        let original_data = vec![0; 1024 * 1024];
+        let mut produce = ProduceEvent {
+            data: original_data.clone(),
+            ..Default::default()
+        };
+        produce.compress_gzip().expect("failed to compress data");
+        let compressed = produce.data.clone();
+
+        let err = produce.decompress(original_data.len() - 1).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert_eq!(Compression::Gzip, produce.compression);
+        assert_eq!(compressed, produce.data);
+    }
+
+    #[test]
+    fn parse_producer_event_returns_error_when_compression_is_unknown() {
+        let input = ProduceEvent {
+            namespace: "/foo".to_owned(),
+            parent_id: None,
+            ttl: None,
+            compression: Compression::Gzip,
+            trace: false,
+            op_id: 1,
+            partition: 1,
+            data: Vec::new(),
+        };
+        let mut buffer = [0; 128];
+        let len = ProtocolMessage::ProduceEvent::<OwnedFloEvent>(input).serialize(&mut buffer[..]);
+        // the compression byte comes just before the trace flag at the end of the header
+        buffer[len - 2] = 99;
+        assert!(parse_any(&buffer[..len]).is_err());
+    }
+
+    #[test]
+    fn parse_producer_event_returns_error_when_namespace_is_not_valid_utf8() {
+        let input = ProduceEvent {
+            namespace: "/foo".to_owned(),
+            parent_id: None,
+            ttl: None,
+            compression: Compression::None,
+            trace: false,
+            op_id: 1,
+            partition: 1,
+            data: Vec::new(),
+        };
+        let mut buffer = [0; 128];
+        let len = ProtocolMessage::ProduceEvent::<OwnedFloEvent>(input).serialize(&mut buffer[..]);
+        let namespace_start = buffer.windows(4).position(|bytes| bytes == b"/foo").unwrap();
+        buffer[namespace_start + 1] = 0xFF;
+        assert!(parse_any(&buffer[..len]).is_err());
+    }
+
     #[test]
     fn parse_string_returns_empty_string_string_length_is_0() {
         let input = vec![0, 0, 110, 4, 5, 6, 7];
@@ -920,6 +3072,38 @@ mod test {
         assert_eq!(input.to_owned(), result);
     }
 
+    #[test]
+    fn strings_with_embedded_newlines_round_trip_byte_identically() {
+        let namespace = "/foo\nbar\n/baz\r\n\n".to_owned();
+        let event = OwnedFloEvent {
+            id: FloEventId::new(4, 5),
+            timestamp: time::from_millis_since_epoch(99),
+            parent_id: None,
+            namespace: namespace.clone(),
+            data: vec![1, 2, 3],
+        };
+        let message = ProtocolMessage::ReceiveEvent(event);
+        let result = serde_with_body(&message, true);
+        assert_eq!(message, result);
+
+        let produce = ProtocolMessage::ProduceEvent(ProduceEvent {
+            namespace: namespace.clone(),
+            parent_id: None,
+            ttl: None,
+            compression: Compression::None,
+            trace: false,
+            op_id: 9,
+            partition: 1,
+            data: Vec::new(),
+        });
+        test_serialize_then_deserialize(&produce);
+
+        test_serialize_then_deserialize(&ProtocolMessage::SetEventStream(SetEventStream {
+            op_id: 3,
+            name: "\n".to_owned(),
+        }));
+    }
+
     #[test]
     fn this_works_how_i_think_it_does() {
         let input = vec![
@@ -934,4 +3118,32 @@ mod test {
         let expected = IResult::Incomplete(Needed::Size(12164));
         assert_eq!(expected, result);
     }
+
+    #[test]
+    fn cluster_state_finds_the_lowest_actor_id_including_its_own() {
+        let member = |actor_id: ActorId| {
+            ClusterMember {
+                addr: format!("127.0.0.1:{}", 3000 + actor_id).parse().unwrap(),
+                actor_id: actor_id,
+                connected: true,
+            }
+        };
+        let mut state = ClusterState {
+            actor_id: 3,
+            actor_port: 3003,
+            version_vector: Vec::new(),
+            other_members: Vec::new(),
+        };
+        // a single member is always the lowest
+        assert_eq!(3, state.lowest_actor_id());
+        assert!(state.is_self_lowest());
+
+        state.other_members = vec![member(5), member(4)];
+        assert_eq!(3, state.lowest_actor_id());
+        assert!(state.is_self_lowest());
+
+        state.other_members.push(member(2));
+        assert_eq!(2, state.lowest_actor_id());
+        assert!(!state.is_self_lowest());
+    }
 }
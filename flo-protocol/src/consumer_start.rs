@@ -0,0 +1,305 @@
+use event::FloEventId;
+use client::{NewConsumerStart, ReadConsistency, CONSUME_UNLIMITED};
+use namespace::validate_namespace_glob;
+
+/// Builds a `NewConsumerStart` message, checking that the options make sense together before it's sent. A consumer must
+/// have exactly one of `namespace` or `namespace_regex`, and may have at most one starting position out of
+/// `version_vector`, `start_tag`, and `start_at_tail`. Consumers without a starting position read from the beginning of
+/// the stream. A `snapshot` can't start at the tail, since there would never be anything for it to read.
+#[derive(Debug, Clone)]
+pub struct ConsumerStartBuilder {
+    op_id: u32,
+    max_events: u64,
+    namespace: Option<String>,
+    namespace_regex: Option<String>,
+    version_vector: Option<Vec<FloEventId>>,
+    start_tag: Option<String>,
+    start_at_tail: bool,
+    body_prefix_bytes: Option<u32>,
+    max_delivery_rate: Option<u32>,
+    error_on_empty: bool,
+    unlimited_lifetime: bool,
+    read_consistency: ReadConsistency,
+    cursor_op_ids: bool,
+    snapshot: bool,
+    idle_signal_interval: Option<u32>,
+    see_own_writes: bool,
+    trace: bool,
+}
+
+impl ConsumerStartBuilder {
+    pub fn new(op_id: u32) -> ConsumerStartBuilder {
+        ConsumerStartBuilder {
+            op_id: op_id,
+            max_events: CONSUME_UNLIMITED,
+            namespace: None,
+            namespace_regex: None,
+            version_vector: None,
+            start_tag: None,
+            start_at_tail: false,
+            body_prefix_bytes: None,
+            max_delivery_rate: None,
+            error_on_empty: false,
+            unlimited_lifetime: false,
+            read_consistency: ReadConsistency::Local,
+            cursor_op_ids: false,
+            snapshot: false,
+            idle_signal_interval: None,
+            see_own_writes: true,
+            trace: false,
+        }
+    }
+
+    /// Consume events whose namespace matches this glob pattern
+    pub fn namespace<S: Into<String>>(mut self, namespace: S) -> Self {
+        self.namespace = Some(namespace.into());
+        self
+    }
+
+    /// Consume events whose whole namespace matches this regular expression
+    pub fn namespace_regex<S: Into<String>>(mut self, regex: S) -> Self {
+        self.namespace_regex = Some(regex.into());
+        self
+    }
+
+    /// The maximum number of events to consume. By default there's no limit
+    pub fn max_events(mut self, max_events: u64) -> Self {
+        self.max_events = max_events;
+        self
+    }
+
+    /// Start after the given event ids, one for each partition
+    pub fn version_vector(mut self, version_vector: Vec<FloEventId>) -> Self {
+        self.version_vector = Some(version_vector);
+        self
+    }
+
+    /// Start just after the event with the given tag
+    pub fn start_tag<S: Into<String>>(mut self, tag: S) -> Self {
+        self.start_tag = Some(tag.into());
+        self
+    }
+
+    /// Start after the newest event in each partition, so that only events produced afterwards are received
+    pub fn start_at_tail(mut self) -> Self {
+        self.start_at_tail = true;
+        self
+    }
+
+    pub fn body_prefix_bytes(mut self, prefix_bytes: u32) -> Self {
+        self.body_prefix_bytes = Some(prefix_bytes);
+        self
+    }
+
+    pub fn max_delivery_rate(mut self, events_per_second: u32) -> Self {
+        self.max_delivery_rate = Some(events_per_second);
+        self
+    }
+
+    pub fn error_on_empty(mut self) -> Self {
+        self.error_on_empty = true;
+        self
+    }
+
+    pub fn unlimited_lifetime(mut self) -> Self {
+        self.unlimited_lifetime = true;
+        self
+    }
+
+    pub fn read_consistency(mut self, read_consistency: ReadConsistency) -> Self {
+        self.read_consistency = read_consistency;
+        self
+    }
+
+    pub fn cursor_op_ids(mut self) -> Self {
+        self.cursor_op_ids = true;
+        self
+    }
+
+    /// Only receive the events that exist when the cursor starts, and then stop
+    pub fn snapshot(mut self) -> Self {
+        self.snapshot = true;
+        self
+    }
+
+    /// Once caught up, receive a `StreamIdle` each time this many milliseconds pass without any events
+    pub fn idle_signal_interval(mut self, millis: u32) -> Self {
+        self.idle_signal_interval = Some(millis);
+        self
+    }
+
+    /// Don't receive events that were produced on the same connection as this consumer
+    pub fn exclude_own_writes(mut self) -> Self {
+        self.see_own_writes = false;
+        self
+    }
+
+    /// Have the server send a `Trace` of how long each stage of starting the cursor took, right after the `CursorCreated`
+    pub fn trace(mut self) -> Self {
+        self.trace = true;
+        self
+    }
+
+    /// Returns the `NewConsumerStart`, or a description of the problem if the options conflict or can't be represented on
+    /// the wire
+    pub fn build(self) -> Result<NewConsumerStart, String> {
+        let ConsumerStartBuilder {op_id, max_events, namespace, namespace_regex, version_vector, start_tag, start_at_tail,
+                body_prefix_bytes, max_delivery_rate, error_on_empty, unlimited_lifetime, read_consistency, cursor_op_ids, snapshot,
+                idle_signal_interval, see_own_writes, trace} = self;
+
+        let namespace = match (namespace, namespace_regex.as_ref()) {
+            (Some(_), Some(_)) => return Err("Only one of namespace or namespace_regex may be set".to_owned()),
+            (None, None) => return Err("Either namespace or namespace_regex must be set".to_owned()),
+            (Some(glob), None) => {
+                validate_namespace_glob(&glob)?;
+                glob
+            }
+            // the server ignores the namespace when there's a regex
+            (None, Some(regex)) => {
+                if regex.is_empty() {
+                    return Err("namespace_regex must not be empty".to_owned());
+                }
+                String::new()
+            }
+        };
+
+        let start_positions = [version_vector.is_some(), start_tag.is_some(), start_at_tail];
+        if start_positions.iter().filter(|is_set| **is_set).count() > 1 {
+            return Err("Only one of version_vector, start_tag, or start_at_tail may be set".to_owned());
+        }
+        if snapshot && start_at_tail {
+            return Err("A snapshot cannot start at the tail".to_owned());
+        }
+        if start_tag.as_ref().map(|tag| tag.is_empty()).unwrap_or(false) {
+            return Err("start_tag must not be empty".to_owned());
+        }
+        if max_delivery_rate == Some(0) {
+            return Err("max_delivery_rate must be greater than 0".to_owned());
+        }
+        if idle_signal_interval == Some(0) {
+            return Err("idle_signal_interval must be greater than 0".to_owned());
+        }
+
+        Ok(NewConsumerStart {
+            op_id: op_id,
+            version_vector: version_vector.unwrap_or_else(Vec::new),
+            max_events: max_events,
+            namespace: namespace,
+            body_prefix_bytes: body_prefix_bytes,
+            start_tag: start_tag,
+            max_delivery_rate: max_delivery_rate,
+            namespace_regex: namespace_regex,
+            error_on_empty: error_on_empty,
+            unlimited_lifetime: unlimited_lifetime,
+            read_consistency: read_consistency,
+            cursor_op_ids: cursor_op_ids,
+            start_at_tail: start_at_tail,
+            snapshot: snapshot,
+            idle_signal_interval: idle_signal_interval,
+            see_own_writes: see_own_writes,
+            trace: trace,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn minimal_consumer_start_reads_the_namespace_from_the_beginning() {
+        let result = ConsumerStartBuilder::new(7).namespace("/foo/*").build().expect("failed to build");
+        let expected = NewConsumerStart {
+            op_id: 7,
+            namespace: "/foo/*".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn every_option_is_set_on_the_consumer_start() {
+        let version_vector = vec![FloEventId::new(1, 5), FloEventId::new(2, 8)];
+        let result = ConsumerStartBuilder::new(3)
+                .namespace_regex("/foo/[0-9]+")
+                .max_events(100)
+                .version_vector(version_vector.clone())
+                .body_prefix_bytes(16)
+                .max_delivery_rate(500)
+                .error_on_empty()
+                .unlimited_lifetime()
+                .read_consistency(ReadConsistency::Quorum)
+                .cursor_op_ids()
+                .snapshot()
+                .idle_signal_interval(1000)
+                .exclude_own_writes()
+                .build().expect("failed to build");
+        let expected = NewConsumerStart {
+            op_id: 3,
+            version_vector: version_vector,
+            max_events: 100,
+            namespace: String::new(),
+            body_prefix_bytes: Some(16),
+            max_delivery_rate: Some(500),
+            namespace_regex: Some("/foo/[0-9]+".to_owned()),
+            error_on_empty: true,
+            unlimited_lifetime: true,
+            read_consistency: ReadConsistency::Quorum,
+            cursor_op_ids: true,
+            snapshot: true,
+            idle_signal_interval: Some(1000),
+            see_own_writes: false,
+            ..Default::default()
+        };
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn start_tag_and_start_at_tail_may_each_be_set_alone() {
+        let tagged = ConsumerStartBuilder::new(1).namespace("/foo").start_tag("checkpoint").build().expect("failed to build");
+        assert_eq!(Some("checkpoint".to_owned()), tagged.start_tag);
+
+        let tail = ConsumerStartBuilder::new(1).namespace("/foo").start_at_tail().build().expect("failed to build");
+        assert!(tail.start_at_tail);
+    }
+
+    #[test]
+    fn conflicting_start_positions_are_rejected() {
+        let builders = vec![
+            ConsumerStartBuilder::new(1).namespace("/foo").version_vector(vec![FloEventId::new(1, 1)]).start_tag("checkpoint"),
+            ConsumerStartBuilder::new(1).namespace("/foo").version_vector(Vec::new()).start_at_tail(),
+            ConsumerStartBuilder::new(1).namespace("/foo").start_tag("checkpoint").start_at_tail(),
+        ];
+        for builder in builders {
+            let err = builder.clone().build().expect_err(&format!("expected {:?} to be rejected", builder));
+            assert!(err.contains("Only one of version_vector"), "unexpected description: {}", err);
+        }
+    }
+
+    #[test]
+    fn namespace_must_be_set_exactly_once() {
+        let err = ConsumerStartBuilder::new(1).build().expect_err("expected missing namespace to be rejected");
+        assert!(err.contains("must be set"), "unexpected description: {}", err);
+
+        let err = ConsumerStartBuilder::new(1).namespace("/foo").namespace_regex("/foo").build()
+                .expect_err("expected both namespace and regex to be rejected");
+        assert!(err.contains("Only one of namespace"), "unexpected description: {}", err);
+    }
+
+    #[test]
+    fn values_that_mean_none_on_the_wire_are_rejected() {
+        assert!(ConsumerStartBuilder::new(1).namespace("/foo[").build().is_err());
+        assert!(ConsumerStartBuilder::new(1).namespace_regex("").build().is_err());
+        assert!(ConsumerStartBuilder::new(1).namespace("/foo").start_tag("").build().is_err());
+        assert!(ConsumerStartBuilder::new(1).namespace("/foo").max_delivery_rate(0).build().is_err());
+        assert!(ConsumerStartBuilder::new(1).namespace("/foo").idle_signal_interval(0).build().is_err());
+    }
+
+    #[test]
+    fn snapshot_starting_at_the_tail_is_rejected() {
+        let err = ConsumerStartBuilder::new(1).namespace("/foo").start_at_tail().snapshot().build()
+                .expect_err("expected snapshot at tail to be rejected");
+        assert!(err.contains("snapshot"), "unexpected description: {}", err);
+    }
+}
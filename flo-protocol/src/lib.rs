@@ -6,17 +6,30 @@ extern crate log;
 
 extern crate flo_event as event;
 extern crate byteorder;
+extern crate flate2;
+extern crate glob;
+
+#[cfg(test)]
+extern crate serde_cbor;
 
 pub mod serializer;
+pub mod cbor;
 mod client;
+mod namespace;
+mod consumer_start;
 
 use std::io::{self, Read, Write};
 use std::cmp;
 use std::fmt::{self, Debug};
 
 pub use self::client::*;
+pub use self::namespace::validate_namespace_glob;
+pub use self::consumer_start::ConsumerStartBuilder;
 use event::{FloEvent, OwnedFloEvent};
 
+/// The default size of the buffer that messages are read into. A larger buffer can take in more messages with each read
+/// call, at the cost of that much memory for every connection. Messages that don't fit are still read correctly, since
+/// the buffer grows to hold them.
 pub const BUFFER_LENGTH: usize = 8 * 1024;
 
 pub struct Buffer {
@@ -52,8 +65,12 @@ fn read<R: Read>(buffer: &mut [u8], reader: &mut R) -> io::Result<usize> {
 
 impl Buffer {
     pub fn new() -> Buffer {
+        Buffer::with_capacity(BUFFER_LENGTH)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Buffer {
         Buffer {
-            bytes: vec![0u8; BUFFER_LENGTH],
+            bytes: vec![0u8; capacity],
             pos: 0,
             len: 0
         }
@@ -133,10 +150,31 @@ impl Debug for Buffer {
     }
 }
 
+/// How `ProtocolMessage`s are encoded on a connection. The client chooses the framing with the first bytes that it sends.
+/// See the `cbor` module for details on CBOR framing
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Framing {
+    Binary,
+    Cbor,
+}
+
+impl Framing {
+    /// Determines the framing of a connection from the first byte that the client sent
+    pub fn detect(first_byte: u8) -> Framing {
+        if first_byte == cbor::SELF_DESCRIBE_PREFIX[0] {
+            Framing::Cbor
+        } else {
+            Framing::Binary
+        }
+    }
+}
+
 pub struct MessageStream<T, E: FloEvent> {
     io: T,
     read_buffer: Buffer,
     current_read_message: Option<InProgressMessage<E>>,
+    /// Detected from the first message that's read
+    framing: Option<Framing>,
 }
 
 impl <T, E: FloEvent> Debug for MessageStream<T, E> {
@@ -147,12 +185,35 @@ impl <T, E: FloEvent> Debug for MessageStream<T, E> {
 
 impl <T, E: FloEvent> MessageStream<T, E> {
     pub fn new(io: T) -> MessageStream<T, E> {
+        MessageStream::with_read_buffer_size(io, BUFFER_LENGTH)
+    }
+
+    /// Creates a `MessageStream` that starts out reading into a buffer of `read_buffer_size` bytes
+    pub fn with_read_buffer_size(io: T, read_buffer_size: usize) -> MessageStream<T, E> {
         MessageStream {
             io: io,
-            read_buffer: Buffer::new(),
+            read_buffer: Buffer::with_capacity(read_buffer_size),
             current_read_message: None,
+            framing: None,
         }
     }
+
+    /// Returns the framing used by the other end of the connection, or `None` if nothing has been read yet
+    pub fn framing(&self) -> Option<Framing> {
+        self.framing
+    }
+
+    /// Returns the number of bytes that have been read but are not yet part of a complete message. This includes both the
+    /// unparsed bytes in the read buffer and the memory that's been allocated for the current event body so far, which
+    /// only grows as the body is read
+    pub fn buffered_bytes(&self) -> usize {
+        let body_bytes = self.current_read_message.as_ref().map(|message| message.body_allocated_bytes()).unwrap_or(0);
+        self.read_buffer.len() + body_bytes
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
 }
 
 impl <T, E> MessageStream<T, E> where T: Write, E: FloEvent {
@@ -166,7 +227,15 @@ impl <T> MessageStream<T, OwnedFloEvent> where T: Read {
     pub fn read_next(&mut self) -> io::Result<ProtocolMessage<OwnedFloEvent>> {
         use nom::IResult;
 
-        let MessageStream {ref mut io, ref mut read_buffer, ref mut current_read_message} = *self;
+        if self.framing.is_none() {
+            let first_byte = self.read_buffer.fill(&mut self.io)?[0];
+            self.framing = Some(Framing::detect(first_byte));
+        }
+        if self.framing == Some(Framing::Cbor) {
+            return self.read_next_cbor();
+        }
+
+        let MessageStream {ref mut io, ref mut read_buffer, ref mut current_read_message, ..} = *self;
         // if there's an in-progress message, then try to push the bytes into it
         // otherwise try to deserialize a new message
 
@@ -180,11 +249,11 @@ impl <T> MessageStream<T, OwnedFloEvent> where T: Read {
                     read_buffer.fill(io)?
                 };
                 let buffer_start_length = bytes.len();
-                match self::client::parse_any(bytes) {
-                    IResult::Done(remaining, message) => {
+                match self::client::parse_any_header(bytes) {
+                    IResult::Done(remaining, (message, body_len)) => {
                         bytes_consumed += buffer_start_length - remaining.len();
                         trace!("Successful parse used {} bytes; got message: {:?}", bytes_consumed, message);
-                        *current_read_message = Some(InProgressMessage::new(message));
+                        *current_read_message = Some(InProgressMessage::new(message, body_len));
                         break
                     }
                     IResult::Error(err) => {
@@ -220,35 +289,79 @@ impl <T> MessageStream<T, OwnedFloEvent> where T: Read {
 
         Ok(current_read_message.take().unwrap().message)
     }
+
+    /// CBOR messages always include the event data, so the whole message is decoded at once
+    fn read_next_cbor(&mut self) -> io::Result<ProtocolMessage<OwnedFloEvent>> {
+        let MessageStream {ref mut io, ref mut read_buffer, ..} = *self;
+
+        let mut grow_buffer = false;
+        loop {
+            let result = {
+                let bytes = if grow_buffer {
+                    read_buffer.grow(io)?
+                } else {
+                    read_buffer.fill(io)?
+                };
+                cbor::decode(bytes)
+            };
+            match result {
+                Ok((message, bytes_consumed)) => {
+                    trace!("Successful CBOR decode used {} bytes; got message: {:?}", bytes_consumed, message);
+                    read_buffer.consume(bytes_consumed);
+                    return Ok(message);
+                }
+                Err(cbor::CborError::Incomplete) => {
+                    grow_buffer = true;
+                    trace!("Not enough data to decode CBOR message, trying again");
+                }
+                Err(err) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Error decoding CBOR message: {:?}", err)));
+                }
+            }
+        }
+    }
 }
 
 
+/// A message whose header has been parsed, but whose body may still be being read. The body is allocated only as its bytes
+/// arrive, rather than all at once from the length in the header, so a client can't make the server allocate memory for
+/// data that it never sends
 #[derive(Debug)]
 struct InProgressMessage<E: FloEvent> {
     message: ProtocolMessage<E>,
+    body_len: usize,
     body_read_pos: usize,
 }
 
 impl <E: FloEvent> InProgressMessage<E> {
-    fn new(message: ProtocolMessage<E>) -> InProgressMessage<E> {
+    fn new(message: ProtocolMessage<E>, body_len: usize) -> InProgressMessage<E> {
         InProgressMessage {
             message: message,
+            body_len: body_len,
             body_read_pos: 0,
         }
     }
 
     fn body_bytes_remaining(&mut self) -> usize {
-        let pos = self.body_read_pos;
-        get_body_buffer(&mut self.message).map(|buf| buf.capacity() - pos).unwrap_or(0)
+        self.body_len - self.body_read_pos
+    }
+
+    fn body_allocated_bytes(&self) -> usize {
+        match self.message {
+            ProtocolMessage::ProduceEvent(ref event) => event.data.capacity(),
+            _ => 0
+        }
     }
 
     fn append_body(&mut self, bytes: &[u8]) -> usize {
-        let InProgressMessage {ref mut message, ref mut body_read_pos} = *self;
+        let InProgressMessage {ref mut message, ref mut body_read_pos, body_len} = *self;
 
-        let mut total_body_size = 0;
         let n_appended = get_body_buffer(message).map(|message_buffer| {
-            total_body_size = message_buffer.capacity();
-            copy_until_capacity(bytes, message_buffer)
+            let len = cmp::min(bytes.len(), body_len - *body_read_pos);
+            trace!("filling body with {} bytes", len);
+            message_buffer.reserve_exact(len);
+            message_buffer.extend_from_slice(&bytes[..len]);
+            len
         }).unwrap_or(0);
         *body_read_pos += n_appended;
         n_appended
@@ -262,14 +375,6 @@ fn get_body_buffer<E: FloEvent>(message: &mut ProtocolMessage<E>) -> Option<&mut
     }
 }
 
-fn copy_until_capacity(src: &[u8], dst: &mut Vec<u8>) -> usize {
-    let len = cmp::min(src.len(), dst.capacity() - dst.len());
-    let s = &src[..len];
-    trace!("filling body with {} bytes", len);
-    dst.extend_from_slice(s);
-    len
-}
-
 
 #[derive(Debug)]
 pub struct MessageWriter<E: FloEvent> {
@@ -277,16 +382,25 @@ pub struct MessageWriter<E: FloEvent> {
     body_position: usize,
     body_len: usize,
     header_written: bool,
+    framing: Framing,
+    /// The whole CBOR encoded message, which is written the same way as a body
+    encoded: Vec<u8>,
 }
 
 impl <E: FloEvent> MessageWriter<E> {
 
     pub fn new_owned(message: ProtocolMessage<E>) -> MessageWriter<E> {
+        MessageWriter::with_framing(message, Framing::Binary)
+    }
+
+    pub fn with_framing(message: ProtocolMessage<E>, framing: Framing) -> MessageWriter<E> {
         MessageWriter {
             message: message,
             body_position: 0,
             body_len: 0,
             header_written: false,
+            framing: framing,
+            encoded: Vec::new(),
         }
     }
 
@@ -295,30 +409,61 @@ impl <E: FloEvent> MessageWriter<E> {
     }
 
     pub fn write<T: Write>(&mut self, dest: &mut T) -> io::Result<()> {
-        let MessageWriter {ref message, ref mut body_position, ref mut body_len, ref mut header_written} = *self;
+        if self.framing == Framing::Cbor {
+            return self.write_cbor(dest);
+        }
+
+        let MessageWriter {ref message, ref mut body_position, ref mut body_len, ref mut header_written, ..} = *self;
         if !*header_written {
+            if let Some(control) = message.serialize_control() {
+                // control messages are just a single byte, so there's no need for the whole buffer
+                dest.write_all(&[control])?;
+                *header_written = true;
+                return Ok(());
+            }
             let mut buffer = [0; BUFFER_LENGTH];
             let len = message.serialize(&mut buffer[..]);
-            dest.write_all(&buffer[..len])?;
+            if len <= BUFFER_LENGTH {
+                dest.write_all(&buffer[..len])?;
+            } else {
+                // headers that list things, like tags or streams, can be larger than the usual buffer
+                let mut large_buffer = vec![0; len];
+                message.serialize(&mut large_buffer[..]);
+                dest.write_all(&large_buffer[..])?;
+            }
             *header_written = true;
         }
 
         if let Some(body) = message.get_body() {
             *body_len = body.len();
-            while *body_position < *body_len {
-                let to_write = &body[*body_position..];
-                match dest.write(to_write) {
-                    Ok(n) => *body_position += n,
-                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {} // ignore and retry
-                    Err(ref e) if cfg!(target_os = "macos") && e.raw_os_error() == Some(41) => {
-                        // osx is weird, and can sometimes return an EPROTOTYPE when writing
-                        debug!(target: "eprototype", "Retrying write due to error: {:?}", e);
-                    }
-                    Err(other) => return Err(other)
-                }
-            }
+            write_remaining(body, body_position, dest)?;
         }
         Ok(())
     }
+
+    fn write_cbor<T: Write>(&mut self, dest: &mut T) -> io::Result<()> {
+        if !self.header_written {
+            self.encoded = cbor::encode(&self.message);
+            self.body_len = self.encoded.len();
+            self.header_written = true;
+        }
+        write_remaining(&self.encoded, &mut self.body_position, dest)
+    }
+}
+
+fn write_remaining<T: Write>(bytes: &[u8], position: &mut usize, dest: &mut T) -> io::Result<()> {
+    while *position < bytes.len() {
+        let to_write = &bytes[*position..];
+        match dest.write(to_write) {
+            Ok(n) => *position += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {} // ignore and retry
+            Err(ref e) if cfg!(target_os = "macos") && e.raw_os_error() == Some(41) => {
+                // osx is weird, and can sometimes return an EPROTOTYPE when writing
+                debug!(target: "eprototype", "Retrying write due to error: {:?}", e);
+            }
+            Err(other) => return Err(other)
+        }
+    }
+    Ok(())
 }
 
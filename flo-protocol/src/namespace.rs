@@ -0,0 +1,48 @@
+use glob::Pattern;
+
+/// Checks that `pattern` is a namespace glob that the server will accept, so that clients can find out about a bad pattern
+/// before sending it. The server responds to an invalid glob with an `InvalidNamespaceGlob` error. Patterns must not be
+/// empty, and `**` must form a whole path segment, as in `/foo/**/bar`. Returns a description of the problem if the pattern
+/// is invalid.
+pub fn validate_namespace_glob(pattern: &str) -> Result<(), String> {
+    if pattern.is_empty() {
+        return Err("Invalid namespace pattern: pattern must not be empty".to_owned());
+    }
+    Pattern::new(pattern).map(|_| ()).map_err(|err| {
+        format!("Invalid namespace pattern: '{}': {}", pattern, err)
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn valid_patterns_are_accepted() {
+        for pattern in vec!["/foo", "/foo/bar", "/*", "/foo/*", "/foo/**/bar", "/**/*", "**/*", "/foo/ba?", "/foo/[abc]", "/foo/[!a-c]*"] {
+            assert_eq!(Ok(()), validate_namespace_glob(pattern), "expected pattern: '{}' to be valid", pattern);
+        }
+    }
+
+    #[test]
+    fn empty_pattern_is_rejected() {
+        let err = validate_namespace_glob("").expect_err("expected empty pattern to be invalid");
+        assert!(err.contains("must not be empty"), "unexpected description: {}", err);
+    }
+
+    #[test]
+    fn malformed_bracket_expressions_are_rejected() {
+        for pattern in vec!["/foo[unclosed", "/foo/[", "/foo/[]", "/foo/[!]"] {
+            let err = validate_namespace_glob(pattern).expect_err(&format!("expected pattern: '{}' to be invalid", pattern));
+            assert!(err.contains(pattern), "description: '{}' should contain the pattern", err);
+        }
+    }
+
+    #[test]
+    fn misplaced_recursive_wildcards_are_rejected() {
+        for pattern in vec!["/***", "/**foo", "/foo**"] {
+            assert!(validate_namespace_glob(pattern).is_err(), "expected pattern: '{}' to be invalid", pattern);
+        }
+    }
+}
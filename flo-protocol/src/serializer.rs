@@ -1,5 +1,8 @@
 use byteorder::{ByteOrder, BigEndian};
 
+/// Writes values into a fixed size buffer. If a value doesn't fit in the remaining space then it's skipped, but the
+/// position still advances, so `finish` always returns the number of bytes that the message needs. Callers compare that
+/// with the length of their buffer to tell whether the message was written completely.
 pub struct Serializer<'a> {
     buffer: &'a mut [u8],
     position: usize,
@@ -19,40 +22,25 @@ impl <'a> Serializer<'a> {
         self.write_u8(b)
     }
 
-    pub fn write_u8(mut self, byte: u8) -> Self {
-        let pos = self.position;
-        self.buffer[pos] = byte;
-        self.position += 1;
-        self
+    pub fn write_u8(self, byte: u8) -> Self {
+        self.write_with(1, |buf| buf[0] = byte)
     }
 
-    pub fn write_u16(mut self, number: u16) -> Self {
-        let pos = self.position;
-        BigEndian::write_u16(&mut self.buffer[pos..(pos+2)], number);
-        self.position += 2;
-        self
+    pub fn write_u16(self, number: u16) -> Self {
+        self.write_with(2, |buf| BigEndian::write_u16(buf, number))
     }
 
-    pub fn write_u32(mut self, n: u32) -> Self {
-        let pos = self.position;
-        BigEndian::write_u32(&mut self.buffer[pos..(pos+4)], n);
-        self.position += 4;
-        self
+    pub fn write_u32(self, n: u32) -> Self {
+        self.write_with(4, |buf| BigEndian::write_u32(buf, n))
     }
 
-    pub fn write_u64(mut self, n: u64) -> Self {
-        let pos = self.position;
-        BigEndian::write_u64(&mut self.buffer[pos..(pos+8)], n);
-        self.position += 8;
-        self
+    pub fn write_u64(self, n: u64) -> Self {
+        self.write_with(8, |buf| BigEndian::write_u64(buf, n))
     }
 
-    pub fn write_bytes<T: AsRef<[u8]>>(mut self, bytes: T) -> Self {
-        let pos = self.position;
-        let write_len = bytes.as_ref().len();
-        self.buffer[pos..(pos+write_len)].copy_from_slice(bytes.as_ref());
-        self.position += write_len;
-        self
+    pub fn write_bytes<T: AsRef<[u8]>>(self, bytes: T) -> Self {
+        let bytes = bytes.as_ref();
+        self.write_with(bytes.len(), |buf| buf.copy_from_slice(bytes))
     }
 
     /// Writes a string prepended by a u16 length
@@ -76,6 +64,15 @@ impl <'a> Serializer<'a> {
     pub fn finish(self) -> usize {
         self.position
     }
+
+    fn write_with<F: FnOnce(&mut [u8])>(mut self, len: usize, fun: F) -> Self {
+        let pos = self.position;
+        if pos + len <= self.buffer.len() {
+            fun(&mut self.buffer[pos..(pos + len)]);
+        }
+        self.position += len;
+        self
+    }
 }
 
 #[cfg(test)]
@@ -161,6 +158,14 @@ mod test {
         assert_eq!(&expected[..], &buffer[..(value.len()+2)]);
     }
 
+    #[test]
+    fn finish_returns_the_required_length_when_the_values_do_not_fit_in_the_buffer() {
+        let mut buffer = [0; 4];
+        let result = Serializer::new(&mut buffer[..]).write_u16(987).write_string("bacon").write_u8(3).finish();
+        assert_eq!(10, result);
+        assert_eq!(&[3, 219, 0, 5], &buffer[..]);
+    }
+
     #[test]
     fn multiple_values_are_written_in_sequence() {
         let mut buffer = [0; 64];
@@ -1,5 +1,5 @@
 //! An abstraction over an `AtomicUsize` to provide a monotonically increasing counter with one writer and many readers.
-//! The value of the counter can only ever increase, and can only be mutated by a singe reference. There can be many readers,
+//! The value of the counter can only ever increase (except by an explicit `reset`), and can only be mutated by a singe reference. There can be many readers,
 //! though. Limiting the mutation of the value to a single reference makes certain operations easier and faster.
 
 use std::sync::atomic::{AtomicUsize, Ordering};
@@ -36,6 +36,12 @@ impl AtomicCounterWriter {
         }
     }
 
+    /// Unconditionally sets the value. This is the only way for the value to decrease, which should only be done when events
+    /// are being intentionally discarded, such as when truncating a partition
+    pub fn reset(&mut self, new_value: usize) {
+        self.inner.store(new_value, Ordering::SeqCst);
+    }
+
     pub fn fetch_add(&mut self, amount: usize, ordering: Ordering) -> usize {
         self.inner.fetch_add(amount, ordering)
     }
@@ -12,10 +12,12 @@ use protocol::ProtocolMessage;
 use flo_client_lib::async::{AsyncConnection, MessageReceiver, MessageSender, ClientProtocolMessage};
 use flo_client_lib::codec::EventCodec;
 use event::FloEvent;
-use engine::{EngineRef, create_client_channels, start_controller, ConnectionHandler, SendProtocolMessage};
+use event_loops;
+use engine::{EngineRef, create_client_channels_with_capacity, start_controller, ConnectionHandler, SendProtocolMessage};
 
-pub use engine::ControllerOptions;
+pub use engine::{ControllerOptions, validate_options};
 pub use engine::event_stream::EventStreamOptions;
+pub use engine::DEFAULT_CLIENT_CHANNEL_CAPACITY;
 
 
 #[derive(Clone, Debug)]
@@ -28,7 +30,7 @@ impl EmbeddedFloServer {
     pub fn connect_client<D: Debug>(&self, name: String, codec: Box<EventCodec<EventData=D>>, handle: Handle) -> AsyncConnection<D> {
         let engine_ref = self.engine_ref.clone();
         let connection_id = engine_ref.next_connection_id();
-        let (client_sender, client_receiver) = create_client_channels();
+        let (client_sender, client_receiver) = create_client_channels_with_capacity(engine_ref.client_channel_capacity());
 
         let connection_handler = ConnectionHandler::new(connection_id,
                                                             client_sender.clone(),
@@ -53,6 +55,7 @@ impl EmbeddedFloServer {
 fn message_to_owned(server_msg: SendProtocolMessage) -> ClientProtocolMessage {
     match server_msg {
         ProtocolMessage::ReceiveEvent(event) => ProtocolMessage::ReceiveEvent(event.to_owned()),
+        ProtocolMessage::ReceiveEventPrefix(event, total_len) => ProtocolMessage::ReceiveEventPrefix(event.to_owned(), total_len),
         ProtocolMessage::StopConsuming(op) => ProtocolMessage::StopConsuming(op),
         ProtocolMessage::AwaitingEvents => ProtocolMessage::AwaitingEvents,
         ProtocolMessage::Error(op) => ProtocolMessage::Error(op),
@@ -66,10 +69,45 @@ fn message_to_owned(server_msg: SendProtocolMessage) -> ClientProtocolMessage {
         ProtocolMessage::CursorCreated(op) => ProtocolMessage::CursorCreated(op),
         ProtocolMessage::Announce(op) => ProtocolMessage::Announce(op),
         ProtocolMessage::SetEventStream(op) => ProtocolMessage::SetEventStream(op),
+        ProtocolMessage::TruncateStream(op) => ProtocolMessage::TruncateStream(op),
+        ProtocolMessage::ListStreams(op) => ProtocolMessage::ListStreams(op),
+        ProtocolMessage::StreamList(op) => ProtocolMessage::StreamList(op),
+        ProtocolMessage::PauseWrites(op) => ProtocolMessage::PauseWrites(op),
+        ProtocolMessage::ResumeWrites(op) => ProtocolMessage::ResumeWrites(op),
+        ProtocolMessage::GetCapabilities(op) => ProtocolMessage::GetCapabilities(op),
+        ProtocolMessage::Capabilities(op) => ProtocolMessage::Capabilities(op),
+        ProtocolMessage::ListTags(op) => ProtocolMessage::ListTags(op),
+        ProtocolMessage::GetTag(op) => ProtocolMessage::GetTag(op),
+        ProtocolMessage::TagList(op) => ProtocolMessage::TagList(op),
+        ProtocolMessage::VerifyStream(op) => ProtocolMessage::VerifyStream(op),
+        ProtocolMessage::IntegrityReport(op) => ProtocolMessage::IntegrityReport(op),
+        ProtocolMessage::GetAncestry(op) => ProtocolMessage::GetAncestry(op),
+        ProtocolMessage::Ancestry(op) => ProtocolMessage::Ancestry(op),
+        ProtocolMessage::CountEvents(op) => ProtocolMessage::CountEvents(op),
+        ProtocolMessage::GetConsumerLag(op) => ProtocolMessage::GetConsumerLag(op),
+        ProtocolMessage::CountResult(op) => ProtocolMessage::CountResult(op),
+        ProtocolMessage::Heartbeat(op) => ProtocolMessage::Heartbeat(op),
+        ProtocolMessage::GetStreamStatus(op) => ProtocolMessage::GetStreamStatus(op),
+        ProtocolMessage::CursorMessage(op_id, message) => ProtocolMessage::CursorMessage(op_id, Box::new(message_to_owned(*message))),
+        ProtocolMessage::Trace(trace) => ProtocolMessage::Trace(trace),
+        ProtocolMessage::StreamIdle(idle) => ProtocolMessage::StreamIdle(idle),
     }
 }
 
+/// Starts an embedded server. Its internal tasks are spawned using the given `remote`, unless the options ask for a
+/// `dedicated_event_loop`, in which case a new event loop thread is started for them and the `remote` is unused. That
+/// thread keeps running for the life of the process. Either way, connections from `connect_client` always run on the
+/// `Handle` that's passed for them.
 pub fn run_embedded_server(options: ControllerOptions, remote: Remote) -> io::Result<EmbeddedFloServer> {
+    let remote = if options.dedicated_event_loop {
+        let (_join_handle, dedicated_remote) = event_loops::spawn_event_loop_thread(0).map_err(|err| {
+            io::Error::new(io::ErrorKind::Other, err)
+        })?;
+        dedicated_remote
+    } else {
+        remote
+    };
+
     start_controller(options, remote).map(|engine_ref| {
         EmbeddedFloServer {
             engine_ref: engine_ref,
@@ -1,14 +1,19 @@
 
 use tokio_core::reactor::Handle;
+use futures::Future;
 
 use protocol::*;
 
 use engine::{ConnectionId, ClientSender, EngineRef, SendProtocolMessage};
-use engine::event_stream::EventStreamRef;
+use engine::event_stream::{EventStreamRef, CountFuture};
+use engine::event_stream::partition::{ProducedEvents, EventFilter};
+use super::consumer::consumer_stream::ReplayLimiter;
 
 use super::ConnectionHandlerResult;
 
 const DEFAULT_CONSUME_BATCH_SIZE: u32 = 10_000;
+pub const DEFAULT_MAX_CURSORS_PER_CONNECTION: usize = 64;
+pub const DEFAULT_MAX_IN_FLIGHT_PRODUCE_BYTES: usize = 64 * 1024 * 1024;
 
 #[derive(Debug)]
 pub struct ConnectionState {
@@ -17,13 +22,24 @@ pub struct ConnectionState {
     pub client_sender: ClientSender,
     pub engine: EngineRef,
     pub event_stream: EventStreamRef,
+    /// The engine's stream generation as of when `event_stream` was last looked up
+    pub stream_generation: usize,
     pub reactor: Handle,
     pub consume_batch_size: u32,
+    pub max_cursors_per_connection: usize,
+    /// The maximum total size of event data that may be received on this connection but not yet persisted
+    pub max_in_flight_produce_bytes: usize,
+    /// If set, then consumers on this connection must get a permit from this limiter before replaying older segments
+    pub replay_limiter: Option<ReplayLimiter>,
+    /// The ids of the events produced on this connection. Only present once a consumer on this connection has asked not to
+    /// see its own writes, so that other connections don't pay to track them
+    pub produced_events: Option<ProducedEvents>,
 }
 
 
 impl ConnectionState {
     pub fn new(connection_id: ConnectionId, client_sender: ClientSender, engine: EngineRef, reactor: Handle) -> ConnectionState {
+        let stream_generation = engine.stream_generation();
         let event_stream = engine.get_default_stream();
         ConnectionState {
             client_name: None,
@@ -32,7 +48,24 @@ impl ConnectionState {
             engine,
             reactor,
             event_stream,
+            stream_generation,
             consume_batch_size: DEFAULT_CONSUME_BATCH_SIZE,
+            max_cursors_per_connection: DEFAULT_MAX_CURSORS_PER_CONNECTION,
+            max_in_flight_produce_bytes: DEFAULT_MAX_IN_FLIGHT_PRODUCE_BYTES,
+            replay_limiter: None,
+            produced_events: None,
+        }
+    }
+
+    /// Looks up the current event stream again if any stream has been swapped since it was last looked up
+    pub fn refresh_event_stream(&mut self) {
+        let generation = self.engine.stream_generation();
+        if generation != self.stream_generation {
+            if let Ok(stream) = self.engine.get_stream(self.event_stream.name()) {
+                debug!("Switching connection_id: {} to the new version of event stream: '{}'", self.connection_id, stream.name());
+                self.event_stream = stream;
+            }
+            self.stream_generation = generation;
         }
     }
 
@@ -41,6 +74,14 @@ impl ConnectionState {
         // todo: return error if client name is already set or if protocol version != 1
         self.client_name = Some(client_name);
 
+        if consume_batch_size == Some(0) {
+            // a consumer could never send an event with a batch size of 0
+            return self.send_to_client(ProtocolMessage::Error(ErrorMessage {
+                op_id: op_id,
+                kind: ErrorKind::InvalidConsumerState,
+                description: "consume_batch_size must be greater than 0".to_owned(),
+            }));
+        }
         if let Some(batch_size) = consume_batch_size {
             debug!("Using consume batch size of {} for connection_id: {}", batch_size, self.connection_id);
             self.consume_batch_size = batch_size;
@@ -55,6 +96,22 @@ impl ConnectionState {
         })
     }
 
+    /// Sends the status of the named event stream, without changing the connection's current stream
+    pub fn send_named_stream_status(&mut self, get_status: GetStreamStatus) -> ConnectionHandlerResult {
+        let GetStreamStatus {op_id, name} = get_status;
+        let response = match self.engine.get_stream(&name) {
+            Ok(stream) => ProtocolMessage::StreamStatus(create_stream_status(op_id, &stream)),
+            Err(_) => {
+                ProtocolMessage::Error(ErrorMessage {
+                    op_id: op_id,
+                    kind: ErrorKind::NoSuchStream,
+                    description: format!("Event stream: '{}' does not exist", name),
+                })
+            }
+        };
+        self.send_to_client(response)
+    }
+
     pub fn set_event_stream(&mut self, op_id: u32, name: String) -> ConnectionHandlerResult {
         use engine::ConnectError;
         trace!("attempting to set event stream for {:?} to '{}'", self, name);
@@ -84,6 +141,272 @@ impl ConnectionState {
         }
     }
 
+    /// Starts truncating the named stream. The response is sent to the client asynchronously once all of the partitions
+    /// have finished, so the connection can continue processing other messages in the meantime
+    pub fn truncate_stream(&mut self, truncate: TruncateStream) -> ConnectionHandlerResult {
+        use engine::ConnectError;
+
+        let TruncateStream {op_id, name, up_to} = truncate;
+        info!("Truncating event stream: '{}' to: {} for connection_id: {}", name, up_to, self.connection_id);
+
+        let stream = match self.engine.get_stream(&name) {
+            Ok(stream) => stream,
+            Err(ConnectError::NoStream) => {
+                return self.send_to_client(ProtocolMessage::Error(ErrorMessage {
+                    op_id: op_id,
+                    kind: ErrorKind::NoSuchStream,
+                    description: format!("Event stream: '{}' does not exist", name),
+                }));
+            }
+            Err(ConnectError::InitFailed(io_err)) => {
+                return self.send_to_client(ProtocolMessage::Error(ErrorMessage {
+                    op_id: op_id,
+                    kind: ErrorKind::StorageEngineError,
+                    description: format!("Failed to create stream: '{}': {:?}", name, io_err)
+                }));
+            }
+        };
+
+        let client_sender = self.client_sender.clone();
+        let connection_id = self.connection_id;
+        let future = stream.truncate(connection_id, up_to).then(move |result| {
+            let response = match result {
+                Ok(()) => ProtocolMessage::StreamStatus(create_stream_status(op_id, &stream)),
+                Err(io_err) => {
+                    ProtocolMessage::Error(ErrorMessage {
+                        op_id: op_id,
+                        kind: ErrorKind::StorageEngineError,
+                        description: format!("Failed to truncate stream: '{}': {}", stream.name(), io_err),
+                    })
+                }
+            };
+            client_sender.unbounded_send(response).map_err(|e| {
+                warn!("Unable to send truncate response to connection_id: {}, message: {:?}", connection_id, e.into_inner());
+            })
+        });
+        self.reactor.spawn(future);
+        Ok(())
+    }
+
+    /// Starts verifying the named stream. Like truncating, the response is sent to the client asynchronously once every
+    /// partition has been read
+    pub fn verify_stream(&mut self, verify: VerifyStream) -> ConnectionHandlerResult {
+        use engine::event_stream::VerifyOptions;
+
+        let VerifyStream {op_id, name, version_vector, max_events, check_parents} = verify;
+        info!("Verifying event stream: '{}' starting at: {:?} for connection_id: {}", name, version_vector, self.connection_id);
+
+        let options = VerifyOptions {
+            version_vector: version_vector,
+            max_events: if max_events == 0 { None } else { Some(max_events) },
+            check_parents: check_parents,
+        };
+        let future = match self.engine.verify_stream(&name, options) {
+            Ok(future) => future,
+            Err(_) => {
+                return self.send_to_client(ProtocolMessage::Error(ErrorMessage {
+                    op_id: op_id,
+                    kind: ErrorKind::NoSuchStream,
+                    description: format!("Event stream: '{}' does not exist", name),
+                }));
+            }
+        };
+
+        let client_sender = self.client_sender.clone();
+        let connection_id = self.connection_id;
+        let future = future.then(move |result| {
+            let response = match result {
+                Ok(mut report) => {
+                    report.op_id = op_id;
+                    ProtocolMessage::IntegrityReport(report)
+                }
+                Err(io_err) => {
+                    ProtocolMessage::Error(ErrorMessage {
+                        op_id: op_id,
+                        kind: ErrorKind::StorageEngineError,
+                        description: format!("Failed to verify stream: '{}': {}", name, io_err),
+                    })
+                }
+            };
+            client_sender.unbounded_send(response).map_err(|e| {
+                warn!("Unable to send verify response to connection_id: {}, message: {:?}", connection_id, e.into_inner());
+            })
+        });
+        self.reactor.spawn(future);
+        Ok(())
+    }
+
+    /// Starts reading the requested event and its ancestors from the current event stream. The events are sent to the
+    /// client asynchronously once the whole chain has been read
+    pub fn send_ancestry(&mut self, get_ancestry: GetAncestry) -> ConnectionHandlerResult {
+        use engine::event_stream::MAX_ANCESTRY_DEPTH;
+
+        let GetAncestry {op_id, event_id} = get_ancestry;
+        debug!("Reading ancestry of event: {} for connection_id: {}", event_id, self.connection_id);
+
+        let client_sender = self.client_sender.clone();
+        let connection_id = self.connection_id;
+        let stream_name = self.event_stream.name().to_owned();
+        let future = self.event_stream.read_ancestry(event_id, MAX_ANCESTRY_DEPTH).then(move |result| {
+            let responses = match result {
+                Ok(ref events) if events.is_empty() => {
+                    vec![ProtocolMessage::Error(ErrorMessage {
+                        op_id: op_id,
+                        kind: ErrorKind::InvalidEventId,
+                        description: format!("Event: {} does not exist in event stream: '{}'", event_id, stream_name),
+                    })]
+                }
+                Ok(events) => {
+                    let info = AncestryInfo {
+                        op_id: op_id,
+                        event_count: events.len() as u32,
+                    };
+                    Some(ProtocolMessage::Ancestry(info)).into_iter()
+                            .chain(events.into_iter().map(|event| ProtocolMessage::ReceiveEvent(event)))
+                            .collect()
+                }
+                Err(io_err) => {
+                    vec![ProtocolMessage::Error(ErrorMessage {
+                        op_id: op_id,
+                        kind: ErrorKind::StorageEngineError,
+                        description: format!("Failed to read ancestry of event: {}: {}", event_id, io_err),
+                    })]
+                }
+            };
+            for response in responses {
+                client_sender.unbounded_send(response).map_err(|e| {
+                    warn!("Unable to send ancestry response to connection_id: {}, message: {:?}", connection_id, e.into_inner());
+                })?;
+            }
+            Ok(())
+        });
+        self.reactor.spawn(future);
+        Ok(())
+    }
+
+    /// Starts counting the matching events in the current event stream. The result is sent to the client asynchronously
+    /// once every partition has been read
+    pub fn count_events(&mut self, count: CountEvents) -> ConnectionHandlerResult {
+        let CountEvents {op_id, namespace, since} = count;
+        let filter = match parse_namespace_filter(op_id, &namespace, None) {
+            Ok(filter) => filter,
+            Err(error) => return self.send_to_client(error),
+        };
+        let start = since.map(|id| id.event_counter).unwrap_or(0);
+        debug!("Counting events in namespace: '{}' after counter: {} for connection_id: {}", namespace, start, self.connection_id);
+
+        let count = self.event_stream.count_events(filter, start);
+        self.send_count_result(op_id, namespace, count)
+    }
+
+    /// Starts counting the events that a consumer at the given version vector has yet to receive, summed across every
+    /// partition. Like `count_events`, the result is sent to the client asynchronously. Each partition uses its index to
+    /// find the first event after the consumer's position, so only the events that the consumer hasn't received are read
+    pub fn consumer_lag(&mut self, get_lag: GetConsumerLag) -> ConnectionHandlerResult {
+        let GetConsumerLag {op_id, namespace, version_vector} = get_lag;
+        let filter = match parse_namespace_filter(op_id, &namespace, None) {
+            Ok(filter) => filter,
+            Err(error) => return self.send_to_client(error),
+        };
+        debug!("Getting lag of consumer in namespace: '{}' at: {:?} for connection_id: {}", namespace, version_vector, self.connection_id);
+
+        let count = self.event_stream.count_events_since(filter, &version_vector);
+        self.send_count_result(op_id, namespace, count)
+    }
+
+    fn send_count_result(&mut self, op_id: u32, namespace: String, count: CountFuture) -> ConnectionHandlerResult {
+        let client_sender = self.client_sender.clone();
+        let connection_id = self.connection_id;
+        let future = count.then(move |result| {
+            let response = match result {
+                Ok(count) => {
+                    ProtocolMessage::CountResult(CountResult {
+                        op_id: op_id,
+                        count: count,
+                    })
+                }
+                Err(io_err) => {
+                    ProtocolMessage::Error(ErrorMessage {
+                        op_id: op_id,
+                        kind: ErrorKind::StorageEngineError,
+                        description: format!("Failed to count events in namespace: '{}': {}", namespace, io_err),
+                    })
+                }
+            };
+            client_sender.unbounded_send(response).map_err(|e| {
+                warn!("Unable to send count result to connection_id: {}, message: {:?}", connection_id, e.into_inner());
+            })
+        });
+        self.reactor.spawn(future);
+        Ok(())
+    }
+
+    /// Sends every tag in the connection's current event stream
+    pub fn send_tag_list(&mut self, op_id: u32) -> ConnectionHandlerResult {
+        let tags = self.event_stream.tags().all();
+        self.send_to_client(ProtocolMessage::TagList(TagList {
+            op_id: op_id,
+            tags: tags,
+        }))
+    }
+
+    /// Sends a `TagList` with just the requested tag, or an error if the connection's current event stream doesn't have it
+    pub fn send_tag(&mut self, get_tag: GetTag) -> ConnectionHandlerResult {
+        let GetTag {op_id, name} = get_tag;
+        let response = match self.event_stream.tags().get(&name) {
+            Some(event_id) => {
+                ProtocolMessage::TagList(TagList {
+                    op_id: op_id,
+                    tags: vec![StreamTag { name: name, event_id: event_id }],
+                })
+            }
+            None => self.no_such_tag(op_id, &name),
+        };
+        self.send_to_client(response)
+    }
+
+    pub fn no_such_tag(&self, op_id: u32, name: &str) -> SendProtocolMessage {
+        ProtocolMessage::Error(ErrorMessage {
+            op_id: op_id,
+            kind: ErrorKind::NoSuchTag,
+            description: format!("Tag: '{}' does not exist in event stream: '{}'", name, self.event_stream.name()),
+        })
+    }
+
+    /// Sends the set of optional protocol features that this server supports
+    pub fn send_capabilities(&mut self, op_id: u32) -> ConnectionHandlerResult {
+        let capabilities = server_capabilities(op_id);
+        self.send_to_client(ProtocolMessage::Capabilities(capabilities))
+    }
+
+    /// Sends a `StreamList` to the client once all of the streams have been described
+    pub fn list_streams(&mut self, op_id: u32) -> ConnectionHandlerResult {
+        let client_sender = self.client_sender.clone();
+        let connection_id = self.connection_id;
+        let future = self.engine.list_streams().then(move |result| {
+            let response = match result {
+                Ok(streams) => {
+                    ProtocolMessage::StreamList(StreamList {
+                        op_id: op_id,
+                        streams: streams,
+                    })
+                }
+                Err(io_err) => {
+                    ProtocolMessage::Error(ErrorMessage {
+                        op_id: op_id,
+                        kind: ErrorKind::StorageEngineError,
+                        description: format!("Failed to list event streams: {}", io_err),
+                    })
+                }
+            };
+            client_sender.unbounded_send(response).map_err(|e| {
+                warn!("Unable to send stream list to connection_id: {}, message: {:?}", connection_id, e.into_inner());
+            })
+        });
+        self.reactor.spawn(future);
+        Ok(())
+    }
+
     pub fn send_to_client(&self, message: SendProtocolMessage) -> ConnectionHandlerResult {
         self.client_sender.unbounded_send(message).map_err(|e| {
             format!("Error sending outgoing message for connection_id: {}, message: {:?}", self.connection_id, e.into_inner())
@@ -91,6 +414,22 @@ impl ConnectionState {
     }
 }
 
+/// Parses the namespace of a consumer or count request into a filter, using the regex instead of the glob if one is given.
+/// Every request that filters by namespace uses this, so that invalid namespaces are always reported the same way
+pub fn parse_namespace_filter(op_id: u32, namespace: &str, namespace_regex: Option<&str>) -> Result<EventFilter, SendProtocolMessage> {
+    let (filter, kind) = match namespace_regex {
+        Some(regex) => (EventFilter::regex(regex), ErrorKind::InvalidNamespaceRegex),
+        None => (EventFilter::parse(namespace), ErrorKind::InvalidNamespaceGlob),
+    };
+    filter.map_err(|description| {
+        ProtocolMessage::Error(ErrorMessage {
+            op_id: op_id,
+            kind: kind,
+            description: description,
+        })
+    })
+}
+
 fn create_stream_status(op_id: u32, stream_ref: &EventStreamRef) -> EventStreamStatus {
     let mut partition_statuses = Vec::with_capacity(stream_ref.get_partition_count() as usize);
 
@@ -113,3 +452,14 @@ fn create_stream_status(op_id: u32, stream_ref: &EventStreamRef) -> EventStreamS
     }
 }
 
+
+/// Every optional feature is supported by this server, so they're all advertised
+fn server_capabilities(op_id: u32) -> Capabilities {
+    let flags = features::NAMES.iter().fold(0, |flags, &(flag, _)| flags | flag);
+    let names = features::NAMES.iter().map(|&(_, name)| name.to_owned()).collect();
+    Capabilities {
+        op_id: op_id,
+        flags: flags,
+        features: names,
+    }
+}
@@ -0,0 +1,54 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Poll, Async};
+use tokio_core::reactor::{Handle, Timeout};
+
+use event::{FloEventId, time};
+use engine::event_stream::partition::PartitionRef;
+use protocol::StreamIdle;
+
+/// Tells a caught up consumer that its connection is still alive, each time its `idle_signal_interval` passes without any
+/// events being sent to it. The interval starts over whenever an event is sent, so busy consumers never get a signal.
+pub struct IdleSignal {
+    op_id: u32,
+    interval: Duration,
+    timeout: Timeout,
+    partitions: Vec<PartitionRef>,
+}
+
+impl IdleSignal {
+    pub fn new(op_id: u32, interval: Duration, partitions: Vec<PartitionRef>, handle: &Handle) -> io::Result<IdleSignal> {
+        let timeout = Timeout::new(interval, handle)?;
+        Ok(IdleSignal {
+            op_id: op_id,
+            interval: interval,
+            timeout: timeout,
+            partitions: partitions,
+        })
+    }
+
+    /// Starts the interval over, because the consumer was just sent something
+    pub fn reset(&mut self) {
+        self.timeout.reset(Instant::now() + self.interval);
+    }
+
+    /// Returns the `StreamIdle` to send once the interval has passed, and starts the next interval. Otherwise, the current
+    /// task will be notified when it passes
+    pub fn poll_idle(&mut self) -> Poll<StreamIdle, io::Error> {
+        try_ready!(self.timeout.poll());
+        self.reset();
+        Ok(Async::Ready(StreamIdle {
+            op_id: self.op_id,
+            tail_id: self.tail_id(),
+            server_time: time::now(),
+        }))
+    }
+
+    /// Counters are shared by every partition, so the partition with the highest counter has the newest event
+    fn tail_id(&self) -> FloEventId {
+        self.partitions.iter().map(|partition| {
+            FloEventId::new(partition.partition_num(), partition.get_highest_event_counter())
+        }).filter(|id| id.event_counter > 0).max().unwrap_or(FloEventId::zero())
+    }
+}
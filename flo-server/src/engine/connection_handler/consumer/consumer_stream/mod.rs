@@ -1,24 +1,67 @@
 mod status_check;
 mod notifier;
 mod multi_partition_reader;
+mod rate_limit;
+mod replay_limit;
+mod idle_signal;
 
 use std::io;
 
-use futures::{Stream, Poll, Async};
+use futures::{Future, Stream, Poll, Async};
+use tokio_core::reactor::Timeout;
 
+use event::FloEventId;
 use engine::{ConnectionId, SendProtocolMessage};
 use engine::event_stream::partition::{PartitionReader, PersistentEvent};
-use protocol::{ProtocolMessage};
+use protocol::{ProtocolMessage, ErrorMessage, ErrorKind};
+use metrics::Counter;
 
 pub use self::notifier::{ConsumerTaskSetter};
 pub use self::status_check::{ConsumerStatus, ConsumerStatusChecker, ConsumerStatusSetter, create_status_channel};
+pub use self::rate_limit::DeliveryRateLimiter;
+pub use self::replay_limit::{ReplayLimiter, ReplayPermit};
+pub use self::idle_signal::IdleSignal;
 
 use self::multi_partition_reader::MultiPartitionEventReader;
 
+/// The options that a consumer is started with, which are all optional behaviors that a client may ask for
+pub struct ConsumerOptions {
+    /// if set, then only this many bytes from the start of each event body are sent
+    pub body_prefix_bytes: Option<u32>,
+    /// if set, then events are sent no faster than the consumer's `max_delivery_rate`
+    pub rate_limiter: Option<DeliveryRateLimiter>,
+    /// if set, then the consumer finishes with an error instead of awaiting events if none matched before reaching the end
+    pub error_on_empty: bool,
+    /// if set, then the consumer is stopped by the server once this fires, because it reached the stream's `max_cursor_lifetime`
+    pub lifetime: Option<Timeout>,
+    /// if set, then events and batch status messages are wrapped in a `CursorMessage` with this consumer's op_id
+    pub cursor_op_ids: bool,
+    /// if set, then the readers stop at these events, which were the newest when the cursor started, and the consumer is
+    /// stopped instead of awaiting new events once it's read all of them
+    pub snapshot_heads: Option<Vec<FloEventId>>,
+    /// if set, then a `StreamIdle` is sent each time the consumer goes its `idle_signal_interval` without being sent anything
+    pub idle_signal: Option<IdleSignal>,
+    /// if set, then a permit must be held while reading from any segment other than the newest one in a partition
+    pub replay_limiter: Option<ReplayLimiter>,
+}
+
 pub struct Consumer {
     connection_id: ConnectionId,
     op_id: u32,
     total_events_remaining: Option<u64>,
+    // the options that the consumer was started with, which are described on `ConsumerOptions`
+    body_prefix_bytes: Option<u32>,
+    rate_limiter: Option<DeliveryRateLimiter>,
+    error_on_empty: bool,
+    lifetime: Option<Timeout>,
+    cursor_op_ids: bool,
+    /// whether the consumer was given `snapshot_heads`
+    snapshot: bool,
+    idle_signal: Option<IdleSignal>,
+    replay_limiter: Option<ReplayLimiter>,
+    /// whether any events have been sent yet
+    any_events_sent: bool,
+    replay_permit: Option<ReplayPermit>,
     batch_size: u32,
     batch_remaining: u32,
 
@@ -35,7 +78,10 @@ pub struct Consumer {
     task_setter: ConsumerTaskSetter,
 
     /// used to receive consumer status from ConnectionHandler
-    status_checker: ConsumerStatusChecker
+    status_checker: ConsumerStatusChecker,
+
+    /// the `bytes_consumed` metric of the event stream
+    bytes_consumed: Counter,
 }
 
 impl Consumer {
@@ -45,20 +91,33 @@ impl Consumer {
                task_setter: ConsumerTaskSetter,
                readers: Vec<PartitionReader>,
                op_id: u32,
-               max_events: Option<u64>) -> Consumer {
-
+               max_events: Option<u64>,
+               options: ConsumerOptions,
+               bytes_consumed: Counter) -> Consumer {
+        let ConsumerOptions {body_prefix_bytes, rate_limiter, error_on_empty, lifetime, cursor_op_ids, snapshot_heads, idle_signal, replay_limiter} = options;
 
         Consumer {
             connection_id: connection_id,
             op_id: op_id,
             total_events_remaining: max_events,
+            body_prefix_bytes: body_prefix_bytes,
+            rate_limiter: rate_limiter,
+            error_on_empty: error_on_empty,
+            any_events_sent: false,
+            lifetime: lifetime,
+            cursor_op_ids: cursor_op_ids,
+            snapshot: snapshot_heads.is_some(),
+            idle_signal: idle_signal,
+            replay_limiter: replay_limiter,
+            replay_permit: None,
             batch_size: batch_size,
             batch_remaining: batch_size,
-            readers: MultiPartitionEventReader::new(readers),
+            readers: MultiPartitionEventReader::new(readers, snapshot_heads),
             task_setter: task_setter,
             status_checker: status_checker,
             end_of_batch_sent: false,
             await_new_events_sent: false,
+            bytes_consumed: bytes_consumed,
         }
     }
 
@@ -66,19 +125,80 @@ impl Consumer {
         self.total_events_remaining.map(|n| n == 0).unwrap_or(false)
     }
 
+    /// Returns true once the consumer has reached its maximum lifetime. Otherwise, the current task will be notified when it does
+    fn lifetime_expired(&mut self) -> Result<bool, ConsumerError> {
+        match self.lifetime {
+            Some(ref mut timeout) => Ok(timeout.poll()?.is_ready()),
+            None => Ok(false),
+        }
+    }
+
+    /// Returns `Ready` once the consumer may read its next event. A permit is only needed while replaying older segments,
+    /// and it's kept until the consumer catches up or reaches the end of a batch. Otherwise, the current task will be
+    /// notified when a permit is available.
+    fn poll_replay_permit(&mut self) -> Async<()> {
+        let needs_permit = self.replay_limiter.is_some() && self.readers.is_replaying();
+        if !needs_permit {
+            self.replay_permit = None;
+            return Async::Ready(());
+        }
+        if self.replay_permit.is_none() {
+            match self.replay_limiter.as_ref().unwrap().poll_acquire() {
+                Async::Ready(permit) => {
+                    debug!("Consumer for connection_id: {}, op_id: {} acquired a replay permit", self.connection_id, self.op_id);
+                    self.replay_permit = Some(permit);
+                }
+                Async::NotReady => {
+                    trace!("Consumer for connection_id: {}, op_id: {} is waiting on a replay permit", self.connection_id, self.op_id);
+                    return Async::NotReady;
+                }
+            }
+        }
+        Async::Ready(())
+    }
+
     fn await_more_events(&mut self) -> Poll<Option<SendProtocolMessage>, ConsumerError> {
+        self.replay_permit = None;
+        if self.error_on_empty && !self.any_events_sent {
+            debug!("No events matched for consumer: connection_id: {}, op_id: {}, so it is finishing with an error", self.connection_id, self.op_id);
+            // set the total remaining to 0 to make sure that all future poll calls will return None
+            self.total_events_remaining = Some(0);
+            return Ok(Async::Ready(Some(ProtocolMessage::Error(ErrorMessage {
+                op_id: self.op_id,
+                kind: ErrorKind::NoMatchingEvents,
+                description: "No events currently match the consumer's namespace".to_owned(),
+            }))));
+        }
+
+        if self.snapshot {
+            debug!("Snapshot consumer: connection_id: {}, op_id: {} has read every event, so it is being stopped", self.connection_id, self.op_id);
+            // set the total remaining to 0 to make sure that all future poll calls will return None
+            self.total_events_remaining = Some(0);
+            return Ok(Async::Ready(Some(ProtocolMessage::StopConsuming(self.op_id))));
+        }
+
         trace!("Awaiting more events for connection_id: {}", self.connection_id);
         self.task_setter.await_more_events();
         if self.await_new_events_sent {
-            Ok(Async::NotReady)
+            match self.idle_signal {
+                Some(ref mut idle_signal) => {
+                    let idle = try_ready!(idle_signal.poll_idle());
+                    debug!("Sending StreamIdle for connection_id: {}, op_id: {}, tail_id: {}", self.connection_id, self.op_id, idle.tail_id);
+                    Ok(Async::Ready(Some(ProtocolMessage::StreamIdle(idle))))
+                }
+                None => Ok(Async::NotReady)
+            }
         } else {
             debug!("Sending AwaitingEvents for connection_id: {}", self.connection_id);
             self.await_new_events_sent = true;
+            if let Some(ref mut idle_signal) = self.idle_signal {
+                idle_signal.reset();
+            }
             Ok(Async::Ready(Some(ProtocolMessage::AwaitingEvents)))
         }
     }
 
-    fn send_event(&mut self, event: PersistentEvent) -> Poll<Option<SendProtocolMessage>, ConsumerError> {
+    fn send_event(&mut self, mut event: PersistentEvent) -> Poll<Option<SendProtocolMessage>, ConsumerError> {
         use event::FloEvent;
 
         // decrement total count and batch remaining. We've already checked to ensure that both counts are > 0
@@ -86,6 +206,13 @@ impl Consumer {
             *total -= 1;
         }
         self.batch_remaining -= 1;
+        self.any_events_sent = true;
+        if let Some(ref mut limiter) = self.rate_limiter {
+            limiter.take_token();
+        }
+        if let Some(ref mut idle_signal) = self.idle_signal {
+            idle_signal.reset();
+        }
 
         trace!("Sending event: {} to connection_id: {}", event.id(), self.connection_id);
 
@@ -96,7 +223,18 @@ impl Consumer {
             self.status_checker.await_status_change();
         }
 
-        let message = ProtocolMessage::ReceiveEvent(event);
+        let message = match self.body_prefix_bytes {
+            Some(prefix_len) => {
+                let total_len = event.data_len();
+                event.truncate_data(prefix_len as usize);
+                self.bytes_consumed.add(event.data_len() as usize);
+                ProtocolMessage::ReceiveEventPrefix(event, total_len)
+            }
+            None => {
+                self.bytes_consumed.add(event.data_len() as usize);
+                ProtocolMessage::ReceiveEvent(event)
+            }
+        };
 
         // return the event, which will get forwarded to the client Sink
         Ok(Async::Ready(Some(message)))
@@ -128,12 +266,14 @@ impl Consumer {
                     // We're at the end of a batch, so we need to wait for the status to change
                     self.status_checker.await_status_change();
 
+                    self.replay_permit = None;
                     if self.end_of_batch_sent {
                         debug!("consumer for connection_id: {} still awaiting next batch", self.connection_id);
                         Ok(Async::NotReady)
                     } else {
                         debug!("consumer for connection_id: {} sending end of batch", self.connection_id);
                         self.end_of_batch_sent = true;
+                        self.status_checker.set_awaiting_next_batch();
                         Ok(Async::Ready(Some(StreamStatus::EndOfBatch)))
                     }
                 }
@@ -154,6 +294,47 @@ impl Consumer {
         }
     }
 
+    /// Wraps the messages that would otherwise have no way of identifying which cursor they belong to
+    fn tag_with_op_id(&self, message: SendProtocolMessage) -> SendProtocolMessage {
+        match message {
+            ProtocolMessage::ReceiveEvent(_) | ProtocolMessage::ReceiveEventPrefix(_, _) |
+            ProtocolMessage::EndOfBatch | ProtocolMessage::AwaitingEvents if self.cursor_op_ids => {
+                ProtocolMessage::CursorMessage(self.op_id, Box::new(message))
+            }
+            other @ _ => other
+        }
+    }
+
+    fn next_message(&mut self) -> Poll<Option<SendProtocolMessage>, ConsumerError> {
+        if !self.is_done() && self.lifetime_expired()? {
+            debug!("Consumer for connection_id: {}, op_id: {} reached its maximum lifetime, so it is being stopped", self.connection_id, self.op_id);
+            // set the total remaining to 0 to make sure that all future poll calls will return None
+            self.total_events_remaining = Some(0);
+            self.lifetime = None;
+            return Ok(Async::Ready(Some(ProtocolMessage::StopConsuming(self.op_id))));
+        }
+
+        let stream_status = try_ready!(self.check_status());
+
+        match stream_status {
+            None => {
+                Ok(Async::Ready(None))
+            }
+            Some(StreamStatus::EndOfBatch) => {
+                Ok(Async::Ready(Some(ProtocolMessage::EndOfBatch)))
+            }
+            Some(StreamStatus::Continue) => {
+                if let Some(ref mut limiter) = self.rate_limiter {
+                    try_ready!(limiter.poll_ready());
+                }
+                if self.poll_replay_permit().is_not_ready() {
+                    return Ok(Async::NotReady);
+                }
+                self.next_matching_result()
+            }
+        }
+    }
+
     fn next_matching_result(&mut self) -> Poll<Option<SendProtocolMessage>, ConsumerError> {
         let result = self.readers.next_matching();
         match result {
@@ -176,19 +357,8 @@ impl Stream for Consumer {
     type Error = ConsumerError;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let stream_status = try_ready!(self.check_status());
-
-        match stream_status {
-            None => {
-                Ok(Async::Ready(None))
-            }
-            Some(StreamStatus::EndOfBatch) => {
-                Ok(Async::Ready(Some(ProtocolMessage::EndOfBatch)))
-            }
-            Some(StreamStatus::Continue) => {
-                self.next_matching_result()
-            }
-        }
+        let message = try_ready!(self.next_message());
+        Ok(Async::Ready(message.map(|message| self.tag_with_op_id(message))))
     }
 }
 
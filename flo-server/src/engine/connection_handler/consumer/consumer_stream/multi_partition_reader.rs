@@ -1,7 +1,7 @@
 
 use std::io;
 
-use event::{FloEvent, EventCounter};
+use event::{FloEvent, FloEventId, EventCounter};
 use engine::event_stream::partition::{PartitionReader, PersistentEvent};
 
 
@@ -11,11 +11,20 @@ pub struct MultiPartitionEventReader {
 
 impl MultiPartitionEventReader {
 
-    pub fn new(readers: Vec<PartitionReader>) -> MultiPartitionEventReader {
+    /// If `end_ids` is given, then each partition's reader stops after the event with the counter in that partition's id,
+    /// and partitions that aren't included are read as though they were empty
+    pub fn new(readers: Vec<PartitionReader>, end_ids: Option<Vec<FloEventId>>) -> MultiPartitionEventReader {
         let inner = readers.into_iter().map(|reader| {
+            let end_inclusive = end_ids.as_ref().map(|ids| {
+                ids.iter().find(|id| id.actor == reader.partition_num())
+                        .map(|id| id.event_counter)
+                        .unwrap_or(0)
+            });
             PartReaderInternal {
                 next_val: None,
                 reader: reader,
+                end_inclusive: end_inclusive,
+                reached_end: false,
             }
         }).collect();
 
@@ -45,19 +54,36 @@ impl MultiPartitionEventReader {
 
         self.readers[reader_index].next_val.take()
     }
+
+    /// Returns true if any of the partitions is still reading from a segment that isn't its newest one
+    pub fn is_replaying(&self) -> bool {
+        self.readers.iter().any(|reader| reader.reader.is_replaying())
+    }
 }
 
 
 struct PartReaderInternal {
     next_val: Option<io::Result<PersistentEvent>>,
     reader: PartitionReader,
+    /// the counter of the last event that may be read from this partition, if there is one
+    end_inclusive: Option<EventCounter>,
+    reached_end: bool,
 }
 
 impl PartReaderInternal {
     fn advance(&mut self) {
-        if self.next_val.is_none() {
+        if self.next_val.is_none() && !self.reached_end {
             let next = self.reader.next_matching();
-            self.next_val = next;
+            let past_end = match (next.as_ref(), self.end_inclusive) {
+                (Some(&Ok(ref event)), Some(end)) => event.id().event_counter > end,
+                _ => false
+            };
+            if past_end {
+                // counters only increase within a partition, so every later event would be past the end, too
+                self.reached_end = true;
+            } else {
+                self.next_val = next;
+            }
         }
     }
 }
@@ -13,6 +13,7 @@ pub struct ConsumerNotifierImpl {
     task_ref: Arc<AtomicTask>,
     active: AtomicBoolReader,
     connection_id: ConnectionId,
+    op_id: u32,
 }
 
 impl ConsumerNotifier for ConsumerNotifierImpl {
@@ -27,6 +28,10 @@ impl ConsumerNotifier for ConsumerNotifierImpl {
     fn connection_id(&self) -> ConnectionId {
         self.connection_id
     }
+
+    fn op_id(&self) -> u32 {
+        self.op_id
+    }
 }
 
 
@@ -45,7 +50,7 @@ impl ConsumerTaskSetter {
         }
     }
 
-    pub fn create_notifier(&self, connection_id: ConnectionId) -> Box<ConsumerNotifier> {
+    pub fn create_notifier(&self, connection_id: ConnectionId, op_id: u32) -> Box<ConsumerNotifier> {
         let task_ref = self.task_ref.clone();
         let active = self.active.reader();
 
@@ -53,6 +58,7 @@ impl ConsumerTaskSetter {
             task_ref,
             active,
             connection_id,
+            op_id,
         })
     }
 
@@ -0,0 +1,105 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Poll, Async};
+use tokio_core::reactor::{Handle, Timeout};
+
+/// Paces the events sent to a single consumer using a token bucket. Each event uses one token, and tokens are added
+/// continuously at the configured rate. The bucket only holds a tenth of a second's worth of tokens, so a consumer that's
+/// been waiting on new events can't receive a large burst once they arrive.
+pub struct DeliveryRateLimiter {
+    events_per_second: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+    handle: Handle,
+    timeout: Option<Timeout>,
+}
+
+impl DeliveryRateLimiter {
+    pub fn new(events_per_second: u32, handle: Handle) -> DeliveryRateLimiter {
+        let events_per_second = events_per_second as f64;
+        let capacity = (events_per_second / 10.0).max(1.0);
+        DeliveryRateLimiter {
+            events_per_second: events_per_second,
+            capacity: capacity,
+            tokens: capacity,
+            last_refill: Instant::now(),
+            handle: handle,
+            timeout: None,
+        }
+    }
+
+    /// Returns `Ready` once there's a token available for the next event. Otherwise, the current task will be notified
+    /// when one becomes available. This never uses a token, since there may not be an event to send.
+    pub fn poll_ready(&mut self) -> Poll<(), io::Error> {
+        loop {
+            if let Some(ref mut timeout) = self.timeout {
+                try_ready!(timeout.poll());
+            }
+            self.timeout = None;
+
+            self.refill(Instant::now());
+            if self.tokens >= 1.0 {
+                return Ok(Async::Ready(()));
+            }
+
+            let wait_secs = (1.0 - self.tokens) / self.events_per_second;
+            let wait = Duration::new(wait_secs as u64, (wait_secs.fract() * 1_000_000_000.0) as u32);
+            self.timeout = Some(Timeout::new(wait, &self.handle)?);
+        }
+    }
+
+    /// Uses a token for an event that's being sent
+    pub fn take_token(&mut self) {
+        self.tokens = (self.tokens - 1.0).max(0.0);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill);
+        let elapsed_secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+        self.tokens = (self.tokens + elapsed_secs * self.events_per_second).min(self.capacity);
+        self.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio_core::reactor::Core;
+    use futures::future;
+
+    #[test]
+    fn tokens_are_added_at_the_configured_rate_up_to_the_capacity() {
+        let core = Core::new().unwrap();
+        let mut subject = DeliveryRateLimiter::new(100, core.handle());
+        let start = subject.last_refill;
+
+        // the bucket starts with a tenth of a second's worth of tokens
+        for _ in 0..10 {
+            subject.take_token();
+        }
+        assert_eq!(0.0, subject.tokens);
+
+        subject.refill(start + Duration::from_millis(30));
+        assert!((subject.tokens - 3.0).abs() < 0.001, "tokens: {}", subject.tokens);
+
+        subject.refill(start + Duration::from_millis(1000));
+        assert_eq!(10.0, subject.tokens);
+    }
+
+    #[test]
+    fn poll_ready_is_not_ready_once_every_token_is_used() {
+        let mut core = Core::new().unwrap();
+        let mut subject = DeliveryRateLimiter::new(1, core.handle());
+
+        core.run(future::lazy(|| {
+            assert!(subject.poll_ready().unwrap().is_ready());
+            subject.take_token();
+            // the next token takes a whole second to be added
+            assert!(subject.poll_ready().unwrap().is_not_ready());
+            assert!(subject.timeout.is_some());
+            Ok::<(), ()>(())
+        })).unwrap();
+    }
+}
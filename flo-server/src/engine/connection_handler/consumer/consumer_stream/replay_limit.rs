@@ -0,0 +1,136 @@
+use std::fmt::{self, Debug};
+use std::sync::{Arc, Mutex};
+
+use futures::Async;
+use futures::task::{self, Task};
+
+/// Limits how many consumers may be replaying older segments at the same time, across every connection. When many
+/// consumers start reading from the beginning of a stream at once, their reads of segments that are no longer in memory
+/// would otherwise compete for the disk with each other and with producers.
+///
+/// A consumer only needs a permit while it's reading from a segment that isn't the newest one in its partition, since the
+/// newest segment was written recently and is expected to still be in the page cache. Consumers release their permit when
+/// they reach the end of a batch or catch up, so a permit is not held while waiting for the client to ask for the next
+/// batch. It is still held while a consumer's events are waiting to be written to a slow client, or while the consumer is
+/// paced by its `max_delivery_rate`, so those consumers can hold up others that are waiting to replay.
+#[derive(Clone)]
+pub struct ReplayLimiter {
+    inner: Arc<Mutex<LimiterInner>>,
+}
+
+struct LimiterInner {
+    max_concurrent: usize,
+    active: usize,
+    waiting_tasks: Vec<Task>,
+}
+
+impl ReplayLimiter {
+    pub fn new(max_concurrent: usize) -> ReplayLimiter {
+        ReplayLimiter {
+            inner: Arc::new(Mutex::new(LimiterInner {
+                max_concurrent: max_concurrent,
+                active: 0,
+                waiting_tasks: Vec::new(),
+            }))
+        }
+    }
+
+    /// Returns the number of permits that are currently held
+    pub fn active_replays(&self) -> usize {
+        self.inner.lock().unwrap().active
+    }
+
+    /// Returns a permit if one is available. Otherwise, the current task will be notified when one is released
+    pub fn poll_acquire(&self) -> Async<ReplayPermit> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.active < inner.max_concurrent {
+            inner.active += 1;
+            Async::Ready(ReplayPermit {
+                limiter: self.clone(),
+            })
+        } else {
+            // a task that's polled again before a permit is released only needs to be notified once
+            if !inner.waiting_tasks.iter().any(|task| task.will_notify_current()) {
+                inner.waiting_tasks.push(task::current());
+            }
+            Async::NotReady
+        }
+    }
+
+    fn release(&self) {
+        let tasks = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.active -= 1;
+            ::std::mem::replace(&mut inner.waiting_tasks, Vec::new())
+        };
+        // every waiting task gets a chance to acquire the permit, since some of them may have finished in the meantime
+        for task in tasks {
+            task.notify();
+        }
+    }
+}
+
+impl Debug for ReplayLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        write!(f, "ReplayLimiter{{ max_concurrent: {}, active: {}, waiting: {} }}", inner.max_concurrent, inner.active, inner.waiting_tasks.len())
+    }
+}
+
+/// Allows a single consumer to read from older segments. The permit is released when this is dropped
+pub struct ReplayPermit {
+    limiter: ReplayLimiter,
+}
+
+impl Drop for ReplayPermit {
+    fn drop(&mut self) {
+        self.limiter.release();
+    }
+}
+
+impl Debug for ReplayPermit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ReplayPermit")
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::{future, Future};
+
+    #[test]
+    fn permits_are_limited_to_the_max_and_released_on_drop() {
+        let subject = ReplayLimiter::new(2);
+
+        future::lazy(|| {
+            let first = subject.poll_acquire();
+            let second = subject.poll_acquire();
+            assert!(first.is_ready());
+            assert!(second.is_ready());
+            assert!(subject.poll_acquire().is_not_ready());
+            assert_eq!(2, subject.active_replays());
+
+            drop(first);
+            assert_eq!(1, subject.active_replays());
+            assert!(subject.poll_acquire().is_ready());
+            assert_eq!(1, subject.active_replays());
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn a_task_that_polls_repeatedly_is_only_added_to_the_waiters_once() {
+        let subject = ReplayLimiter::new(1);
+
+        future::lazy(|| {
+            let permit = subject.poll_acquire();
+            assert!(permit.is_ready());
+            assert!(subject.poll_acquire().is_not_ready());
+            assert!(subject.poll_acquire().is_not_ready());
+            assert_eq!(1, subject.inner.lock().unwrap().waiting_tasks.len());
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+}
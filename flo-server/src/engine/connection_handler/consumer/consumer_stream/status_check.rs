@@ -1,6 +1,7 @@
 
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::time::Instant;
 
 use futures::task::{self, Task};
 
@@ -22,7 +23,9 @@ const NEXT_BATCH: usize = 2;
 #[derive(Debug)]
 struct Inner {
     state: ConsumerStatus,
-    task: Option<Task>
+    task: Option<Task>,
+    /// set when the consumer sends `EndOfBatch`, and cleared once it's given the next batch
+    awaiting_next_batch: Option<Instant>,
 }
 
 impl Inner {
@@ -30,6 +33,7 @@ impl Inner {
         Inner {
             state: ConsumerStatus::NoChange,
             task: None,
+            awaiting_next_batch: None,
         }
     }
 }
@@ -45,6 +49,14 @@ impl ConsumerStatusChecker {
         val
     }
 
+    /// Records that the consumer has sent `EndOfBatch` and won't send any more events until it gets `NextBatch`
+    pub fn set_awaiting_next_batch(&self) {
+        let mut inner = self.0.borrow_mut();
+        if inner.awaiting_next_batch.is_none() {
+            inner.awaiting_next_batch = Some(Instant::now());
+        }
+    }
+
     pub fn await_status_change(&self) {
         let mut inner = self.0.borrow_mut();
         if inner.task.is_none() || !inner.task.as_ref().unwrap().will_notify_current() {
@@ -61,10 +73,23 @@ impl ConsumerStatusSetter {
     pub fn set(&mut self, status: ConsumerStatus) {
         let mut inner = self.0.borrow_mut();
         inner.state = status;
+        if status == ConsumerStatus::NextBatch {
+            inner.awaiting_next_batch = None;
+        }
         if let Some(ref task) = inner.task {
             task.notify();
         }
     }
+
+    /// Returns when the consumer started waiting for `NextBatch`, or `None` if it isn't waiting for one
+    pub fn awaiting_next_batch_since(&self) -> Option<Instant> {
+        self.0.borrow().awaiting_next_batch
+    }
+
+    /// Returns true once the consumer that holds the `ConsumerStatusChecker` has been dropped
+    pub fn is_consumer_finished(&self) -> bool {
+        Rc::strong_count(&self.0) == 1
+    }
 }
 
 pub fn create_status_channel() -> (ConsumerStatusSetter, ConsumerStatusChecker) {
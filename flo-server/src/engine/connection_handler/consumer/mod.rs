@@ -2,21 +2,28 @@ pub mod consumer_stream;
 pub mod pending_consume;
 
 use std::io;
+use std::collections::HashMap;
+use std::time::Instant;
 
 use futures::{Stream, Future, Async, Poll};
+use tokio_core::reactor::Timeout;
 
-use event::ActorId;
+use event::{ActorId, FloEventId};
 use protocol::*;
 use engine::connection_handler::ConnectionHandlerResult;
-use engine::connection_handler::connection_state::ConnectionState;
-use engine::event_stream::partition::{PartitionReader, EventFilter};
+use engine::connection_handler::connection_state::{ConnectionState, parse_namespace_filter};
+use engine::connection_handler::producer::operation_trace;
+use engine::event_stream::partition::{PartitionReader, ProducedEvents};
 
 use self::consumer_stream::{Consumer,
+                            ConsumerOptions,
+                            DeliveryRateLimiter,
                             ConsumerStatus,
                             ConsumerStatusSetter,
+                            IdleSignal,
                             create_status_channel};
 
-use self::pending_consume::PendingConsumeOperation;
+use self::pending_consume::{PendingConsumeOperation, StartOptions};
 
 
 #[derive(Debug)]
@@ -28,7 +35,8 @@ struct ActiveConsumer {
 #[derive(Debug)]
 pub struct ConsumerConnectionState {
     pending_consume_operation: Option<PendingConsumeOperation>,
-    consumer_ref: Option<ActiveConsumer>,
+    /// All the cursors that are active on this connection, keyed by the op_id of the message that started them
+    active_consumers: HashMap<u32, ActiveConsumer>,
 }
 
 
@@ -36,57 +44,192 @@ impl ConsumerConnectionState {
     pub fn new() -> ConsumerConnectionState {
         ConsumerConnectionState {
             pending_consume_operation: None,
-            consumer_ref: None,
+            active_consumers: HashMap::new(),
         }
     }
 
     pub fn shutdown(&mut self, connection: &mut ConnectionState) {
-        if let Some(ref mut consumer) = self.consumer_ref.take() {
-            // tell the active consumer to stop sending events
-            consumer.status_setter.set(ConsumerStatus::Stop);
-
-            // tell the partitions to remove their consumer notifiers
-            let connection_id = connection.connection_id;
-            for partition_num in consumer.partitions.iter() {
-                if let Some(partition_ref) = connection.event_stream.get_partition(*partition_num) {
-                    debug!("Sending consumer stop to partition: {} for connection_id: {}", partition_num, connection_id);
-                    partition_ref.stop_consuming(connection_id)
-                }
-            }
+        for (op_id, consumer) in self.active_consumers.drain() {
+            ConsumerConnectionState::stop_consumer(op_id, consumer, connection);
         }
     }
 
+    /// Stops only the cursor that was started by `op_id`, leaving any others on the connection running
     pub fn stop_consuming(&mut self, op_id: u32, connection: &mut ConnectionState) -> ConnectionHandlerResult {
-        self.shutdown(connection);
+        match self.active_consumers.remove(&op_id) {
+            Some(consumer) => ConsumerConnectionState::stop_consumer(op_id, consumer, connection),
+            None => debug!("Ignoring StopConsuming for connection_id: {} since op_id: {} is not an active cursor", connection.connection_id, op_id),
+        }
         connection.send_stream_status(op_id)
     }
 
+    fn stop_consumer(op_id: u32, mut consumer: ActiveConsumer, connection: &mut ConnectionState) {
+        // tell the active consumer to stop sending events
+        consumer.status_setter.set(ConsumerStatus::Stop);
+
+        // tell the partitions to remove its consumer notifiers
+        let connection_id = connection.connection_id;
+        for partition_num in consumer.partitions.iter() {
+            if let Some(partition_ref) = connection.event_stream.get_partition(*partition_num) {
+                debug!("Sending consumer stop to partition: {} for connection_id: {}, op_id: {}", partition_num, connection_id, op_id);
+                partition_ref.stop_consuming(connection_id, op_id, &connection.reactor)
+            }
+        }
+    }
+
     pub fn requires_poll_complete(&self) -> bool {
         self.pending_consume_operation.is_some()
     }
 
+    /// `NextBatch` doesn't say which cursor it's for, so each one resumes the cursor that has been waiting the longest. A
+    /// client sends one for each `EndOfBatch` it receives, so every waiting cursor gets exactly one
     pub fn handle_next_batch(&mut self, connection: &mut ConnectionState) -> ConnectionHandlerResult {
-        if let Some(ref mut active_consumer) = self.consumer_ref {
-            debug!("Setting NextBatch status for consumer for connection_id: {}", connection.connection_id);
-            active_consumer.status_setter.set(ConsumerStatus::NextBatch);
-        } else {
-            warn!("Ignoring NextBatch message for connection_id: {} since no active consumer is in progress", connection.connection_id);
+        self.remove_finished_consumers();
+        let waiting = self.active_consumers.iter_mut().filter_map(|(op_id, consumer)| {
+            consumer.status_setter.awaiting_next_batch_since().map(|since| (since, *op_id, consumer))
+        }).min_by_key(|&(since, op_id, _)| (since, op_id));
+
+        match waiting {
+            Some((_, op_id, active_consumer)) => {
+                debug!("Setting NextBatch status for consumer for connection_id: {}, op_id: {}", connection.connection_id, op_id);
+                active_consumer.status_setter.set(ConsumerStatus::NextBatch);
+            }
+            None => {
+                warn!("Ignoring NextBatch message for connection_id: {} since no active consumer is awaiting one", connection.connection_id);
+            }
         }
         Ok(())
     }
 
+    fn remove_finished_consumers(&mut self) {
+        self.active_consumers.retain(|_, consumer| !consumer.status_setter.is_consumer_finished());
+    }
+
     pub fn handle_start_consuming(&mut self, start: NewConsumerStart, connection: &mut ConnectionState) -> ConnectionHandlerResult {
-        let NewConsumerStart {op_id, version_vector, namespace, max_events} = start;
+        let NewConsumerStart {op_id, version_vector, namespace, max_events, body_prefix_bytes, start_tag, max_delivery_rate, namespace_regex, error_on_empty, unlimited_lifetime, read_consistency, cursor_op_ids, start_at_tail, snapshot, idle_signal_interval, see_own_writes, trace} = start;
+        let trace = if trace {
+            Some(vec![(TraceStage::Received, Instant::now())])
+        } else {
+            None
+        };
+
+        self.remove_finished_consumers();
+        if self.active_consumers.contains_key(&op_id) {
+            // the existing consumer could never be stopped if it were replaced, so a client whose op_ids have wrapped around
+            // must not reuse one that's still active
+            debug!("Rejecting consumer start for connection_id: {} since op_id: {} is already used by an active cursor", connection.connection_id, op_id);
+            return connection.send_to_client(ProtocolMessage::Error(ErrorMessage {
+                op_id: op_id,
+                kind: ErrorKind::InvalidConsumerState,
+                description: format!("op_id: {} is already in use by an active cursor", op_id),
+            }));
+        }
+        if self.active_consumers.len() >= connection.max_cursors_per_connection {
+            debug!("Rejecting consumer start for connection_id: {}, op_id: {} since it already has {} active cursors",
+                   connection.connection_id, op_id, self.active_consumers.len());
+            return connection.send_to_client(ProtocolMessage::Error(ErrorMessage {
+                op_id: op_id,
+                kind: ErrorKind::TooManyCursors,
+                description: format!("Connection already has the maximum of {} active cursors", connection.max_cursors_per_connection),
+            }));
+        }
+        if idle_signal_interval == Some(0) {
+            // an interval of 0 would have the consumer send StreamIdle continuously
+            debug!("Rejecting consumer start for connection_id: {}, op_id: {} since its idle_signal_interval is 0", connection.connection_id, op_id);
+            return connection.send_to_client(ProtocolMessage::Error(ErrorMessage {
+                op_id: op_id,
+                kind: ErrorKind::InvalidConsumerState,
+                description: "idle_signal_interval must be greater than 0".to_owned(),
+            }));
+        }
+
+        // Event counters are assigned in order across all the partitions in a stream, so starting every partition at the
+        // tagged counter gives exactly the events that were produced after the tagged one
+        let version_vector = match start_tag {
+            // The consume operation is sent to each partition right after its counter is read, so any event that's produced
+            // in the meantime has a greater counter and will still be received. An empty partition starts at 0
+            _ if start_at_tail => {
+                connection.event_stream.partitions().iter().map(|partition| {
+                    FloEventId::new(partition.partition_num(), partition.get_highest_event_counter())
+                }).collect()
+            }
+            Some(tag) => {
+                match connection.event_stream.tags().get(&tag) {
+                    Some(tagged) => {
+                        connection.event_stream.partitions().iter().map(|partition| {
+                            FloEventId::new(partition.partition_num(), tagged.event_counter)
+                        }).collect()
+                    }
+                    None => {
+                        let response = connection.no_such_tag(op_id, &tag);
+                        return connection.send_to_client(response);
+                    }
+                }
+            }
+            None => version_vector
+        };
+
+        let unknown_partition = version_vector.iter().map(|id| id.actor).find(|actor| {
+            connection.event_stream.partitions().iter().all(|partition| partition.partition_num() != *actor)
+        });
+        if let Some(actor) = unknown_partition {
+            debug!("Rejecting consumer start for connection_id: {}, op_id: {} since the stream has no partition: {}", connection.connection_id, op_id, actor);
+            let description = format!("Event stream: '{}' has no partition: {}", connection.event_stream.name(), actor);
+            return connection.send_to_client(ProtocolMessage::Error(ErrorMessage {
+                op_id: op_id,
+                kind: ErrorKind::InvalidVersionVector,
+                description: description,
+            }));
+        }
+
+        let max_lifetime = if unlimited_lifetime {
+            None
+        } else {
+            connection.event_stream.max_cursor_lifetime()
+        };
+
         let event_limit = if max_events == CONSUME_UNLIMITED {
             None
         } else {
             Some(max_events)
         };
 
-        match EventFilter::parse(&namespace) {
+        // read before the consume operations are sent, so that every event the snapshot includes has already been written
+        let snapshot_heads = if snapshot {
+            Some(connection.event_stream.partitions().iter().map(|partition| {
+                FloEventId::new(partition.partition_num(), partition.get_highest_event_counter())
+            }).collect::<Vec<FloEventId>>())
+        } else {
+            None
+        };
+
+        // tracking starts once a reader of a partition needs it, so earlier events from this connection can't be told apart
+        let excluded_events = if see_own_writes {
+            Vec::new()
+        } else {
+            let produced = connection.produced_events.get_or_insert_with(ProducedEvents::new);
+            version_vector.iter().map(|id| produced.register_reader(id.actor, id.event_counter)).collect()
+        };
+
+        let filter = parse_namespace_filter(op_id, &namespace, namespace_regex.as_ref().map(|regex| regex.as_str()));
+
+        match filter {
             Ok(filter) => {
                 let connection_id = connection.connection_id;
-                let mut pending_consume = PendingConsumeOperation::new(op_id, event_limit);
+                let options = StartOptions {
+                    max_events: event_limit,
+                    body_prefix_bytes: body_prefix_bytes,
+                    max_delivery_rate: max_delivery_rate,
+                    error_on_empty: error_on_empty,
+                    max_lifetime: max_lifetime,
+                    read_consistency: read_consistency,
+                    cursor_op_ids: cursor_op_ids,
+                    snapshot_heads: snapshot_heads,
+                    idle_signal_interval: idle_signal_interval,
+                    excluded_events: excluded_events,
+                    trace: trace,
+                };
+                let mut pending_consume = PendingConsumeOperation::new(op_id, options);
 
                 for id in version_vector {
                     let start = id.event_counter;
@@ -105,16 +248,13 @@ impl ConsumerConnectionState {
 
                     pending_consume.add_partition(partition, receiver);
                 }
+                if let Some(ref mut stages) = pending_consume.options.trace {
+                    stages.push((TraceStage::Queued, Instant::now()));
+                }
                 self.pending_consume_operation = Some(pending_consume);
                 self.poll_pending_consume(connection)
             }
-            Err(description) => {
-                connection.send_to_client(ProtocolMessage::Error(ErrorMessage {
-                    op_id: op_id,
-                    kind: ErrorKind::InvalidNamespaceGlob,
-                    description: description,
-                }))
-            }
+            Err(error) => connection.send_to_client(error)
         }
     }
 
@@ -137,10 +277,17 @@ impl ConsumerConnectionState {
     }
 
 
-    fn spawn_consumer(&mut self, readers: Vec<PartitionReader>, connection: &mut ConnectionState) -> Poll<(), io::Error> {
+    fn spawn_consumer(&mut self, mut readers: Vec<PartitionReader>, connection: &mut ConnectionState) -> Poll<(), io::Error> {
         let pending = self.pending_consume_operation.take().unwrap();
         let partition_numbers = pending.get_partition_numbers();
-        let PendingConsumeOperation {op_id, task_setter, max_events, ..} = pending;
+        let PendingConsumeOperation {op_id, task_setter, options, ..} = pending;
+        let StartOptions {max_events, body_prefix_bytes, max_delivery_rate, error_on_empty, max_lifetime, read_consistency, cursor_op_ids, snapshot_heads, idle_signal_interval, excluded_events, trace} = options;
+        let opened_at = Instant::now();
+        for excluded in excluded_events {
+            if let Some(reader) = readers.iter_mut().find(|reader| reader.partition_num() == excluded.partition_num()) {
+                reader.exclude(excluded);
+            }
+        }
 
         let batch_size = connection.consume_batch_size;
         let send_result = connection.send_to_client(ProtocolMessage::CursorCreated(CursorInfo {
@@ -152,10 +299,46 @@ impl ConsumerConnectionState {
             return Err(io::Error::new(io::ErrorKind::Other, desc));
         }
 
+        // sent before the consumer is spawned, so that it comes before any of the cursor's events
+        if let Some(mut stages) = trace {
+            stages.push((TraceStage::Opened, opened_at));
+            stages.push((TraceStage::Acked, Instant::now()));
+            let trace = operation_trace(op_id, stages);
+            debug!("Trace of consumer start for connection_id: {}: {:?}", connection.connection_id, trace);
+            connection.send_to_client(ProtocolMessage::Trace(trace)).map_err(|desc| {
+                io::Error::new(io::ErrorKind::Other, desc)
+            })?;
+        }
+
         let (status_setter, status_checker) = create_status_channel();
 
         let connection_id = connection.connection_id;
-        let consumer = Consumer::new(connection_id, batch_size, status_checker, task_setter, readers, op_id, max_events);
+        // every event on a single server is already as durable as it's going to get, so a quorum read is the same as a local one
+        debug!("Starting consumer for connection_id: {}, op_id: {} with read_consistency: {:?}", connection_id, op_id, read_consistency);
+        let rate_limiter = max_delivery_rate.map(|rate| DeliveryRateLimiter::new(rate, connection.reactor.clone()));
+        // stream options are validated to have a positive lifetime, so converting it can't fail
+        let lifetime = match max_lifetime {
+            Some(lifetime) => Some(Timeout::new(lifetime.to_std().unwrap_or_default(), &connection.reactor)?),
+            None => None
+        };
+        let idle_signal = match idle_signal_interval {
+            Some(millis) => {
+                let interval = ::std::time::Duration::from_millis(millis as u64);
+                Some(IdleSignal::new(op_id, interval, connection.event_stream.partitions().to_vec(), &connection.reactor)?)
+            }
+            None => None
+        };
+        let options = ConsumerOptions {
+            body_prefix_bytes: body_prefix_bytes,
+            rate_limiter: rate_limiter,
+            error_on_empty: error_on_empty,
+            lifetime: lifetime,
+            cursor_op_ids: cursor_op_ids,
+            snapshot_heads: snapshot_heads,
+            idle_signal: idle_signal,
+            replay_limiter: connection.replay_limiter.clone(),
+        };
+        let consumer = Consumer::new(connection_id, batch_size, status_checker, task_setter, readers, op_id, max_events, options, connection.event_stream.metrics().bytes_consumed.clone());
         let future = consumer.forward(connection.client_sender.clone()).map_err(move |err| {
             error!("Consumer failed for connection_id: {}, op_id: {}, err: {:?}", connection_id, op_id, err);
             ()
@@ -168,7 +351,7 @@ impl ConsumerConnectionState {
             status_setter: status_setter,
             partitions: partition_numbers,
         };
-        self.consumer_ref = Some(active_consumer);
+        self.active_consumers.insert(op_id, active_consumer);
         connection.reactor.spawn(future);
 
         Ok(Async::Ready(()))
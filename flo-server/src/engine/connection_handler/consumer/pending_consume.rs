@@ -2,11 +2,14 @@
 use std::io;
 
 use futures::{Future, Async, Poll};
+use chrono::Duration;
 
-use event::ActorId;
+use event::{ActorId, FloEventId};
+use protocol::ReadConsistency;
 use engine::ConnectionId;
-use engine::event_stream::partition::{ConsumeResponseReceiver, ConsumerNotifier, PartitionReader};
+use engine::event_stream::partition::{ConsumeResponseReceiver, ConsumerNotifier, PartitionReader, ExcludedEvents};
 use engine::connection_handler::consumer::consumer_stream::{ConsumerTaskSetter};
+use engine::connection_handler::producer::StageTimes;
 
 
 #[derive(Debug)]
@@ -32,28 +35,54 @@ impl PendingConsumer {
 }
 
 
+/// Everything about how a cursor should behave, taken from its `NewConsumerStart` once that's been validated
+#[derive(Debug)]
+pub struct StartOptions {
+    pub max_events: Option<u64>,
+    pub body_prefix_bytes: Option<u32>,
+    pub max_delivery_rate: Option<u32>,
+    pub error_on_empty: bool,
+    /// How long the cursor may stay open before it's stopped by the server, or `None` if there's no limit
+    pub max_lifetime: Option<Duration>,
+    /// The consistency requested by the consumer. There's no cluster yet, so this is only recorded, and every consumer
+    /// reads all the events that are known locally
+    pub read_consistency: ReadConsistency,
+    /// Whether the messages sent by the consumer should be wrapped in a `CursorMessage` with its op_id
+    pub cursor_op_ids: bool,
+    /// For snapshot consumers, the newest event in each partition when the cursor was started, which is the last one
+    /// that it may receive from that partition
+    pub snapshot_heads: Option<Vec<FloEventId>>,
+    /// How many milliseconds a caught up consumer may go without any events before it's sent a `StreamIdle`
+    pub idle_signal_interval: Option<u32>,
+    /// for consumers that skip the events that were produced on their own connection, the registration of each of the
+    /// consumer's readers with the connection's `ProducedEvents`
+    pub excluded_events: Vec<ExcludedEvents>,
+    /// When each stage of starting the cursor was reached, for consumers that set `trace`
+    pub trace: Option<StageTimes>,
+}
+
 #[derive(Debug)]
 pub struct PendingConsumeOperation {
     pub op_id: u32,
     pub complete: bool,
     pub task_setter: ConsumerTaskSetter,
-    pub max_events: Option<u64>,
+    pub options: StartOptions,
     pub pending: Vec<PendingConsumer>,
 }
 
 impl PendingConsumeOperation {
-    pub fn new(op_id: u32, max_events: Option<u64>) -> PendingConsumeOperation {
+    pub fn new(op_id: u32, options: StartOptions) -> PendingConsumeOperation {
         PendingConsumeOperation {
             op_id,
             task_setter: ConsumerTaskSetter::create(),
-            max_events,
+            options,
             complete: false,
             pending: Vec::new(),
         }
     }
 
     pub fn create_notifier(&self, connection_id: ConnectionId) -> Box<ConsumerNotifier> {
-        self.task_setter.create_notifier(connection_id)
+        self.task_setter.create_notifier(connection_id, self.op_id)
     }
 
     pub fn add_partition(&mut self, partition: ActorId, receiver: ConsumeResponseReceiver) {
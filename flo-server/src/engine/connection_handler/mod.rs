@@ -15,6 +15,8 @@ use self::connection_state::ConnectionState;
 use self::consumer::ConsumerConnectionState;
 use self::producer::ProducerConnectionState;
 
+pub use self::consumer::consumer_stream::ReplayLimiter;
+
 
 pub struct ConnectionHandler {
     common_state: ConnectionState,
@@ -34,6 +36,29 @@ impl ConnectionHandler {
         }
     }
 
+    /// Sets the maximum number of cursors that may be active at once on this connection. Attempts to start another
+    /// cursor beyond the limit will receive a `TooManyCursors` error
+    pub fn with_max_cursors_per_connection(mut self, max_cursors: usize) -> ConnectionHandler {
+        self.common_state.max_cursors_per_connection = max_cursors;
+        self
+    }
+
+    /// Sets the maximum total size of event data that may be in flight on this connection, having been received but not
+    /// yet persisted. While a produce is in flight, no further messages are processed, so a producer that pipelines many
+    /// events is held back by TCP flow control. A produce whose data, together with the produce that's in flight, would
+    /// exceed the limit is rejected with a `ServerBusy` error as soon as it's received.
+    pub fn with_max_in_flight_produce_bytes(mut self, max_bytes: usize) -> ConnectionHandler {
+        self.common_state.max_in_flight_produce_bytes = max_bytes;
+        self
+    }
+
+    /// Sets the limiter that's shared by every connection to bound how many consumers may be replaying older segments at
+    /// once. Consumers that are reading recent events don't need a permit
+    pub fn with_replay_limiter(mut self, limiter: ReplayLimiter) -> ConnectionHandler {
+        self.common_state.replay_limiter = Some(limiter);
+        self
+    }
+
     pub fn can_process(&self, _message: &ReceivedProtocolMessage) -> bool {
         !self.producer_state.requires_poll_complete() && !self.consumer_state.requires_poll_complete()
     }
@@ -42,6 +67,7 @@ impl ConnectionHandler {
         trace!("client: {:?}, received message: {:?}", self.common_state, message);
 
         let ConnectionHandler{ref mut common_state, ref mut consumer_state, ref mut producer_state } = *self;
+        common_state.refresh_event_stream();
 
         match message {
             ProtocolMessage::SetEventStream(SetEventStream{op_id, name}) => {
@@ -62,10 +88,73 @@ impl ConnectionHandler {
             ProtocolMessage::StopConsuming(op_id) => {
                 consumer_state.stop_consuming(op_id, common_state)
             }
-            _ => unimplemented!()
+            ProtocolMessage::TruncateStream(truncate) => {
+                common_state.truncate_stream(truncate)
+            }
+            ProtocolMessage::ListStreams(op_id) => {
+                common_state.list_streams(op_id)
+            }
+            ProtocolMessage::PauseWrites(op_id) => {
+                common_state.engine.pause_writes();
+                common_state.send_stream_status(op_id)
+            }
+            ProtocolMessage::ResumeWrites(op_id) => {
+                common_state.engine.resume_writes();
+                common_state.send_stream_status(op_id)
+            }
+            ProtocolMessage::GetCapabilities(op_id) => {
+                common_state.send_capabilities(op_id)
+            }
+            ProtocolMessage::ListTags(op_id) => {
+                common_state.send_tag_list(op_id)
+            }
+            ProtocolMessage::GetTag(get_tag) => {
+                common_state.send_tag(get_tag)
+            }
+            ProtocolMessage::VerifyStream(verify) => {
+                common_state.verify_stream(verify)
+            }
+            ProtocolMessage::GetAncestry(get_ancestry) => {
+                common_state.send_ancestry(get_ancestry)
+            }
+            ProtocolMessage::CountEvents(count) => {
+                common_state.count_events(count)
+            }
+            ProtocolMessage::GetConsumerLag(get_lag) => {
+                common_state.consumer_lag(get_lag)
+            }
+            ProtocolMessage::Heartbeat(heartbeat) => {
+                common_state.send_to_client(ProtocolMessage::Heartbeat(heartbeat))
+            }
+            ProtocolMessage::GetStreamStatus(get_status) => {
+                common_state.send_named_stream_status(get_status)
+            }
+            other @ _ => {
+                // the rest are only ever sent by the server, so a client that sends one can't be trusted to follow the protocol
+                warn!("Closing connection_id: {} since it sent an unexpected message: {:?}", common_state.connection_id, other);
+                common_state.send_to_client(ProtocolMessage::Error(ErrorMessage {
+                    op_id: other.get_op_id(),
+                    kind: ErrorKind::UnexpectedMessage,
+                    description: "Clients may not send this type of message".to_owned(),
+                }))?;
+                Err(format!("Received unexpected message from connection_id: {}", common_state.connection_id))
+            }
         }
     }
 
+    /// Called with a message that can't be processed yet. A produce that would put the connection over its in flight limit
+    /// is rejected now, instead of waiting for the produce that's ahead of it. Anything else waits
+    fn hold_back(&mut self, item: ReceivedProtocolMessage) -> StartSend<ReceivedProtocolMessage, io::Error> {
+        let ConnectionHandler {ref mut common_state, ref producer_state, ..} = *self;
+        match item {
+            ProtocolMessage::ProduceEvent(ref produce) if producer_state.exceeds_in_flight_limit(produce, common_state) => {
+                producer_state.reject_over_in_flight_limit(produce, common_state).map(|()| AsyncSink::Ready).map_err(|err_string| {
+                    io::Error::new(io::ErrorKind::Other, err_string)
+                })
+            }
+            other @ _ => Ok(AsyncSink::NotReady(other))
+        }
+    }
 }
 
 
@@ -76,7 +165,12 @@ impl Sink for ConnectionHandler {
 
     fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
         if !self.can_process(&item) {
-            return Ok(AsyncSink::NotReady(item));
+            // Try to finish the pending operation first. If it's still not done, then polling it will have ensured that
+            // this task gets notified once it is, so that the message isn't stuck waiting forever
+            self.poll_complete()?;
+            if !self.can_process(&item) {
+                return self.hold_back(item);
+            }
         }
 
         self.handle_incoming_message(item).map(|()| {
@@ -127,11 +221,11 @@ mod test {
     use tokio_core::reactor::Core;
 
     use super::*;
-    use event::ActorId;
+    use event::{ActorId, FloEventId, time};
     use engine::{SYSTEM_STREAM_NAME, system_stream_name};
     use engine::event_stream::EventStreamRef;
     use engine::event_stream::partition::*;
-    use engine::ClientReceiver;
+    use engine::{ClientReceiver, create_client_channels};
     use atomics::{AtomicCounterWriter, AtomicBoolWriter};
 
     struct Fixture {
@@ -146,7 +240,7 @@ mod test {
         fn create() -> (ConnectionHandler, Fixture) {
             let reactor = Core::new().unwrap();
 
-            let (client_sender, client_rx) = ::futures::sync::mpsc::unbounded();
+            let (client_sender, client_rx) = create_client_channels();
             let counter_writer = AtomicCounterWriter::zero();
             let primary = AtomicBoolWriter::with_value(true);
 
@@ -154,6 +248,7 @@ mod test {
             let part_ref = PartitionRef::new(system_stream_name(),
                                              1,
                                              counter_writer.reader(),
+                                             AtomicCounterWriter::zero().reader(),
                                              primary.reader(),
                                              tx);
             let stream = EventStreamRef::new(system_stream_name(), vec![part_ref]);
@@ -192,6 +287,7 @@ mod test {
                 let part_ref = PartitionRef::new(name.to_owned(),
                                                  partition_num,
                                                  counter_writer.reader(),
+                                                 AtomicCounterWriter::zero().reader(),
                                                  primary.reader(),
                                                  tx);
                 partition_refs.push(part_ref);
@@ -296,5 +392,304 @@ mod test {
 
         fixture.assert_sent_to_client(ProtocolMessage::Error(expected));
     }
-}
 
+    #[test]
+    fn get_stream_status_responds_with_status_of_named_stream_without_changing_the_current_stream() {
+        let (mut subject, mut fixture) = Fixture::create();
+        fixture.add_new_stream("foo", 2);
+
+        let get_status = GetStreamStatus {
+            op_id: 8,
+            name: "foo".to_owned(),
+        };
+        subject.handle_incoming_message(ProtocolMessage::GetStreamStatus(get_status)).expect("failed to handle message");
+        assert_eq!(SYSTEM_STREAM_NAME, subject.common_state.event_stream.name());
+
+        let expected = EventStreamStatus {
+            op_id: 8,
+            name: "foo".to_owned(),
+            partitions: vec![
+                PartitionStatus {
+                    partition_num: 1,
+                    head: 0,
+                    primary: true,
+                },
+                PartitionStatus {
+                    partition_num: 2,
+                    head: 0,
+                    primary: true,
+                },
+            ],
+        };
+        fixture.assert_sent_to_client(ProtocolMessage::StreamStatus(expected));
+    }
+
+    #[test]
+    fn get_stream_status_sends_error_message_when_named_stream_does_not_exist() {
+        let (mut subject, mut fixture) = Fixture::create();
+
+        let get_status = GetStreamStatus {
+            op_id: 9,
+            name: "foo".to_owned(),
+        };
+        subject.handle_incoming_message(ProtocolMessage::GetStreamStatus(get_status)).expect("failed to handle message");
+
+        fixture.assert_sent_to_client(ProtocolMessage::Error(ErrorMessage {
+            op_id: 9,
+            kind: ErrorKind::NoSuchStream,
+            description: "Event stream: 'foo' does not exist".to_owned()
+        }));
+    }
+
+    #[test]
+    fn get_capabilities_responds_with_every_supported_feature() {
+        let (mut subject, mut fixture) = Fixture::create();
+
+        subject.handle_incoming_message(ProtocolMessage::GetCapabilities(3)).expect("failed to handle message");
+
+        let expected = Capabilities {
+            op_id: 3,
+            flags: features::EVENT_TTL | features::BODY_PREFIX | features::PAUSE_WRITES | features::LIST_STREAMS | features::STREAM_TAGS | features::VERIFY_STREAM | features::CBOR_FRAMING | features::DELIVERY_RATE | features::NAMESPACE_REGEX | features::ANCESTRY | features::ERROR_ON_EMPTY | features::COUNT_EVENTS | features::CURSOR_LIFETIME | features::HEARTBEAT | features::COMPRESSION | features::READ_CONSISTENCY | features::STREAM_STATUS | features::CURSOR_OP_IDS | features::START_AT_TAIL | features::OPERATION_TRACE | features::CONSUMER_LAG | features::SNAPSHOT | features::IDLE_SIGNAL | features::SEE_OWN_WRITES,
+            features: vec!["event_ttl".to_owned(), "body_prefix".to_owned(), "pause_writes".to_owned(), "list_streams".to_owned(), "stream_tags".to_owned(), "verify_stream".to_owned(), "cbor_framing".to_owned(), "delivery_rate".to_owned(), "namespace_regex".to_owned(), "ancestry".to_owned(), "error_on_empty".to_owned(), "count_events".to_owned(), "cursor_lifetime".to_owned(), "heartbeat".to_owned(), "compression".to_owned(), "read_consistency".to_owned(), "stream_status".to_owned(), "cursor_op_ids".to_owned(), "start_at_tail".to_owned(), "operation_trace".to_owned(), "consumer_lag".to_owned(), "snapshot".to_owned(), "idle_signal".to_owned(), "see_own_writes".to_owned()],
+        };
+        fixture.assert_sent_to_client(ProtocolMessage::Capabilities(expected));
+    }
+
+    #[test]
+    fn heartbeat_is_echoed_back_to_the_client() {
+        let (mut subject, mut fixture) = Fixture::create();
+
+        let heartbeat = Heartbeat {
+            op_id: 5,
+            timestamp: time::from_millis_since_epoch(1_500_000_000_000),
+        };
+        subject.handle_incoming_message(ProtocolMessage::Heartbeat(heartbeat.clone())).expect("failed to handle message");
+        fixture.assert_sent_to_client(ProtocolMessage::Heartbeat(heartbeat));
+    }
+
+    #[test]
+    fn produce_sends_error_without_blocking_when_partition_queue_is_full() {
+        let (mut subject, mut fixture) = Fixture::create();
+
+        // nothing is reading from the partition receiver, so this simulates a partition that's stuck on a slow write
+        let mut partition = fixture.engine.get_default_stream().get_partition(1).unwrap().clone();
+        for _ in 0..MAX_QUEUED_OPERATIONS {
+            partition.tick().expect("failed to fill partition queue");
+        }
+
+        let produce = ProduceEvent {
+            op_id: 5,
+            partition: 1,
+            namespace: "/foo".to_owned(),
+            parent_id: None,
+            ttl: None,
+            compression: Compression::None,
+            trace: false,
+            data: vec![1, 2, 3],
+        };
+        subject.handle_incoming_message(ProtocolMessage::ProduceEvent(produce)).expect("failed to handle produce");
+        assert!(subject.can_process(&ProtocolMessage::NextBatch));
+
+        let expected = ErrorMessage {
+            op_id: 5,
+            kind: ErrorKind::PartitionBusy,
+            description: "Partition is busy, try again later".to_owned(),
+        };
+        fixture.assert_sent_to_client(ProtocolMessage::Error(expected));
+
+        // other requests are still handled promptly
+        let set_stream = SetEventStream {
+            op_id: 6,
+            name: "nope".to_owned()
+        };
+        subject.handle_incoming_message(ProtocolMessage::SetEventStream(set_stream)).expect("failed to handle message");
+        let expected = ErrorMessage {
+            op_id: 6,
+            kind: ErrorKind::NoSuchStream,
+            description: "Event stream: 'nope' does not exist".to_owned()
+        };
+        fixture.assert_sent_to_client(ProtocolMessage::Error(expected));
+    }
+
+    #[test]
+    fn produces_are_rejected_while_writes_are_paused_and_accepted_again_after_resuming() {
+        let (mut subject, mut fixture) = Fixture::create();
+        let produce = |op_id: u32| {
+            ProtocolMessage::ProduceEvent(ProduceEvent {
+                op_id: op_id,
+                partition: 1,
+                namespace: "/foo".to_owned(),
+                parent_id: None,
+                ttl: None,
+                compression: Compression::None,
+                trace: false,
+                data: vec![1, 2, 3],
+            })
+        };
+        let stream_status = |op_id: u32| {
+            ProtocolMessage::StreamStatus(EventStreamStatus {
+                op_id: op_id,
+                name: system_stream_name(),
+                partitions: vec![PartitionStatus {
+                    partition_num: 1,
+                    head: 0,
+                    primary: true,
+                }],
+            })
+        };
+
+        subject.handle_incoming_message(ProtocolMessage::PauseWrites(1)).expect("failed to handle pause");
+        fixture.assert_sent_to_client(stream_status(1));
+        assert!(fixture.engine.writes_paused());
+
+        subject.handle_incoming_message(produce(2)).expect("failed to handle produce");
+        fixture.assert_sent_to_client(ProtocolMessage::Error(ErrorMessage {
+            op_id: 2,
+            kind: ErrorKind::ServerBusy,
+            description: "Writes are paused, try again later".to_owned(),
+        }));
+        assert!(subject.can_process(&ProtocolMessage::NextBatch));
+        let partition_receiver = fixture.partition_receivers.get(&(system_stream_name(), 1)).unwrap();
+        assert!(partition_receiver.try_recv().is_err());
+
+        subject.handle_incoming_message(ProtocolMessage::ResumeWrites(3)).expect("failed to handle resume");
+        fixture.assert_sent_to_client(stream_status(3));
+        assert!(!fixture.engine.writes_paused());
+
+        subject.handle_incoming_message(produce(4)).expect("failed to handle produce");
+        let operation = fixture.message_sent_to_partition(SYSTEM_STREAM_NAME, 1);
+        match operation.op_type {
+            OpType::Produce(produce_op) => {
+                assert_eq!(4, produce_op.op_id);
+                produce_op.client.send(Ok(ProduceComplete { last_id: FloEventId::new(1, 1), written_at: ::std::time::Instant::now() })).unwrap();
+            }
+            other @ _ => panic!("expected produce operation, got: {:?}", other),
+        }
+        fixture.reactor.run(::futures::future::poll_fn(|| subject.poll_complete())).expect("failed to complete produce");
+        fixture.assert_sent_to_client(ProtocolMessage::AckEvent(EventAck {
+            op_id: 4,
+            event_id: FloEventId::new(1, 1),
+        }));
+    }
+
+    #[test]
+    fn bytes_produced_are_counted_only_for_produces_that_succeed() {
+        let (mut subject, mut fixture) = Fixture::create();
+        let produce = |op_id: u32| {
+            ProtocolMessage::ProduceEvent(ProduceEvent {
+                op_id: op_id,
+                partition: 1,
+                namespace: "/foo".to_owned(),
+                parent_id: None,
+                ttl: None,
+                compression: Compression::None,
+                trace: false,
+                data: vec![1, 2, 3, 4, 5],
+            })
+        };
+
+        subject.handle_incoming_message(produce(1)).expect("failed to handle produce");
+        match fixture.message_sent_to_partition(SYSTEM_STREAM_NAME, 1).op_type {
+            OpType::Produce(produce_op) => {
+                produce_op.client.send(Ok(ProduceComplete { last_id: FloEventId::new(1, 1), written_at: ::std::time::Instant::now() })).unwrap();
+            }
+            other @ _ => panic!("expected produce operation, got: {:?}", other),
+        }
+        fixture.reactor.run(::futures::future::poll_fn(|| subject.poll_complete())).expect("failed to complete produce");
+
+        subject.handle_incoming_message(produce(2)).expect("failed to handle produce");
+        match fixture.message_sent_to_partition(SYSTEM_STREAM_NAME, 1).op_type {
+            OpType::Produce(produce_op) => {
+                let io_err = ::std::io::Error::new(::std::io::ErrorKind::Other, "failed to write event");
+                produce_op.client.send(Err(io_err)).unwrap();
+            }
+            other @ _ => panic!("expected produce operation, got: {:?}", other),
+        }
+        fixture.reactor.run(::futures::future::poll_fn(|| subject.poll_complete())).expect("failed to complete produce");
+
+        let metrics = fixture.engine.stream_metrics();
+        assert_eq!(1, metrics.len());
+        assert_eq!(SYSTEM_STREAM_NAME, &metrics[0].0);
+        assert_eq!(5, metrics[0].1.bytes_produced.get());
+        assert_eq!(0, metrics[0].1.bytes_consumed.get());
+    }
+
+    #[test]
+    fn compressed_produce_is_decompressed_before_it_is_sent_to_the_partition() {
+        let (mut subject, mut fixture) = Fixture::create();
+        let original_data = b"compress me ".iter().cycle().take(4096).cloned().collect::<Vec<u8>>();
+        let mut produce = ProduceEvent {
+            op_id: 5,
+            partition: 1,
+            namespace: "/foo".to_owned(),
+            parent_id: None,
+            ttl: None,
+            compression: Compression::None,
+            trace: false,
+            data: original_data.clone(),
+        };
+        produce.compress_gzip().expect("failed to compress data");
+
+        subject.handle_incoming_message(ProtocolMessage::ProduceEvent(produce)).expect("failed to handle produce");
+        let operation = fixture.message_sent_to_partition(SYSTEM_STREAM_NAME, 1);
+        match operation.op_type {
+            OpType::Produce(produce_op) => {
+                assert_eq!(1, produce_op.events.len());
+                assert_eq!(Compression::None, produce_op.events[0].compression);
+                assert_eq!(original_data, produce_op.events[0].data);
+            }
+            other @ _ => panic!("expected produce operation, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn produce_with_invalid_compressed_data_is_rejected() {
+        let (mut subject, mut fixture) = Fixture::create();
+        let produce = ProduceEvent {
+            op_id: 5,
+            partition: 1,
+            namespace: "/foo".to_owned(),
+            parent_id: None,
+            ttl: None,
+            compression: Compression::Gzip,
+            trace: false,
+            data: vec![1, 2, 3],
+        };
+
+        let decompress_err = produce.clone().decompress(::engine::DEFAULT_MAX_IN_FLIGHT_PRODUCE_BYTES).unwrap_err();
+
+        subject.handle_incoming_message(ProtocolMessage::ProduceEvent(produce)).expect("failed to handle produce");
+        fixture.assert_sent_to_client(ProtocolMessage::Error(ErrorMessage {
+            op_id: 5,
+            kind: ErrorKind::InvalidEventData,
+            description: format!("Failed to decompress event data: {}", decompress_err),
+        }));
+        let partition_receiver = fixture.partition_receivers.get(&(system_stream_name(), 1)).unwrap();
+        assert!(partition_receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn produce_that_decompresses_to_more_than_the_in_flight_limit_is_rejected() {
+        let (subject, mut fixture) = Fixture::create();
+        let mut subject = subject.with_max_in_flight_produce_bytes(1000);
+        let mut produce = ProduceEvent {
+            op_id: 5,
+            namespace: "/foo".to_owned(),
+            data: vec![0; 1001],
+            ..Default::default()
+        };
+        produce.compress_gzip().expect("failed to compress data");
+        assert!(produce.data.len() < 1000);
+
+        let decompress_err = produce.clone().decompress(1000).unwrap_err();
+
+        subject.handle_incoming_message(ProtocolMessage::ProduceEvent(produce)).expect("failed to handle produce");
+        fixture.assert_sent_to_client(ProtocolMessage::Error(ErrorMessage {
+            op_id: 5,
+            kind: ErrorKind::InvalidEventData,
+            description: format!("Failed to decompress event data: {}", decompress_err),
+        }));
+        let partition_receiver = fixture.partition_receivers.get(&(system_stream_name(), 1)).unwrap();
+        assert!(partition_receiver.try_recv().is_err());
+    }
+}
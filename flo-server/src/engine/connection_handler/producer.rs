@@ -1,70 +1,222 @@
 use std::io;
 use std::error::Error;
+use std::time::Instant;
 
 use protocol::*;
 use futures::{Future, Poll, Async};
 
-use engine::event_stream::partition::{ProduceResponseReceiver};
+use event::{FloEvent, FloEventId};
+use engine::event_stream::partition::{ProduceResponseReceiver, ConsumeResponseReceiver, EventFilter};
 use engine::ConnectionHandlerResult;
 use engine::connection_handler::connection_state::ConnectionState;
 
 
+/// Tracks the produce operation that's in progress for a connection. Only one produce may be in flight at a time, and the
+/// connection will not process any further messages until it's been acknowledged. This is what guarantees that events
+/// produced on a single connection get strictly increasing event counters in the order they were submitted, no matter
+/// which namespaces or partitions they were produced to. It also bounds the amount of event data that's buffered for a
+/// connection, since the next message isn't read until the current produce is done. A produce that arrives while another
+/// is in flight is rejected right away if the data of both would exceed the connection's in flight limit.
 #[derive(Debug)]
 pub struct ProducerConnectionState {
-    produce_operation: Option<(u32, ProduceResponseReceiver)>,
+    /// the op_id and size of the event data for the produce that's currently being persisted
+    produce_operation: Option<(u32, usize, Option<StageTimes>, ProduceResponseReceiver)>,
+    /// present while checking that the parent of an event exists, before the event is sent to its partition
+    parent_check: Option<(ProduceEvent, Option<StageTimes>, ConsumeResponseReceiver)>,
 }
 
+/// When each stage was reached, for a produce or consumer start that set `trace`
+pub type StageTimes = Vec<(TraceStage, Instant)>;
+
 
 impl ProducerConnectionState {
     pub fn new() -> ProducerConnectionState {
         ProducerConnectionState {
             produce_operation: None,
+            parent_check: None,
         }
     }
 
     pub fn requires_poll_complete(&self) -> bool {
-        self.produce_operation.is_some()
+        self.produce_operation.is_some() || self.parent_check.is_some()
+    }
+
+    /// Returns the total size of event data that's been accepted, but not yet acknowledged
+    pub fn in_flight_bytes(&self) -> usize {
+        let checking = self.parent_check.as_ref().map(|&(ref produce, _, _)| produce.data.len()).unwrap_or(0);
+        let producing = self.produce_operation.as_ref().map(|&(_, bytes, _, _)| bytes).unwrap_or(0);
+        checking + producing
+    }
+
+    /// Returns true if accepting the produce would put more than the connection's limit of event data in flight
+    pub fn exceeds_in_flight_limit(&self, produce: &ProduceEvent, common_state: &ConnectionState) -> bool {
+        self.in_flight_bytes() + produce.data.len() > common_state.max_in_flight_produce_bytes
+    }
+
+    /// Responds to a produce that `exceeds_in_flight_limit` with a `ServerBusy` error
+    pub fn reject_over_in_flight_limit(&self, produce: &ProduceEvent, common_state: &mut ConnectionState) -> ConnectionHandlerResult {
+        let max_bytes = common_state.max_in_flight_produce_bytes;
+        warn!("Rejecting produce for connection_id: {}, op_id: {} because {} bytes of event data would exceed the limit of {} bytes in flight",
+              common_state.connection_id, produce.op_id, produce.data.len(), max_bytes);
+        common_state.send_to_client(ProtocolMessage::Error(ErrorMessage {
+            op_id: produce.op_id,
+            kind: ErrorKind::ServerBusy,
+            description: format!("Event data exceeds the limit of {} bytes in flight per connection", max_bytes),
+        }))
     }
 
 
-    pub fn handle_produce(&mut self, produce: ProduceEvent, common_state: &mut ConnectionState) -> ConnectionHandlerResult {
+    pub fn handle_produce(&mut self, mut produce: ProduceEvent, common_state: &mut ConnectionState) -> ConnectionHandlerResult {
         let op_id = produce.op_id;
+        let trace = if produce.trace {
+            Some(vec![(TraceStage::Received, Instant::now())])
+        } else {
+            None
+        };
         let connection_id = common_state.connection_id;
 
-        let receiver = {
-            let partition = common_state.event_stream.get_partition(produce.partition).unwrap();
-            partition.produce(connection_id, op_id, vec![produce]).map_err(|err| {
-                format!("Failed to send operation: {:?}", err.0)
-            })?
+        if common_state.engine.writes_paused() {
+            debug!("Rejecting produce for connection_id: {}, op_id: {} because writes are paused", connection_id, op_id);
+            return common_state.send_to_client(ProtocolMessage::Error(ErrorMessage {
+                op_id: op_id,
+                kind: ErrorKind::ServerBusy,
+                description: "Writes are paused, try again later".to_owned(),
+            }));
+        }
+
+        if self.exceeds_in_flight_limit(&produce, common_state) {
+            return self.reject_over_in_flight_limit(&produce, common_state);
+        }
+        let max_bytes = common_state.max_in_flight_produce_bytes;
+
+        // events are always persisted uncompressed, so that consumers don't need to know how they were produced. The
+        // uncompressed data has to fit within the same in flight limit as the compressed data that was just checked
+        if let Err(io_err) = produce.decompress(max_bytes - self.in_flight_bytes()) {
+            warn!("Rejecting produce for connection_id: {}, op_id: {} because the {:?} data could not be decompressed: {}",
+                  connection_id, op_id, produce.compression, io_err);
+            return common_state.send_to_client(ProtocolMessage::Error(ErrorMessage {
+                op_id: op_id,
+                kind: ErrorKind::InvalidEventData,
+                description: format!("Failed to decompress event data: {}", io_err),
+            }));
+        }
+
+        match produce.parent_id {
+            Some(parent) if common_state.event_stream.validates_parent() => {
+                self.start_parent_check(produce, trace, parent, common_state)
+            }
+            _ => self.send_produce(produce, trace, common_state)
+        }
+    }
+
+    /// Starts reading the parent event from its partition. The produce is only sent once the parent is known to exist
+    fn start_parent_check(&mut self, produce: ProduceEvent, trace: Option<StageTimes>, parent: FloEventId, common_state: &mut ConnectionState) -> ConnectionHandlerResult {
+        let op_id = produce.op_id;
+        let connection_id = common_state.connection_id;
+
+        let result = if parent.event_counter == 0 {
+            None
+        } else {
+            common_state.event_stream.partitions().iter().find(|p| p.partition_num() == parent.actor).map(|partition| {
+                partition.read(connection_id, EventFilter::All, parent.event_counter - 1)
+            })
         };
 
-        self.produce_operation = Some((op_id, receiver));
+        match result {
+            Some(Ok(receiver)) => {
+                self.parent_check = Some((produce, trace, receiver));
+                Ok(())
+            }
+            Some(Err(ref err)) if err.is_full() => {
+                warn!("Rejecting produce for connection_id: {}, op_id: {} because the parent's partition is falling behind", connection_id, op_id);
+                common_state.send_to_client(ProtocolMessage::Error(ErrorMessage {
+                    op_id: op_id,
+                    kind: ErrorKind::PartitionBusy,
+                    description: "Partition is busy, try again later".to_owned(),
+                }))
+            }
+            Some(Err(err)) => {
+                Err(format!("Failed to send operation: {:?}", err.into_operation()))
+            }
+            None => send_invalid_parent(op_id, parent, common_state)
+        }
+    }
+
+    fn send_produce(&mut self, produce: ProduceEvent, trace: Option<StageTimes>, common_state: &mut ConnectionState) -> ConnectionHandlerResult {
+        let op_id = produce.op_id;
+        let connection_id = common_state.connection_id;
+
+        let data_len = produce.data.len();
+        // taken before sending, since the partition may write the event before this thread gets to run again
+        let queued_at = Instant::now();
+        // once every consumer that excluded this connection's events has stopped, there's no need to keep tracking them
+        if common_state.produced_events.as_ref().map(|produced| !produced.has_readers()).unwrap_or(false) {
+            common_state.produced_events = None;
+        }
+        let result = {
+            let partition = common_state.event_stream.get_partition(produce.partition).unwrap();
+            match common_state.produced_events {
+                Some(ref produced) => partition.produce_tracked(connection_id, op_id, vec![produce], produced.clone()),
+                None => partition.produce(connection_id, op_id, vec![produce]),
+            }
+        };
 
-        Ok(())
+        match result {
+            Ok(receiver) => {
+                let trace = trace.map(|mut stages| {
+                    stages.push((TraceStage::Queued, queued_at));
+                    stages
+                });
+                self.produce_operation = Some((op_id, data_len, trace, receiver));
+                Ok(())
+            }
+            Err(ref err) if err.is_full() => {
+                warn!("Rejecting produce for connection_id: {}, op_id: {} because the partition is falling behind", connection_id, op_id);
+                common_state.send_to_client(ProtocolMessage::Error(ErrorMessage {
+                    op_id: op_id,
+                    kind: ErrorKind::PartitionBusy,
+                    description: "Partition is busy, try again later".to_owned(),
+                }))
+            }
+            Err(err) => {
+                Err(format!("Failed to send operation: {:?}", err.into_operation()))
+            }
+        }
     }
 
 
     pub fn poll_produce_complete(&mut self, common_state: &mut ConnectionState) -> Poll<(), io::Error> {
-        let response = match self.produce_operation {
-            Some((op_id, ref mut pending)) => {
+        if self.parent_check.is_some() {
+            try_ready!(self.poll_parent_check(common_state));
+        }
+
+        let (response, trace) = match self.produce_operation {
+            Some((op_id, data_len, ref mut trace, ref mut pending)) => {
                 let result = try_ready!(pending.poll().map_err(|recv_err| {
                     error!("Failed to poll produce operation for client: op_id: {}: {:?}", op_id, recv_err);
                     io::Error::new(io::ErrorKind::Other, "failed to poll produce operation")
                 }));
 
                 match result {
-                    Ok(id) => {
-                        ProtocolMessage::AckEvent(EventAck{
+                    Ok(complete) => {
+                        common_state.event_stream.metrics().bytes_produced.add(data_len);
+                        let ack = ProtocolMessage::AckEvent(EventAck{
                             op_id: op_id,
-                            event_id: id,
-                        })
+                            event_id: complete.last_id,
+                        });
+                        let trace = trace.take().map(|mut stages| {
+                            stages.push((TraceStage::Written, complete.written_at));
+                            stages
+                        });
+                        (ack, trace)
                     }
                     Err(io_err) => {
-                        ProtocolMessage::Error(ErrorMessage {
+                        let error = ProtocolMessage::Error(ErrorMessage {
                             op_id: op_id,
                             kind: ErrorKind::StorageEngineError,
                             description: format!("Persistence Error: {}", io_err.description()),
-                        })
+                        });
+                        (error, None)
                     }
                 }
             },
@@ -73,10 +225,77 @@ impl ProducerConnectionState {
 
         self.produce_operation = None;
 
+        let op_id = response.get_op_id();
         common_state.send_to_client(response).map_err(|e| {
             io::Error::new(io::ErrorKind::Other, e)
         })?;
 
+        if let Some(mut stages) = trace {
+            stages.push((TraceStage::Acked, Instant::now()));
+            let trace = operation_trace(op_id, stages);
+            debug!("Trace of produce for connection_id: {}: {:?}", common_state.connection_id, trace);
+            common_state.send_to_client(ProtocolMessage::Trace(trace)).map_err(|e| {
+                io::Error::new(io::ErrorKind::Other, e)
+            })?;
+        }
+
         Ok(Async::Ready(()))
     }
+
+    fn poll_parent_check(&mut self, common_state: &mut ConnectionState) -> Poll<(), io::Error> {
+        let mut reader = match self.parent_check {
+            Some((ref produce, _, ref mut receiver)) => {
+                let op_id = produce.op_id;
+                try_ready!(receiver.poll().map_err(|recv_err| {
+                    error!("Failed to poll parent check for client: op_id: {}: {:?}", op_id, recv_err);
+                    io::Error::new(io::ErrorKind::Other, "failed to poll parent check")
+                }))
+            }
+            None => return Ok(Async::Ready(()))
+        };
+
+        let (produce, trace, _) = self.parent_check.take().unwrap();
+        let op_id = produce.op_id;
+        let parent = produce.parent_id.unwrap();
+
+        let result = match reader.next_matching() {
+            Some(Ok(event)) if *event.id() == parent => self.send_produce(produce, trace, common_state),
+            Some(Err(io_err)) => {
+                common_state.send_to_client(ProtocolMessage::Error(ErrorMessage {
+                    op_id: op_id,
+                    kind: ErrorKind::StorageEngineError,
+                    description: format!("Persistence Error: {}", io_err.description()),
+                }))
+            }
+            _ => send_invalid_parent(op_id, parent, common_state)
+        };
+        result.map(|()| Async::Ready(())).map_err(|e| {
+            io::Error::new(io::ErrorKind::Other, e)
+        })
+    }
+}
+
+/// Converts the stage times into microseconds since the first stage, which is always when the operation was received
+pub fn operation_trace(op_id: u32, stages: StageTimes) -> OperationTrace {
+    let received = stages[0].1;
+    let stages = stages.into_iter().map(|(stage, time)| {
+        let elapsed = if time > received { time.duration_since(received) } else { Default::default() };
+        StageTiming {
+            stage: stage,
+            elapsed_micros: elapsed.as_secs() * 1_000_000 + (elapsed.subsec_nanos() / 1000) as u64,
+        }
+    }).collect();
+    OperationTrace {
+        op_id: op_id,
+        stages: stages,
+    }
+}
+
+fn send_invalid_parent(op_id: u32, parent: FloEventId, common_state: &mut ConnectionState) -> ConnectionHandlerResult {
+    debug!("Rejecting produce for connection_id: {}, op_id: {} because parent: {} does not exist", common_state.connection_id, op_id, parent);
+    common_state.send_to_client(ProtocolMessage::Error(ErrorMessage {
+        op_id: op_id,
+        kind: ErrorKind::InvalidEventId,
+        description: format!("Parent event: {} does not exist in event stream: '{}'", parent, common_state.event_stream.name()),
+    }))
 }
@@ -1,5 +1,6 @@
+pub mod registry;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::collections::HashMap;
 use std::io;
@@ -11,11 +12,20 @@ use engine::event_stream::{EventStreamRef,
                                EventStreamOptions,
                                init_existing_event_stream,
                                init_new_event_stream};
+use self::registry::{load_registry, save_registry, load_tags, RegisteredStream, REGISTRY_FILE_NAME};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ControllerOptions {
     pub storage_dir: PathBuf,
     pub default_stream_options: EventStreamOptions,
+    /// If true, then no system stream is created, and the default stream is registered under its own name
+    pub standalone: bool,
+    /// Only used by embedded servers. If true, the server's internal tasks, like segment expiration, run on an event loop
+    /// thread of its own instead of on the caller's `Remote`, so that they can't compete with the application's own IO
+    pub dedicated_event_loop: bool,
+    /// The number of messages that may be waiting to be sent to each client before that client's consumers stop reading
+    /// events. Must be at least 1
+    pub client_channel_capacity: usize,
 }
 
 
@@ -28,15 +38,52 @@ pub struct FloController {
 }
 
 
+/// Checks that the options could be used to start a controller, without starting anything. This means that the
+/// `client_channel_capacity` and default stream options are valid, the storage directory is writable, and its stream
+/// registry can be read. The storage directory doesn't need to exist yet, as long as it could be created. Returns the
+/// first problem that's found.
+pub fn validate_options(options: &ControllerOptions) -> io::Result<()> {
+    if options.client_channel_capacity == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "client_channel_capacity must be at least 1"));
+    }
+    options.default_stream_options.validate()?;
+    check_writable(&options.storage_dir)?;
+    load_registry(&options.storage_dir).map(|_| ())
+}
+
+/// Creates and then removes a file in `dir`, or in its closest existing ancestor if `dir` doesn't exist yet. The file's
+/// name is unique to this process and moment, so one that's left behind by a crash never gets in the way of a later check
+fn check_writable(dir: &Path) -> io::Result<()> {
+    use std::fs::{self, OpenOptions};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let mut existing = dir;
+    while !existing.exists() {
+        existing = match existing.parent() {
+            // a relative path with no existing parts is created in the working directory
+            Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+            Some(parent) => parent,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("No part of the storage_dir: {:?} exists", dir))),
+        };
+    }
+    if !existing.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Cannot use storage_dir: {:?} because {:?} is not a directory", dir, existing)));
+    }
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.subsec_nanos()).unwrap_or(0);
+    let probe = existing.join(format!(".flo-write-check-{}-{}", ::std::process::id(), nanos));
+    OpenOptions::new().write(true).create_new(true).open(&probe).map_err(|err| {
+        io::Error::new(err.kind(), format!("Cannot use storage_dir: {:?} because {:?} is not writable: {}", dir, existing, err))
+    })?;
+    fs::remove_file(&probe)
+}
+
 pub fn start_controller(options: ControllerOptions, remote: Remote) -> io::Result<EngineRef> {
-    use std::collections::HashMap;
-    use std::sync::{Arc, Mutex};
-    use std::sync::atomic::AtomicUsize;
     use atomics::AtomicBoolWriter;
 
     debug!("Starting Flo Controller with: {:?}", options);
 
-    let ControllerOptions{storage_dir, default_stream_options} = options;
+    validate_options(&options)?;
+    let ControllerOptions{storage_dir, default_stream_options, standalone, client_channel_capacity, ..} = options;
 
     // for now, we'll just create a default "system" stream. This is temporary.
     // Once we start work on clustering, the system stream will be used exclusively for cluster communication
@@ -45,19 +92,266 @@ pub fn start_controller(options: ControllerOptions, remote: Remote) -> io::Resul
     // There's only one machine, so all partitions will always be primary. Again, this is just temporary
     let status_writer = AtomicBoolWriter::with_value(true);
 
-    let system_stream_dir = storage_dir.join(&default_stream_options.name);
-    let event_stream_ref = if system_stream_dir.exists() {
-        init_existing_event_stream(system_stream_dir, default_stream_options, status_writer.reader(), remote)?
+    // A standalone server has no system stream at all, so the default stream is registered under its own name
+    let default_stream_name = if standalone {
+        default_stream_options.name.clone()
     } else {
-        init_new_event_stream(system_stream_dir, default_stream_options, status_writer.reader(), remote)?
+        system_stream_name()
     };
 
-    let mut streams = HashMap::with_capacity(1);
-    streams.insert(system_stream_name(), event_stream_ref);
+    let registered = load_registry(&storage_dir)?;
+    let mut all_options = Vec::with_capacity(registered.len() + 1);
+    let mut streams = HashMap::with_capacity(registered.len() + 1);
+    let mut without_keys = Vec::new();
+
+    // the configured options always win for the default stream
+    if let Some(previous) = registered.iter().find(|r| r.options.name == default_stream_options.name) {
+        if previous.options != default_stream_options {
+            warn!("Options for default stream: '{}' differ from the stream registry, using the configured options: {:?}", default_stream_options.name, default_stream_options);
+        }
+    }
+    all_options.push(default_stream_options.clone());
 
-    let engine = EngineRef {
-        current_connection_id: Arc::new(AtomicUsize::new(0)),
-        event_streams: Arc::new(Mutex::new(streams)),
+    let default_stream_dir = storage_dir.join(&default_stream_options.name);
+    let event_stream_ref = if default_stream_dir.exists() {
+        init_existing_event_stream(default_stream_dir, default_stream_options, status_writer.reader(), remote.clone())?
+    } else {
+        init_new_event_stream(default_stream_dir, default_stream_options, status_writer.reader(), remote.clone())?
     };
-    Ok(engine)
+    streams.insert(default_stream_name.clone(), event_stream_ref);
+
+    for stream in registered {
+        let name = stream.options.name.clone();
+        if all_options.iter().any(|o| o.name == name) {
+            continue;
+        }
+        let stream_dir = storage_dir.join(&name);
+        if !stream_dir.is_dir() {
+            warn!("Event stream: '{}' is in the stream registry, but its directory: {:?} does not exist, so it will be removed from the registry", name, stream_dir);
+            continue;
+        }
+        if let Some(cipher) = stream.cipher {
+            // keys are never stored in the registry, so we have no way to read this stream
+            warn!("Event stream: '{}' is encrypted with {:?}, but no key is available for it. It will not be started", name, cipher);
+            without_keys.push(stream);
+            continue;
+        }
+        debug!("Restoring event stream: '{}' from the stream registry", name);
+        let event_stream_ref = init_existing_event_stream(stream_dir, stream.options.clone(), status_writer.reader(), remote.clone())?;
+        streams.insert(name, event_stream_ref);
+        all_options.push(stream.options);
+    }
+
+    warn_unregistered_stream_dirs(&storage_dir, &all_options, &without_keys)?;
+    save_registry(&storage_dir, &all_options, &without_keys)?;
+
+    for tag in load_tags(&storage_dir)? {
+        match streams.get(&tag.stream) {
+            Some(stream) => stream.tags().set(tag.name, tag.event_id),
+            None => warn!("Ignoring tag: '{}' for event stream: '{}' because the stream was not started", tag.name, tag.stream),
+        }
+    }
+
+    Ok(EngineRef::with_default_stream(default_stream_name, streams)
+            .with_storage_dir(storage_dir)
+            .with_client_channel_capacity(client_channel_capacity))
+}
+
+fn warn_unregistered_stream_dirs(storage_dir: &Path, registered: &[EventStreamOptions], without_keys: &[RegisteredStream]) -> io::Result<()> {
+    for entry in ::std::fs::read_dir(storage_dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let dir_name = entry.file_name();
+        let dir_name = dir_name.to_string_lossy();
+        if !registered.iter().any(|o| o.name == dir_name) && !without_keys.iter().any(|s| s.options.name == dir_name) {
+            warn!("Directory: {:?} is not in the stream registry: {:?}, so it will not be started", entry.path(), storage_dir.join(REGISTRY_FILE_NAME));
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+    use tokio_core::reactor::Core;
+    use engine::{ConnectError, DEFAULT_CLIENT_CHANNEL_CAPACITY};
+
+    #[test]
+    fn standalone_controller_uses_default_stream_without_creating_system_stream() {
+        let tempdir = TempDir::new("standalone_controller").unwrap();
+        let core = Core::new().unwrap();
+        let options = ControllerOptions {
+            storage_dir: tempdir.path().to_owned(),
+            default_stream_options: EventStreamOptions {
+                name: "mystream".to_owned(),
+                ..Default::default()
+            },
+            standalone: true,
+            dedicated_event_loop: false,
+            client_channel_capacity: DEFAULT_CLIENT_CHANNEL_CAPACITY,
+        };
+
+        let engine = start_controller(options, core.remote()).expect("failed to start controller");
+        assert_eq!("mystream", engine.get_default_stream().name());
+        assert!(engine.get_stream("mystream").is_ok());
+        match engine.get_stream(&system_stream_name()) {
+            Err(ConnectError::NoStream) => {}
+            other @ _ => panic!("expected NoStream for system stream, got: {:?}", other),
+        }
+        assert!(!tempdir.path().join(system_stream_name()).exists());
+    }
+
+    #[test]
+    fn streams_are_restored_from_the_registry_with_their_options() {
+        use atomics::AtomicBoolWriter;
+
+        let tempdir = TempDir::new("controller_registry").unwrap();
+        let mut core = Core::new().unwrap();
+        let options = || {
+            ControllerOptions {
+                storage_dir: tempdir.path().to_owned(),
+                default_stream_options: Default::default(),
+                standalone: false,
+                dedicated_event_loop: false,
+                client_channel_capacity: DEFAULT_CLIENT_CHANNEL_CAPACITY,
+            }
+        };
+
+        // the first startup only registers the default stream
+        start_controller(options(), core.remote()).expect("failed to start controller");
+        let registered = load_registry(tempdir.path()).unwrap();
+        assert_eq!(vec![EventStreamOptions::default()], registered.into_iter().map(|r| r.options).collect::<Vec<_>>());
+
+        // simulate another stream having been created while the server was running
+        let other_options = EventStreamOptions {
+            name: "other".to_owned(),
+            num_partitions: 3,
+            segment_max_size_bytes: 8192,
+            ..Default::default()
+        };
+        let status = AtomicBoolWriter::with_value(true);
+        init_new_event_stream(tempdir.path().join("other"), other_options.clone(), status.reader(), core.remote()).unwrap();
+        save_registry(tempdir.path(), &[EventStreamOptions::default(), other_options.clone()], &[]).unwrap();
+
+        // restart
+        let engine = start_controller(options(), core.remote()).expect("failed to restart controller");
+        let other = engine.get_stream("other").expect("other stream was not restored");
+        assert_eq!(3, other.get_partition_count());
+
+        let list = core.run(engine.list_streams()).unwrap();
+        assert_eq!(vec!["default".to_owned(), "other".to_owned()], list.into_iter().map(|s| s.name).collect::<Vec<_>>());
+
+        let registered = load_registry(tempdir.path()).unwrap().into_iter().map(|r| r.options).collect::<Vec<_>>();
+        assert_eq!(vec![EventStreamOptions::default(), other_options], registered);
+    }
+
+    #[test]
+    fn encrypted_streams_without_a_key_are_kept_in_the_registry() {
+        use engine::event_stream::{EncryptionOptions, EncryptionCipher};
+
+        let tempdir = TempDir::new("controller_registry_encrypted").unwrap();
+        let core = Core::new().unwrap();
+        let options = ControllerOptions {
+            storage_dir: tempdir.path().to_owned(),
+            default_stream_options: Default::default(),
+            standalone: false,
+            dedicated_event_loop: false,
+            client_channel_capacity: DEFAULT_CLIENT_CHANNEL_CAPACITY,
+        };
+        let secret_options = EventStreamOptions {
+            name: "secret".to_owned(),
+            encryption: Some(EncryptionOptions { cipher: EncryptionCipher::ChaCha20Poly1305, key: [3; 32] }),
+            ..Default::default()
+        };
+        ::std::fs::create_dir(tempdir.path().join("secret")).unwrap();
+        save_registry(tempdir.path(), &[EventStreamOptions::default(), secret_options], &[]).unwrap();
+
+        let engine = start_controller(options, core.remote()).expect("failed to start controller");
+        assert!(engine.get_stream("secret").is_err());
+
+        let registered = load_registry(tempdir.path()).unwrap();
+        assert_eq!(2, registered.len());
+        assert_eq!("secret", registered[1].options.name);
+        assert_eq!(Some(EncryptionCipher::ChaCha20Poly1305), registered[1].cipher);
+    }
+
+    #[test]
+    fn stream_tags_are_restored_after_restarting() {
+        use event::FloEventId;
+
+        let tempdir = TempDir::new("controller_tags").unwrap();
+        let core = Core::new().unwrap();
+        let options = || {
+            ControllerOptions {
+                storage_dir: tempdir.path().to_owned(),
+                default_stream_options: Default::default(),
+                standalone: false,
+                dedicated_event_loop: false,
+                client_channel_capacity: DEFAULT_CLIENT_CHANNEL_CAPACITY,
+            }
+        };
+
+        let engine = start_controller(options(), core.remote()).expect("failed to start controller");
+        engine.tag_stream(&system_stream_name(), "v1.1".to_owned(), FloEventId::new(1, 3)).expect("failed to tag stream");
+        engine.tag_stream(&system_stream_name(), "v1.2".to_owned(), FloEventId::new(1, 5)).expect("failed to tag stream");
+        engine.tag_stream(&system_stream_name(), "v1.1".to_owned(), FloEventId::new(1, 4)).expect("failed to move tag");
+
+        let missing_stream = engine.tag_stream("nope", "v1.1".to_owned(), FloEventId::new(1, 4)).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, missing_stream.kind());
+        let invalid_tag = engine.tag_stream(&system_stream_name(), "".to_owned(), FloEventId::new(1, 4)).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, invalid_tag.kind());
+        drop(engine);
+
+        let engine = start_controller(options(), core.remote()).expect("failed to restart controller");
+        let tags = engine.get_default_stream().tags().all().into_iter().map(|tag| (tag.name, tag.event_id)).collect::<Vec<_>>();
+        let expected = vec![
+            ("v1.1".to_owned(), FloEventId::new(1, 4)),
+            ("v1.2".to_owned(), FloEventId::new(1, 5)),
+        ];
+        assert_eq!(expected, tags);
+    }
+
+    #[test]
+    fn validate_options_returns_the_first_problem_without_creating_anything() {
+        use std::fs::{self, File};
+        use std::io::Write;
+
+        let tempdir = TempDir::new("validate_controller_options").unwrap();
+        let storage_dir = tempdir.path().join("not_yet_created");
+        let valid = ControllerOptions {
+            storage_dir: storage_dir.clone(),
+            default_stream_options: Default::default(),
+            standalone: false,
+            dedicated_event_loop: false,
+            client_channel_capacity: DEFAULT_CLIENT_CHANNEL_CAPACITY,
+        };
+        validate_options(&valid).expect("expected options to be valid");
+        assert!(!storage_dir.exists());
+        assert_eq!(0, fs::read_dir(tempdir.path()).unwrap().count());
+
+        let zero_capacity = ControllerOptions { client_channel_capacity: 0, ..valid.clone() };
+        assert_eq!(io::ErrorKind::InvalidInput, validate_options(&zero_capacity).unwrap_err().kind());
+
+        let mut no_partitions = valid.clone();
+        no_partitions.default_stream_options.num_partitions = 0;
+        let err = validate_options(&no_partitions).unwrap_err();
+        assert!(err.to_string().contains("num_partitions"), "unexpected error: {}", err);
+
+        File::create(tempdir.path().join("a_file")).unwrap();
+        let not_a_dir = ControllerOptions { storage_dir: tempdir.path().join("a_file").join("data"), ..valid.clone() };
+        assert!(validate_options(&not_a_dir).is_err());
+
+        // a probe file that was left behind by an earlier crash doesn't matter
+        fs::create_dir(&storage_dir).unwrap();
+        File::create(storage_dir.join(".flo-write-check")).unwrap();
+        validate_options(&valid).expect("expected options to be valid with a leftover probe file");
+        assert_eq!(1, fs::read_dir(&storage_dir).unwrap().count());
+
+        File::create(storage_dir.join(REGISTRY_FILE_NAME)).unwrap().write_all(b"not a registry").unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, validate_options(&valid).unwrap_err().kind());
+    }
 }
@@ -0,0 +1,272 @@
+//! The stream registry is a small file in the root of the storage directory that records the name and options of every event
+//! stream, so that they can all be restored on startup. Each line describes one stream as tab separated fields:
+//!
+//! `name  num_partitions  event_retention_millis  max_segment_duration_millis  segment_max_size_bytes  cipher  validate_parent  starting_counter  max_cursor_lifetime_millis  fsync_policy`
+//!
+//! Encryption keys are never written to the registry. Only the name of the cipher is recorded, or `none` if the stream is not
+//! encrypted. Lines written before `validate_parent` was added are still accepted, and are read as `false`. Likewise, lines
+//! without a `starting_counter` are read as starting at 1, and a `max_cursor_lifetime_millis` that's missing or 0 means that
+//! cursors may stay open indefinitely. A missing `fsync_policy` is read as the default policy.
+//!
+//! Stream tags are kept in a separate file next to the registry, since they change while the server is running. Each line
+//! is one tag, as tab separated fields: `stream_name  tag_name  actor  event_counter`
+
+use std::path::Path;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+
+use chrono::Duration;
+
+use event::FloEventId;
+use engine::event_stream::{EventStreamOptions, EncryptionCipher, FsyncPolicy};
+
+pub const REGISTRY_FILE_NAME: &'static str = "streams.registry";
+pub const TAGS_FILE_NAME: &'static str = "tags.registry";
+
+/// A stream that was read from the registry. Since keys are not stored, the `options` never include encryption, and the
+/// `cipher` is set if the stream was encrypted
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisteredStream {
+    pub options: EventStreamOptions,
+    pub cipher: Option<EncryptionCipher>,
+}
+
+/// A tag on an event in the named stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisteredTag {
+    pub stream: String,
+    pub name: String,
+    pub event_id: FloEventId,
+}
+
+/// Writes the registry, replacing any existing one. The new file is written completely before it replaces the old one, so
+/// a crash part way through will leave the previous registry intact. Encrypted streams that could not be started because no
+/// key was available are passed in `without_keys` and are written back unchanged, so they can be started again once a key is
+pub fn save_registry(storage_dir: &Path, streams: &[EventStreamOptions], without_keys: &[RegisteredStream]) -> io::Result<()> {
+    let mut contents = String::new();
+    for options in streams {
+        push_line(&mut contents, options, options.encryption.as_ref().map(|e| e.cipher))?;
+    }
+    for stream in without_keys {
+        push_line(&mut contents, &stream.options, stream.cipher)?;
+    }
+    replace_file(storage_dir, REGISTRY_FILE_NAME, &contents)
+}
+
+fn push_line(contents: &mut String, options: &EventStreamOptions, cipher: Option<EncryptionCipher>) -> io::Result<()> {
+    if options.name.contains(|c| c == '\t' || c == '\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Cannot register stream: {:?} because its name contains a tab or newline", options.name)));
+    }
+    let cipher = match cipher {
+        Some(EncryptionCipher::ChaCha20Poly1305) => "chacha20poly1305",
+        Some(EncryptionCipher::Aes256Gcm) => "aes256gcm",
+        None => "none",
+    };
+    contents.push_str(&format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                               options.name,
+                               options.num_partitions,
+                               options.event_retention.num_milliseconds(),
+                               options.max_segment_duration.num_milliseconds(),
+                               options.segment_max_size_bytes,
+                               cipher,
+                               options.validate_parent,
+                               options.starting_counter,
+                               options.max_cursor_lifetime.map(|lifetime| lifetime.num_milliseconds()).unwrap_or(0),
+                               options.fsync_policy));
+    Ok(())
+}
+
+/// Writes all of the tags for every stream, replacing any that were saved previously. Like the registry, the new file is
+/// written completely before it replaces the old one
+pub fn save_tags(storage_dir: &Path, tags: &[RegisteredTag]) -> io::Result<()> {
+    let mut contents = String::new();
+    for tag in tags {
+        if tag.name.contains(|c| c == '\t' || c == '\n') {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Cannot save tag: {:?} because it contains a tab or newline", tag.name)));
+        }
+        contents.push_str(&format!("{}\t{}\t{}\t{}\n", tag.stream, tag.name, tag.event_id.actor, tag.event_id.event_counter));
+    }
+    replace_file(storage_dir, TAGS_FILE_NAME, &contents)
+}
+
+/// Reads all of the saved tags. Returns an empty `Vec` if no tags have been saved
+pub fn load_tags(storage_dir: &Path) -> io::Result<Vec<RegisteredTag>> {
+    let path = storage_dir.join(TAGS_FILE_NAME);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut contents = String::new();
+    File::open(&path)?.read_to_string(&mut contents)?;
+
+    contents.lines().filter(|line| !line.is_empty()).map(|line| {
+        parse_tag_line(line).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Invalid line in stream tags: {:?}", line))
+        })
+    }).collect()
+}
+
+fn parse_tag_line(line: &str) -> Option<RegisteredTag> {
+    let fields = line.split('\t').collect::<Vec<&str>>();
+    if fields.len() != 4 {
+        return None;
+    }
+    Some(RegisteredTag {
+        stream: fields[0].to_owned(),
+        name: fields[1].to_owned(),
+        event_id: FloEventId::new(fields[2].parse().ok()?, fields[3].parse().ok()?),
+    })
+}
+
+fn replace_file(storage_dir: &Path, file_name: &str, contents: &str) -> io::Result<()> {
+    let temp_path = storage_dir.join(format!("{}.tmp", file_name));
+    {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&temp_path, storage_dir.join(file_name))
+}
+
+/// Reads all the streams from the registry. Returns an empty `Vec` if there is no registry in the storage directory
+pub fn load_registry(storage_dir: &Path) -> io::Result<Vec<RegisteredStream>> {
+    let path = storage_dir.join(REGISTRY_FILE_NAME);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut contents = String::new();
+    File::open(&path)?.read_to_string(&mut contents)?;
+
+    contents.lines().filter(|line| !line.is_empty()).map(|line| {
+        parse_line(line).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Invalid line in stream registry: {:?}", line))
+        })
+    }).collect()
+}
+
+fn parse_line(line: &str) -> Option<RegisteredStream> {
+    let fields = line.split('\t').collect::<Vec<&str>>();
+    if fields.len() < 6 || fields.len() > 10 {
+        return None;
+    }
+
+    let cipher = match fields[5] {
+        "chacha20poly1305" => Some(EncryptionCipher::ChaCha20Poly1305),
+        "aes256gcm" => Some(EncryptionCipher::Aes256Gcm),
+        "none" => None,
+        _ => return None,
+    };
+
+    let options = EventStreamOptions {
+        name: fields[0].to_owned(),
+        num_partitions: fields[1].parse().ok()?,
+        event_retention: Duration::milliseconds(fields[2].parse().ok()?),
+        max_segment_duration: Duration::milliseconds(fields[3].parse().ok()?),
+        segment_max_size_bytes: fields[4].parse().ok()?,
+        encryption: None,
+        validate_parent: match fields.get(6) {
+            Some(value) => value.parse().ok()?,
+            None => false,
+        },
+        starting_counter: match fields.get(7) {
+            Some(value) => value.parse().ok()?,
+            None => 1,
+        },
+        max_cursor_lifetime: match fields.get(8) {
+            Some(value) => match value.parse().ok()? {
+                0 => None,
+                millis => Some(Duration::milliseconds(millis)),
+            },
+            None => None,
+        },
+        fsync_policy: match fields.get(9) {
+            Some(value) => value.parse().ok()?,
+            None => FsyncPolicy::default(),
+        },
+    };
+    Some(RegisteredStream {
+        options: options,
+        cipher: cipher,
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use engine::event_stream::EncryptionOptions;
+    use tempdir::TempDir;
+
+    #[test]
+    fn registry_is_saved_and_loaded_without_encryption_keys() {
+        let tempdir = TempDir::new("stream_registry").unwrap();
+        let plain = EventStreamOptions {
+            name: "plain".to_owned(),
+            num_partitions: 3,
+            event_retention: Duration::max_value(),
+            max_segment_duration: Duration::hours(2),
+            segment_max_size_bytes: 4096,
+            encryption: None,
+            validate_parent: true,
+            starting_counter: 1000,
+            max_cursor_lifetime: Some(Duration::minutes(30)),
+            fsync_policy: FsyncPolicy::EveryN(100),
+        };
+        let encrypted = EventStreamOptions {
+            name: "secret".to_owned(),
+            encryption: Some(EncryptionOptions { cipher: EncryptionCipher::Aes256Gcm, key: [9; 32] }),
+            ..Default::default()
+        };
+
+        save_registry(tempdir.path(), &[plain.clone(), encrypted.clone()], &[]).expect("failed to save registry");
+        let result = load_registry(tempdir.path()).expect("failed to load registry");
+
+        assert_eq!(2, result.len());
+        assert_eq!(RegisteredStream { options: plain, cipher: None }, result[0]);
+        assert_eq!(Some(EncryptionCipher::Aes256Gcm), result[1].cipher);
+        assert_eq!(None, result[1].options.encryption);
+
+        let mut contents = String::new();
+        File::open(tempdir.path().join(REGISTRY_FILE_NAME)).unwrap().read_to_string(&mut contents).unwrap();
+        assert!(!contents.as_bytes().windows(32).any(|w| w == &[9; 32][..]));
+    }
+
+    #[test]
+    fn lines_without_validate_parent_are_loaded_with_it_disabled() {
+        let tempdir = TempDir::new("stream_registry_old").unwrap();
+        File::create(tempdir.path().join(REGISTRY_FILE_NAME)).unwrap()
+                .write_all(b"old\t2\t1000\t2000\t4096\tnone\n").unwrap();
+
+        let result = load_registry(tempdir.path()).unwrap();
+        assert_eq!(1, result.len());
+        assert_eq!(2, result[0].options.num_partitions);
+        assert!(!result[0].options.validate_parent);
+        assert_eq!(1, result[0].options.starting_counter);
+        assert_eq!(None, result[0].options.max_cursor_lifetime);
+        assert_eq!(FsyncPolicy::Never, result[0].options.fsync_policy);
+    }
+
+    #[test]
+    fn tags_are_saved_and_loaded() {
+        let tempdir = TempDir::new("stream_tags").unwrap();
+        assert!(load_tags(tempdir.path()).unwrap().is_empty());
+
+        let tags = vec![
+            RegisteredTag { stream: "foo".to_owned(), name: "v1.2".to_owned(), event_id: FloEventId::new(2, 77) },
+            RegisteredTag { stream: "bar".to_owned(), name: "release candidate".to_owned(), event_id: FloEventId::new(1, 3) },
+        ];
+        save_tags(tempdir.path(), &tags).expect("failed to save tags");
+        assert_eq!(tags, load_tags(tempdir.path()).unwrap());
+
+        let invalid = RegisteredTag { stream: "foo".to_owned(), name: "a\tb".to_owned(), event_id: FloEventId::new(1, 1) };
+        assert!(save_tags(tempdir.path(), &[invalid]).is_err());
+        assert_eq!(tags, load_tags(tempdir.path()).unwrap());
+    }
+
+    #[test]
+    fn load_registry_returns_empty_vec_when_there_is_no_registry() {
+        let tempdir = TempDir::new("stream_registry_missing").unwrap();
+        assert!(load_registry(tempdir.path()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,203 @@
+use std::sync::{Arc, Weak, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::{Stream, Poll, Async};
+use futures::sync::mpsc::{channel, Sender, Receiver};
+
+use event::FloEventId;
+
+/// The number of ids that may be waiting to be taken from each subscription before further ids are dropped for it
+pub const ACK_SUBSCRIPTION_BUFFER: usize = 1024;
+
+#[derive(Debug)]
+struct Subscriber {
+    key: usize,
+    sender: Sender<FloEventId>,
+    dropped: Arc<AtomicUsize>,
+    closed: bool,
+}
+
+impl Subscriber {
+    /// Returns false once the receiving end has gone away
+    fn send(&mut self, id: FloEventId) -> bool {
+        match self.sender.try_send(id) {
+            Ok(()) => {}
+            Err(ref err) if err.is_full() => {
+                self.dropped.fetch_add(1, Ordering::SeqCst);
+            }
+            Err(_) => {
+                self.closed = true;
+            }
+        }
+        !self.closed
+    }
+}
+
+#[derive(Debug)]
+struct Subscribers {
+    count: AtomicUsize,
+    next_key: AtomicUsize,
+    senders: Mutex<Vec<Subscriber>>,
+}
+
+impl Subscribers {
+    fn remove(&self, key: usize) {
+        let mut senders = self.senders.lock().unwrap();
+        senders.retain(|subscriber| subscriber.key != key);
+        self.count.store(senders.len(), Ordering::SeqCst);
+    }
+}
+
+/// Shared by all the partitions in an event stream, so that in-process observers can be told the id of every event as
+/// soon as it's been persisted, without having to be the producer of the event. Each subscription is bounded, so a
+/// subscriber that falls behind can never hold up an append. Instead, it lags: the ids that don't fit are dropped for that
+/// subscriber and counted in `AckSubscription::dropped_count`.
+#[derive(Clone, Debug)]
+pub struct AckSubscribers(Arc<Subscribers>);
+
+impl AckSubscribers {
+    pub fn new() -> AckSubscribers {
+        AckSubscribers(Arc::new(Subscribers {
+            count: AtomicUsize::new(0),
+            next_key: AtomicUsize::new(0),
+            senders: Mutex::new(Vec::new()),
+        }))
+    }
+
+    pub fn subscribe(&self) -> AckSubscription {
+        let (tx, rx) = channel(ACK_SUBSCRIPTION_BUFFER);
+        let key = self.0.next_key.fetch_add(1, Ordering::SeqCst);
+        let dropped = Arc::new(AtomicUsize::new(0));
+
+        let mut senders = self.0.senders.lock().unwrap();
+        senders.push(Subscriber { key, sender: tx, dropped: dropped.clone(), closed: false });
+        self.0.count.store(senders.len(), Ordering::SeqCst);
+
+        AckSubscription {
+            receiver: Some(rx),
+            dropped: dropped,
+            registration: Some((Arc::downgrade(&self.0), key)),
+        }
+    }
+
+    /// Sends each of the ids, in order, to every subscriber, without ever waiting on one. When there are no subscribers,
+    /// which is the usual case, this returns without taking the lock
+    pub fn notify<I: IntoIterator<Item=FloEventId>>(&self, ids: I) {
+        if self.0.count.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+
+        let mut senders = self.0.senders.lock().unwrap();
+        for id in ids {
+            let mut any_closed = false;
+            for subscriber in senders.iter_mut() {
+                any_closed |= !subscriber.send(id);
+            }
+            if any_closed {
+                senders.retain(|subscriber| !subscriber.closed);
+            }
+        }
+        self.0.count.store(senders.len(), Ordering::SeqCst);
+    }
+}
+
+/// A `Stream` of the ids of events as they're persisted to an event stream. Within a partition, ids are always yielded in
+/// order, although ids are skipped if the subscription falls more than `ACK_SUBSCRIPTION_BUFFER` ids behind. The stream
+/// ends once the event stream is gone, or immediately if it didn't exist in the first place. Dropping the subscription
+/// removes it from the event stream right away.
+#[derive(Debug)]
+pub struct AckSubscription {
+    receiver: Option<Receiver<FloEventId>>,
+    dropped: Arc<AtomicUsize>,
+    registration: Option<(Weak<Subscribers>, usize)>,
+}
+
+impl AckSubscription {
+    pub fn empty() -> AckSubscription {
+        AckSubscription {
+            receiver: None,
+            dropped: Arc::new(AtomicUsize::new(0)),
+            registration: None,
+        }
+    }
+
+    /// Returns the number of ids that were skipped because this subscription had fallen too far behind
+    pub fn dropped_count(&self) -> usize {
+        self.dropped.load(Ordering::SeqCst)
+    }
+}
+
+impl Stream for AckSubscription {
+    type Item = FloEventId;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<FloEventId>, ()> {
+        match self.receiver {
+            Some(ref mut receiver) => receiver.poll(),
+            None => Ok(Async::Ready(None))
+        }
+    }
+}
+
+impl Drop for AckSubscription {
+    fn drop(&mut self) {
+        if let Some((ref subscribers, key)) = self.registration {
+            if let Some(subscribers) = subscribers.upgrade() {
+                subscribers.remove(key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::Future;
+
+    #[test]
+    fn dropped_subscribers_are_removed_immediately_and_others_still_receive_ids() {
+        let subject = AckSubscribers::new();
+        let dropped = subject.subscribe();
+        let kept = subject.subscribe();
+        assert_eq!(2, subject.0.count.load(Ordering::SeqCst));
+
+        drop(dropped);
+        assert_eq!(1, subject.0.count.load(Ordering::SeqCst));
+        assert_eq!(1, subject.0.senders.lock().unwrap().len());
+
+        subject.notify(vec![FloEventId::new(1, 1), FloEventId::new(1, 2)]);
+
+        drop(subject);
+        let ids = kept.collect().wait().unwrap();
+        assert_eq!(vec![FloEventId::new(1, 1), FloEventId::new(1, 2)], ids);
+    }
+
+    #[test]
+    fn ids_are_dropped_for_a_subscriber_that_falls_behind_without_affecting_others() {
+        let subject = AckSubscribers::new();
+        let slow = subject.subscribe();
+        let mut fast = subject.subscribe();
+
+        let total = ACK_SUBSCRIPTION_BUFFER as u64 * 2;
+        let mut fast_ids = Vec::new();
+        for counter in 1..(total + 1) {
+            subject.notify(Some(FloEventId::new(1, counter)));
+            fast_ids.push(fast.by_ref().take(1).collect().wait().unwrap()[0]);
+        }
+        assert_eq!(total as usize, fast_ids.len());
+        assert_eq!(0, fast.dropped_count());
+
+        drop(subject);
+        let dropped = slow.dropped_count();
+        assert!(dropped > 0);
+        let slow_ids = slow.collect().wait().unwrap();
+        assert_eq!(total as usize, slow_ids.len() + dropped);
+        assert_eq!(FloEventId::new(1, 1), slow_ids[0]);
+    }
+
+    #[test]
+    fn empty_subscription_ends_immediately() {
+        let ids = AckSubscription::empty().collect().wait().unwrap();
+        assert!(ids.is_empty());
+    }
+}
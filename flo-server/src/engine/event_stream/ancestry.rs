@@ -0,0 +1,75 @@
+use std::collections::HashSet;
+use std::io;
+
+use futures::{Future, future};
+use futures::future::{Loop, Either};
+
+use event::{FloEvent, FloEventId};
+use super::partition::{PartitionRef, PersistentEvent, EventFilter};
+
+/// The maximum number of events that will be returned for a single ancestry chain. Events are always produced after their
+/// parents, so chains can only be longer than this in streams with a very deep hierarchy
+pub const MAX_ANCESTRY_DEPTH: usize = 1024;
+
+/// Completes with the requested event followed by each of its ancestors, or an empty `Vec` if the event doesn't exist
+pub type AncestryFuture = Box<Future<Item=Vec<PersistentEvent>, Error=io::Error> + Send>;
+
+/// Follows the `parent_id` of each event back to the root of the chain, reading one event at a time. The chain ends at the
+/// first ancestor that can't be found, which includes any that belong to a different stream. A `seen` set guards against
+/// malformed data with a cycle of parent ids, and `max_depth` against chains that are simply too long.
+pub fn read_ancestry(partitions: Vec<PartitionRef>, event_id: FloEventId, max_depth: usize) -> AncestryFuture {
+    let initial_state = (Some(event_id), Vec::new(), HashSet::new());
+
+    Box::new(future::loop_fn(initial_state, move |(next_id, mut chain, mut seen)| {
+        let id = match next_id {
+            Some(id) => id,
+            None => return Either::A(future::ok(Loop::Break(chain))),
+        };
+        if chain.len() >= max_depth {
+            warn!("Ancestry of event: {} is longer than the limit of {} events, so it is being cut off at: {}", event_id, max_depth, id);
+            return Either::A(future::ok(Loop::Break(chain)));
+        }
+        if !seen.insert(id) {
+            warn!("Ancestry of event: {} contains a cycle back to: {}", event_id, id);
+            return Either::A(future::ok(Loop::Break(chain)));
+        }
+
+        Either::B(read_event(&partitions, id).map(move |result| {
+            match result {
+                Some(event) => {
+                    let parent = event.parent_id();
+                    chain.push(event);
+                    Loop::Continue((parent, chain, seen))
+                }
+                None => Loop::Break(chain)
+            }
+        }))
+    }))
+}
+
+fn read_event(partitions: &[PartitionRef], id: FloEventId) -> Box<Future<Item=Option<PersistentEvent>, Error=io::Error> + Send> {
+    let partition = partitions.iter().find(|p| p.partition_num() == id.actor);
+    let result = match partition {
+        Some(partition) if id.event_counter > 0 => partition.read(0, EventFilter::All, id.event_counter - 1),
+        _ => return Box::new(future::ok(None)),
+    };
+
+    match result {
+        Ok(receiver) => {
+            Box::new(receiver.map_err(move |_| {
+                io::Error::new(io::ErrorKind::BrokenPipe, format!("Partition: {} shut down before creating reader", id.actor))
+            }).and_then(move |mut reader| {
+                // the reader starts with the first event after the previous counter, which may not be the one we want
+                match reader.next() {
+                    Some(Ok(event)) => Ok(if *event.id() == id { Some(event) } else { None }),
+                    Some(Err(io_err)) => Err(io_err),
+                    None => Ok(None),
+                }
+            }))
+        }
+        Err(err) => {
+            let description = format!("Failed to send read to partition: {}: {:?}", id.actor, err);
+            Box::new(future::err(io::Error::new(io::ErrorKind::Other, description)))
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Optional encryption of event bodies at rest. Only the `data` portion of each event is encrypted. Ids, timestamps, and
+//! namespaces are all left in cleartext so that they can still be indexed and filtered without decrypting anything.
+//!
+//! Each encrypted body is stored as `nonce + ciphertext + tag`, where the nonce is randomly generated for each event. The
+//! event id is used as the associated data, so an encrypted body cannot be copied to a different event without detection.
+
+use std::fmt::{self, Debug};
+use std::io;
+
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::aead::generic_array::GenericArray;
+use byteorder::{ByteOrder, BigEndian};
+
+use event::FloEventId;
+
+/// The number of bytes used for the nonce that's stored at the start of each encrypted body
+pub const NONCE_LEN: usize = 12;
+/// The number of bytes used for the authentication tag that's stored at the end of each encrypted body
+pub const TAG_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncryptionCipher {
+    ChaCha20Poly1305,
+    Aes256Gcm,
+}
+
+/// Configures encryption of event bodies for an event stream. The same key must be used every time the stream is initialized,
+/// or else existing events will fail to be decrypted.
+#[derive(Clone, PartialEq)]
+pub struct EncryptionOptions {
+    pub cipher: EncryptionCipher,
+    pub key: [u8; 32],
+}
+
+impl Debug for EncryptionOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // never write the key to the logs
+        write!(f, "EncryptionOptions {{ cipher: {:?}, key: <redacted> }}", self.cipher)
+    }
+}
+
+#[derive(Clone)]
+enum CipherImpl {
+    ChaCha(ChaCha20Poly1305),
+    Aes(Aes256Gcm),
+}
+
+/// Encrypts and decrypts event bodies. This is cheap to clone, so each reader gets its own copy
+#[derive(Clone)]
+pub struct EventEncryptor {
+    cipher: CipherImpl,
+}
+
+impl Debug for EventEncryptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self.cipher {
+            CipherImpl::ChaCha(_) => "ChaCha20Poly1305",
+            CipherImpl::Aes(_) => "Aes256Gcm",
+        };
+        write!(f, "EventEncryptor({})", name)
+    }
+}
+
+impl EventEncryptor {
+    pub fn new(options: &EncryptionOptions) -> EventEncryptor {
+        let key = GenericArray::from_slice(&options.key);
+        let cipher = match options.cipher {
+            EncryptionCipher::ChaCha20Poly1305 => CipherImpl::ChaCha(ChaCha20Poly1305::new(key)),
+            EncryptionCipher::Aes256Gcm => CipherImpl::Aes(Aes256Gcm::new(key)),
+        };
+        EventEncryptor {
+            cipher: cipher,
+        }
+    }
+
+    /// Returns the encrypted body, prefixed with the nonce that was used to encrypt it
+    pub fn encrypt(&self, id: &FloEventId, data: &[u8]) -> io::Result<Vec<u8>> {
+        let aad = associated_data(id);
+        let payload = Payload { msg: data, aad: &aad };
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+        let ciphertext = match self.cipher {
+            CipherImpl::ChaCha(ref cipher) => cipher.encrypt(&nonce, payload),
+            CipherImpl::Aes(ref cipher) => cipher.encrypt(&nonce, payload),
+        }.map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, format!("Failed to encrypt body of event: {}", id))
+        })?;
+
+        let mut stored = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        stored.extend_from_slice(&nonce);
+        stored.extend_from_slice(&ciphertext);
+        Ok(stored)
+    }
+
+    /// Decrypts a body that was previously returned from `encrypt`
+    pub fn decrypt(&self, id: &FloEventId, stored: &[u8]) -> io::Result<Vec<u8>> {
+        if stored.len() < NONCE_LEN + TAG_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Encrypted body of event: {} is too short", id)));
+        }
+        let (nonce, ciphertext) = stored.split_at(NONCE_LEN);
+        let nonce = GenericArray::from_slice(nonce);
+        let aad = associated_data(id);
+        let payload = Payload { msg: ciphertext, aad: &aad };
+
+        match self.cipher {
+            CipherImpl::ChaCha(ref cipher) => cipher.decrypt(nonce, payload),
+            CipherImpl::Aes(ref cipher) => cipher.decrypt(nonce, payload),
+        }.map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Failed to decrypt body of event: {}", id))
+        })
+    }
+}
+
+fn associated_data(id: &FloEventId) -> [u8; 10] {
+    let mut buf = [0; 10];
+    BigEndian::write_u16(&mut buf[..2], id.actor);
+    BigEndian::write_u64(&mut buf[2..], id.event_counter);
+    buf
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn options(cipher: EncryptionCipher) -> EncryptionOptions {
+        EncryptionOptions {
+            cipher: cipher,
+            key: [7; 32],
+        }
+    }
+
+    #[test]
+    fn body_is_encrypted_and_decrypted_with_each_cipher() {
+        for cipher in vec![EncryptionCipher::ChaCha20Poly1305, EncryptionCipher::Aes256Gcm] {
+            let subject = EventEncryptor::new(&options(cipher));
+            let id = FloEventId::new(2, 6);
+            let stored = subject.encrypt(&id, b"the quick brown fox").unwrap();
+            assert_eq!(19 + NONCE_LEN + TAG_LEN, stored.len());
+
+            let result = subject.decrypt(&id, &stored).unwrap();
+            assert_eq!(b"the quick brown fox".to_vec(), result);
+        }
+    }
+
+    #[test]
+    fn decrypt_returns_error_when_event_id_does_not_match() {
+        let subject = EventEncryptor::new(&options(EncryptionCipher::ChaCha20Poly1305));
+        let stored = subject.encrypt(&FloEventId::new(1, 1), b"data").unwrap();
+        assert!(subject.decrypt(&FloEventId::new(1, 2), &stored).is_err());
+    }
+}
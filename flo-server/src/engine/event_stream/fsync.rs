@@ -0,0 +1,193 @@
+//! Controls when partitions flush newly written events to disk. Events are written into memory mapped segment files, so
+//! once a produce is acknowledged its events survive the server process crashing, whatever the policy is. Syncing is what
+//! protects them from the operating system crashing or the machine losing power before the page cache is written back.
+//!
+//! - `Always` syncs before every produce is acknowledged, so an acknowledged event is never lost. This is the slowest
+//! - `EveryN(n)` syncs once at least `n` events have been written since the last sync. Up to `n - 1` acknowledged events
+//!   may be lost, and `EveryN(1)` is the same as `Always`
+//! - `Interval(duration)` syncs when events are written, or when the stream ticks, once `duration` has passed since the
+//!   last sync. Events acknowledged within roughly that long of a crash may be lost
+//! - `Never` leaves writing back entirely up to the operating system, except that partitions still sync when they're
+//!   truncated or shut down cleanly. It's the fastest, and is the default, but it's only appropriate for data that can
+//!   be thrown away or recreated
+//!
+//! Syncing happens after a produce's events have been appended. If the sync fails, the producer gets an error even though
+//! the events were appended and may already have been read by consumers, so retrying that produce duplicates its events.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::time::Instant;
+
+use chrono::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    Always,
+    EveryN(usize),
+    Interval(Duration),
+    Never,
+}
+
+impl Default for FsyncPolicy {
+    fn default() -> Self {
+        FsyncPolicy::Never
+    }
+}
+
+impl FsyncPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            FsyncPolicy::EveryN(0) => Err("fsync policy EveryN must sync after at least 1 event".to_owned()),
+            FsyncPolicy::Interval(duration) if duration <= Duration::zero() => {
+                Err("fsync policy Interval must be greater than 0".to_owned())
+            }
+            _ => Ok(())
+        }
+    }
+}
+
+/// Formats the policy as `always`, `never`, `every:<events>`, or `interval:<millis>`, which is what `from_str` accepts
+impl Display for FsyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FsyncPolicy::Always => write!(f, "always"),
+            FsyncPolicy::EveryN(event_count) => write!(f, "every:{}", event_count),
+            FsyncPolicy::Interval(duration) => write!(f, "interval:{}", duration.num_milliseconds()),
+            FsyncPolicy::Never => write!(f, "never"),
+        }
+    }
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid fsync policy: '{}', must be one of always, never, every:<events>, or interval:<millis>", s);
+        let policy = match s {
+            "always" => FsyncPolicy::Always,
+            "never" => FsyncPolicy::Never,
+            other @ _ if other.starts_with("every:") => {
+                FsyncPolicy::EveryN(other["every:".len()..].parse().map_err(|_| invalid())?)
+            }
+            other @ _ if other.starts_with("interval:") => {
+                FsyncPolicy::Interval(Duration::milliseconds(other["interval:".len()..].parse().map_err(|_| invalid())?))
+            }
+            _ => return Err(invalid())
+        };
+        policy.validate()?;
+        Ok(policy)
+    }
+}
+
+/// Tracks the writes to a single partition since it was last synced, in order to tell when the `FsyncPolicy` requires
+/// another sync
+#[derive(Debug)]
+pub struct FsyncSchedule {
+    policy: FsyncPolicy,
+    unsynced_events: usize,
+    last_sync: Instant,
+}
+
+impl FsyncSchedule {
+    pub fn new(policy: FsyncPolicy) -> FsyncSchedule {
+        FsyncSchedule {
+            policy: policy,
+            unsynced_events: 0,
+            last_sync: Instant::now(),
+        }
+    }
+
+    /// The number of events that have been written since the last sync
+    pub fn unsynced_events(&self) -> usize {
+        self.unsynced_events
+    }
+
+    /// Records that `event_count` events were just written, and returns true if the partition should now be synced
+    pub fn events_written(&mut self, event_count: usize, now: Instant) -> bool {
+        self.unsynced_events += event_count;
+        match self.policy {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::EveryN(n) => self.unsynced_events >= n,
+            FsyncPolicy::Interval(_) => self.is_interval_elapsed(now),
+            FsyncPolicy::Never => false,
+        }
+    }
+
+    /// Returns true if there are unsynced events that should be synced even though nothing new was written, so that an
+    /// `Interval` is still honored after writes stop
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.unsynced_events > 0 && self.is_interval_elapsed(now)
+    }
+
+    pub fn synced(&mut self, now: Instant) {
+        self.unsynced_events = 0;
+        self.last_sync = now;
+    }
+
+    fn is_interval_elapsed(&self, now: Instant) -> bool {
+        match self.policy {
+            FsyncPolicy::Interval(interval) => {
+                // validated to be positive, so it can always be converted
+                now.duration_since(self.last_sync) >= interval.to_std().unwrap()
+            }
+            _ => false
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration as StdDuration;
+
+    #[test]
+    fn every_n_is_due_once_n_events_have_been_written() {
+        let now = Instant::now();
+        let mut subject = FsyncSchedule::new(FsyncPolicy::EveryN(3));
+        assert!(!subject.events_written(2, now));
+        assert!(subject.events_written(1, now));
+        subject.synced(now);
+        assert_eq!(0, subject.unsynced_events());
+        assert!(subject.events_written(5, now));
+    }
+
+    #[test]
+    fn interval_is_due_once_it_has_elapsed_since_the_last_sync() {
+        let start = Instant::now();
+        let mut subject = FsyncSchedule::new(FsyncPolicy::Interval(Duration::milliseconds(100)));
+        subject.synced(start);
+        assert!(!subject.events_written(1, start + StdDuration::from_millis(50)));
+        assert!(!subject.is_due(start + StdDuration::from_millis(99)));
+        assert!(subject.is_due(start + StdDuration::from_millis(100)));
+
+        subject.synced(start + StdDuration::from_millis(100));
+        // nothing has been written since, so there's nothing to sync
+        assert!(!subject.is_due(start + StdDuration::from_millis(500)));
+    }
+
+    #[test]
+    fn always_and_never_ignore_the_number_of_events() {
+        let now = Instant::now();
+        assert!(FsyncSchedule::new(FsyncPolicy::Always).events_written(1, now));
+        let mut never = FsyncSchedule::new(FsyncPolicy::Never);
+        assert!(!never.events_written(1000, now));
+        assert!(!never.is_due(now + StdDuration::from_secs(3600)));
+    }
+
+    #[test]
+    fn policy_is_formatted_and_parsed() {
+        let policies = vec![
+            FsyncPolicy::Always,
+            FsyncPolicy::Never,
+            FsyncPolicy::EveryN(64),
+            FsyncPolicy::Interval(Duration::milliseconds(250)),
+        ];
+        for policy in policies {
+            assert_eq!(Ok(policy), policy.to_string().parse::<FsyncPolicy>());
+        }
+        assert!("every:0".parse::<FsyncPolicy>().is_err());
+        assert!("interval:0".parse::<FsyncPolicy>().is_err());
+        assert!("sometimes".parse::<FsyncPolicy>().is_err());
+    }
+}
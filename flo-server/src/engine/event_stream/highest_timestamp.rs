@@ -0,0 +1,89 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use event::{Timestamp, time};
+
+/// Tracks the greatest timestamp that's been assigned to any event in an event stream, in milliseconds since the unix
+/// epoch. This is shared between all the partitions in a stream so that event timestamps never go backwards, even if the
+/// system clock does.
+#[derive(Clone, Debug)]
+pub struct HighestTimestamp(Arc<AtomicUsize>);
+
+impl HighestTimestamp {
+    pub fn new() -> HighestTimestamp {
+        HighestTimestamp(Arc::new(AtomicUsize::new(0)))
+    }
+
+    pub fn set_if_greater(&self, timestamp: Timestamp) {
+        let millis = time::millis_since_epoch(timestamp) as usize;
+        let mut current = self.0.load(Ordering::SeqCst);
+        while millis > current {
+            match self.0.compare_exchange(current, millis, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => break,
+                Err(prev) => current = prev,
+            }
+        }
+    }
+
+    /// Returns the timestamp to assign to the next event, given the current time according to the clock. Normally this is
+    /// just `now`, but if the clock reads earlier than the last assigned timestamp, then one millisecond after the last
+    /// assigned timestamp is used instead.
+    pub fn next_timestamp(&self, now: Timestamp) -> Timestamp {
+        let now_millis = time::millis_since_epoch(now) as usize;
+        let mut current = self.0.load(Ordering::SeqCst);
+        loop {
+            let next = if now_millis < current {
+                current + 1
+            } else {
+                now_millis
+            };
+            match self.0.compare_exchange(current, next, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => {
+                    if next != now_millis {
+                        debug!("Clock reads: {} ms, which is before the last assigned timestamp: {} ms, so using: {} ms", now_millis, current, next);
+                    }
+                    return time::from_millis_since_epoch(next as u64);
+                }
+                Err(prev) => current = prev,
+            }
+        }
+    }
+
+    pub fn get(&self) -> Timestamp {
+        time::from_millis_since_epoch(self.0.load(Ordering::SeqCst) as u64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn next_timestamp_returns_now_when_the_clock_moves_forward() {
+        let subject = HighestTimestamp::new();
+        let now = time::from_millis_since_epoch(5000);
+        assert_eq!(now, subject.next_timestamp(now));
+        assert_eq!(now, subject.next_timestamp(now));
+
+        let later = time::from_millis_since_epoch(6000);
+        assert_eq!(later, subject.next_timestamp(later));
+        assert_eq!(later, subject.get());
+    }
+
+    #[test]
+    fn next_timestamp_never_goes_backwards_when_the_clock_does() {
+        let subject = HighestTimestamp::new();
+        subject.next_timestamp(time::from_millis_since_epoch(5000));
+
+        assert_eq!(time::from_millis_since_epoch(5001), subject.next_timestamp(time::from_millis_since_epoch(3000)));
+        assert_eq!(time::from_millis_since_epoch(5002), subject.next_timestamp(time::from_millis_since_epoch(3001)));
+    }
+
+    #[test]
+    fn set_if_greater_only_moves_forward() {
+        let subject = HighestTimestamp::new();
+        subject.set_if_greater(time::from_millis_since_epoch(5000));
+        subject.set_if_greater(time::from_millis_since_epoch(4000));
+        assert_eq!(time::from_millis_since_epoch(5000), subject.get());
+    }
+}
@@ -1,26 +1,69 @@
 pub mod partition;
+pub mod encryption;
+mod ack_subscribers;
+mod ancestry;
+mod fsync;
 mod highest_counter;
+mod highest_timestamp;
+mod tags;
+mod verify;
 
 use std::path::{PathBuf, Path};
+use std::collections::{HashMap, HashSet};
 use std::io;
 
 use tokio_core::reactor::Remote;
-use futures::{Sink, Async, AsyncSink, StartSend, Poll};
+use futures::{Future, Sink, Async, AsyncSink, StartSend, Poll};
 use chrono::Duration;
 
-use event::ActorId;
-use self::partition::{PartitionRef, initialize_existing_partition, initialize_new_partition};
+use event::{ActorId, EventCounter, FloEventId, FloEvent};
+use self::partition::{PartitionRef, PartitionReader, PersistentEvent, EventFilter, ScanReaders, initialize_existing_partition, initialize_new_partition};
 use atomics::AtomicBoolReader;
+use metrics::StreamMetrics;
+use engine::ConnectionId;
+use protocol::{StreamDescriptor, IntegrityReport};
 
 pub use self::highest_counter::HighestCounter;
+pub use self::highest_timestamp::HighestTimestamp;
+pub use self::ack_subscribers::{AckSubscribers, AckSubscription};
+pub use self::tags::StreamTags;
+pub use self::verify::VerifyOptions;
+pub use self::ancestry::{AncestryFuture, MAX_ANCESTRY_DEPTH};
+pub use self::encryption::{EncryptionOptions, EncryptionCipher};
+pub use self::fsync::{FsyncPolicy, FsyncSchedule};
 
-#[derive(Debug, PartialEq)]
+/// Completes once every partition in the stream has been truncated
+pub type TruncateFuture = Box<Future<Item=(), Error=io::Error> + Send>;
+
+/// Completes with a description of the event stream once every partition has been read
+pub type DescribeFuture = Box<Future<Item=StreamDescriptor, Error=io::Error> + Send>;
+
+/// Completes with the number of matching events once every partition has been read
+pub type CountFuture = Box<Future<Item=u64, Error=io::Error> + Send>;
+
+/// Completes once the requested events in every partition have been verified. The `op_id` of the report is always 0
+pub type VerifyFuture = Box<Future<Item=IntegrityReport, Error=io::Error> + Send>;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct EventStreamOptions {
     pub name: String,
     pub num_partitions: u16,
     pub event_retention: Duration,
     pub max_segment_duration: Duration,
     pub segment_max_size_bytes: usize,
+    /// If present, the bodies of all events in the stream will be encrypted on disk
+    pub encryption: Option<EncryptionOptions>,
+    /// If true, then produced events with a `parent_id` are rejected unless the parent event exists in the stream
+    pub validate_parent: bool,
+    /// The event counter that will be assigned to the first event produced to the stream. Setting this higher than 1 is
+    /// useful when migrating data into a fresh stream, so that new ids can't collide with ones from a previous incarnation
+    pub starting_counter: u64,
+    /// If present, cursors that have been open for longer than this are stopped by the server, even if they're still
+    /// receiving events. Consumers may opt out of this when they start, which is appropriate for live tailing
+    pub max_cursor_lifetime: Option<Duration>,
+    /// Controls how often each partition syncs newly written events to disk, trading throughput for durability if the
+    /// machine crashes. See the `fsync` module for what each policy guarantees
+    pub fsync_policy: FsyncPolicy,
 }
 
 
@@ -32,6 +75,11 @@ impl Default for EventStreamOptions {
             event_retention: Duration::max_value(),     // For-ev-er
             max_segment_duration: Duration::days(1),    // 24 hours
             segment_max_size_bytes: 1024 * 1024 * 1024, // 1GB
+            encryption: None,
+            validate_parent: false,
+            starting_counter: 1,
+            max_cursor_lifetime: None,
+            fsync_policy: FsyncPolicy::default(),
         }
     }
 }
@@ -40,6 +88,33 @@ impl EventStreamOptions {
     pub fn get_tick_interval(&self) -> Duration {
         self.max_segment_duration / 3
     }
+
+    pub fn validate(&self) -> io::Result<()> {
+        if self.num_partitions == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid options for event stream: '{}', num_partitions must be greater than 0", self.name)));
+        }
+        if self.segment_max_size_bytes == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid options for event stream: '{}', segment_max_size_bytes must be greater than 0", self.name)));
+        }
+        if self.max_segment_duration <= Duration::zero() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid options for event stream: '{}', max_segment_duration must be greater than 0", self.name)));
+        }
+        if self.starting_counter == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid options for event stream: '{}', starting_counter must be greater than 0", self.name)));
+        }
+        if self.max_cursor_lifetime.map(|lifetime| lifetime <= Duration::zero()).unwrap_or(false) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid options for event stream: '{}', max_cursor_lifetime must be greater than 0", self.name)));
+        }
+        if let Err(description) = self.fsync_policy.validate() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid options for event stream: '{}', {}", self.name, description)));
+        }
+        Ok(())
+    }
+
+    /// The value of the stream's `HighestCounter` before any events have been produced
+    fn initial_counter(&self) -> HighestCounter {
+        HighestCounter::starting_at(self.starting_counter - 1)
+    }
 }
 
 
@@ -47,14 +122,17 @@ impl EventStreamOptions {
 pub fn init_existing_event_stream(event_stream_storage_dir: PathBuf, options: EventStreamOptions, status_reader: AtomicBoolReader, remote: Remote) -> Result<EventStreamRef, io::Error> {
 
     debug!("Starting initialization of existing event stream with: {:?}", &options);
+    options.validate()?;
     let partition_numbers = determine_existing_partition_dirs(&event_stream_storage_dir)?;
     debug!("Initializing {} partition(s)", partition_numbers.len());
 
-    let highest_counter = HighestCounter::zero();
+    let highest_counter = options.initial_counter();
+    let highest_timestamp = HighestTimestamp::new();
+    let ack_subscribers = AckSubscribers::new();
 
     let mut partition_refs = Vec::with_capacity(partition_numbers.len());
     for partition_num in partition_numbers {
-        let partition_ref = initialize_existing_partition(partition_num, &event_stream_storage_dir, &options, status_reader.clone(), highest_counter.clone())?;
+        let partition_ref = initialize_existing_partition(partition_num, &event_stream_storage_dir, &options, status_reader.clone(), highest_counter.clone(), highest_timestamp.clone(), ack_subscribers.clone())?;
         partition_refs.push(partition_ref);
     }
 
@@ -64,6 +142,11 @@ pub fn init_existing_event_stream(event_stream_storage_dir: PathBuf, options: Ev
     let event_stream = EventStreamRef {
         name: options.name,
         partitions: partition_refs,
+        validate_parent: options.validate_parent,
+        max_cursor_lifetime: options.max_cursor_lifetime,
+        ack_subscribers: ack_subscribers,
+        tags: StreamTags::new(),
+        metrics: StreamMetrics::new(),
     };
 
     start_tick_timer(remote, event_stream.clone(), tick_interval);
@@ -74,25 +157,33 @@ pub fn init_existing_event_stream(event_stream_storage_dir: PathBuf, options: Ev
 pub fn init_new_event_stream(event_stream_storage_dir: PathBuf, options: EventStreamOptions, status_reader: AtomicBoolReader, remote: Remote) -> Result<EventStreamRef, io::Error> {
 
     debug!("Starting initialization of new event stream with: {:?}", &options);
+    options.validate()?;
     let partition_count = options.num_partitions;
     ::std::fs::create_dir_all(&event_stream_storage_dir)?;
 
     let mut partition_refs: Vec<PartitionRef> = Vec::with_capacity(partition_count as usize);
-    let highest_counter = HighestCounter::zero();
+    let highest_counter = options.initial_counter();
+    let highest_timestamp = HighestTimestamp::new();
+    let ack_subscribers = AckSubscribers::new();
     for i in 0..partition_count {
         let partition_num: ActorId = i + 1;
-        let partition_ref = initialize_new_partition(partition_num, &event_stream_storage_dir, &options, status_reader.clone(), highest_counter.clone())?;
+        let partition_ref = initialize_new_partition(partition_num, &event_stream_storage_dir, &options, status_reader.clone(), highest_counter.clone(), highest_timestamp.clone(), ack_subscribers.clone())?;
 
         // We're appending these in order so that they can be indexed up by partition number later
         partition_refs.push(partition_ref);
     }
 
     let tick_interval = options.get_tick_interval();
-    let EventStreamOptions{name, ..} = options;
+    let EventStreamOptions{name, validate_parent, max_cursor_lifetime, ..} = options;
     debug!("Finished initializing {} partitions for event stream: '{}'", partition_count, &name);
     let event_stream = EventStreamRef {
         name: name,
         partitions: partition_refs,
+        validate_parent: validate_parent,
+        max_cursor_lifetime: max_cursor_lifetime,
+        ack_subscribers: ack_subscribers,
+        tags: StreamTags::new(),
+        metrics: StreamMetrics::new(),
     };
     start_tick_timer(remote, event_stream.clone(), tick_interval);
     Ok(event_stream)
@@ -129,6 +220,11 @@ fn determine_existing_partition_dirs(event_stream_dir: &Path) -> io::Result<Vec<
 pub struct EventStreamRef {
     name: String,
     partitions: Vec<PartitionRef>,
+    validate_parent: bool,
+    max_cursor_lifetime: Option<Duration>,
+    ack_subscribers: AckSubscribers,
+    tags: StreamTags,
+    metrics: StreamMetrics,
 }
 
 impl EventStreamRef {
@@ -136,6 +232,11 @@ impl EventStreamRef {
         EventStreamRef {
             name: name,
             partitions: partitions,
+            validate_parent: false,
+            max_cursor_lifetime: None,
+            ack_subscribers: AckSubscribers::new(),
+            tags: StreamTags::new(),
+            metrics: StreamMetrics::new(),
         }
     }
 
@@ -143,6 +244,31 @@ impl EventStreamRef {
         &self.name
     }
 
+    /// Whether produced events must have a `parent_id` that refers to an existing event in this stream
+    pub fn validates_parent(&self) -> bool {
+        self.validate_parent
+    }
+
+    /// The longest that a cursor may stay open before the server stops it, unless the consumer opted out
+    pub fn max_cursor_lifetime(&self) -> Option<Duration> {
+        self.max_cursor_lifetime
+    }
+
+    /// Returns a `Stream` of the id of every event as it's persisted to any partition in this stream. Ids are dropped for a
+    /// subscription that falls too far behind, rather than holding up the partitions
+    pub fn subscribe_acks(&self) -> AckSubscription {
+        self.ack_subscribers.subscribe()
+    }
+
+    pub fn tags(&self) -> &StreamTags {
+        &self.tags
+    }
+
+    /// Counters of the event data transferred between clients and this stream, shared by every connection that uses it
+    pub fn metrics(&self) -> &StreamMetrics {
+        &self.metrics
+    }
+
     pub fn get_partition_count(&self) -> ActorId {
         self.partitions.len() as ActorId
     }
@@ -151,11 +277,305 @@ impl EventStreamRef {
         &self.partitions
     }
 
+    /// The total number of bytes stored on disk for all partitions in the stream. This goes down as old segments expire
+    /// or the stream is truncated
+    pub fn stored_bytes(&self) -> u64 {
+        self.partitions.iter().map(|p| p.stored_bytes()).sum()
+    }
+
     pub fn get_partition(&mut self, partition: ActorId) -> Option<&mut PartitionRef> {
         self.partitions.get_mut(partition as usize - 1)
     }
+
+    /// Returns all the events in this stream that are not covered by the given version vector, in order of their ids.
+    /// Partitions that are not represented in the version vector are read from the beginning. Remote clients get the same
+    /// events by sending a `NewStartConsuming` with their version vector.
+    /// Events are read lazily, one partition reader per partition, and only events that had already been persisted when
+    /// this was called are returned. This blocks while waiting on each partition, so it must not be called from an event
+    /// loop thread
+    pub fn events_since(&self, version_vector: &[FloEventId]) -> io::Result<EventsSince> {
+        let end_counter = self.partitions.iter().map(|p| p.get_highest_event_counter()).max().unwrap_or(0);
+        let mut readers = Vec::with_capacity(self.partitions.len());
+        for partition in self.partitions.iter() {
+            let partition_num = partition.partition_num();
+            let start = version_vector.iter()
+                    .find(|id| id.actor == partition_num)
+                    .map(|id| id.event_counter)
+                    .unwrap_or(0);
+
+            let reader = partition.read(0, EventFilter::All, start).ok().and_then(|receiver| {
+                receiver.wait().ok()
+            }).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::BrokenPipe, format!("Partition: {} of event stream: '{}' has shut down", partition_num, self.name))
+            })?;
+            readers.push(reader);
+        }
+        Ok(EventsSince {
+            heads: readers.iter().map(|_| None).collect(),
+            readers: readers.into_iter().map(Some).collect(),
+            end_counter: end_counter,
+        })
+    }
+
+    /// Removes all events with ids greater than `up_to` from every partition in the stream. Each partition is truncated
+    /// independently, so if one of them rejects the truncation (because it has active consumers), the others may still
+    /// have been truncated.
+    pub fn truncate(&self, connection_id: ConnectionId, up_to: FloEventId) -> TruncateFuture {
+        use futures::future;
+
+        let mut receivers = Vec::with_capacity(self.partitions.len());
+        for partition in self.partitions.iter() {
+            let partition_num = partition.partition_num();
+            match partition.truncate(connection_id, up_to.event_counter) {
+                Ok(receiver) => {
+                    receivers.push(receiver.then(move |result| {
+                        result.unwrap_or_else(|_| {
+                            Err(io::Error::new(io::ErrorKind::BrokenPipe, format!("Partition: {} shut down before completing truncate", partition_num)))
+                        })
+                    }));
+                }
+                Err(err) => {
+                    let description = format!("Failed to send truncate to partition: {}: {:?}", partition_num, err);
+                    return Box::new(future::err(io::Error::new(io::ErrorKind::Other, description)));
+                }
+            }
+        }
+        Box::new(future::join_all(receivers).map(|_| ()))
+    }
+
+    /// Returns a description of the stream, including the number of events and the ids of the oldest and newest events.
+    /// There's no index of event counts, so each partition reads through all of its events on its own thread.
+    pub fn describe(&self) -> DescribeFuture {
+        use futures::future;
+
+        let mut scans = Vec::with_capacity(self.partitions.len());
+        for partition in self.partitions.iter() {
+            // returns the number of events in the partition, along with the ids of the first and last ones
+            let scan = scan_partition(partition, |readers| -> io::Result<(u64, FloEventId, FloEventId)> {
+                let mut summary = (0, FloEventId::zero(), FloEventId::zero());
+                for result in readers.reader(EventFilter::All, 0) {
+                    let id = *result?.id();
+                    if summary.0 == 0 {
+                        summary.1 = id;
+                    }
+                    summary.0 += 1;
+                    summary.2 = id;
+                }
+                Ok(summary)
+            });
+            match scan {
+                Ok(future) => scans.push(future),
+                Err(io_err) => return Box::new(future::err(io_err)),
+            }
+        }
+
+        let name = self.name.clone();
+        let writable = self.partitions.iter().any(|p| p.is_primary());
+        let partitions = self.partitions.clone();
+        Box::new(future::join_all(scans).and_then(move |summaries| {
+            let mut descriptor = StreamDescriptor {
+                name: name,
+                event_count: 0,
+                head: FloEventId::zero(),
+                tail: FloEventId::zero(),
+                writable: writable,
+                stored_bytes: 0,
+            };
+            for summary in summaries {
+                let (count, first, last) = summary?;
+                if count == 0 {
+                    continue;
+                }
+                if descriptor.event_count == 0 || first < descriptor.tail {
+                    descriptor.tail = first;
+                }
+                if last > descriptor.head {
+                    descriptor.head = last;
+                }
+                descriptor.event_count += count;
+            }
+            // read this after the partitions are read so that it includes every event they saw
+            descriptor.stored_bytes = partitions.iter().map(|p| p.stored_bytes()).sum();
+            Ok(descriptor)
+        }))
+    }
+
+    /// Counts the events in every partition that match the filter and have a counter greater than `start`. There's no index
+    /// of namespaces, so each partition reads through every one of those events on its own thread, and only the counts are
+    /// sent back
+    pub fn count_events(&self, filter: EventFilter, start: EventCounter) -> CountFuture {
+        self.count_matching(filter, |_| start)
+    }
+
+    /// Counts the events that match the filter and aren't covered by the version vector, which is how far a consumer at
+    /// that position is behind the head of the stream. The counts from every partition are summed, so the filter may match
+    /// events in any of them. Partitions that aren't represented in the version vector are counted from the beginning
+    pub fn count_events_since(&self, filter: EventFilter, version_vector: &[FloEventId]) -> CountFuture {
+        self.count_matching(filter, |partition_num| {
+            version_vector.iter()
+                    .find(|id| id.actor == partition_num)
+                    .map(|id| id.event_counter)
+                    .unwrap_or(0)
+        })
+    }
+
+    fn count_matching<F: Fn(ActorId) -> EventCounter>(&self, filter: EventFilter, start_for: F) -> CountFuture {
+        use futures::future;
+
+        let mut scans = Vec::with_capacity(self.partitions.len());
+        for partition in self.partitions.iter() {
+            let filter = filter.clone();
+            let start = start_for(partition.partition_num());
+            let scan = scan_partition(partition, move |readers| -> io::Result<u64> {
+                let mut count = 0;
+                for result in readers.reader(filter, start) {
+                    result?;
+                    count += 1;
+                }
+                Ok(count)
+            });
+            match scan {
+                Ok(future) => scans.push(future),
+                Err(io_err) => return Box::new(future::err(io_err)),
+            }
+        }
+
+        Box::new(future::join_all(scans).and_then(|counts| {
+            counts.into_iter().sum::<io::Result<u64>>()
+        }))
+    }
+
+    /// Checks the integrity of the events in the stream, without modifying anything. Every event is read to make sure that
+    /// its framing and lengths are intact, and that events in each partition have increasing counters. Bodies of encrypted
+    /// streams are also authenticated, but events in unencrypted streams have no checksums, so their contents can't be
+    /// verified. Each partition is read on its own thread, which can't write any events until it's finished, so large
+    /// streams should be verified in chunks using `max_events`, resuming each time from the previous report's
+    /// `last_verified` ids.
+    pub fn verify(&self, options: VerifyOptions) -> VerifyFuture {
+        use futures::future;
+
+        if options.max_events == Some(0) {
+            let description = "max_events must be greater than 0, or None to verify every event";
+            return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidInput, description)));
+        }
+
+        let partition_nums = self.partitions.iter().map(|p| p.partition_num()).collect::<Vec<_>>();
+        let mut scans = Vec::with_capacity(self.partitions.len());
+        for partition in self.partitions.iter() {
+            let partition_num = partition.partition_num();
+            let all_partitions = partition_nums.clone();
+            let options = options.clone();
+            let scan = scan_partition(partition, move |readers| {
+                let events = readers.reader(EventFilter::All, options.start_for(partition_num));
+                verify::verify_partition(partition_num, events, &all_partitions, &options)
+            });
+            match scan {
+                Ok(future) => scans.push(future),
+                Err(io_err) => return Box::new(future::err(io_err)),
+            }
+        }
+
+        let name = self.name.clone();
+        let partitions = self.partitions.clone();
+        Box::new(future::join_all(scans).and_then(move |verifications| {
+            find_missing_parents(&partitions, &verifications).map(move |missing| {
+                IntegrityReport {
+                    op_id: 0,
+                    stream: name,
+                    partitions: verifications.into_iter().map(|v| v.finish(&missing)).collect(),
+                }
+            })
+        }))
+    }
+
+    /// Reads the event with the given id, followed by its parent, and so on back to the root of the chain. See
+    /// `ancestry::read_ancestry` for the ways in which the chain can end early
+    pub fn read_ancestry(&self, event_id: FloEventId, max_depth: usize) -> AncestryFuture {
+        ancestry::read_ancestry(self.partitions.clone(), event_id, max_depth)
+    }
+}
+
+
+/// An `Iterator` over the events from every partition of a stream, in order of their ids, as returned by
+/// `EventStreamRef::events_since`. Each partition is read lazily, so only one event per partition is held at a time.
+pub struct EventsSince {
+    readers: Vec<Option<PartitionReader>>,
+    heads: Vec<Option<PersistentEvent>>,
+    end_counter: EventCounter,
+}
+
+impl Iterator for EventsSince {
+    type Item = io::Result<PersistentEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (reader, head) in self.readers.iter_mut().zip(self.heads.iter_mut()) {
+            if head.is_none() {
+                match reader.as_mut().and_then(|r| r.next()) {
+                    Some(Ok(event)) => {
+                        // counters only increase within a partition, so once one event is past the end, they all are
+                        if event.id().event_counter <= self.end_counter {
+                            *head = Some(event);
+                        } else {
+                            *reader = None;
+                        }
+                    }
+                    Some(Err(err)) => return Some(Err(err)),
+                    None => *reader = None,
+                }
+            }
+        }
+
+        let next_index = self.heads.iter().enumerate()
+                .filter_map(|(index, head)| head.as_ref().map(|event| (index, *event.id())))
+                .min_by_key(|&(_, id)| id)
+                .map(|(index, _)| index);
+        next_index.and_then(|index| self.heads[index].take()).map(Ok)
+    }
+}
+
+/// Runs `fun` on the partition's own thread, and returns a future of its result
+fn scan_partition<F, T>(partition: &PartitionRef, fun: F) -> io::Result<Box<Future<Item=T, Error=io::Error> + Send>>
+        where F: FnOnce(&mut ScanReaders) -> T + Send + 'static,
+              T: Send + 'static {
+    let partition_num = partition.partition_num();
+    match partition.scan(0, fun) {
+        Ok(receiver) => {
+            Ok(Box::new(receiver.map_err(move |_| {
+                io::Error::new(io::ErrorKind::BrokenPipe, format!("Partition: {} shut down before finishing scan", partition_num))
+            })))
+        }
+        Err(err) => {
+            let description = format!("Failed to send scan to partition: {}: {:?}", partition_num, err);
+            Err(io::Error::new(io::ErrorKind::Other, description))
+        }
+    }
 }
 
+/// Looks up the parents that were found by verifying each partition, and returns the ids of the ones that don't exist.
+/// Each parent is looked up on the thread of the partition that it belongs to
+fn find_missing_parents(partitions: &[PartitionRef], verifications: &[verify::PartitionVerification]) -> Box<Future<Item=HashSet<FloEventId>, Error=io::Error> + Send> {
+    use futures::future;
+
+    let mut parents_by_partition: HashMap<ActorId, Vec<FloEventId>> = HashMap::new();
+    for &(_, parent) in verifications.iter().flat_map(|v| v.parents.iter()) {
+        parents_by_partition.entry(parent.actor).or_insert_with(Vec::new).push(parent);
+    }
+
+    let mut lookups = Vec::with_capacity(parents_by_partition.len());
+    for (partition_num, ids) in parents_by_partition {
+        let partition = match partitions.iter().find(|p| p.partition_num() == partition_num) {
+            Some(partition) => partition,
+            None => continue, // verify_partition only returns parents in one of the stream's partitions
+        };
+        match scan_partition(partition, move |readers| verify::find_missing_events(readers, ids)) {
+            Ok(future) => lookups.push(future),
+            Err(io_err) => return Box::new(future::err(io_err)),
+        }
+    }
+    Box::new(future::join_all(lookups).map(|missing| {
+        missing.into_iter().flat_map(|ids| ids.into_iter()).collect()
+    }))
+}
 
 fn start_tick_timer(remote: Remote, event_stream: EventStreamRef, tick_interval: Duration) {
     use tokio_core::reactor::Interval;
@@ -178,9 +598,17 @@ impl Sink for TickTimerSink {
 
     fn start_send(&mut self, _item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
         for partition in self.0.partitions.iter_mut() {
-            partition.tick().map_err(|io_err| {
-                error!("Failed to send Tick operation to Partition: {}, {:?}", partition.partition_num(), io_err);
-            })?;
+            match partition.tick() {
+                Ok(()) => {}
+                Err(ref err) if err.is_full() => {
+                    // the partition is behind on its queue, so it'll get the next tick instead of this one
+                    debug!("Skipping Tick for Partition: {} because its queue is full", partition.partition_num());
+                }
+                Err(err) => {
+                    error!("Failed to send Tick operation to Partition: {}, {:?}", partition.partition_num(), err);
+                    return Err(());
+                }
+            }
         }
         Ok(AsyncSink::Ready)
     }
@@ -195,4 +623,483 @@ impl Sink for TickTimerSink {
 }
 
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+    use tokio_core::reactor::Core;
+
+    use protocol::ProduceEvent;
+    use atomics::AtomicBoolWriter;
 
+    /// Holds everything that has to outlive a stream that was created by `new_stream`
+    struct StreamFixture {
+        tempdir: TempDir,
+        _core: Core,
+        _status: AtomicBoolWriter,
+    }
+
+    fn new_stream(options: EventStreamOptions) -> (StreamFixture, EventStreamRef) {
+        let tempdir = TempDir::new(&options.name).unwrap();
+        let core = Core::new().unwrap();
+        let status = AtomicBoolWriter::with_value(true);
+        let stream = init_new_event_stream(tempdir.path().to_owned(), options, status.reader(), core.remote()).unwrap();
+        let fixture = StreamFixture {
+            tempdir: tempdir,
+            _core: core,
+            _status: status,
+        };
+        (fixture, stream)
+    }
+
+    fn produce_event(namespace: &str, data: &[u8]) -> ProduceEvent {
+        ProduceEvent {
+            namespace: namespace.to_owned(),
+            data: data.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn produce_events(stream: &mut EventStreamRef, partition: ActorId, mut events: Vec<ProduceEvent>) {
+        for event in events.iter_mut() {
+            event.partition = partition;
+        }
+        stream.get_partition(partition).unwrap()
+                .produce(1, 1, events).expect("failed to send produce")
+                .wait().expect("failed to receive produce result")
+                .expect("failed to produce");
+    }
+
+    fn produce(stream: &mut EventStreamRef, partition: ActorId, count: usize) {
+        let events = (0..count).map(|_| produce_event("/foo", b"data")).collect();
+        produce_events(stream, partition, events);
+    }
+
+    fn produce_child(stream: &mut EventStreamRef, partition: ActorId, parent_id: Option<FloEventId>) {
+        let event = ProduceEvent {
+            parent_id: parent_id,
+            ..produce_event("/foo", b"data")
+        };
+        produce_events(stream, partition, vec![event]);
+    }
+
+    fn ancestry_ids(stream: &EventStreamRef, event_id: FloEventId, max_depth: usize) -> Vec<FloEventId> {
+        stream.read_ancestry(event_id, max_depth).wait().expect("failed to read ancestry")
+                .iter()
+                .map(|event| *event.id())
+                .collect()
+    }
+
+    #[test]
+    fn read_ancestry_follows_parent_ids_across_partitions_back_to_the_root() {
+        let options = EventStreamOptions {
+            name: "read_ancestry".to_owned(),
+            num_partitions: 2,
+            ..Default::default()
+        };
+        let (_fixture, mut stream) = new_stream(options);
+
+        let a = FloEventId::new(1, 1);
+        let b = FloEventId::new(2, 2);
+        let c = FloEventId::new(1, 3);
+        produce_child(&mut stream, 1, None);
+        produce_child(&mut stream, 2, Some(a));
+        produce_child(&mut stream, 1, Some(b));
+
+        assert_eq!(vec![c, b, a], ancestry_ids(&stream, c, MAX_ANCESTRY_DEPTH));
+        assert_eq!(vec![b, a], ancestry_ids(&stream, b, MAX_ANCESTRY_DEPTH));
+        assert_eq!(vec![c, b], ancestry_ids(&stream, c, 2));
+        assert!(ancestry_ids(&stream, FloEventId::new(1, 2), MAX_ANCESTRY_DEPTH).is_empty());
+        assert!(ancestry_ids(&stream, FloEventId::new(1, 99), MAX_ANCESTRY_DEPTH).is_empty());
+    }
+
+    #[test]
+    fn read_ancestry_stops_when_parent_ids_form_a_cycle() {
+        let options = EventStreamOptions {
+            name: "read_ancestry_cycle".to_owned(),
+            ..Default::default()
+        };
+        let (_fixture, mut stream) = new_stream(options);
+
+        // parents aren't validated, so the first event can name the second one as its parent
+        let first = FloEventId::new(1, 1);
+        let second = FloEventId::new(1, 2);
+        produce_child(&mut stream, 1, Some(second));
+        produce_child(&mut stream, 1, Some(first));
+
+        assert_eq!(vec![second, first], ancestry_ids(&stream, second, MAX_ANCESTRY_DEPTH));
+        assert_eq!(vec![first, second], ancestry_ids(&stream, first, MAX_ANCESTRY_DEPTH));
+    }
+
+    #[test]
+    fn events_since_returns_only_events_not_covered_by_version_vector() {
+        let options = EventStreamOptions {
+            name: "events_since".to_owned(),
+            num_partitions: 2,
+            ..Default::default()
+        };
+        let (_fixture, mut stream) = new_stream(options);
+
+        produce(&mut stream, 1, 3); // 1.1 - 3.1
+        produce(&mut stream, 2, 3); // 4.2 - 6.2
+        produce(&mut stream, 1, 2); // 7.1 - 8.1
+
+        let version_vector = vec![FloEventId::new(1, 2), FloEventId::new(2, 5)];
+        let result = stream.events_since(&version_vector).unwrap()
+                .map(|event| *event.unwrap().id())
+                .collect::<Vec<_>>();
+
+        let expected = vec![
+            FloEventId::new(1, 3),
+            FloEventId::new(2, 6),
+            FloEventId::new(1, 7),
+            FloEventId::new(1, 8),
+        ];
+        assert_eq!(expected, result);
+
+        let all = stream.events_since(&[]).unwrap().count();
+        assert_eq!(8, all);
+
+        // events produced while reading are left out, so piping a stream into itself can't go on forever
+        let mut reading = stream.events_since(&[]).unwrap();
+        assert_eq!(FloEventId::new(1, 1), *reading.next().unwrap().unwrap().id());
+        produce(&mut stream, 2, 2); // 9.2 - 10.2
+        assert_eq!(7, reading.count());
+    }
+
+    #[test]
+    fn truncate_removes_all_events_after_the_given_id() {
+        let options = EventStreamOptions {
+            name: "truncate".to_owned(),
+            num_partitions: 1,
+            segment_max_size_bytes: 256,
+            ..Default::default()
+        };
+        let (_fixture, mut stream) = new_stream(options);
+        for _ in 0..10 {
+            produce(&mut stream, 1, 1);
+        }
+        assert_eq!(10, stream.events_since(&[]).unwrap().count());
+
+        stream.truncate(1, FloEventId::new(1, 5)).wait().expect("failed to truncate");
+
+        let result = stream.events_since(&[]).unwrap().map(|e| *e.unwrap().id()).collect::<Vec<_>>();
+        let expected = (1..6).map(|i| FloEventId::new(1, i)).collect::<Vec<_>>();
+        assert_eq!(expected, result);
+        assert_eq!(5, stream.partitions()[0].get_highest_event_counter());
+
+        // ids of the discarded events are never reused
+        produce(&mut stream, 1, 1);
+        let last = stream.events_since(&[FloEventId::new(1, 5)]).unwrap().map(|e| *e.unwrap().id()).collect::<Vec<_>>();
+        assert_eq!(vec![FloEventId::new(1, 11)], last);
+    }
+
+    #[test]
+    fn truncate_is_rejected_only_for_consumers_that_have_read_past_the_given_id() {
+        use engine::event_stream::partition::ConsumerNotifier;
+        use atomics::AtomicBoolReader;
+
+        struct TestNotifier(AtomicBoolReader);
+        impl ConsumerNotifier for TestNotifier {
+            fn notify(&self) {}
+            fn is_active(&self) -> bool { self.0.get_relaxed() }
+            fn connection_id(&self) -> ConnectionId { 2 }
+            fn op_id(&self) -> u32 { 1 }
+        }
+
+        let options = EventStreamOptions {
+            name: "truncate_with_consumers".to_owned(),
+            num_partitions: 1,
+            segment_max_size_bytes: 256,
+            ..Default::default()
+        };
+        let (_fixture, mut stream) = new_stream(options);
+        for _ in 0..10 {
+            produce(&mut stream, 1, 1);
+        }
+
+        let mut active = AtomicBoolWriter::with_value(true);
+        let mut partition = stream.get_partition(1).unwrap().clone();
+        let mut reader = partition.consume(2, 1, Box::new(TestNotifier(active.reader())), EventFilter::All, 0).unwrap().wait().unwrap();
+        assert_eq!(FloEventId::new(1, 1), *reader.next().unwrap().unwrap().id());
+
+        // the consumer is positioned before the truncation point, so it only stops seeing the discarded events
+        stream.truncate(1, FloEventId::new(1, 8)).wait().expect("failed to truncate");
+        let rest = reader.by_ref().map(|e| e.unwrap().id().event_counter).collect::<Vec<_>>();
+        assert_eq!((2..9).collect::<Vec<_>>(), rest);
+
+        // now that it's read past 5, the consumer must prevent truncating to 5
+        let err = stream.truncate(1, FloEventId::new(1, 5)).wait().unwrap_err();
+        assert_eq!(io::ErrorKind::Other, err.kind());
+        assert_eq!(8, stream.events_since(&[]).unwrap().count());
+
+        active.set(false);
+        drop(reader);
+        stream.truncate(1, FloEventId::new(1, 5)).wait().expect("failed to truncate after the consumer stopped");
+        assert_eq!(5, stream.events_since(&[]).unwrap().count());
+    }
+
+    #[test]
+    fn truncate_is_rejected_while_events_that_would_be_discarded_are_still_referenced() {
+        let options = EventStreamOptions {
+            name: "truncate_with_readers".to_owned(),
+            num_partitions: 1,
+            ..Default::default()
+        };
+        let (_fixture, mut stream) = new_stream(options);
+        for _ in 0..10 {
+            produce(&mut stream, 1, 1);
+        }
+
+        let event = stream.partitions()[0].read(1, EventFilter::All, 7).unwrap().wait().unwrap().next().unwrap().unwrap();
+        assert!(stream.truncate(1, FloEventId::new(1, 5)).wait().is_err());
+        assert_eq!(FloEventId::new(1, 8), *event.id());
+        assert_eq!(b"data", event.data());
+
+        drop(event);
+        stream.truncate(1, FloEventId::new(1, 5)).wait().expect("failed to truncate");
+        assert_eq!(5, stream.events_since(&[]).unwrap().count());
+    }
+
+    #[test]
+    fn verify_reports_no_problems_for_a_healthy_stream_and_can_resume_from_last_verified() {
+        let options = EventStreamOptions {
+            name: "verify_healthy".to_owned(),
+            num_partitions: 2,
+            ..Default::default()
+        };
+        let (_fixture, mut stream) = new_stream(options);
+        produce(&mut stream, 1, 3); // 1.1 - 3.1
+        produce(&mut stream, 2, 2); // 4.2 - 5.2
+
+        let options = VerifyOptions { check_parents: true, ..Default::default() };
+        let report = stream.verify(options).wait().expect("failed to verify");
+        assert!(report.is_clean());
+        assert!(report.is_complete());
+        let checked = report.partitions.iter().map(|p| (p.partition_num, p.events_checked, p.last_verified)).collect::<Vec<_>>();
+        assert_eq!(vec![(1, 3, FloEventId::new(1, 3)), (2, 2, FloEventId::new(2, 5))], checked);
+
+        let options = VerifyOptions { max_events: Some(2), ..Default::default() };
+        let partial = stream.verify(options).wait().expect("failed to verify");
+        assert!(!partial.partitions[0].complete);
+        assert_eq!(FloEventId::new(1, 2), partial.partitions[0].last_verified);
+        assert!(partial.partitions[1].complete);
+
+        let options = VerifyOptions {
+            version_vector: partial.partitions.iter().map(|p| p.last_verified).collect(),
+            max_events: Some(2),
+            check_parents: false,
+        };
+        let rest = stream.verify(options).wait().expect("failed to verify");
+        assert!(rest.is_clean());
+        assert!(rest.is_complete());
+        assert_eq!(1, rest.partitions[0].events_checked);
+        assert_eq!(0, rest.partitions[1].events_checked);
+    }
+
+    #[test]
+    fn verify_reports_the_first_unreadable_event_in_a_partition() {
+        use std::fs::OpenOptions;
+        use std::io::{Read, Seek, SeekFrom, Write};
+        use protocol::IntegrityProblemKind;
+
+        let options = EventStreamOptions {
+            name: "verify_corrupt".to_owned(),
+            num_partitions: 1,
+            ..Default::default()
+        };
+        let (fixture, mut stream) = new_stream(options);
+        produce(&mut stream, 1, 3);
+
+        let segment_path = fixture.tempdir.path().join("1").join("1.events");
+        let mut file = OpenOptions::new().read(true).write(true).open(&segment_path).unwrap();
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).unwrap();
+        let second_event = contents.windows(8)
+                .enumerate()
+                .filter(|&(_, bytes)| bytes == b"FLO_EVT\n")
+                .map(|(position, _)| position)
+                .nth(1)
+                .expect("expected a second event in the segment");
+        file.seek(SeekFrom::Start(second_event as u64)).unwrap();
+        file.write_all(b"GARBAGE!").unwrap();
+        file.flush().unwrap();
+
+        let report = stream.verify(VerifyOptions::default()).wait().expect("failed to verify");
+        assert!(!report.is_clean());
+        let partition = &report.partitions[0];
+        assert_eq!(1, partition.events_checked);
+        assert_eq!(1, partition.problems.len());
+        assert_eq!(IntegrityProblemKind::Unreadable, partition.problems[0].kind);
+        assert_eq!(FloEventId::new(1, 1), partition.problems[0].event_id);
+    }
+
+    #[test]
+    fn verify_reports_parents_that_do_not_exist_in_any_partition() {
+        use protocol::IntegrityProblemKind;
+
+        let options = EventStreamOptions {
+            name: "verify_parents".to_owned(),
+            num_partitions: 2,
+            ..Default::default()
+        };
+        let (_fixture, mut stream) = new_stream(options);
+        produce_child(&mut stream, 1, None); // 1.1
+        produce_child(&mut stream, 2, Some(FloEventId::new(1, 1))); // 2.2
+        produce_child(&mut stream, 2, Some(FloEventId::new(1, 2))); // 2.3, counter 2 is in partition 2
+        produce_child(&mut stream, 1, Some(FloEventId::new(2, 2))); // 1.4
+
+        let unchecked = stream.verify(VerifyOptions::default()).wait().expect("failed to verify");
+        assert!(unchecked.is_clean());
+
+        let options = VerifyOptions { check_parents: true, ..Default::default() };
+        let report = stream.verify(options).wait().expect("failed to verify");
+        assert!(report.partitions[0].problems.is_empty());
+        let problems = &report.partitions[1].problems;
+        assert_eq!(1, problems.len());
+        assert_eq!(IntegrityProblemKind::InvalidParent, problems[0].kind);
+        assert_eq!(FloEventId::new(2, 3), problems[0].event_id);
+    }
+
+    #[test]
+    fn verify_returns_an_error_when_max_events_is_zero() {
+        let options = EventStreamOptions {
+            name: "verify_zero".to_owned(),
+            ..Default::default()
+        };
+        let (_fixture, mut stream) = new_stream(options);
+        produce(&mut stream, 1, 1);
+
+        let options = VerifyOptions { max_events: Some(0), ..Default::default() };
+        let err = stream.verify(options).wait().expect_err("expected an error");
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn stored_bytes_grows_with_produced_events_and_shrinks_after_truncating() {
+        let options = EventStreamOptions {
+            name: "stored_bytes".to_owned(),
+            num_partitions: 1,
+            ..Default::default()
+        };
+        let (_fixture, mut stream) = new_stream(options);
+        assert_eq!(0, stream.stored_bytes());
+
+        let body_len = 1000;
+        for _ in 0..10 {
+            produce_events(&mut stream, 1, vec![produce_event("/foo", &vec![7; body_len])]);
+        }
+
+        // each event has some overhead for its header and namespace, and the segment has a header of its own
+        let stored = stream.stored_bytes();
+        assert!(stored > 10 * body_len as u64, "stored bytes: {} is too small", stored);
+        assert!(stored < 10 * (body_len as u64 + 100) + 100, "stored bytes: {} is too large", stored);
+
+        stream.truncate(1, FloEventId::new(1, 5)).wait().expect("failed to truncate");
+        let truncated = stream.stored_bytes();
+        assert!(truncated > 5 * body_len as u64, "stored bytes after truncating: {} is too small", truncated);
+        assert!(truncated < stored - 5 * body_len as u64, "stored bytes after truncating: {} did not shrink enough from: {}", truncated, stored);
+    }
+
+    #[test]
+    fn describe_returns_count_and_oldest_and_newest_ids() {
+        let options = EventStreamOptions {
+            name: "describe".to_owned(),
+            num_partitions: 2,
+            ..Default::default()
+        };
+        let (_fixture, mut stream) = new_stream(options);
+
+        let result = stream.describe().wait().expect("failed to describe empty stream");
+        assert_eq!(0, result.event_count);
+        assert_eq!(FloEventId::zero(), result.head);
+        assert_eq!(FloEventId::zero(), result.tail);
+        assert_eq!(0, result.stored_bytes);
+
+        produce(&mut stream, 2, 3); // 1.2 - 3.2
+        produce(&mut stream, 1, 2); // 4.1 - 5.1
+
+        let expected = StreamDescriptor {
+            name: "describe".to_owned(),
+            event_count: 5,
+            head: FloEventId::new(1, 5),
+            tail: FloEventId::new(2, 1),
+            writable: true,
+            stored_bytes: stream.stored_bytes(),
+        };
+        let result = stream.describe().wait().expect("failed to describe stream");
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn event_bodies_are_encrypted_on_disk_and_decrypted_when_read() {
+        use std::fs;
+        use std::io::Read;
+        use std::path::Path;
+
+        fn all_file_contents(dir: &Path, contents: &mut Vec<u8>) {
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    all_file_contents(&path, contents);
+                } else {
+                    fs::File::open(&path).unwrap().read_to_end(contents).unwrap();
+                }
+            }
+        }
+
+        let options = EventStreamOptions {
+            name: "encrypted".to_owned(),
+            num_partitions: 1,
+            encryption: Some(EncryptionOptions {
+                cipher: EncryptionCipher::ChaCha20Poly1305,
+                key: [3; 32],
+            }),
+            ..Default::default()
+        };
+        let (fixture, mut stream) = new_stream(options);
+        let secret = "super secret plaintext".to_owned().into_bytes();
+        produce_events(&mut stream, 1, vec![produce_event("/foo", &secret)]);
+
+        let events = stream.events_since(&[]).unwrap().collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(1, events.len());
+        assert_eq!(&secret[..], events[0].data());
+        assert_eq!("/foo", events[0].namespace());
+
+        let mut on_disk = Vec::new();
+        all_file_contents(fixture.tempdir.path(), &mut on_disk);
+        assert!(!on_disk.is_empty());
+        assert!(!on_disk.windows(secret.len()).any(|w| w == &secret[..]));
+    }
+
+    #[test]
+    fn first_event_is_assigned_the_starting_counter() {
+        let options = EventStreamOptions {
+            name: "starting_counter".to_owned(),
+            num_partitions: 2,
+            starting_counter: 1000,
+            ..Default::default()
+        };
+        let (_fixture, mut stream) = new_stream(options);
+
+        produce(&mut stream, 2, 1);
+        produce(&mut stream, 1, 1);
+
+        let result = stream.events_since(&[]).unwrap().map(|e| *e.unwrap().id()).collect::<Vec<_>>();
+        assert_eq!(vec![FloEventId::new(2, 1000), FloEventId::new(1, 1001)], result);
+    }
+
+    #[test]
+    fn event_stream_is_not_created_when_starting_counter_is_zero() {
+        let tempdir = TempDir::new("zero_starting_counter").unwrap();
+        let core = Core::new().unwrap();
+        let status = AtomicBoolWriter::with_value(true);
+        let options = EventStreamOptions {
+            name: "zero".to_owned(),
+            starting_counter: 0,
+            ..Default::default()
+        };
+        let err = init_new_event_stream(tempdir.path().to_owned(), options, status.reader(), core.remote()).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+}
@@ -1,9 +1,16 @@
 
+use event::EventCounter;
 use engine::ConnectionId;
 use engine::event_stream::partition::ConsumerNotifier;
+use atomics::AtomicCounterReader;
+
+struct Consumer {
+    notifier: Box<ConsumerNotifier>,
+    position: AtomicCounterReader,
+}
 
 pub struct ConsumerManager {
-    uncommitted_consumers: Vec<Box<ConsumerNotifier>>,
+    uncommitted_consumers: Vec<Consumer>,
 }
 
 impl ConsumerManager {
@@ -14,25 +21,36 @@ impl ConsumerManager {
         }
     }
 
-    pub fn add_uncommitted(&mut self, consumer: Box<ConsumerNotifier>) {
-        self.uncommitted_consumers.push(consumer);
+    /// Adds a consumer along with the position of its `PartitionReader`
+    pub fn add_uncommitted(&mut self, notifier: Box<ConsumerNotifier>, position: AtomicCounterReader) {
+        self.uncommitted_consumers.push(Consumer {
+            notifier: notifier,
+            position: position,
+        });
+    }
+
+    pub fn remove(&mut self, connection_id: ConnectionId, op_id: u32) {
+        self.uncommitted_consumers.retain(|consumer| {
+            consumer.notifier.connection_id() != connection_id || consumer.notifier.op_id() != op_id
+        })
     }
 
-    pub fn remove(&mut self, consumer: ConnectionId) {
-        self.uncommitted_consumers.retain(|notifier| {
-            notifier.connection_id() != consumer
+    /// Returns true if any active consumer has read past `counter`, or started after it
+    pub fn has_active_consumers_after(&self, counter: EventCounter) -> bool {
+        self.uncommitted_consumers.iter().any(|consumer| {
+            consumer.notifier.is_active() && consumer.position.load_relaxed() as EventCounter > counter
         })
     }
 
     pub fn notify_uncommitted(&mut self) {
         let mut count = 0;
         self.uncommitted_consumers.retain(|consumer| {
-            let active = consumer.is_active();
+            let active = consumer.notifier.is_active();
             if active {
-                consumer.notify();
+                consumer.notifier.notify();
                 count += 1;
             } else {
-                debug!("Removing consumer for connection_id: {} because it is inactive", consumer.connection_id());
+                debug!("Removing consumer for connection_id: {} because it is inactive", consumer.notifier.connection_id());
             }
             active
         });
@@ -8,12 +8,13 @@ use std::path::PathBuf;
 use chrono::{Duration};
 
 use atomics::{AtomicCounterWriter, AtomicCounterReader, AtomicBoolReader};
-use protocol::ProduceEvent;
+use protocol::{ProduceEvent, duration_millis};
 use event::{ActorId, FloEventId, EventCounter, FloEvent, Timestamp, time};
-use super::{SharedReaderRefsMut, Operation, OpType, ProduceOperation, ConsumeOperation, PartitionReader, EventFilter, SegmentNum};
+use super::{SharedReaderRefsMut, Operation, OpType, ProduceOperation, ProduceComplete, ProducedEvents, ConsumeOperation, ReadOperation, ScanReaders, TruncateOperation, PartitionReader, EventFilter, SegmentNum};
 use super::segment::Segment;
 use super::index::{PartitionIndex, IndexEntry};
-use engine::event_stream::{EventStreamOptions, HighestCounter};
+use engine::event_stream::{EventStreamOptions, HighestCounter, HighestTimestamp, AckSubscribers, FsyncSchedule};
+use engine::event_stream::encryption::EventEncryptor;
 use engine::ConnectionId;
 use self::util::get_segment_files;
 use self::consumer_manager::ConsumerManager;
@@ -26,17 +27,37 @@ pub struct PartitionImpl {
     partition_dir: PathBuf,
     max_segment_size: usize,
     max_segment_duration: Duration,
+    /// segments are deleted once every event in them is older than this
+    event_retention: Duration,
     segments: VecDeque<Segment>,
     index: PartitionIndex,
     event_stream_highest_counter: HighestCounter,
     partition_highest_counter: AtomicCounterWriter,
     primary: AtomicBoolReader,
 
+    /// total bytes stored in all of the partition's segments, which is updated whenever segments are added to or removed
+    stored_bytes: AtomicCounterWriter,
+
+    /// shared by all partitions in the stream to ensure that event timestamps never decrease
+    event_stream_highest_timestamp: HighestTimestamp,
+
+    /// returns the current time for assigning event timestamps
+    clock: fn() -> Timestamp,
+
+    /// shared by all partitions in the stream, and notified of the id of each event once it's persisted
+    ack_subscribers: AckSubscribers,
+
     /// new segments each have a reader added here. The readers are then accessed as needed by the EventReader
     reader_refs: SharedReaderRefsMut,
 
     /// consumers each have a notifier added here
     consumer_manager: ConsumerManager,
+
+    /// present if event bodies are encrypted at rest
+    encryptor: Option<EventEncryptor>,
+
+    /// decides when newly written events need to be synced, according to the stream's `FsyncPolicy`
+    fsync_schedule: FsyncSchedule,
 }
 
 impl PartitionImpl {
@@ -45,7 +66,9 @@ impl PartitionImpl {
                          partition_data_dir: PathBuf,
                          options: &EventStreamOptions,
                          status_reader: AtomicBoolReader,
-                         highest_counter: HighestCounter) -> io::Result<PartitionImpl> {
+                         highest_counter: HighestCounter,
+                         highest_timestamp: HighestTimestamp,
+                         ack_subscribers: AckSubscribers) -> io::Result<PartitionImpl> {
 
         let start_time = ::std::time::Instant::now();
         debug!("Starting to init partition: {} with directory: {:?}, and options: {:?}", partition_num, partition_data_dir, options);
@@ -64,6 +87,10 @@ impl PartitionImpl {
         let current_greatest_id = index.greatest_event_counter();
         highest_counter.set_if_greater(current_greatest_id);
         let partition_id_counter = AtomicCounterWriter::with_value(current_greatest_id as usize);
+        let stored_bytes = AtomicCounterWriter::with_value(initialized_segments.iter().map(|s| s.stored_bytes()).sum());
+        if let Some(last_timestamp) = get_last_event_timestamp(&index, &initialized_segments)? {
+            highest_timestamp.set_if_greater(last_timestamp);
+        }
 
         // TODO: factor out a more legit method of timing and logging perf stats
         let init_time = start_time.elapsed();
@@ -81,13 +108,20 @@ impl PartitionImpl {
             partition_dir: partition_data_dir,
             max_segment_size: options.segment_max_size_bytes,
             max_segment_duration: options.max_segment_duration,
+            event_retention: options.event_retention,
             segments: initialized_segments,
             index: index,
             event_stream_highest_counter: highest_counter,
             partition_highest_counter: partition_id_counter,
             primary: status_reader,
+            stored_bytes: stored_bytes,
+            event_stream_highest_timestamp: highest_timestamp,
+            clock: time::now,
+            ack_subscribers: ack_subscribers,
             reader_refs: reader_refs,
             consumer_manager: ConsumerManager::new(),
+            encryptor: options.encryption.as_ref().map(EventEncryptor::new),
+            fsync_schedule: FsyncSchedule::new(options.fsync_policy),
         })
     }
 
@@ -95,7 +129,9 @@ impl PartitionImpl {
                     partition_data_dir: PathBuf,
                     options: &EventStreamOptions,
                     status_reader: AtomicBoolReader,
-                    highest_counter: HighestCounter) -> io::Result<PartitionImpl> {
+                    highest_counter: HighestCounter,
+                    highest_timestamp: HighestTimestamp,
+                    ack_subscribers: AckSubscribers) -> io::Result<PartitionImpl> {
 
         ::std::fs::create_dir_all(&partition_data_dir)?;
 
@@ -104,14 +140,21 @@ impl PartitionImpl {
             partition_num: partition_num,
             partition_dir: partition_data_dir,
             max_segment_duration: options.max_segment_duration,
+            event_retention: options.event_retention,
             max_segment_size: options.segment_max_size_bytes,
             segments: VecDeque::with_capacity(4),
             index: PartitionIndex::new(partition_num),
             event_stream_highest_counter: highest_counter,
             partition_highest_counter: AtomicCounterWriter::zero(),
             primary: status_reader,
+            stored_bytes: AtomicCounterWriter::zero(),
+            event_stream_highest_timestamp: highest_timestamp,
+            clock: time::now,
+            ack_subscribers: ack_subscribers,
             reader_refs: SharedReaderRefsMut::new(),
             consumer_manager: ConsumerManager::new(),
+            encryptor: options.encryption.as_ref().map(EventEncryptor::new),
+            fsync_schedule: FsyncSchedule::new(options.fsync_policy),
         })
     }
 
@@ -123,6 +166,10 @@ impl PartitionImpl {
         self.partition_highest_counter.reader()
     }
 
+    pub fn stored_bytes_reader(&self) -> AtomicCounterReader {
+        self.stored_bytes.reader()
+    }
+
     pub fn primary_status_reader(&self) -> AtomicBoolReader {
         self.primary.clone()
     }
@@ -144,42 +191,96 @@ impl PartitionImpl {
             OpType::Consume(consume_op) => {
                 self.handle_consume(connection_id, consume_op)
             }
-            OpType::StopConsumer => {
-                self.consumer_manager.remove(connection_id);
+            OpType::Read(read_op) => {
+                let ReadOperation {client_sender, filter, start_exclusive} = read_op;
+                let reader = self.create_reader(connection_id, filter, start_exclusive);
+                let _ = client_sender.send(reader);
+                Ok(())
+            }
+            OpType::Scan(scan_op) => {
+                let mut readers = PartitionScanReaders { partition: self, connection_id: connection_id };
+                scan_op.scan.scan(&mut readers);
+                Ok(())
+            }
+            OpType::Truncate(truncate_op) => {
+                let TruncateOperation {client, up_to} = truncate_op;
+                let result = self.truncate(up_to);
+                if let Err(ref err) = result {
+                    warn!("Failed to truncate partition: {} of event stream: '{}' to counter: {}: {:?}", self.partition_num, self.event_stream_name, up_to, err);
+                }
+                let _ = client.send(result);
+                Ok(())
+            }
+            OpType::StopConsumer(op_id) => {
+                self.consumer_manager.remove(connection_id, op_id);
                 Ok(())
             }
             OpType::Tick => {
                 self.expire_old_events();
+                if self.fsync_schedule.is_due(::std::time::Instant::now()) {
+                    self.fsync()?;
+                }
                 Ok(())
             }
         }
     }
 
+    /// Deletes the oldest segments once every event in them is older than the stream's `event_retention`, or has passed the
+    /// time to live it was produced with. Segments are deleted in order, stopping at the first one that's still being read,
+    /// so that a consumer never has events removed from in front of it.
     fn expire_old_events(&mut self) {
-        let now = time::now();
-        let expired_segment_index = self.segments.iter().enumerate().take_while(|&(_, ref segment)| {
-            segment.is_expired(now)
-        }).last().map(|(ref index, _)| *index);
-        if let Some(drop_through_index) = expired_segment_index {
-            self.drop_segments_through_index(drop_through_index);
+        let now = (self.clock)();
+        // if retention is longer than the clock goes back, then segments are only removed once all of their events have
+        // passed their time to live
+        let cutoff = now.checked_sub(self.event_retention);
+
+        let mut segment_count = 0;
+        let mut removed_through = 0;
+        // segments are ordered newest first
+        while self.segments.back().map(|s| cutoff.map(|c| s.is_expired(c)).unwrap_or(false) || s.all_events_have_expired(now)).unwrap_or(false) {
+            if self.segments.back().unwrap().has_active_readers() {
+                info!("Not removing expired Segment: {:?} of partition: {} of event stream: '{}' because it is still being read",
+                      self.segments.back().unwrap().segment_num, self.partition_num, self.event_stream_name);
+                break;
+            }
+
+            let mut drop_segment = self.segments.pop_back().unwrap();
+            info!("Removing expired Segment: {:?} with highest_event counter: {}", drop_segment.segment_num, drop_segment.get_highest_event_counter());
+            // counting the events would mean reading the whole segment on this thread, holding up produces
+            removed_through = drop_segment.get_highest_event_counter();
+            segment_count += 1;
+            self.reader_refs.remove_through(drop_segment.segment_num);
+            self.index.remove_through(drop_segment.get_highest_event_counter());
+            drop_segment.delete_on_drop();
         }
-    }
 
-    fn drop_segments_through_index(&mut self, segment_index: usize) {
-        info!("Dropping first {} segment(s)", segment_index + 1);
-        let PartitionImpl { ref mut segments, ref mut index, ref mut reader_refs, .. } = *self;
+        if segment_count > 0 {
+            info!("Reclaimed {} segment(s) with events through counter: {} from partition: {} of event stream: '{}'",
+                  segment_count, removed_through, self.partition_num, self.event_stream_name);
+            self.update_stored_bytes();
+        }
+    }
 
-        segments.drain(..(segment_index + 1)).for_each(|mut drop_segment| {
-            info!("Removing Segment: {:?} with highest_event counter: {}", drop_segment.segment_num, drop_segment.get_highest_event_counter());
-            reader_refs.remove_through(drop_segment.segment_num);
-            index.remove_through(drop_segment.get_highest_event_counter());
-            drop_segment.delete_on_drop();
-        });
+    fn update_stored_bytes(&mut self) {
+        let total = self.segments.iter().map(|s| s.stored_bytes()).sum();
+        self.stored_bytes.reset(total);
     }
 
     fn handle_produce(&mut self, produce: ProduceOperation) -> io::Result<()> {
-        let ProduceOperation {client, op_id, events} = produce;
-        let result = self.append_all(events);
+        let ProduceOperation {client, op_id, events, produced_events} = produce;
+        let event_count = events.len();
+        let result = self.append_all(events, produced_events.as_ref()).and_then(|last_id| {
+            // sync before acknowledging, so that policies like `Always` can guarantee that acknowledged events are on disk.
+            // If the sync fails, the events have still been appended and may be read by consumers, but the producer gets
+            // the error, so a producer that retries will end up persisting the same events twice
+            if self.fsync_schedule.events_written(event_count, ::std::time::Instant::now()) {
+                self.fsync()?;
+            }
+            Ok(ProduceComplete {
+                last_id: last_id,
+                written_at: ::std::time::Instant::now(),
+            })
+        });
         if let Err(e) = result.as_ref() {
             error!("Failed to handle produce operation for op_id: {}, err: {:?}", op_id, e);
         }
@@ -189,17 +290,25 @@ impl PartitionImpl {
         Ok(())
     }
 
-    fn append_all(&mut self, events: Vec<ProduceEvent>) -> io::Result<FloEventId> {
+    fn append_all(&mut self, events: Vec<ProduceEvent>, produced_events: Option<&ProducedEvents>) -> io::Result<FloEventId> {
         let event_count = events.len();
         // reserve the range of ids for the events
         let new_highest = self.event_stream_highest_counter.increment_and_get(event_count as u64);
 
-        let timestamp = time::now();
+        let timestamp = self.event_stream_highest_timestamp.next_timestamp((self.clock)());
         let mut event_counter = new_highest - event_count as u64;
-        for produce_event in events {
+        let first_counter = event_counter + 1;
+        for mut produce_event in events {
             event_counter += 1;
+            let id = FloEventId::new(self.partition_num, event_counter);
+            if let Some(produced) = produced_events {
+                produced.insert(id);
+            }
+            if let Some(ref encryptor) = self.encryptor {
+                produce_event.data = encryptor.encrypt(&id, &produce_event.data)?;
+            }
             let event = EventToProduce {
-                id: FloEventId::new(self.partition_num, event_counter),
+                id: id,
                 ts: timestamp,
                 produce: produce_event,
             };
@@ -207,10 +316,14 @@ impl PartitionImpl {
             self.append(&event)?;
         }
         debug!("partition: {} finished appending {} events ending with counter: {}", self.partition_num, event_count, event_counter);
-        // now increment our counter and notify consumers
-        self.partition_highest_counter.increment_and_get_relaxed(event_count);
+        // now update our counter and notify consumers. Counters are shared by every partition in the stream, so the new
+        // highest counter can be more than `event_count` greater than the previous one
+        self.partition_highest_counter.set_if_greater(event_counter as usize);
+        self.update_stored_bytes();
         ::std::sync::atomic::fence(::std::sync::atomic::Ordering::SeqCst);
         self.consumer_manager.notify_uncommitted();
+        let partition_num = self.partition_num;
+        self.ack_subscribers.notify((first_counter..(event_counter + 1)).map(|counter| FloEventId::new(partition_num, counter)));
         Ok(FloEventId::new(self.partition_num, event_counter))
     }
 
@@ -242,7 +355,7 @@ impl PartitionImpl {
                 s.segment_num.next()
             }).unwrap_or(FIRST_SEGMENT_NUM);
 
-            let segment_end_time = time::now() + self.max_segment_duration;
+            let segment_end_time = (self.clock)() + self.max_segment_duration;
             let new_segment = Segment::init_new(&self.partition_dir,
                                                 segment_num,
                                                 self.max_segment_size,
@@ -273,10 +386,53 @@ impl PartitionImpl {
         Ok(())
     }
 
+    /// Discards every event with a counter greater than `up_to`. Segments that come after the one containing the first
+    /// discarded event are deleted entirely. This is rejected if any active consumer has already read past `up_to`, since
+    /// it may have seen events that are about to be discarded. It's also rejected if anything besides the partition is
+    /// still reading the segment containing the first discarded event, since the discarded bytes are zeroed. The event
+    /// stream's highest counter is left alone so that the ids of discarded events are never reused.
+    fn truncate(&mut self, up_to: EventCounter) -> io::Result<()> {
+        if self.consumer_manager.has_active_consumers_after(up_to) {
+            return Err(io::Error::new(io::ErrorKind::Other, "Cannot truncate a partition with active consumers that have read past the truncation point"));
+        }
+
+        let first_removed = match self.index.get_next_entry(up_to) {
+            Some(entry) => entry,
+            None => return Ok(()) // nothing to remove
+        };
+        if self.segments.iter().find(|s| s.segment_num == first_removed.segment).map(|s| s.has_active_readers()).unwrap_or(false) {
+            return Err(io::Error::new(io::ErrorKind::Other, format!("Cannot truncate while segment: {:?} is still being read", first_removed.segment)));
+        }
+        info!("Truncating partition: {} of event stream: '{}' to event counter: {}", self.partition_num, self.event_stream_name, up_to);
+
+        let new_highest = self.index.remove_after(up_to);
+
+        // segments are ordered newest first
+        while self.segments.front().map(|s| s.segment_num > first_removed.segment).unwrap_or(false) {
+            let mut segment = self.segments.pop_front().unwrap();
+            info!("Removing Segment: {:?} while truncating", segment.segment_num);
+            segment.delete_on_drop();
+        }
+        self.reader_refs.remove_after(first_removed.segment);
+
+        {
+            let segment = self.segments.front_mut().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::Other, format!("Missing segment: {:?}", first_removed.segment))
+            })?;
+            segment.truncate(first_removed.file_offset, new_highest);
+            segment.fsync()?;
+        }
+
+        self.partition_highest_counter.reset(new_highest as usize);
+        self.update_stored_bytes();
+        Ok(())
+    }
+
     pub fn fsync(&mut self) -> io::Result<()> {
         for segment in self.segments.iter_mut() {
             segment.fsync()?
         }
+        self.fsync_schedule.synced(::std::time::Instant::now());
         Ok(())
     }
 
@@ -290,9 +446,10 @@ impl PartitionImpl {
 
         // We don't really care if the receiving end has hung up already
         // but we don't want to actually add the notifier to the consumer manager in that case
+        let position = reader.position();
         let result = client_sender.send(reader);
         if let Ok(_) = result {
-            self.consumer_manager.add_uncommitted(notifier);
+            self.consumer_manager.add_uncommitted(notifier, position);
         }
         Ok(())
     }
@@ -317,12 +474,45 @@ impl PartitionImpl {
             }
         };
 
-        PartitionReader::new(connection_id, self.partition_num, filter, current_segment, self.reader_refs.get_reader_refs())
+        PartitionReader::new(connection_id, self.partition_num, filter, current_segment, self.reader_refs.get_reader_refs(), self.encryptor.clone(), start_exclusive)
     }
 
 
 }
 
+/// Reads the timestamp of the event with the greatest counter in the partition, if there is one
+fn get_last_event_timestamp(index: &PartitionIndex, segments: &VecDeque<Segment>) -> io::Result<Option<Timestamp>> {
+    let greatest = index.greatest_event_counter();
+    if greatest == 0 {
+        return Ok(None);
+    }
+    let entry = match index.get_next_entry(greatest - 1) {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+    let segment = match segments.iter().find(|s| s.segment_num == entry.segment) {
+        Some(segment) => segment,
+        None => return Ok(None),
+    };
+    match segment.range_iter(entry.file_offset).read_next() {
+        Some(Ok(event)) => Ok(Some(event.timestamp())),
+        Some(Err(io_err)) => Err(io_err),
+        None => Ok(None),
+    }
+}
+
+/// Gives a `PartitionScan` readers of the partition that's running it
+struct PartitionScanReaders<'a> {
+    partition: &'a mut PartitionImpl,
+    connection_id: ConnectionId,
+}
+
+impl <'a> ScanReaders for PartitionScanReaders<'a> {
+    fn reader(&mut self, filter: EventFilter, start_exclusive: EventCounter) -> PartitionReader {
+        self.partition.create_reader(self.connection_id, filter, start_exclusive)
+    }
+}
+
 #[derive(Debug, PartialEq)]
 struct EventToProduce {
     id: FloEventId,
@@ -354,71 +544,116 @@ impl FloEvent for EventToProduce {
     fn data(&self) -> &[u8] {
         &self.produce.data
     }
+
+    fn expiration(&self) -> Option<Timestamp> {
+        self.produce.ttl.map(|ttl| {
+            time::from_millis_since_epoch(time::millis_since_epoch(self.ts) + duration_millis(ttl))
+        })
+    }
 }
 
 
 #[cfg(test)]
 mod test {
+    use std::cell::Cell;
+    use std::path::Path;
+
     use chrono::Duration;
     use tempdir::TempDir;
     use futures::sync::oneshot;
+    use futures::Future;
 
     use super::*;
     use protocol::ProduceEvent;
     use engine::event_stream::partition::{ProduceOperation, EventFilter, PartitionReader};
-    use engine::event_stream::{EventStreamOptions, HighestCounter};
+    use engine::event_stream::{EventStreamOptions, HighestCounter, HighestTimestamp, AckSubscribers, FsyncPolicy};
     use engine::ConnectionId;
     use atomics::AtomicBoolWriter;
 
     const PARTITION_NUM: ActorId = 1;
     const CONNECTION: ConnectionId = 55;
 
+    thread_local! {
+        // each test runs on its own thread, so tests that set the time can't affect each other
+        static FAKE_NOW_MILLIS: Cell<u64> = Cell::new(0);
+    }
+
+    fn fake_clock() -> Timestamp {
+        time::from_millis_since_epoch(FAKE_NOW_MILLIS.with(|now| now.get()))
+    }
+
+    fn set_fake_now(millis: u64) {
+        FAKE_NOW_MILLIS.with(|now| now.set(millis));
+    }
+
+    fn produce_event(namespace: &str, data: &[u8]) -> ProduceEvent {
+        ProduceEvent {
+            partition: PARTITION_NUM,
+            namespace: namespace.to_owned(),
+            data: data.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn produce(partition: &mut PartitionImpl, op_id: u32, events: Vec<ProduceEvent>) -> ProduceComplete {
+        let (client_tx, client_rx) = oneshot::channel();
+        partition.handle_produce(ProduceOperation {
+            client: client_tx,
+            produced_events: None,
+            op_id: op_id,
+            events: events,
+        }).expect("failed to produce");
+        client_rx.wait().expect("failed to receive ack").expect("failed to produce")
+    }
+
+    fn new_partition(name: &str, options: &EventStreamOptions) -> (TempDir, PartitionImpl) {
+        let tempdir = TempDir::new(name).unwrap();
+        let status = AtomicBoolWriter::with_value(true);
+        let partition = PartitionImpl::init_new(PARTITION_NUM,
+                                                tempdir.path().to_owned(),
+                                                options,
+                                                status.reader(),
+                                                HighestCounter::zero(),
+                                                HighestTimestamp::new(),
+                                                AckSubscribers::new()).unwrap();
+        (tempdir, partition)
+    }
+
+    fn open_partition(dir: &Path, options: &EventStreamOptions, highest_timestamp: HighestTimestamp) -> PartitionImpl {
+        let status = AtomicBoolWriter::with_value(true);
+        PartitionImpl::init_existing(PARTITION_NUM,
+                                     dir.to_owned(),
+                                     options,
+                                     status.reader(),
+                                     HighestCounter::zero(),
+                                     highest_timestamp,
+                                     AckSubscribers::new()).expect("failed to open existing partition")
+    }
+
     #[test]
     fn partition_impl_integration_test() {
         let _ = ::env_logger::init();
 
-        let status = AtomicBoolWriter::with_value(true);
         let options = EventStreamOptions {
             name: "superduper".to_owned(),
             num_partitions: 1,
             event_retention: Duration::seconds(20),
             max_segment_duration: Duration::seconds(5),
             segment_max_size_bytes: 256,
+            encryption: None,
+            validate_parent: false,
+            starting_counter: 1,
+            max_cursor_lifetime: None,
+            fsync_policy: FsyncPolicy::Never,
         };
-        let tempdir = TempDir::new("partition_persist_events_and_read_them_back").unwrap();
+        let (tempdir, mut partition) = new_partition("partition_persist_events_and_read_them_back", &options);
 
         // Init a new partition and append a bunch of events in two groups
         {
-            let mut partition = PartitionImpl::init_new(PARTITION_NUM,
-                                                        tempdir.path().to_owned(),
-                                                        &options,
-                                                        status.reader(),
-                                                        HighestCounter::zero()).unwrap();
-
-            let (client_tx, _client_rx) = oneshot::channel();
-
-            let produce = ProduceOperation {
-                client: client_tx,
-                op_id: 3,
-                events: vec![
-                    ProduceEvent {
-                        op_id: 3,
-                        partition: PARTITION_NUM,
-                        namespace: "/foo/bar".to_owned(),
-                        parent_id: None,
-                        data: "the quick".to_owned().into_bytes(),
-                    },
-                    ProduceEvent {
-                        op_id: 3,
-                        partition: PARTITION_NUM,
-                        namespace: "/foo/bar".to_owned(),
-                        parent_id: None,
-                        data: "brown fox".to_owned().into_bytes(),
-                    }
-                ],
-            };
-
-            partition.handle_produce(produce).unwrap();
+            produce(&mut partition, 3, vec![
+                produce_event("/foo/bar", b"the quick"),
+                produce_event("/foo/bar", b"brown fox"),
+            ]);
 
             let mut reader: PartitionReader = partition.create_reader(CONNECTION, EventFilter::All, 0);
             let event = reader.next_matching().expect("read_next returned None").expect("read_next returned error");
@@ -427,23 +662,8 @@ mod test {
             assert_eq!(b"brown fox", event2.data());
             assert!(reader.next().is_none());
 
-            let moar_events = (0..100).map(|_| {
-                ProduceEvent {
-                    op_id: 4,
-                    partition: PARTITION_NUM,
-                    namespace: "/boo/hoo".to_owned(),
-                    parent_id: None,
-                    data: "stew".to_owned().into_bytes()
-                }
-            }).collect::<Vec<_>>();
-
-            let (client_tx, _client_rx) = oneshot::channel();
-
-            partition.handle_produce(ProduceOperation {
-                client: client_tx,
-                op_id: 4,
-                events: moar_events,
-            }).expect("failed to persist large batch");
+            let moar_events = (0..100).map(|_| produce_event("/boo/hoo", b"stew")).collect::<Vec<_>>();
+            produce(&mut partition, 4, moar_events);
 
             let mut total = 0;
             for result in reader {
@@ -453,10 +673,12 @@ mod test {
             assert_eq!(100, total);
             partition.fsync().expect("failed to fsync");
         }
+        drop(partition);
 
         // now try to initialize the partition from an existing file
-        let result = PartitionImpl::init_existing(PARTITION_NUM, tempdir.path().to_owned(), &options, status.reader(), HighestCounter::zero());
-        let mut partition = result.expect("Failed to init partitionImpl");
+        let highest_timestamp = HighestTimestamp::new();
+        let mut partition = open_partition(tempdir.path(), &options, highest_timestamp.clone());
+        assert!(highest_timestamp.get() > time::from_millis_since_epoch(0));
 
         let reader = partition.create_reader(77, EventFilter::All, 0);
         let count = reader.map(|read_result| {
@@ -464,4 +686,155 @@ mod test {
         }).count();
         assert_eq!(102, count);
     }
+
+    #[test]
+    fn every_event_is_flushed_before_it_is_acked_with_fsync_every_event() {
+        fn produce_and_wait(partition: &mut PartitionImpl, i: u32) {
+            let event = produce_event("/foo", format!("event {}", i).as_bytes());
+            let complete = produce(partition, i, vec![event]);
+            assert_eq!(FloEventId::new(PARTITION_NUM, i as EventCounter), complete.last_id);
+        }
+        fn has_unsynced_writes(partition: &PartitionImpl) -> bool {
+            partition.segments.iter().any(|segment| segment.has_unsynced_writes())
+        }
+
+        let never_options = EventStreamOptions {
+            fsync_policy: FsyncPolicy::Never,
+            ..Default::default()
+        };
+        let (_never_dir, mut never) = new_partition("partition_fsync_never", &never_options);
+        produce_and_wait(&mut never, 1);
+        // without a policy that requires it, the segment is left for the operating system to write back
+        assert!(has_unsynced_writes(&never));
+
+        let options = EventStreamOptions {
+            fsync_policy: FsyncPolicy::EveryN(1),
+            ..Default::default()
+        };
+        let (tempdir, mut partition) = new_partition("partition_fsync_every_event", &options);
+
+        for i in 1..4 {
+            produce_and_wait(&mut partition, i);
+            // the segment is flushed before the ack is sent
+            assert!(!has_unsynced_writes(&partition));
+            assert_eq!(0, partition.fsync_schedule.unsynced_events());
+        }
+
+        // simulate a crash by skipping anything that would happen when the partition is shut down normally
+        ::std::mem::forget(partition);
+
+        let mut reopened = open_partition(tempdir.path(), &options, HighestTimestamp::new());
+        let bodies = reopened.create_reader(CONNECTION, EventFilter::All, 0).map(|result| {
+            String::from_utf8(result.expect("failed to read event").data().to_vec()).unwrap()
+        }).collect::<Vec<_>>();
+        assert_eq!(vec!["event 1".to_owned(), "event 2".to_owned(), "event 3".to_owned()], bodies);
+    }
+
+    #[test]
+    fn event_timestamps_never_decrease_when_the_clock_steps_backward() {
+        let options = EventStreamOptions {
+            max_segment_duration: Duration::days(365 * 100),
+            ..Default::default()
+        };
+        let (_tempdir, mut partition) = new_partition("partition_timestamps_never_decrease", &options);
+        partition.clock = fake_clock;
+
+        // the clock gets stepped backward after the second produce
+        for &now in [10_000, 20_000, 15_000, 15_000, 25_000].iter() {
+            set_fake_now(now);
+            produce(&mut partition, 1, vec![produce_event("/foo", b"")]);
+        }
+
+        let timestamps = partition.create_reader(CONNECTION, EventFilter::All, 0).map(|result| {
+            time::millis_since_epoch(result.expect("failed to read event").timestamp())
+        }).collect::<Vec<_>>();
+        assert_eq!(vec![10_000, 20_000, 20_001, 20_002, 25_000], timestamps);
+    }
+
+    #[test]
+    fn expired_segments_are_removed_unless_they_are_still_being_read() {
+        let options = EventStreamOptions {
+            event_retention: Duration::seconds(60),
+            max_segment_duration: Duration::seconds(10),
+            ..Default::default()
+        };
+        let (_tempdir, mut partition) = new_partition("expired_segments_are_removed", &options);
+        partition.clock = fake_clock;
+
+        // each produce is far enough apart to start a new segment, which ends 10 seconds after it's created
+        for &now_seconds in [1000, 1020, 1040].iter() {
+            set_fake_now(now_seconds * 1000);
+            produce(&mut partition, 1, vec![produce_event("/foo", b"")]);
+        }
+        assert_eq!(3, partition.segments.len());
+
+        // nothing is older than the retention yet
+        set_fake_now(1069 * 1000);
+        partition.expire_old_events();
+        assert_eq!(3, partition.segments.len());
+
+        // the first two segments are both expired, but a consumer is still positioned in the first one
+        set_fake_now(1100 * 1000);
+        let mut reader = partition.create_reader(CONNECTION, EventFilter::All, 0);
+        partition.expire_old_events();
+        assert_eq!(3, partition.segments.len());
+
+        // once the consumer moves on to the second segment, then only the first one can be removed
+        reader.next().expect("read returned none").expect("failed to read event");
+        reader.next().expect("read returned none").expect("failed to read event");
+        partition.expire_old_events();
+        assert_eq!(2, partition.segments.len());
+
+        drop(reader);
+        partition.expire_old_events();
+        assert_eq!(1, partition.segments.len());
+
+        let remaining = partition.create_reader(CONNECTION, EventFilter::All, 0).map(|result| {
+            result.expect("failed to read event").id().event_counter
+        }).collect::<Vec<_>>();
+        assert_eq!(vec![3], remaining);
+    }
+
+    #[test]
+    fn segments_are_removed_once_every_event_in_them_has_passed_its_ttl() {
+        // the default retention is forever, so only the ttls can cause segments to be removed
+        let options = EventStreamOptions {
+            max_segment_duration: Duration::seconds(10),
+            ..Default::default()
+        };
+        let (tempdir, mut partition) = new_partition("ttl_segments_are_removed", &options);
+        partition.clock = fake_clock;
+
+        // the first segment only has events with a ttl, and the second also has one without
+        for &(now_seconds, ttl_seconds) in [(1000, Some(30)), (1001, Some(5)), (1020, Some(5)), (1021, None)].iter() {
+            set_fake_now(now_seconds * 1000);
+            let event = ProduceEvent {
+                ttl: ttl_seconds.map(::std::time::Duration::from_secs),
+                ..produce_event("/foo", b"")
+            };
+            produce(&mut partition, 1, vec![event]);
+        }
+        assert_eq!(2, partition.segments.len());
+
+        // the expirations are read back from the segment files when the partition is restarted
+        drop(partition);
+        let mut partition = open_partition(tempdir.path(), &options, HighestTimestamp::new());
+        partition.clock = fake_clock;
+        assert_eq!(2, partition.segments.len());
+
+        // the first segment is closed, but its first event hasn't expired yet
+        set_fake_now(1029 * 1000);
+        partition.expire_old_events();
+        assert_eq!(2, partition.segments.len());
+
+        // the second segment is closed, but it has an event that never expires
+        set_fake_now(1100 * 1000);
+        partition.expire_old_events();
+        assert_eq!(1, partition.segments.len());
+
+        let remaining = partition.create_reader(CONNECTION, EventFilter::All, 0).map(|result| {
+            result.expect("failed to read event").id().event_counter
+        }).collect::<Vec<_>>();
+        assert_eq!(vec![4], remaining);
+    }
 }
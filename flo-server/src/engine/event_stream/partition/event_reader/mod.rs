@@ -2,18 +2,21 @@ mod namespace;
 
 use std::io;
 
-use event::{FloEvent, ActorId};
+use event::{FloEvent, ActorId, EventCounter, time};
 
 use engine::ConnectionId;
-use engine::event_stream::partition::{SharedReaderRefs, SegmentNum};
+use atomics::{AtomicCounterWriter, AtomicCounterReader};
+use engine::event_stream::encryption::EventEncryptor;
+use engine::event_stream::partition::{SharedReaderRefs, SegmentNum, ExcludedEvents};
 use engine::event_stream::partition::segment::{SegmentReader, PersistentEvent};
 
-pub use self::namespace::NamespaceGlob;
+pub use self::namespace::{NamespaceGlob, NamespaceRegex};
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum EventFilter {
     All,
-    Glob(NamespaceGlob)
+    Glob(NamespaceGlob),
+    Regex(NamespaceRegex),
 }
 
 impl EventFilter {
@@ -21,6 +24,7 @@ impl EventFilter {
         match *self {
             EventFilter::All => true,
             EventFilter::Glob(ref glob) => glob.matches(event.namespace()),
+            EventFilter::Regex(ref regex) => regex.matches(event.namespace()),
         }
     }
 
@@ -31,6 +35,10 @@ impl EventFilter {
             NamespaceGlob::new(string).map(|glob| EventFilter::Glob(glob))
         }
     }
+
+    pub fn regex(pattern: &str) -> Result<EventFilter, String> {
+        NamespaceRegex::new(pattern).map(|regex| EventFilter::Regex(regex))
+    }
 }
 
 #[derive(Debug)]
@@ -41,12 +49,23 @@ pub struct PartitionReader {
     current_segment_reader: Option<SegmentReader>,
     segment_readers_ref: SharedReaderRefs,
     returned_error: bool,
+    encryptor: Option<EventEncryptor>,
+    /// if set, then events with any of these ids are skipped
+    excluded: Option<ExcludedEvents>,
+    /// the counter of the last event that was read from the segment, whether it was returned or skipped
+    position: AtomicCounterWriter,
 }
 
 
 impl PartitionReader {
 
-    pub fn new(connection_id: ConnectionId, partition_num: ActorId, filter: EventFilter, current_reader: Option<SegmentReader>, segment_refs: SharedReaderRefs) -> PartitionReader {
+    pub fn new(connection_id: ConnectionId,
+               partition_num: ActorId,
+               filter: EventFilter,
+               current_reader: Option<SegmentReader>,
+               segment_refs: SharedReaderRefs,
+               encryptor: Option<EventEncryptor>,
+               start_exclusive: EventCounter) -> PartitionReader {
         PartitionReader {
             connection_id: connection_id,
             partition_num: partition_num,
@@ -54,25 +73,72 @@ impl PartitionReader {
             current_segment_reader: current_reader,
             segment_readers_ref: segment_refs,
             returned_error: false,
+            encryptor: encryptor,
+            excluded: None,
+            position: AtomicCounterWriter::with_value(start_exclusive as usize),
         }
     }
 
+    /// Returns a reader for the counter of the last event that this reader has passed, which starts at `start_exclusive`
+    pub fn position(&self) -> AtomicCounterReader {
+        self.position.reader()
+    }
+
+    /// Skips every event whose id is in `events`, including ones that are added after this is called
+    pub fn exclude(&mut self, events: ExcludedEvents) {
+        self.excluded = Some(events);
+    }
+
     pub fn next_matching(&mut self) -> Option<io::Result<PersistentEvent>> {
         let mut next = self.read_next();
         while self.should_skip(&next) {
             next = self.read_next();
         }
-        next
+        self.decrypt(next)
+    }
+
+    fn decrypt(&mut self, next: Option<io::Result<PersistentEvent>>) -> Option<io::Result<PersistentEvent>> {
+        match (next, self.encryptor.as_ref()) {
+            (Some(Ok(mut event)), Some(encryptor)) => {
+                let result = event.decrypt(encryptor).map(|()| event);
+                if result.is_err() {
+                    self.returned_error = true;
+                }
+                Some(result)
+            }
+            (other, _) => other
+        }
     }
 
+    /// Events with a corrupted namespace are skipped rather than returned as an error, so that a single bad event doesn't
+    /// end the consumer's whole stream
     fn should_skip(&self, result: &Option<Result<PersistentEvent, io::Error>>) -> bool {
         if let Some(Ok(ref event)) = *result {
-            !self.filter.matches(event)
+            if !event.has_valid_namespace() {
+                warn!("Consumer for connection_id: {} skipping event: {} because its namespace is not valid UTF-8", self.connection_id, event.id());
+                return true;
+            }
+            // checked first, so that the exclusions know about every event this reader passes
+            let excluded = self.is_excluded(event);
+            excluded || !self.filter.matches(event) || is_expired(event)
         } else {
             false
         }
     }
 
+    fn is_excluded(&self, event: &PersistentEvent) -> bool {
+        self.excluded.as_ref().map(|excluded| excluded.read_past(event.id())).unwrap_or(false)
+    }
+
+    pub fn partition_num(&self) -> ActorId {
+        self.partition_num
+    }
+
+    /// Returns true if there's a newer segment in this partition than the one that's currently being read
+    pub fn is_replaying(&self) -> bool {
+        self.segment_readers_ref.has_segment_after(SegmentNum(self.current_reader_segment_id()))
+    }
+
     fn current_reader_is_exhausted(&self) -> bool {
         self.current_segment_reader.as_ref().map(|reader| {
             reader.is_exhausted()
@@ -105,13 +171,20 @@ impl PartitionReader {
         let next = self.current_segment_reader.as_mut().and_then(|reader| {
             reader.read_next()
         });
-        if next.as_ref().map(|r| r.is_err()).unwrap_or(false) {
-            self.returned_error = true;
+        match next {
+            Some(Ok(ref event)) => self.position.set_if_greater(event.id().event_counter as usize),
+            Some(Err(_)) => self.returned_error = true,
+            None => {}
         }
         next
     }
 }
 
+/// Events that were produced with a time to live are never returned to readers once it's passed
+fn is_expired(event: &PersistentEvent) -> bool {
+    event.expiration().map(|expiration| expiration <= time::now()).unwrap_or(false)
+}
+
 impl Iterator for PartitionReader {
     type Item = io::Result<PersistentEvent>;
 
@@ -120,3 +193,57 @@ impl Iterator for PartitionReader {
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use chrono::Duration;
+    use tempdir::TempDir;
+
+    use event::{OwnedFloEvent, FloEventId, EventCounter};
+    use engine::event_stream::partition::SharedReaderRefsMut;
+    use engine::event_stream::partition::segment::{Segment, AppendResult};
+
+    #[test]
+    fn events_with_invalid_namespaces_are_skipped() {
+        let tmpdir = TempDir::new("events_with_invalid_namespaces_are_skipped").unwrap();
+        let mut segment = Segment::init_new(tmpdir.path(), SegmentNum(1), 4096, time::now() + Duration::seconds(10))
+                .expect("failed to initialize segment");
+
+        let mut offsets = Vec::new();
+        for counter in 1..4 {
+            match segment.append(&event(counter)) {
+                AppendResult::Success(offset) => offsets.push(offset),
+                other @ _ => panic!("failed to append event: {:?}", other),
+            }
+        }
+
+        // overwrite the first byte of the second event's namespace, which the mmap shares with the file
+        {
+            let mut file = OpenOptions::new().write(true).open(tmpdir.path().join("1.events")).unwrap();
+            file.seek(SeekFrom::Start(offsets[1] as u64 + 44)).unwrap();
+            file.write_all(&[0xFF]).unwrap();
+        }
+
+        let mut subject = PartitionReader::new(5,
+                                               1,
+                                               EventFilter::All,
+                                               Some(segment.iter_from_start()),
+                                               SharedReaderRefsMut::new().get_reader_refs(),
+                                               None,
+                                               0);
+
+        let first = subject.next().expect("first returned none").expect("failed to read first");
+        assert_eq!(FloEventId::new(1, 1), *first.id());
+        let third = subject.next().expect("third returned none").expect("failed to read third");
+        assert_eq!(FloEventId::new(1, 3), *third.id());
+        assert!(subject.next().is_none());
+        assert_eq!(3, subject.position().load_relaxed());
+    }
+
+    fn event(counter: EventCounter) -> OwnedFloEvent {
+        OwnedFloEvent::new(FloEventId::new(1, counter), None, time::now(), "/foo/bar".to_owned(), vec![1, 2, 3])
+    }
+}
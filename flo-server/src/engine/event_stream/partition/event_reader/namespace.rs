@@ -1,5 +1,8 @@
 
 use glob::{Pattern, MatchOptions};
+use regex::{Regex, RegexBuilder};
+
+use protocol::validate_namespace_glob;
 
 static MATCH_OPTIONS: MatchOptions = MatchOptions {
     case_sensitive: true,
@@ -14,6 +17,7 @@ pub struct NamespaceGlob {
 
 impl NamespaceGlob {
     pub fn new(pattern: &str) -> Result<NamespaceGlob, String> {
+        validate_namespace_glob(pattern)?;
         Pattern::new(pattern).map_err(|err| format!("Invalid namespace pattern: {:?}", err)).map(|pattern| {
             NamespaceGlob {
                 pattern: pattern
@@ -26,6 +30,43 @@ impl NamespaceGlob {
     }
 }
 
+/// Compiled regexes are bounded so that a consumer can't make the server spend unbounded memory or time on a
+/// pathological pattern. Matching is always linear in the length of the namespace, so only compilation needs limits
+const MAX_REGEX_COMPILED_BYTES: usize = 64 * 1024;
+const MAX_REGEX_NESTING: u32 = 16;
+
+#[derive(Debug, Clone)]
+pub struct NamespaceRegex {
+    regex: Regex,
+}
+
+impl NamespaceRegex {
+    /// Creates a regex that must match the whole namespace, rather than just some part of it
+    pub fn new(pattern: &str) -> Result<NamespaceRegex, String> {
+        RegexBuilder::new(&format!("^(?:{})$", pattern))
+                .size_limit(MAX_REGEX_COMPILED_BYTES)
+                .dfa_size_limit(MAX_REGEX_COMPILED_BYTES)
+                .nest_limit(MAX_REGEX_NESTING)
+                .build()
+                .map_err(|err| format!("Invalid namespace regex: {}", err))
+                .map(|regex| {
+                    NamespaceRegex {
+                        regex: regex
+                    }
+                })
+    }
+
+    pub fn matches(&self, namespace: &str) -> bool {
+        self.regex.is_match(namespace)
+    }
+}
+
+impl PartialEq for NamespaceRegex {
+    fn eq(&self, other: &NamespaceRegex) -> bool {
+        self.regex.as_str() == other.regex.as_str()
+    }
+}
+
 
 #[cfg(test)]
 mod test {
@@ -41,6 +82,7 @@ mod test {
         assert!(NamespaceGlob::new("/**foo").is_err());
         assert!(NamespaceGlob::new("/foo**").is_err());
         assert!(NamespaceGlob::new("/foo[unclosed").is_err());
+        assert!(NamespaceGlob::new("").is_err());
     }
 
     #[test]
@@ -98,4 +140,21 @@ mod test {
         let subject = glob(ns);
         assert!(subject.matches(ns));
     }
+
+    #[test]
+    fn regex_must_match_the_whole_namespace() {
+        let subject = NamespaceRegex::new(r"/orders/\d+/shipped").expect("failed to create regex");
+        assert!(subject.matches("/orders/123/shipped"));
+
+        assert!(!subject.matches("/orders/abc/shipped"));
+        assert!(!subject.matches("/orders/123/shipped/late"));
+        assert!(!subject.matches("/archive/orders/123/shipped"));
+    }
+
+    #[test]
+    fn invalid_or_pathological_regexes_return_an_error() {
+        assert!(NamespaceRegex::new("/orders/(unclosed").is_err());
+        assert!(NamespaceRegex::new("((a{100}){100}){100}").is_err());
+        assert!(NamespaceRegex::new(&"(".repeat(20)).is_err());
+    }
 }
@@ -83,6 +83,31 @@ impl PartitionIndex {
         }
     }
 
+    /// Removes all entries with a counter greater than `remove_after`, and returns the highest counter that remains in the
+    /// index, or 0 if the index is now empty
+    pub fn remove_after(&mut self, remove_after: EventCounter) -> EventCounter {
+        let lowest = ::std::cmp::max(self.lowest_counter, 1);
+        let mut counter = self.highest_counter;
+        while counter > remove_after && counter >= lowest {
+            if let Some(index) = self.get_read_index(counter) {
+                self.entries[index] = InternalEntry::default();
+            }
+            counter -= 1;
+        }
+
+        counter = ::std::cmp::min(counter, remove_after);
+        while counter >= lowest && !self.is_set(counter) {
+            counter -= 1;
+        }
+        let new_highest = if counter >= lowest { counter } else { 0 };
+        self.highest_counter = new_highest;
+        new_highest
+    }
+
+    fn is_set(&self, counter: EventCounter) -> bool {
+        self.get_read_index(counter).map(|index| self.entries[index].is_set()).unwrap_or(false)
+    }
+
     pub fn get_next_entry(&self, start_exclusive: EventCounter) -> Option<IndexEntry> {
         let maybe_index = self.get_read_index(start_exclusive + 1);
         if maybe_index.is_none() {
@@ -244,6 +269,24 @@ mod test {
         assert_eq!(Some(entry(2)), result);
     }
 
+    #[test]
+    fn remove_after_removes_entries_with_greater_counters_and_returns_new_highest() {
+        let mut index = PartitionIndex::new(5);
+        index.append(entry(1));
+        index.append(entry(2));
+        index.append(entry(5));
+        index.append(entry(7));
+
+        let result = index.remove_after(4);
+        assert_eq!(2, result);
+        assert_eq!(2, index.greatest_event_counter());
+        assert_next_counter_equals(&index, 1, 2);
+        assert_eq!(None, index.get_next_entry(2));
+
+        index.append(entry(8));
+        assert_next_counter_equals(&index, 2, 8);
+    }
+
     #[test]
     fn remove_range_removes_inclusive_range() {
         let mut index = PartitionIndex::new(5);
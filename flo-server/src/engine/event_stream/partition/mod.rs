@@ -9,11 +9,16 @@ use std::path::{Path, PathBuf};
 use std::collections::VecDeque;
 use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::Duration;
 use std::io;
 
+use futures::Future;
+use futures::future::{self, Loop};
+use tokio_core::reactor::{Handle, Timeout};
+
 use atomics::{AtomicCounterReader, AtomicBoolReader};
 use engine::ConnectionId;
-use engine::event_stream::{EventStreamOptions, HighestCounter};
+use engine::event_stream::{EventStreamOptions, HighestCounter, HighestTimestamp, AckSubscribers};
 use protocol::{ProduceEvent};
 use event::{EventCounter, ActorId};
 use self::segment::SegmentReader;
@@ -23,25 +28,69 @@ pub use self::ops::{OpType,
                     Operation,
                     ProduceOperation,
                     ConsumeOperation,
+                    ReadOperation,
+                    ScanOperation,
+                    ScanReaders,
+                    PartitionScan,
+                    ScanResponseReceiver,
+                    TruncateOperation,
+                    TruncateResult,
+                    TruncateResponseReceiver,
                     ProduceResult,
+                    ProduceComplete,
                     ProduceResponder,
                     ProduceResponseReceiver,
                     ConsumeResponseReceiver,
                     ConsumeResponder,
                     ConsumerNotifier,
+                    ProducedEvents,
+                    ExcludedEvents,
 };
 pub use self::event_reader::{PartitionReader, EventFilter};
 pub use self::segment::PersistentEvent;
 
-pub type PartitionSender = ::std::sync::mpsc::Sender<Operation>;
+pub type PartitionSender = ::std::sync::mpsc::SyncSender<Operation>;
 pub type PartitionReceiver = ::std::sync::mpsc::Receiver<Operation>;
 
+/// The maximum number of operations that may be queued for a partition. Each partition does all of its storage io on
+/// its own thread, so event loop threads only ever enqueue operations. Once a partition falls this far behind, new
+/// operations are rejected instead of blocking the event loop that's trying to send them.
+pub const MAX_QUEUED_OPERATIONS: usize = 1024;
+
+/// How long to wait before trying again to send an operation that must not be lost to a partition whose queue was full
+const FULL_QUEUE_RETRY_MILLIS: u64 = 10;
+
 pub fn create_partition_channels() -> (PartitionSender, PartitionReceiver) {
-    ::std::sync::mpsc::channel()
+    create_partition_channels_with_capacity(MAX_QUEUED_OPERATIONS)
+}
+
+pub fn create_partition_channels_with_capacity(max_queued_operations: usize) -> (PartitionSender, PartitionReceiver) {
+    ::std::sync::mpsc::sync_channel(max_queued_operations)
 }
 
 #[derive(Debug)]
-pub struct PartitionSendError(pub Operation);
+pub enum PartitionSendError {
+    /// The partition already has the maximum number of operations queued
+    Full(Operation),
+    /// The partition has shut down
+    Disconnected(Operation),
+}
+
+impl PartitionSendError {
+    pub fn is_full(&self) -> bool {
+        match *self {
+            PartitionSendError::Full(_) => true,
+            _ => false
+        }
+    }
+
+    pub fn into_operation(self) -> Operation {
+        match self {
+            PartitionSendError::Full(op) => op,
+            PartitionSendError::Disconnected(op) => op,
+        }
+    }
+}
 
 pub type PartitionSendResult = Result<(), PartitionSendError>;
 
@@ -110,6 +159,14 @@ impl SharedReaderRefsMut {
         }
     }
 
+    pub fn remove_after(&self, segment: SegmentNum) {
+        let mut locked = self.inner.write().unwrap();
+        while locked.back().map(|r| r.segment_id > segment).unwrap_or(false) {
+            let removed = locked.pop_back().unwrap();
+            debug!("removing: {} from shared reader refs", removed.segment_id);
+        }
+    }
+
     pub fn get_reader_refs(&self) -> SharedReaderRefs {
         SharedReaderRefs {
             inner: self.inner.clone()
@@ -140,6 +197,12 @@ impl SharedReaderRefs {
         })
     }
 
+    /// Returns true if there's any segment newer than the given one
+    pub fn has_segment_after(&self, segment: SegmentNum) -> bool {
+        let locked = self.inner.read().unwrap();
+        locked.back().map(|r| r.segment_id > segment).unwrap_or(false)
+    }
+
     pub fn get_segment(&self, segment: SegmentNum) -> Option<SegmentReader> {
         if let Some(seg) =  self.get_next_segment(SegmentNum(segment.0.saturating_sub(1))) {
             if seg.segment_id == segment {
@@ -156,22 +219,26 @@ impl SharedReaderRefs {
 
 pub type AsyncProduceResult = Result<ProduceResponseReceiver, PartitionSendError>;
 pub type AsyncConsumeResult = Result<ConsumeResponseReceiver, PartitionSendError>;
+pub type AsyncTruncateResult = Result<TruncateResponseReceiver, PartitionSendError>;
+pub type AsyncScanResult<T> = Result<ScanResponseReceiver<T>, PartitionSendError>;
 
 #[derive(Clone, Debug)]
 pub struct PartitionRef {
     event_stream_name: String,
     partition_num: ActorId,
     highest_event_counter: AtomicCounterReader,
+    stored_bytes: AtomicCounterReader,
     primary: AtomicBoolReader,
     sender: PartitionSender,
 }
 
 impl PartitionRef {
-    pub fn new(event_stream_name: String, partition_num: ActorId, highest_event_counter: AtomicCounterReader, primary: AtomicBoolReader, sender: PartitionSender) -> PartitionRef {
+    pub fn new(event_stream_name: String, partition_num: ActorId, highest_event_counter: AtomicCounterReader, stored_bytes: AtomicCounterReader, primary: AtomicBoolReader, sender: PartitionSender) -> PartitionRef {
         PartitionRef {
             event_stream_name: event_stream_name,
             partition_num: partition_num,
             highest_event_counter: highest_event_counter,
+            stored_bytes: stored_bytes,
             primary: primary,
             sender: sender,
         }
@@ -184,6 +251,11 @@ impl PartitionRef {
         self.highest_event_counter.load_relaxed() as EventCounter
     }
 
+    /// The total number of bytes stored on disk for this partition
+    pub fn stored_bytes(&self) -> u64 {
+        self.stored_bytes.load_relaxed() as u64
+    }
+
     pub fn is_primary(&self) -> bool {
         self.primary.get_relaxed()
     }
@@ -197,23 +269,88 @@ impl PartitionRef {
         self.send(op).map(|()| rx)
     }
 
-    pub fn stop_consuming(&mut self, connection_id: ConnectionId) {
-        let op = Operation::stop_consumer(connection_id);
-        let _ = self.send(op);
+    /// Returns a reader for events with a counter greater than `start`, without registering for notifications of new events
+    pub fn read(&self, connection_id: ConnectionId, filter: EventFilter, start: EventCounter) -> AsyncConsumeResult {
+        let (op, rx) = Operation::read(connection_id, filter, start);
+        self.send(op).map(|()| rx)
+    }
+
+    /// Runs `fun` on the partition's own thread and returns a receiver for its result. Use this instead of `read` for
+    /// anything that reads through more than a handful of events, so that the reading doesn't happen on an event loop
+    pub fn scan<F, T>(&self, connection_id: ConnectionId, fun: F) -> AsyncScanResult<T>
+            where F: FnOnce(&mut ScanReaders) -> T + Send + 'static,
+                  T: Send + 'static {
+        let (op, rx) = Operation::scan(connection_id, fun);
+        self.send(op).map(|()| rx)
+    }
+
+    /// Stops notifying the consumer that was started by `op_id`. Any other consumers on the same connection are unaffected.
+    /// A lost `StopConsumer` would leave the consumer registered with the partition forever, so if the partition's queue is
+    /// full, then it's sent again from a task on `handle` once there's room, instead of failing or blocking the event loop
+    pub fn stop_consuming(&self, connection_id: ConnectionId, op_id: u32, handle: &Handle) {
+        let op = Operation::stop_consumer(connection_id, op_id);
+        match self.send(op) {
+            Ok(()) => {}
+            Err(PartitionSendError::Full(op)) => {
+                debug!("Deferring consumer stop for connection_id: {}, op_id: {} because Partition: {} has a full queue", connection_id, op_id, self.partition_num);
+                handle.spawn(self.clone().retry_while_full(op, handle.clone()));
+            }
+            Err(PartitionSendError::Disconnected(_)) => {
+                debug!("Partition: {} has already shut down, so there's no consumer to stop for connection_id: {}, op_id: {}", self.partition_num, connection_id, op_id);
+            }
+        }
     }
 
     pub fn produce(&mut self, connection_id: ConnectionId, op_id: u32, events: Vec<ProduceEvent>) -> AsyncProduceResult {
-        let (op, rx) = Operation::produce(connection_id, op_id, events);
+        let (op, rx) = Operation::produce(connection_id, op_id, events, None);
         self.send(op).map(|()| rx)
     }
 
+    /// Produces the events the same as `produce`, and also adds their ids to `produced_events`
+    pub fn produce_tracked(&mut self, connection_id: ConnectionId, op_id: u32, events: Vec<ProduceEvent>, produced_events: ProducedEvents) -> AsyncProduceResult {
+        let (op, rx) = Operation::produce(connection_id, op_id, events, Some(produced_events));
+        self.send(op).map(|()| rx)
+    }
+
+    /// Removes all events with a counter greater than `up_to` from the partition. The partition will refuse to truncate
+    /// while it has any active consumers, since they may have already read past that point
+    pub fn truncate(&self, connection_id: ConnectionId, up_to: EventCounter) -> AsyncTruncateResult {
+        let (op, rx) = Operation::truncate(connection_id, up_to);
+        self.send(op).map(|()| rx)
+    }
+
+    /// Asks the partition to expire old events and fsync if it's due. Ticks are sent periodically, so one that's rejected
+    /// because the partition's queue is full is simply replaced by the next one
     pub fn tick(&mut self) -> PartitionSendResult {
         self.send(Operation::tick())
     }
 
-    fn send(&mut self, op: Operation) -> PartitionSendResult {
-        self.sender.send(op).map_err(|err| {
-            PartitionSendError(err.0)
+    /// Returns a future that keeps trying to send the operation, waiting a little between each attempt, until it's either
+    /// accepted or the partition has shut down
+    fn retry_while_full(self, op: Operation, handle: Handle) -> Box<Future<Item=(), Error=()>> {
+        let partition_num = self.partition_num;
+        let retry = future::loop_fn(op, move |op| {
+            let partition = self.clone();
+            future::result(Timeout::new(Duration::from_millis(FULL_QUEUE_RETRY_MILLIS), &handle)).flatten().map(move |()| {
+                match partition.send(op) {
+                    Err(PartitionSendError::Full(op)) => Loop::Continue(op),
+                    _ => Loop::Break(()),
+                }
+            })
+        });
+        Box::new(retry.map_err(move |err| {
+            error!("Failed to retry sending an operation to Partition: {}: {:?}", partition_num, err);
+        }))
+    }
+
+    fn send(&self, op: Operation) -> PartitionSendResult {
+        use std::sync::mpsc::TrySendError;
+
+        self.sender.try_send(op).map_err(|err| {
+            match err {
+                TrySendError::Full(op) => PartitionSendError::Full(op),
+                TrySendError::Disconnected(op) => PartitionSendError::Disconnected(op),
+            }
         })
     }
 }
@@ -224,10 +361,12 @@ pub fn initialize_existing_partition(partition_num: ActorId,
                                      event_stream_data_dir: &Path,
                                      event_stream_options: &EventStreamOptions,
                                      status_reader: AtomicBoolReader,
-                                     highest_counter: HighestCounter) -> io::Result<PartitionRef> {
+                                     highest_counter: HighestCounter,
+                                     highest_timestamp: HighestTimestamp,
+                                     ack_subscribers: AckSubscribers) -> io::Result<PartitionRef> {
 
     let partition_data_dir = get_partition_data_dir(event_stream_data_dir, partition_num);
-    let partition_impl = PartitionImpl::init_existing(partition_num, partition_data_dir, event_stream_options, status_reader, highest_counter)?;
+    let partition_impl = PartitionImpl::init_existing(partition_num, partition_data_dir, event_stream_options, status_reader, highest_counter, highest_timestamp, ack_subscribers)?;
     run_partition(partition_impl)
 }
 
@@ -235,16 +374,19 @@ pub fn initialize_new_partition(partition_num: ActorId,
                                 event_stream_data_dir: &Path,
                                 event_stream_options: &EventStreamOptions,
                                 status_reader: AtomicBoolReader,
-                                highest_counter: HighestCounter) -> io::Result<PartitionRef> {
+                                highest_counter: HighestCounter,
+                                highest_timestamp: HighestTimestamp,
+                                ack_subscribers: AckSubscribers) -> io::Result<PartitionRef> {
 
     let partition_data_dir = get_partition_data_dir(event_stream_data_dir, partition_num);
-    let partition_impl = PartitionImpl::init_new(partition_num, partition_data_dir, &event_stream_options, status_reader, highest_counter)?;
+    let partition_impl = PartitionImpl::init_new(partition_num, partition_data_dir, &event_stream_options, status_reader, highest_counter, highest_timestamp, ack_subscribers)?;
     run_partition(partition_impl)
 }
 
 pub fn run_partition(partition_impl: PartitionImpl) -> io::Result<PartitionRef> {
     let partition_num = partition_impl.partition_num();
     let event_counter_reader = partition_impl.event_counter_reader();
+    let stored_bytes_reader = partition_impl.stored_bytes_reader();
     let primary_status_reader = partition_impl.primary_status_reader();
     let event_stream_name = partition_impl.event_stream_name().to_owned();
     let (tx, rx) = create_partition_channels();
@@ -274,7 +416,7 @@ pub fn run_partition(partition_impl: PartitionImpl) -> io::Result<PartitionRef>
               fsync_result);
     })?;
 
-    Ok(PartitionRef::new(event_stream_name, partition_num, event_counter_reader, primary_status_reader,tx))
+    Ok(PartitionRef::new(event_stream_name, partition_num, event_counter_reader, stored_bytes_reader, primary_status_reader, tx))
 }
 
 fn get_partition_thread_name(event_stream_name: &str, partition_num: ActorId) -> String {
@@ -287,3 +429,45 @@ pub fn get_partition_data_dir(event_stream_dir: &Path, partition_num: ActorId) -
 
 
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+    use tokio_core::reactor::Core;
+    use atomics::{AtomicCounterWriter, AtomicBoolWriter};
+
+    #[test]
+    fn stop_consuming_is_sent_once_there_is_room_in_a_full_queue() {
+        let mut core = Core::new().unwrap();
+        let primary = AtomicBoolWriter::with_value(true);
+        let (tx, rx) = create_partition_channels_with_capacity(1);
+        let mut subject = PartitionRef::new("stop_when_full".to_owned(),
+                                        1,
+                                        AtomicCounterWriter::zero().reader(),
+                                        AtomicCounterWriter::zero().reader(),
+                                        primary.reader(),
+                                        tx);
+
+        subject.tick().expect("failed to send tick");
+        subject.stop_consuming(5, 7, &core.handle());
+
+        // nothing blocked, and the stop waits until the tick has been taken from the queue
+        assert!(rx.try_recv().map(|op| match op.op_type { OpType::Tick => true, _ => false }).unwrap());
+        assert!(rx.try_recv().is_err());
+
+        let start = Instant::now();
+        let stop = loop {
+            core.turn(Some(Duration::from_millis(FULL_QUEUE_RETRY_MILLIS)));
+            if let Ok(op) = rx.try_recv() {
+                break op;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "StopConsumer was never sent");
+        };
+        assert_eq!(5, stop.connection_id);
+        match stop.op_type {
+            OpType::StopConsumer(op_id) => assert_eq!(7, op_id),
+            other @ _ => panic!("expected StopConsumer, got: {:?}", other),
+        }
+    }
+}
@@ -2,22 +2,171 @@
 use std::io;
 use std::fmt::{self, Debug};
 use std::time::Instant;
+use std::collections::{HashMap, BTreeSet};
+use std::sync::{Arc, RwLock};
 
 use futures::sync::oneshot;
 
 use engine::event_stream::partition::{EventFilter, PartitionReader};
 use engine::ConnectionId;
 use protocol::ProduceEvent;
-use event::{FloEventId, EventCounter};
+use event::{FloEventId, EventCounter, ActorId};
 
-pub type ProduceResult = Result<FloEventId, io::Error>;
+/// Sent back to the connection once every event in a `ProduceOperation` has been written to the partition
+#[derive(Debug, PartialEq)]
+pub struct ProduceComplete {
+    /// The id of the last event in the operation
+    pub last_id: FloEventId,
+    /// When the partition finished writing the events
+    pub written_at: Instant,
+}
+
+/// The ids of the events that were produced by a single connection, shared between that connection's producer and the
+/// readers of any of its consumers that exclude its own writes. Partitions add each id before the event can be read, so
+/// a reader never sees one of the connection's events before it's known to be excluded. Ids are only kept while a
+/// registered reader could still reach them, so once every reader of a partition has read past an id it's removed, and
+/// ids in partitions without any readers aren't kept at all. A reader that's registered later, starting from an earlier
+/// position, may therefore see some of the connection's older events.
+#[derive(Debug, Clone, Default)]
+pub struct ProducedEvents {
+    inner: Arc<RwLock<ProducedEventsInner>>,
+}
+
+#[derive(Debug, Default)]
+struct ProducedEventsInner {
+    counters_by_partition: HashMap<ActorId, BTreeSet<EventCounter>>,
+    /// The partition and position of each registered reader, keyed by the reader's `ExcludedEvents::key`
+    readers: HashMap<usize, (ActorId, EventCounter)>,
+    next_reader_key: usize,
+}
+
+impl ProducedEventsInner {
+    fn lowest_position(&self, partition: ActorId) -> Option<EventCounter> {
+        self.readers.values()
+                .filter(|&&(reader_partition, _)| reader_partition == partition)
+                .map(|&(_, position)| position)
+                .min()
+    }
+
+    /// Removes every id in the partition that all of its registered readers have already read past, or all of them if
+    /// the partition has no readers left
+    fn prune(&mut self, partition: ActorId) {
+        match self.lowest_position(partition) {
+            Some(lowest) => {
+                if let Some(counters) = self.counters_by_partition.get_mut(&partition) {
+                    if counters.iter().next().map(|first| *first <= lowest).unwrap_or(false) {
+                        let remaining = counters.split_off(&(lowest + 1));
+                        *counters = remaining;
+                    }
+                }
+            }
+            None => {
+                self.counters_by_partition.remove(&partition);
+            }
+        }
+    }
+}
+
+impl ProducedEvents {
+    pub fn new() -> ProducedEvents {
+        ProducedEvents::default()
+    }
+
+    /// Adds the id, unless there's no reader of its partition that could ever need it
+    pub fn insert(&self, id: FloEventId) {
+        let mut inner = self.inner.write().unwrap();
+        if inner.lowest_position(id.actor).is_some() {
+            inner.counters_by_partition.entry(id.actor).or_insert_with(BTreeSet::new).insert(id.event_counter);
+        }
+    }
+
+    pub fn contains(&self, id: &FloEventId) -> bool {
+        let inner = self.inner.read().unwrap();
+        inner.counters_by_partition.get(&id.actor).map(|counters| counters.contains(&id.event_counter)).unwrap_or(false)
+    }
+
+    /// Registers a reader that will start reading the partition after `start_exclusive`. Ids in the partition are kept
+    /// until the reader has read past them, or until the returned `ExcludedEvents` is dropped
+    pub fn register_reader(&self, partition: ActorId, start_exclusive: EventCounter) -> ExcludedEvents {
+        let mut inner = self.inner.write().unwrap();
+        let key = inner.next_reader_key;
+        inner.next_reader_key += 1;
+        inner.readers.insert(key, (partition, start_exclusive));
+        ExcludedEvents {
+            events: self.clone(),
+            partition: partition,
+            key: key,
+        }
+    }
+
+    /// Returns true if any reader is still registered. Once there are none, nothing needs the ids anymore, so the
+    /// connection can stop tracking them
+    pub fn has_readers(&self) -> bool {
+        !self.inner.read().unwrap().readers.is_empty()
+    }
+
+    /// The total number of ids that are currently kept, across all partitions
+    pub fn tracked_ids(&self) -> usize {
+        self.inner.read().unwrap().counters_by_partition.values().map(|counters| counters.len()).sum()
+    }
+}
+
+/// A single reader's registration with a `ProducedEvents`. The reader reports each event that it reads, which both checks
+/// whether the event is excluded and lets the ids it's read past be removed. The reader is unregistered when this is dropped
+#[derive(Debug)]
+pub struct ExcludedEvents {
+    events: ProducedEvents,
+    partition: ActorId,
+    key: usize,
+}
+
+impl ExcludedEvents {
+    pub fn partition_num(&self) -> ActorId {
+        self.partition
+    }
+
+    /// Records that the reader has read the event with the given id, and returns true if it was produced by the connection
+    pub fn read_past(&self, id: &FloEventId) -> bool {
+        let mut inner = self.events.inner.write().unwrap();
+        let excluded = inner.counters_by_partition.get(&id.actor).map(|counters| counters.contains(&id.event_counter)).unwrap_or(false);
+        let was_lowest = {
+            let lowest = inner.lowest_position(self.partition);
+            let position = inner.readers.get_mut(&self.key).map(|reader| {
+                let previous = reader.1;
+                reader.1 = ::std::cmp::max(previous, id.event_counter);
+                previous
+            });
+            position.is_some() && position == lowest
+        };
+        // only the reader that's furthest behind can allow any more ids to be removed
+        if was_lowest {
+            inner.prune(self.partition);
+        }
+        excluded
+    }
+}
+
+impl Drop for ExcludedEvents {
+    fn drop(&mut self) {
+        let mut inner = self.events.inner.write().unwrap();
+        inner.readers.remove(&self.key);
+        inner.prune(self.partition);
+    }
+}
+
+pub type ProduceResult = Result<ProduceComplete, io::Error>;
 pub type ProduceResponder = oneshot::Sender<ProduceResult>;
 pub type ProduceResponseReceiver = oneshot::Receiver<ProduceResult>;
 
+pub type TruncateResult = Result<(), io::Error>;
+pub type TruncateResponseReceiver = oneshot::Receiver<TruncateResult>;
+
 pub struct ProduceOperation {
-    pub client: oneshot::Sender<io::Result<FloEventId>>,
+    pub client: ProduceResponder,
     pub op_id: u32,
     pub events: Vec<ProduceEvent>,
+    /// if set, then the id of each event is added to it before the event becomes visible to readers
+    pub produced_events: Option<ProducedEvents>,
 }
 
 
@@ -38,6 +187,8 @@ pub trait ConsumerNotifier: Send {
     fn is_active(&self) -> bool;
     /// returns the `ConnectionId` of this consumer
     fn connection_id(&self) -> ConnectionId;
+    /// returns the op_id of the message that started this consumer, which identifies it among the connection's cursors
+    fn op_id(&self) -> u32;
 }
 
 pub struct ConsumeOperation {
@@ -53,11 +204,69 @@ impl Debug for ConsumeOperation {
     }
 }
 
+/// Requests a `PartitionReader` without registering a notifier, for one-off reads that don't need to wait for new events
+pub struct ReadOperation {
+    pub client_sender: oneshot::Sender<PartitionReader>,
+    pub filter: EventFilter,
+    pub start_exclusive: EventCounter,
+}
+
+impl Debug for ReadOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ReadOperation {{ filter: {:?}, start_exclusive: {} }}", self.filter, self.start_exclusive)
+    }
+}
+
+/// Creates the readers used by a `PartitionScan`. Implemented by the partition that's running the scan
+pub trait ScanReaders {
+    /// Returns a reader for events with a counter greater than `start_exclusive`, the same as a `ReadOperation` would
+    fn reader(&mut self, filter: EventFilter, start_exclusive: EventCounter) -> PartitionReader;
+}
+
+/// A read-only computation over the events in a partition. Scans are run on the partition's own thread, so that reading
+/// through a large partition never blocks an event loop. The partition processes no other operations until it's finished
+pub trait PartitionScan: Send {
+    fn scan(self: Box<Self>, readers: &mut ScanReaders);
+}
+
+impl <F> PartitionScan for F where F: FnOnce(&mut ScanReaders) + Send {
+    fn scan(self: Box<Self>, readers: &mut ScanReaders) {
+        (*self)(readers)
+    }
+}
+
+pub struct ScanOperation {
+    pub scan: Box<PartitionScan>,
+}
+
+impl Debug for ScanOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ScanOperation")
+    }
+}
+
+pub type ScanResponseReceiver<T> = oneshot::Receiver<T>;
+
+pub struct TruncateOperation {
+    pub client: oneshot::Sender<TruncateResult>,
+    pub up_to: EventCounter,
+}
+
+impl Debug for TruncateOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TruncateOperation {{ up_to: {} }}", self.up_to)
+    }
+}
+
 #[derive(Debug)]
 pub enum OpType {
     Produce(ProduceOperation),
     Consume(ConsumeOperation),
-    StopConsumer,
+    Read(ReadOperation),
+    Scan(ScanOperation),
+    Truncate(TruncateOperation),
+    /// Removes the notifier of the consumer with this op_id
+    StopConsumer(u32),
     Tick,
 }
 
@@ -86,20 +295,52 @@ impl Operation {
         (op, rx)
     }
 
-    pub fn stop_consumer(connection_id: ConnectionId) -> Operation {
+    pub fn read(connection_id: ConnectionId, filter: EventFilter, start_exclusive: EventCounter) -> (Operation, ConsumeResponseReceiver) {
+        let (tx, rx) = oneshot::channel();
+        let read = ReadOperation {
+            client_sender: tx,
+            filter: filter,
+            start_exclusive: start_exclusive,
+        };
+        let op = Operation {
+            connection_id: connection_id,
+            client_message_recv_time: Instant::now(),
+            op_type: OpType::Read(read)
+        };
+        (op, rx)
+    }
+
+    /// Creates an operation that runs `fun` on the partition's thread and sends back whatever it returns
+    pub fn scan<F, T>(connection_id: ConnectionId, fun: F) -> (Operation, ScanResponseReceiver<T>)
+            where F: FnOnce(&mut ScanReaders) -> T + Send + 'static,
+                  T: Send + 'static {
+        let (tx, rx) = oneshot::channel();
+        let scan = move |readers: &mut ScanReaders| {
+            let _ = tx.send(fun(readers));
+        };
+        let op = Operation {
+            connection_id: connection_id,
+            client_message_recv_time: Instant::now(),
+            op_type: OpType::Scan(ScanOperation { scan: Box::new(scan) }),
+        };
+        (op, rx)
+    }
+
+    pub fn stop_consumer(connection_id: ConnectionId, op_id: u32) -> Operation {
         Operation {
             connection_id: connection_id,
             client_message_recv_time: Instant::now(),
-            op_type: OpType::StopConsumer
+            op_type: OpType::StopConsumer(op_id)
         }
     }
 
-    pub fn produce(connection_id: ConnectionId, op_id: u32, events: Vec<ProduceEvent>) -> (Operation, ProduceResponseReceiver) {
+    pub fn produce(connection_id: ConnectionId, op_id: u32, events: Vec<ProduceEvent>, produced_events: Option<ProducedEvents>) -> (Operation, ProduceResponseReceiver) {
         let (tx, rx) = oneshot::channel();
         let produce = ProduceOperation {
             client: tx,
             op_id: op_id,
-            events: events
+            events: events,
+            produced_events: produced_events,
         };
         let op = Operation {
             connection_id: connection_id,
@@ -109,6 +350,20 @@ impl Operation {
         (op, rx)
     }
 
+    pub fn truncate(connection_id: ConnectionId, up_to: EventCounter) -> (Operation, TruncateResponseReceiver) {
+        let (tx, rx) = oneshot::channel();
+        let truncate = TruncateOperation {
+            client: tx,
+            up_to: up_to,
+        };
+        let op = Operation {
+            connection_id: connection_id,
+            client_message_recv_time: Instant::now(),
+            op_type: OpType::Truncate(truncate),
+        };
+        (op, rx)
+    }
+
     pub fn tick() -> Operation {
         Operation {
             connection_id: 0,
@@ -118,3 +373,50 @@ impl Operation {
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn produced_ids_are_removed_once_every_reader_of_their_partition_has_read_past_them() {
+        let produced = ProducedEvents::new();
+        let first_reader = produced.register_reader(1, 0);
+        let second_reader = produced.register_reader(1, 0);
+        for counter in 1..4 {
+            produced.insert(FloEventId::new(1, counter));
+        }
+
+        assert!(first_reader.read_past(&FloEventId::new(1, 2)));
+        assert_eq!(3, produced.tracked_ids());
+
+        assert!(second_reader.read_past(&FloEventId::new(1, 1)));
+        assert_eq!(2, produced.tracked_ids());
+
+        assert!(second_reader.read_past(&FloEventId::new(1, 3)));
+        assert_eq!(1, produced.tracked_ids());
+        assert!(produced.contains(&FloEventId::new(1, 3)));
+        assert!(!produced.contains(&FloEventId::new(1, 2)));
+
+        drop(first_reader);
+        assert_eq!(0, produced.tracked_ids());
+    }
+
+    #[test]
+    fn produced_ids_are_only_kept_for_partitions_that_have_a_registered_reader() {
+        let produced = ProducedEvents::new();
+        assert!(!produced.has_readers());
+        let reader = produced.register_reader(1, 5);
+        assert!(produced.has_readers());
+
+        produced.insert(FloEventId::new(2, 6));
+        produced.insert(FloEventId::new(1, 7));
+        assert!(!produced.contains(&FloEventId::new(2, 6)));
+        assert!(produced.contains(&FloEventId::new(1, 7)));
+        assert!(!reader.read_past(&FloEventId::new(1, 6)));
+
+        drop(reader);
+        assert!(!produced.has_readers());
+        assert_eq!(0, produced.tracked_ids());
+    }
+}
@@ -12,7 +12,7 @@ use memmap::Mmap;
 use engine::event_stream::partition::segment::PersistentEvent;
 use engine::event_stream::partition::SegmentNum;
 use engine::event_stream::partition::index::{PartitionIndex, IndexEntry};
-use event::{FloEvent, EventCounter};
+use event::{FloEvent, EventCounter, Timestamp, time};
 use super::header::SegmentHeader;
 
 
@@ -83,12 +83,23 @@ impl Drop for SegmentDeleter {
     }
 }
 
+fn latest_expiration<E: FloEvent>(current: Option<Timestamp>, event: &E) -> Option<Timestamp> {
+    match (current, event.expiration()) {
+        (Some(current), Some(expiration)) if expiration > current => Some(expiration),
+        (Some(current), Some(_)) => Some(current),
+        _ => None,
+    }
+}
+
 #[derive(Debug)]
 pub struct MmapAppender {
     dirty: bool,
     inner: MmapRef,
     file_path: PathBuf,
     pub last_event_counter: EventCounter,
+    /// The time at which every event that's been appended will have passed its time to live, or `None` if any of them was
+    /// produced without one. This is not moved back by `truncate`, so it may be later than it needs to be
+    pub all_events_expire_at: Option<Timestamp>,
 }
 
 
@@ -104,6 +115,7 @@ impl MmapAppender {
             inner: Arc::new(inner),
             file_path,
             last_event_counter: 0,
+            all_events_expire_at: Some(time::from_millis_since_epoch(0)),
         }
     }
 
@@ -124,6 +136,7 @@ impl MmapAppender {
             index.append(entry);
             event_count += 1;
             highest_counter = event.id().event_counter;
+            appender.all_events_expire_at = latest_expiration(appender.all_events_expire_at, &event);
         }
         appender.last_event_counter = highest_counter;
         let head = reader.current_offset;
@@ -140,6 +153,14 @@ impl MmapAppender {
     }
 
     pub fn append<E: FloEvent>(&mut self, event: &E) -> io::Result<Option<usize>> {
+        self.append_with(event, |mut buffer| PersistentEvent::write(event, &mut buffer))
+    }
+
+    /// Appends the event using `write_fun` to write it into the mmap. The head is only moved once the whole event has
+    /// been written, so readers never see a partial event. If the write fails, then whatever was written is zeroed out so
+    /// that the segment is left ending at the last complete event, and the error is returned.
+    fn append_with<E, F>(&mut self, event: &E, write_fun: F) -> io::Result<Option<usize>>
+            where E: FloEvent, F: FnOnce(&mut [u8]) -> io::Result<()> {
         unsafe {
             let event_len = PersistentEvent::get_repr_length(event) as usize;
             let start_offset = self.inner.head.load(Ordering::Relaxed);
@@ -150,15 +171,45 @@ impl MmapAppender {
                 return Ok(None);
             }
 
-            PersistentEvent::write_unchecked(event, write_slice);
+            if let Err(io_err) = write_fun(&mut write_slice[..event_len]) {
+                error!("Failed to write event: {} at offset: {}, discarding the partial event: {:?}", event.id(), start_offset, io_err);
+                for byte in write_slice[..event_len].iter_mut() {
+                    *byte = 0;
+                }
+                return Err(io_err);
+            }
             self.inner.head.fetch_add(event_len, Ordering::SeqCst);
             self.dirty = true;
             self.last_event_counter = event.id().event_counter;
+            self.all_events_expire_at = latest_expiration(self.all_events_expire_at, event);
 
             Ok(Some(start_offset))
         }
     }
 
+    /// Moves the head back to the given offset and zeroes out everything that was written after it, so that the discarded
+    /// events will not be found again when the segment is re-initialized
+    pub fn truncate(&mut self, offset: usize, last_event_counter: EventCounter) {
+        let head = self.inner.head.load(Ordering::SeqCst);
+        if offset >= head {
+            return;
+        }
+        self.inner.head.store(offset, Ordering::SeqCst);
+        unsafe {
+            let write_slice = self.inner.get_write_slice(offset);
+            for byte in write_slice[..(head - offset)].iter_mut() {
+                *byte = 0;
+            }
+        }
+        self.last_event_counter = last_event_counter;
+        self.dirty = true;
+    }
+
+    /// Returns true if events have been written or truncated since the last `flush`
+    pub fn has_unsynced_writes(&self) -> bool {
+        self.dirty
+    }
+
     pub fn flush(&mut self) -> io::Result<()> {
         if self.dirty {
             unsafe {
@@ -169,6 +220,11 @@ impl MmapAppender {
         Ok(())
     }
 
+    /// The number of `MmapReader`s and `PersistentEvent`s that currently refer to this mmap
+    pub fn reader_count(&self) -> usize {
+        Arc::strong_count(&self.inner) - 1
+    }
+
     pub fn get_file_position(&self) -> usize {
         self.inner.head.load(Ordering::SeqCst)
     }
@@ -353,6 +409,78 @@ mod test {
         assert_eq!(input3, read_3.to_owned());
     }
 
+    /// Writes up to `remaining` bytes to the inner writer, and then fails like a disk that has filled up
+    struct FailingWriter<W: io::Write> {
+        inner: W,
+        remaining: usize,
+    }
+
+    impl <W: io::Write> io::Write for FailingWriter<W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(io::Error::new(io::ErrorKind::Other, "No space left on device"));
+            }
+            let len = ::std::cmp::min(self.remaining, buf.len());
+            let written = self.inner.write(&buf[..len])?;
+            self.remaining -= written;
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn failed_write_leaves_segment_at_end_of_last_complete_event() {
+        let mut subject = anon_mmap();
+        let input1 = OwnedFloEvent::new(
+            FloEventId::new(3, 4),
+            None,
+            time::from_millis_since_epoch(999),
+            "/foo/bar".to_owned(),
+            vec![1, 2, 3, 4, 5]);
+        let input2 = OwnedFloEvent::new(
+            FloEventId::new(3, 5),
+            None,
+            time::from_millis_since_epoch(999),
+            "/foo/bar".to_owned(),
+            vec![6; 64]);
+
+        subject.append(&input1).unwrap().expect("write returned none");
+        let committed_len = subject.get_file_position();
+
+        // fail part way through the event body
+        let result = subject.append_with(&input2, |buffer| {
+            let mut writer = FailingWriter { inner: buffer, remaining: 70 };
+            PersistentEvent::write(&input2, &mut writer)
+        });
+        assert!(result.is_err());
+        assert_eq!(committed_len, subject.get_file_position());
+        assert_eq!(4, subject.last_event_counter);
+
+        let read_all = subject.reader(0)
+            .map(|result| result.expect("failed to read event").to_owned())
+            .collect::<Vec<OwnedFloEvent>>();
+        assert_eq!(vec![input1.clone()], read_all);
+
+        // the partial event must not be found when the segment is re-initialized either
+        let partial = subject.inner.get_read_slice(committed_len);
+        assert!(partial.is_empty());
+        unsafe {
+            let after_head = subject.inner.get_write_slice(committed_len);
+            assert!(after_head[..70].iter().all(|b| *b == 0));
+        }
+
+        // the next append goes right where the failed one started
+        let offset = subject.append(&input2).unwrap().expect("write returned none");
+        assert_eq!(committed_len, offset);
+        let read_all = subject.reader(0)
+            .map(|result| result.expect("failed to read event").to_owned())
+            .collect::<Vec<OwnedFloEvent>>();
+        assert_eq!(vec![input1, input2], read_all);
+    }
+
     fn assert_read_err<F: Fn(&mut [u8])>(expected_description: &str, modify_buffer_fun: F) {
         use std::error::Error;
 
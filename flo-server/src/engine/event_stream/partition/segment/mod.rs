@@ -49,8 +49,22 @@ pub struct Segment {
 
 impl Segment {
 
-    pub fn is_expired(&self, now: Timestamp) -> bool {
-        now < self.segment_end_time
+    /// Returns true if every event in the segment is older than the `cutoff`. No event newer than the segment's end time
+    /// can ever be appended to it, so this never includes a segment that's still being written to
+    pub fn is_expired(&self, cutoff: Timestamp) -> bool {
+        self.segment_end_time < cutoff
+    }
+
+    /// Returns true if nothing more can be appended to the segment and every event in it was produced with a time to live
+    /// that's passed as of `now`, so that none of them can ever be read again
+    pub fn all_events_have_expired(&self, now: Timestamp) -> bool {
+        self.segment_end_time < now && self.appender.all_events_expire_at.map(|expire_at| expire_at <= now).unwrap_or(false)
+    }
+
+    /// The partition always keeps one reader for each segment, so any more than that belong to consumers that are
+    /// positioned in this segment, or to events from it that are still waiting to be sent
+    pub fn has_active_readers(&self) -> bool {
+        self.appender.reader_count() > 1
     }
 
     pub fn delete_on_drop(&mut self) {
@@ -87,14 +101,30 @@ impl Segment {
         }
     }
 
+    /// The number of bytes that have been written to the segment file, including the header
+    pub fn stored_bytes(&self) -> usize {
+        self.appender.get_file_position()
+    }
+
     pub fn iter_from_start(&self) -> SegmentReader {
         self.range_iter(SegmentHeader::get_repr_length())
     }
 
+    /// Discards all events starting at the given byte offset, which must be the start of an event
+    pub fn truncate(&mut self, offset: usize, new_highest_counter: EventCounter) {
+        let offset = ::std::cmp::max(offset, SegmentHeader::get_repr_length());
+        self.appender.truncate(offset, new_highest_counter);
+        self.current_length_bytes = offset;
+    }
+
     pub fn fsync(&mut self) -> io::Result<()> {
         self.appender.flush()
     }
 
+    pub fn has_unsynced_writes(&self) -> bool {
+        self.appender.has_unsynced_writes()
+    }
+
     pub fn init_from_existing_file(file_path: &Path, segment_num: SegmentNum, index: &mut PartitionIndex) -> io::Result<Segment> {
         let file = OpenOptions::new().read(true).write(true).open(&file_path)?;
         let file_len = file.metadata()?.len() as usize;
@@ -125,7 +155,10 @@ impl Segment {
         // Pre-allocate the file, since we're going to use it for mmap, and extending the file after it's been mapped
         // requires ensuring there are no existing borrows of it in any other threads. Far simpler just to pre-allocate the
         // maximum file size. Space is relatively cheap, anyway
-        file.set_len(max_size as u64)?;
+        if let Err(io_err) = preallocate(&file, max_size as u64) {
+            let _ = ::std::fs::remove_file(&file_path);
+            return Err(io_err);
+        }
 
         let mut mmap = Mmap::open(&file, Protection::ReadWrite)?;
         let header = SegmentHeader {
@@ -150,6 +183,36 @@ impl Segment {
 }
 
 
+/// Reserves disk blocks for the whole file, rather than just setting its length. Writes to the mmap can't return an error,
+/// so if the file were sparse then running out of disk space would crash the process with SIGBUS instead of failing here
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // posix_fallocate returns the error number instead of setting errno
+    match unsafe { ::libc::posix_fallocate(file.as_raw_fd(), 0, len as ::libc::off_t) } {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno))
+    }
+}
+
+/// Reserves disk blocks for the whole file by writing zeros to it, since there's no portable way to preallocate
+#[cfg(not(target_os = "linux"))]
+fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    use std::io::Write;
+
+    let zeros = [0u8; 64 * 1024];
+    let mut writer = file;
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = ::std::cmp::min(remaining, zeros.len() as u64) as usize;
+        writer.write_all(&zeros[..chunk])?;
+        remaining -= chunk as u64;
+    }
+    writer.flush()
+}
+
+
 #[derive(Clone, Debug)]
 pub struct SegmentReader {
     pub segment_id: SegmentNum,
@@ -231,6 +294,30 @@ mod test {
         assert!(iter.next().is_none());
     }
 
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn new_segment_has_disk_blocks_allocated_for_its_whole_length() {
+        use std::os::unix::fs::MetadataExt;
+
+        let tmpdir = TempDir::new("segment_preallocated").unwrap();
+        let max_size = 256 * 1024;
+        let _subject = Segment::init_new(tmpdir.path(), SegmentNum(1), max_size, future_time(2)).expect("failed to initialize segment");
+
+        let metadata = ::std::fs::metadata(tmpdir.path().join("1.events")).expect("failed to read metadata");
+        assert_eq!(max_size as u64, metadata.len());
+        // st_blocks is always in units of 512 bytes
+        assert!(metadata.blocks() * 512 >= max_size as u64, "only {} blocks were allocated", metadata.blocks());
+    }
+
+    #[test]
+    fn segment_that_cannot_be_allocated_returns_an_error_and_leaves_no_file() {
+        let tmpdir = TempDir::new("segment_too_large").unwrap();
+        let result = Segment::init_new(tmpdir.path(), SegmentNum(1), 1 << 62, future_time(2));
+
+        assert!(result.is_err());
+        assert!(!tmpdir.path().join("1.events").exists());
+    }
+
     #[test]
     fn write_multiple_events_and_read_them_back() {
         let tmpdir = TempDir::new("write_events_to_segment").unwrap();
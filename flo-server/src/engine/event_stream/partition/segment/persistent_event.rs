@@ -1,9 +1,10 @@
-use std::io;
+use std::io::{self, Write};
 
-use byteorder::{ByteOrder, BigEndian};
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
 
 use event::{FloEvent, OwnedFloEvent, FloEventId, Timestamp, time};
 use engine::event_stream::partition::segment::mmap::{MmapRef};
+use engine::event_stream::encryption::EventEncryptor;
 
 
 
@@ -12,6 +13,10 @@ pub struct PersistentEvent {
     id: FloEventId,
     file_offset: usize,
     raw_data: MmapRef,
+    /// Set only for streams that are encrypted at rest, in which case the data in the mmap is the encrypted body
+    decrypted_data: Option<Vec<u8>>,
+    /// When set, `data` and `data_len` refer to only this many bytes from the start of the body
+    data_prefix_len: Option<usize>,
 }
 
 
@@ -28,19 +33,55 @@ impl PersistentEvent {
         // 4 for namespace.len +  start = 40
         // x for namespace +      start = 44
         // 4 for data.len +       start = 44 + x = ?
-        // y for data             start = 48 + x = ?
+        // y for data +           start = 48 + x = ?
+        // 8 for expiration       start = 48 + x + y = ? (only if the event has an expiration)
         //
-        // = 48 + x + y
-        48u32 + event.namespace().len() as u32 + event.data_len()
+        // = 48 + x + y (+ 8)
+        let expiration_len = if event.expiration().is_some() { 8 } else { 0 };
+        48u32 + event.namespace().len() as u32 + event.data_len() + expiration_len
     }
 
     pub fn total_repr_len(&self) -> usize {
-        PersistentEvent::get_repr_length(self) as usize
+        // read from the header, since `data_len` may refer to the decrypted body
+        BigEndian::read_u32(self.as_buf(0, 4)) as usize
     }
 
-    pub unsafe fn write_unchecked<E: FloEvent>(event: &E, buffer: &mut [u8]) {
+    /// Decrypts the body of the event, so that `data` and `data_len` will refer to the decrypted body
+    pub fn decrypt(&mut self, encryptor: &EventEncryptor) -> io::Result<()> {
+        let decrypted = encryptor.decrypt(&self.id, self.stored_data())?;
+        self.decrypted_data = Some(decrypted);
+        Ok(())
+    }
+
+    /// Limits `data` and `data_len` to refer to only the first `len` bytes of the (decrypted) body
+    pub fn truncate_data(&mut self, len: usize) {
+        self.data_prefix_len = Some(len);
+    }
+
+    fn full_data(&self) -> &[u8] {
+        match self.decrypted_data {
+            Some(ref data) => data.as_slice(),
+            None => self.stored_data()
+        }
+    }
+
+    fn stored_data_len(&self) -> u32 {
+        let ns_len = self.namespace_len() as usize;
+        let data_len_buf = self.as_buf(44 + ns_len, 4);
+        BigEndian::read_u32(data_len_buf)
+    }
+
+    fn stored_data(&self) -> &[u8] {
+        let ns_len = self.namespace_len() as usize;
+        let data_len = self.stored_data_len() as usize;
+        self.as_buf(48 + ns_len, data_len)
+    }
+
+    /// Writes the event to the given writer. If this returns an error, then some or all of the event may have already
+    /// been written, and it's up to the caller to discard the partial event
+    pub fn write<E: FloEvent, W: Write>(event: &E, writer: &mut W) -> io::Result<()> {
         let len = PersistentEvent::get_repr_length(event);
-        write_event_unchecked(buffer, event, len);
+        write_event(writer, event, len)
     }
 
     pub fn read(mmap: &MmapRef, start_offset: usize) -> io::Result<Self> {
@@ -53,6 +94,13 @@ impl PersistentEvent {
         })
     }
 
+    /// Namespaces are validated as UTF-8 when events are produced, so this only returns false if the stored event has
+    /// been corrupted. `namespace` must not be called on an event with an invalid namespace
+    pub fn has_valid_namespace(&self) -> bool {
+        let ns_len = self.namespace_len() as usize;
+        ::std::str::from_utf8(self.as_buf(44, ns_len)).is_ok()
+    }
+
     pub fn file_offset(&self) -> usize {
         self.file_offset
     }
@@ -63,6 +111,8 @@ impl PersistentEvent {
             id: id,
             file_offset: start_offset,
             raw_data: mmap,
+            decrypted_data: None,
+            data_prefix_len: None,
         })
     }
 
@@ -94,7 +144,8 @@ impl PersistentEvent {
         let data_len_buf = &buffer[data_len_pos..(data_len_pos + 4)];
         let data_len = BigEndian::read_u32(data_len_buf);
 
-        if total_len != 48 + ns_len + data_len {
+        let base_len = 48 + ns_len + data_len;
+        if total_len != base_len && total_len != base_len + 8 {
             return Err(io::Error::new(io::ErrorKind::InvalidData, "mismatched lengths"));
         }
 
@@ -109,6 +160,17 @@ impl PersistentEvent {
         let buf = self.as_buf(40, 4);
         BigEndian::read_u32(buf)
     }
+
+    /// Events that were produced with a time to live have their expiration appended after the data. Those without one
+    /// are stored exactly as they were before expirations were supported
+    fn expiration_offset(&self) -> Option<usize> {
+        let end_of_data = 48 + self.namespace_len() as usize + self.stored_data_len() as usize;
+        if self.total_repr_len() > end_of_data {
+            Some(end_of_data)
+        } else {
+            None
+        }
+    }
 }
 
 impl PartialEq for PersistentEvent {
@@ -117,6 +179,7 @@ impl PartialEq for PersistentEvent {
                 self.parent_id() == other.parent_id() &&
                 self.namespace() == other.namespace() &&
                 self.timestamp() == other.timestamp() &&
+                self.expiration() == other.expiration() &&
                 self.data() == other.data()
     }
 }
@@ -152,15 +215,21 @@ impl FloEvent for PersistentEvent {
     }
 
     fn data_len(&self) -> u32 {
-        let ns_len = self.namespace_len() as usize;
-        let data_len_buf = self.as_buf(44 + ns_len, 4);
-        BigEndian::read_u32(data_len_buf)
+        self.data().len() as u32
     }
 
     fn data(&self) -> &[u8] {
-        let ns_len = self.namespace_len() as usize;
-        let data_len = self.data_len() as usize;
-        self.as_buf(48 + ns_len, data_len)
+        let data = self.full_data();
+        match self.data_prefix_len {
+            Some(len) if len < data.len() => &data[..len],
+            _ => data
+        }
+    }
+
+    fn expiration(&self) -> Option<Timestamp> {
+        self.expiration_offset().map(|offset| {
+            time::from_millis_since_epoch(BigEndian::read_u64(self.as_buf(offset, 8)))
+        })
     }
 
     fn to_owned(&self) -> OwnedFloEvent {
@@ -175,9 +244,8 @@ impl FloEvent for PersistentEvent {
 
 
 /// private function to write the event. `total_size` must match the actual size of the data to be written
-fn write_event_unchecked<E: FloEvent>(buffer: &mut [u8], event: &E, total_size: u32) {
+fn write_event<E: FloEvent, W: Write>(writer: &mut W, event: &E, total_size: u32) -> io::Result<()> {
     use event::time::millis_since_epoch;
-    use protocol::serializer::Serializer;
 
     // Don't change this function without also changing `get_repr_len` above!
     //
@@ -189,23 +257,25 @@ fn write_event_unchecked<E: FloEvent>(buffer: &mut [u8], event: &E, total_size:
     // 4 for namespace.len +  start = 40
     // x for namespace +      start = 44
     // 4 for data.len +       start = 44 + x = ?
-    // y for data             start = 48 + x = ?
+    // y for data +           start = 48 + x = ?
+    // 8 for expiration       start = 48 + x + y = ? (only if the event has an expiration)
     //
-    // = 48 + x + y
-
-    Serializer::new(buffer)
-            .write_u32(total_size)
-            .write_bytes(b"FLO_EVT\n")
-            .write_u16(event.id().actor)
-            .write_u64(event.id().event_counter)
-            .write_u16(event.parent_id().map(|e| e.actor).unwrap_or(0))
-            .write_u64(event.parent_id().map(|e| e.event_counter).unwrap_or(0))
-            .write_u64(millis_since_epoch(event.timestamp()))
-            .write_u32(event.namespace().len() as u32)
-            .write_bytes(event.namespace().as_bytes())
-            .write_u32(event.data_len())
-            .write_bytes(event.data())
-            .finish();
+    // = 48 + x + y (+ 8)
+
+    writer.write_u32::<BigEndian>(total_size)?;
+    writer.write_all(b"FLO_EVT\n")?;
+    writer.write_u16::<BigEndian>(event.id().actor)?;
+    writer.write_u64::<BigEndian>(event.id().event_counter)?;
+    writer.write_u16::<BigEndian>(event.parent_id().map(|e| e.actor).unwrap_or(0))?;
+    writer.write_u64::<BigEndian>(event.parent_id().map(|e| e.event_counter).unwrap_or(0))?;
+    writer.write_u64::<BigEndian>(millis_since_epoch(event.timestamp()))?;
+    writer.write_u32::<BigEndian>(event.namespace().len() as u32)?;
+    writer.write_all(event.namespace().as_bytes())?;
+    writer.write_u32::<BigEndian>(event.data_len())?;
+    writer.write_all(event.data())?;
+
+    if let Some(expiration) = event.expiration() {
+        writer.write_u64::<BigEndian>(millis_since_epoch(expiration))?;
+    }
+    Ok(())
 }
-
-
@@ -0,0 +1,35 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use event::FloEventId;
+use protocol::StreamTag;
+
+/// Names given to specific events in an event stream, so that operators can refer to something like "the state at release
+/// v1.2" instead of an event id. Tags are shared by every clone of an `EventStreamRef`, and are persisted by the engine
+#[derive(Clone, Debug)]
+pub struct StreamTags(Arc<Mutex<BTreeMap<String, FloEventId>>>);
+
+impl StreamTags {
+    pub fn new() -> StreamTags {
+        StreamTags(Arc::new(Mutex::new(BTreeMap::new())))
+    }
+
+    /// Sets the tag to refer to the given event id, replacing it if it already exists
+    pub fn set(&self, name: String, event_id: FloEventId) {
+        self.0.lock().unwrap().insert(name, event_id);
+    }
+
+    pub fn get(&self, name: &str) -> Option<FloEventId> {
+        self.0.lock().unwrap().get(name).cloned()
+    }
+
+    /// Returns every tag, sorted by name
+    pub fn all(&self) -> Vec<StreamTag> {
+        self.0.lock().unwrap().iter().map(|(name, id)| {
+            StreamTag {
+                name: name.clone(),
+                event_id: *id,
+            }
+        }).collect()
+    }
+}
@@ -0,0 +1,137 @@
+use std::io;
+use std::collections::HashSet;
+
+use event::{FloEvent, FloEventId, ActorId, EventCounter};
+use protocol::{PartitionIntegrity, IntegrityProblem, IntegrityProblemKind, MAX_REPORTED_INTEGRITY_PROBLEMS};
+use engine::event_stream::partition::{PersistentEvent, ScanReaders, EventFilter};
+
+/// Controls how much of an event stream is checked by `EventStreamRef::verify`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct VerifyOptions {
+    /// The exclusive starting counter for each partition. Partitions that aren't included are verified from the beginning
+    pub version_vector: Vec<FloEventId>,
+    /// The maximum number of events to verify in each partition
+    pub max_events: Option<u64>,
+    /// Whether to check that the parent of each event is an earlier event that exists in one of the stream's partitions
+    pub check_parents: bool,
+}
+
+impl VerifyOptions {
+    pub fn start_for(&self, partition_num: ActorId) -> EventCounter {
+        self.version_vector.iter()
+                .find(|id| id.actor == partition_num)
+                .map(|id| id.event_counter)
+                .unwrap_or(0)
+    }
+}
+
+/// The result of reading through a single partition, before the parents of its events have been looked up
+#[derive(Debug)]
+pub struct PartitionVerification {
+    pub integrity: PartitionIntegrity,
+    /// The ids of each checked event that has a parent, along with the id of the parent
+    pub parents: Vec<(FloEventId, FloEventId)>,
+}
+
+impl PartitionVerification {
+    /// Adds an `InvalidParent` problem for every event whose parent is in `missing`, and returns the finished result
+    pub fn finish(self, missing: &HashSet<FloEventId>) -> PartitionIntegrity {
+        let PartitionVerification {mut integrity, parents} = self;
+        for (id, parent) in parents {
+            if missing.contains(&parent) {
+                add_problem(&mut integrity, IntegrityProblemKind::InvalidParent, id, format!("Parent: {} does not exist in the stream", parent));
+            }
+        }
+        integrity
+    }
+}
+
+/// Reads through the events of a single partition, checking each one. Reading stops at the first event that can't be
+/// read, since there's no way to find where the next event starts. Events are only ever read, never modified. Parents
+/// that could be valid are returned so that they can be looked up in their own partitions using `find_missing_events`.
+pub fn verify_partition<I>(partition_num: ActorId, events: I, all_partitions: &[ActorId], options: &VerifyOptions) -> PartitionVerification
+        where I: Iterator<Item=io::Result<PersistentEvent>> {
+
+    let mut integrity = PartitionIntegrity {
+        partition_num: partition_num,
+        events_checked: 0,
+        last_verified: FloEventId::new(partition_num, options.start_for(partition_num)),
+        complete: true,
+        problems: Vec::new(),
+    };
+    let mut parents = Vec::new();
+
+    for result in events {
+        if options.max_events.map(|max| integrity.events_checked >= max).unwrap_or(false) {
+            integrity.complete = false;
+            break;
+        }
+
+        let event = match result {
+            Ok(event) => event,
+            Err(io_err) => {
+                let last = integrity.last_verified;
+                add_problem(&mut integrity, IntegrityProblemKind::Unreadable, last, format!("Failed to read the event after: {}: {}", last, io_err));
+                break;
+            }
+        };
+
+        let id = *event.id();
+        if id.actor != partition_num || id.event_counter <= integrity.last_verified.event_counter {
+            let description = format!("Event: {} follows: {} in partition: {}", id, integrity.last_verified, partition_num);
+            add_problem(&mut integrity, IntegrityProblemKind::OutOfOrder, id, description);
+        }
+
+        if options.check_parents {
+            if let Some(parent) = event.parent_id() {
+                // counters are assigned in order across all partitions, so a parent always has a lower counter
+                if !all_partitions.contains(&parent.actor) || parent.event_counter >= id.event_counter {
+                    add_problem(&mut integrity, IntegrityProblemKind::InvalidParent, id, format!("Parent: {} is not an earlier event in the stream", parent));
+                } else {
+                    parents.push((id, parent));
+                }
+            }
+        }
+
+        integrity.events_checked += 1;
+        integrity.last_verified = id;
+    }
+    PartitionVerification {
+        integrity: integrity,
+        parents: parents,
+    }
+}
+
+/// Looks up each of the given ids in a single partition, and returns the ones that don't exist. Ids that are older than
+/// the first event in the partition are assumed to have expired or been truncated, and are not returned. An id is also
+/// returned if the event that would have it can't be read.
+pub fn find_missing_events(readers: &mut ScanReaders, mut ids: Vec<FloEventId>) -> Vec<FloEventId> {
+    ids.sort();
+    ids.dedup();
+
+    let oldest = match readers.reader(EventFilter::All, 0).next() {
+        Some(Ok(event)) => event.id().event_counter,
+        _ => return ids,
+    };
+
+    ids.into_iter().filter(|id| {
+        if id.event_counter < oldest {
+            return false;
+        }
+        match readers.reader(EventFilter::All, id.event_counter - 1).next() {
+            Some(Ok(ref event)) => event.id() != id,
+            _ => true,
+        }
+    }).collect()
+}
+
+fn add_problem(integrity: &mut PartitionIntegrity, kind: IntegrityProblemKind, event_id: FloEventId, description: String) {
+    warn!("Integrity problem in partition: {}: {:?} - {}", integrity.partition_num, kind, description);
+    if integrity.problems.len() < MAX_REPORTED_INTEGRITY_PROBLEMS {
+        integrity.problems.push(IntegrityProblem {
+            kind: kind,
+            event_id: event_id,
+            description: description,
+        });
+    }
+}
@@ -4,15 +4,22 @@ mod controller;
 mod connection_handler;
 
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicUsize};
+use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
+use std::io;
 
-use protocol::ProtocolMessage;
-use event::OwnedFloEvent;
-use self::event_stream::EventStreamRef;
+use futures::Future;
 
-pub use self::controller::{ControllerOptions, start_controller};
-pub use self::connection_handler::{ConnectionHandler, ConnectionHandlerResult};
+use protocol::{ProtocolMessage, StreamDescriptor, ProduceEvent, Compression};
+use event::{OwnedFloEvent, FloEventId, FloEvent};
+use metrics::{StreamMetrics, Gauge};
+use self::event_stream::{EventStreamRef, TruncateFuture, VerifyFuture, VerifyOptions, AncestryFuture, AckSubscription, MAX_ANCESTRY_DEPTH};
+use self::controller::registry::{RegisteredTag, save_tags};
+
+pub use self::controller::{ControllerOptions, start_controller, validate_options};
+pub use self::connection_handler::{ConnectionHandler, ConnectionHandlerResult, ReplayLimiter};
+pub use self::connection_handler::connection_state::{DEFAULT_MAX_CURSORS_PER_CONNECTION, DEFAULT_MAX_IN_FLIGHT_PRODUCE_BYTES};
 
 pub type ConnectionId = usize;
 
@@ -23,11 +30,24 @@ pub type ReceivedProtocolMessage = ProtocolMessage<OwnedFloEvent>;
 /// The type of messages that are sent to client
 pub type SendProtocolMessage = ProtocolMessage<PersistentEvent>;
 
-pub type ClientSender = ::futures::sync::mpsc::UnboundedSender<SendProtocolMessage>;
-pub type ClientReceiver = ::futures::sync::mpsc::UnboundedReceiver<SendProtocolMessage>;
+/// Both ends of the client channel share a gauge of the number of messages that are waiting to be written to the client.
+/// Consumers send events through the `Sink`, which applies the channel's capacity, so a consumer whose client isn't keeping
+/// up stops reading from its partitions until the client catches up, just as it does while it waits for a `NextBatch`
+pub type ClientSender = ::metrics::MeteredSender<SendProtocolMessage>;
+pub type ClientReceiver = ::metrics::MeteredReceiver<SendProtocolMessage>;
+
+/// Completes with a description of every event stream, sorted by name
+pub type ListStreamsFuture = Box<Future<Item=Vec<StreamDescriptor>, Error=io::Error> + Send>;
+
+/// The number of messages that may be waiting to be written to a client before its consumers are paused
+pub const DEFAULT_CLIENT_CHANNEL_CAPACITY: usize = 1024;
 
 pub fn create_client_channels() -> (ClientSender, ClientReceiver) {
-    ::futures::sync::mpsc::unbounded()
+    create_client_channels_with_capacity(DEFAULT_CLIENT_CHANNEL_CAPACITY)
+}
+
+pub fn create_client_channels_with_capacity(capacity: usize) -> (ClientSender, ClientReceiver) {
+    ::metrics::metered_bounded(capacity)
 }
 
 
@@ -37,9 +57,23 @@ pub fn system_stream_name() -> String {
     SYSTEM_STREAM_NAME.to_owned()
 }
 
+/// `EngineRef::pipe` records its position in the source stream with a tag made of this prefix followed by the name of the
+/// destination stream
+pub static PIPE_TAG_PREFIX: &'static str = "pipe:";
+
 #[derive(Clone, Debug)]
 pub struct EngineRef {
     current_connection_id: Arc<AtomicUsize>,
+    default_stream_name: Arc<String>,
+    writes_paused: Arc<AtomicBool>,
+    /// Where stream tags are saved. Tags are only kept in memory if this is `None`
+    storage_dir: Option<Arc<PathBuf>>,
+    /// The capacity of the channel that's created for each new connection
+    client_channel_capacity: usize,
+    /// Incremented whenever a stream is swapped, so that connections can cheaply check whether to look up their stream again
+    stream_generation: Arc<AtomicUsize>,
+    /// The number of accepted connections that haven't finished being set up yet
+    connections_pending_setup: Gauge,
     event_streams: Arc<Mutex<HashMap<String, EventStreamRef>>>
 }
 
@@ -51,18 +85,44 @@ pub enum ConnectError {
 
 impl EngineRef {
     pub fn new(streams: HashMap<String, EventStreamRef>) -> EngineRef {
-        if !streams.contains_key(SYSTEM_STREAM_NAME) {
+        EngineRef::with_default_stream(SYSTEM_STREAM_NAME.to_owned(), streams)
+    }
+
+    /// Creates an engine ref where clients start out using the named stream instead of the system stream
+    pub fn with_default_stream(default_stream_name: String, streams: HashMap<String, EventStreamRef>) -> EngineRef {
+        if !streams.contains_key(&default_stream_name) {
             panic!("Cannot create engine ref without a default stream");
         }
 
         EngineRef {
             current_connection_id: Arc::new(AtomicUsize::new(0)),
+            default_stream_name: Arc::new(default_stream_name),
+            writes_paused: Arc::new(AtomicBool::new(false)),
+            storage_dir: None,
+            client_channel_capacity: DEFAULT_CLIENT_CHANNEL_CAPACITY,
+            stream_generation: Arc::new(AtomicUsize::new(0)),
+            connections_pending_setup: Gauge::new(),
             event_streams: Arc::new(Mutex::new(streams))
         }
     }
 
+    /// Causes stream tags to be saved in the given directory, so that they survive restarts
+    pub fn with_storage_dir(mut self, storage_dir: PathBuf) -> EngineRef {
+        self.storage_dir = Some(Arc::new(storage_dir));
+        self
+    }
+
+    pub fn with_client_channel_capacity(mut self, capacity: usize) -> EngineRef {
+        self.client_channel_capacity = capacity;
+        self
+    }
+
+    pub fn client_channel_capacity(&self) -> usize {
+        self.client_channel_capacity
+    }
+
     pub fn next_connection_id(&self) -> ConnectionId {
-        let old = self.current_connection_id.fetch_add(1, ::std::sync::atomic::Ordering::SeqCst);
+        let old = self.current_connection_id.fetch_add(1, Ordering::SeqCst);
         old + 1
     }
 
@@ -75,12 +135,221 @@ impl EngineRef {
         }
     }
 
+    /// Atomically replaces the named event stream with `new_stream`, which should have been initialized with the same name,
+    /// and returns the stream that was replaced. Consumers that are already active keep reading from the old stream until
+    /// they're stopped, so they never see a mix of the two. Every other operation, including starting a new consumer, uses
+    /// the new stream from then on
+    pub fn swap_stream(&self, stream_name: &str, new_stream: EventStreamRef) -> Result<EventStreamRef, ConnectError> {
+        let mut streams = self.event_streams.lock().unwrap();
+        if !streams.contains_key(stream_name) {
+            return Err(ConnectError::NoStream);
+        }
+        info!("Swapping in a new version of event stream: '{}'", stream_name);
+        let old_stream = streams.insert(stream_name.to_owned(), new_stream).unwrap();
+        self.stream_generation.fetch_add(1, Ordering::SeqCst);
+        Ok(old_stream)
+    }
+
+    pub fn stream_generation(&self) -> usize {
+        self.stream_generation.load(Ordering::SeqCst)
+    }
+
+    /// Removes all events after `up_to` from the named event stream on behalf of the given connection
+    pub fn truncate_stream(&self, connection_id: ConnectionId, stream_name: &str, up_to: FloEventId) -> Result<TruncateFuture, ConnectError> {
+        self.get_stream(stream_name).map(|stream| stream.truncate(connection_id, up_to))
+    }
+
+    /// Checks the integrity of the events in the named event stream. See `EventStreamRef::verify`
+    pub fn verify_stream(&self, stream_name: &str, options: VerifyOptions) -> Result<VerifyFuture, ConnectError> {
+        self.get_stream(stream_name).map(|stream| stream.verify(options))
+    }
+
+    /// Reads an event from the named stream along with all of its ancestors, starting with the event itself
+    pub fn read_ancestry(&self, stream_name: &str, event_id: FloEventId) -> Result<AncestryFuture, ConnectError> {
+        self.get_stream(stream_name).map(|stream| stream.read_ancestry(event_id, MAX_ANCESTRY_DEPTH))
+    }
+
+    /// Gives a name to an event in the named stream, so that consumers can start from it by setting a `start_tag`. If the
+    /// tag already exists, it's moved to the new event id. The event id isn't checked, so a tag may refer to an event that
+    /// hasn't been produced yet
+    pub fn tag_stream(&self, stream_name: &str, tag: String, event_id: FloEventId) -> io::Result<()> {
+        if tag.is_empty() || tag.contains(|c| c == '\t' || c == '\n') {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid tag: {:?}, tags must be non-empty and not contain a tab or newline", tag)));
+        }
+
+        let streams = self.event_streams.lock().unwrap();
+        if !streams.contains_key(stream_name) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("Event stream: '{}' does not exist", stream_name)));
+        }
+
+        if let Some(ref storage_dir) = self.storage_dir {
+            // save first, so that the tag is never visible unless it's been persisted
+            let mut all_tags = streams.iter().flat_map(|(name, stream)| {
+                stream.tags().all().into_iter().map(move |stream_tag| {
+                    RegisteredTag {
+                        stream: name.clone(),
+                        name: stream_tag.name,
+                        event_id: stream_tag.event_id,
+                    }
+                })
+            }).filter(|registered| registered.stream != stream_name || registered.name != tag).collect::<Vec<_>>();
+            all_tags.push(RegisteredTag {
+                stream: stream_name.to_owned(),
+                name: tag.clone(),
+                event_id: event_id,
+            });
+            save_tags(storage_dir, &all_tags)?;
+        }
+
+        streams[stream_name].tags().set(tag, event_id);
+        Ok(())
+    }
+
+    /// Reads every event in the `source` stream that hasn't already been piped to `dest`, and produces the result of calling
+    /// `transform` on each one into `dest`, using the same namespace. Events that `transform` returns `None` for are
+    /// skipped. The last event that was read from `source` is saved as a tag on that stream, so calling this again only
+    /// pipes the events that were produced since. Events are produced before the tag is saved, so a crash in between may
+    /// cause some of them to be piped twice. If writes are paused part way through, piping stops with an error, and the events
+    /// that were already produced are still recorded in the tag. Returns the number of events that were produced to `dest`.
+    /// This blocks while reading and producing events, so it must not be called from an event loop thread
+    pub fn pipe<F>(&self, source: &str, dest: &str, mut transform: F) -> io::Result<u64> where F: FnMut(&PersistentEvent) -> Option<Vec<u8>> {
+        let no_such_stream = |name: &str| io::Error::new(io::ErrorKind::NotFound, format!("Event stream: '{}' does not exist", name));
+        let source_stream = self.get_stream(source).map_err(|_| no_such_stream(source))?;
+        let mut dest_stream = self.get_stream(dest).map_err(|_| no_such_stream(dest))?;
+        let paused = || io::Error::new(io::ErrorKind::Other, format!("Cannot pipe events to: '{}' while writes are paused", dest));
+        if self.writes_paused() {
+            return Err(paused());
+        }
+
+        // the tag is the last event that was read, and counters increase across every partition, so starting each partition
+        // at the tagged counter skips everything that was already piped
+        let position_tag = format!("{}{}", PIPE_TAG_PREFIX, dest);
+        let start_counter = source_stream.tags().get(&position_tag).map(|id| id.event_counter).unwrap_or(0);
+        let version_vector = source_stream.partitions().iter().map(|partition| {
+            FloEventId::new(partition.partition_num(), start_counter)
+        }).collect::<Vec<_>>();
+
+        let mut last_read = None;
+        let mut produced = 0;
+        let mut result = Ok(());
+        for event in source_stream.events_since(&version_vector)? {
+            // writes may be paused at any point while piping, so stop before producing anything else once they are
+            if self.writes_paused() {
+                result = Err(paused());
+                break;
+            }
+            let event = match event {
+                Ok(event) => event,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            };
+            if let Some(data) = transform(&event) {
+                if let Err(err) = produce_piped_event(&mut dest_stream, &event, data) {
+                    result = Err(err);
+                    break;
+                }
+                produced += 1;
+            }
+            last_read = Some(*event.id());
+        }
+
+        // save the position even if producing failed part way through, so that the events that made it aren't piped again
+        if let Some(event_id) = last_read {
+            debug!("Piped {} events from: '{}' to: '{}', up to: {}", produced, source, dest, event_id);
+            self.tag_stream(source, position_tag, event_id)?;
+        }
+        result.map(|()| produced)
+    }
+
+    /// Returns a `Stream` that yields the id of every event as it's durably persisted to the named event stream, in the
+    /// order they were persisted. The stream ends once the event stream is gone, or immediately if there's no such stream
+    pub fn subscribe_acks(&self, stream_name: &str) -> AckSubscription {
+        self.get_stream(stream_name).map(|stream| stream.subscribe_acks()).unwrap_or_else(|_| {
+            debug!("Cannot subscribe to acks for event stream: '{}' because it does not exist", stream_name);
+            AckSubscription::empty()
+        })
+    }
+
+    /// Describes all of the event streams known to this server
+    pub fn list_streams(&self) -> ListStreamsFuture {
+        use futures::future;
+
+        let descriptions = {
+            let streams = self.event_streams.lock().unwrap();
+            streams.values().map(|stream| stream.describe()).collect::<Vec<_>>()
+        };
+        Box::new(future::join_all(descriptions).map(|mut streams| {
+            streams.sort_by(|a, b| a.name.cmp(&b.name));
+            streams
+        }))
+    }
+
+    /// Returns the metrics of every event stream known to this server, sorted by stream name. The counters are shared with
+    /// the streams, so they can be read again at any time to get the current values
+    pub fn stream_metrics(&self) -> Vec<(String, StreamMetrics)> {
+        let streams = self.event_streams.lock().unwrap();
+        let mut metrics = streams.values().map(|stream| {
+            (stream.name().to_owned(), stream.metrics().clone())
+        }).collect::<Vec<_>>();
+        metrics.sort_by(|a, b| a.0.cmp(&b.0));
+        metrics
+    }
+
+    /// The number of accepted connections that are still being set up. The server's `SetupLimiter` updates this gauge, so
+    /// it can be read at any time along with the other metrics
+    pub fn connections_pending_setup(&self) -> &Gauge {
+        &self.connections_pending_setup
+    }
+
+    /// Causes produces to every event stream to be rejected with a `ServerBusy` error until `resume_writes` is called.
+    /// Connections stay open and consumers continue to receive events while writes are paused
+    pub fn pause_writes(&self) {
+        info!("Pausing writes to all event streams");
+        self.writes_paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume_writes(&self) {
+        info!("Resuming writes to all event streams");
+        self.writes_paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn writes_paused(&self) -> bool {
+        self.writes_paused.load(Ordering::SeqCst)
+    }
+
     pub fn get_default_stream(&self) -> EventStreamRef {
         let guard = self.event_streams.lock().unwrap();
-        guard.get(SYSTEM_STREAM_NAME).unwrap().clone()
+        guard.get(self.default_stream_name.as_str()).unwrap().clone()
     }
 }
 
+/// Produces a piped event to the partition of `dest` with the same number as the event's partition in the source stream, or
+/// wraps around if `dest` has fewer partitions, so that events from the same source partition stay in order
+fn produce_piped_event(dest: &mut EventStreamRef, event: &PersistentEvent, data: Vec<u8>) -> io::Result<FloEventId> {
+    let partition_count = dest.get_partition_count();
+    if partition_count == 0 {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("Event stream: '{}' has no partitions", dest.name())));
+    }
+    let partition_num = event.id().actor.saturating_sub(1) % partition_count + 1;
+    let produce = ProduceEvent {
+        op_id: 0,
+        partition: partition_num,
+        namespace: event.namespace().to_owned(),
+        parent_id: None,
+        ttl: None,
+        compression: Compression::None,
+        trace: false,
+        data: data,
+    };
 
-
-
+    let partition = dest.get_partition(partition_num).unwrap();
+    let receiver = partition.produce(0, 0, vec![produce]).map_err(|err| {
+        io::Error::new(io::ErrorKind::Other, format!("Failed to send produce to partition: {}: {:?}", partition_num, err))
+    })?;
+    let result = receiver.wait().map_err(|_| {
+        io::Error::new(io::ErrorKind::BrokenPipe, format!("Partition: {} shut down before producing event", partition_num))
+    })?;
+    result.map(|complete| complete.last_id)
+}
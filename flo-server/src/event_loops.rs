@@ -6,6 +6,8 @@ use std::cmp::{max, min};
 use std::sync::mpsc::sync_channel;
 use std::time::Duration;
 
+use metrics::Gauge;
+
 fn thread_startup_timeout() -> Duration {
     Duration::from_millis(500)
 }
@@ -87,27 +89,43 @@ pub fn spawn_event_loop_threads(max: Option<usize>) -> Result<(EventLoopsJoinHan
 pub struct LoopHandles {
     handles: Vec<Remote>,
     reactor_ids: Vec<CoreId>,
+    /// the number of tasks currently running on each event loop, in the same order as `handles`
+    active_tasks: Vec<Gauge>,
     current: usize,
 }
 impl LoopHandles {
     fn new(remotes: Vec<Remote>) -> LoopHandles {
         let ids = remotes.iter().map(|r| r.id()).collect::<Vec<CoreId>>();
+        let active_tasks = remotes.iter().map(|_| Gauge::new()).collect::<Vec<Gauge>>();
         LoopHandles {
             handles: remotes,
             reactor_ids: ids,
+            active_tasks: active_tasks,
             current: 0,
         }
     }
 
     pub fn next_handle(&mut self) -> Remote {
+        self.next_handle_with_gauge().0
+    }
+
+    /// Returns the next event loop along with the gauge of tasks running on it. Callers are responsible for incrementing
+    /// the gauge when they spawn a task and decrementing it when the task completes
+    pub fn next_handle_with_gauge(&mut self) -> (Remote, Gauge) {
         let remote = self.handles[self.current].clone();
+        let gauge = self.active_tasks[self.current].clone();
         self.current = (self.current + 1) % self.handles.len();
-        remote
+        (remote, gauge)
+    }
+
+    /// Returns the number of tasks currently running on each event loop
+    pub fn active_task_counts(&self) -> Vec<usize> {
+        self.active_tasks.iter().map(|g| g.get()).collect()
     }
 }
 
 impl Debug for LoopHandles {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "LoopHandles{{ reactors: {:?}, current_index: {} }}", self.reactor_ids, self.current)
+        write!(f, "LoopHandles{{ reactors: {:?}, active_tasks: {:?}, current_index: {} }}", self.reactor_ids, self.active_task_counts(), self.current)
     }
 }
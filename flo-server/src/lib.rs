@@ -11,11 +11,15 @@ extern crate futures;
 extern crate tokio_core;
 extern crate chrono;
 extern crate glob;
+extern crate regex;
 extern crate memmap;
+extern crate libc;
 extern crate clap;
 extern crate log4rs;
 extern crate num_cpus;
 extern crate byteorder;
+extern crate chacha20poly1305;
+extern crate aes_gcm;
 
 
 #[cfg(test)]
@@ -31,3 +35,4 @@ pub mod engine;
 pub mod event_loops;
 pub mod channels;
 pub mod atomics;
+pub mod metrics;
@@ -92,6 +92,50 @@ fn app_args() -> App<'static, 'static> {
                     .long("max-io-threads")
                     .takes_value(true)
                     .help("The maximum number of threads to spawn for handling client connections. The actual number of threads used may be less"))
+            .arg(Arg::with_name("standalone")
+                    .long("standalone")
+                    .conflicts_with("join-cluster-address")
+                    .help("Run as a single node without a system stream. The default event stream is used directly by all clients"))
+            .arg(Arg::with_name("max-cursors-per-connection")
+                    .long("max-cursors-per-connection")
+                    .takes_value(true)
+                    .help("The maximum number of cursors that a single client connection may have active at once"))
+            .arg(Arg::with_name("max-in-flight-produce-bytes")
+                    .long("max-in-flight-produce-bytes")
+                    .value_name("bytes")
+                    .help("The maximum total size of events that a single client connection may have sent but not yet had acknowledged. Larger events are rejected"))
+            .arg(Arg::with_name("client-read-timeout")
+                    .long("client-read-timeout")
+                    .value_name("seconds")
+                    .help("Close client connections that go this many seconds without sending a message. If unspecified, then idle connections are never closed"))
+            .arg(Arg::with_name("client-write-timeout")
+                    .long("client-write-timeout")
+                    .value_name("seconds")
+                    .help("Close client connections that stop reading the messages sent to them for this many seconds. If unspecified, then writes never time out"))
+            .arg(Arg::with_name("read-buffer-size")
+                    .long("read-buffer-size")
+                    .value_name("bytes")
+                    .help("The size of the buffer that each client connection reads messages into. Larger buffers mean fewer reads, but use more memory per connection. Defaults to 8192"))
+            .arg(Arg::with_name("max-buffered-read-memory")
+                    .long("max-buffered-read-memory")
+                    .value_name("amount")
+                    .help("Pause reading from all client connections while the data they've sent that hasn't been parsed into complete messages adds up to this much memory, for example '256MB'. If unspecified, then there is no limit"))
+            .arg(Arg::with_name("max-connection-buffered-read-memory")
+                    .long("max-connection-buffered-read-memory")
+                    .value_name("amount")
+                    .help("Close client connections that send this much data without completing a message, for example '16MB'. If unspecified, then there is no limit"))
+            .arg(Arg::with_name("max-concurrent-replays")
+                    .long("max-concurrent-replays")
+                    .value_name("count")
+                    .help("The maximum number of consumers across all connections that may be reading older events from disk at once. Consumers that are reading recent events are not limited. If unspecified, then there is no limit"))
+            .arg(Arg::with_name("max-pending-connection-setups")
+                    .long("max-pending-connection-setups")
+                    .value_name("count")
+                    .help("The maximum number of new connections that may be waiting to be set up at once. Connections beyond this are refused with a server busy error"))
+            .arg(Arg::with_name("client-channel-capacity")
+                    .long("client-channel-capacity")
+                    .value_name("count")
+                    .help("The maximum number of messages that may be waiting to be written to each client. A client's consumers stop reading events while this many are waiting"))
 }
 
 fn main() {
@@ -137,6 +181,17 @@ fn main() {
         cluster_addresses: cluster_addresses,
         actor_id: actor_id,
         max_io_threads: max_io_threads,
+        standalone: args.is_present("standalone"),
+        max_cursors_per_connection: parse_arg_or_exit(&args, "max-cursors-per-connection", engine::DEFAULT_MAX_CURSORS_PER_CONNECTION),
+        max_in_flight_produce_bytes: parse_arg_or_exit(&args, "max-in-flight-produce-bytes", engine::DEFAULT_MAX_IN_FLIGHT_PRODUCE_BYTES),
+        client_read_timeout: get_optional_seconds(&args, "client-read-timeout"),
+        client_write_timeout: get_optional_seconds(&args, "client-write-timeout"),
+        read_buffer_size: parse_arg_or_exit(&args, "read-buffer-size", protocol::BUFFER_LENGTH),
+        max_buffered_read_memory: get_optional_memory_limit(&args, "max-buffered-read-memory"),
+        max_connection_buffered_read_memory: get_optional_memory_limit(&args, "max-connection-buffered-read-memory"),
+        max_concurrent_replays: get_optional_count(&args, "max-concurrent-replays"),
+        max_pending_connection_setups: parse_arg_or_exit(&args, "max-pending-connection-setups", server::DEFAULT_MAX_PENDING_CONNECTION_SETUPS),
+        client_channel_capacity: parse_arg_or_exit(&args, "client-channel-capacity", engine::DEFAULT_CLIENT_CHANNEL_CAPACITY),
     };
 
     server_options.validate().or_bail();
@@ -180,6 +235,30 @@ fn get_max_cache_mem_amount(args: &ArgMatches) -> MemoryLimit {
     MemoryLimit::new(mb, MemoryUnit::Megabyte)
 }
 
+fn get_optional_memory_limit(args: &ArgMatches, arg_name: &str) -> Option<MemoryLimit> {
+    args.value_of(arg_name).map(|value| {
+        value.parse::<MemoryLimit>().map_err(|err| {
+            format!("argument {} invalid value: {}", arg_name, err)
+        }).or_bail()
+    })
+}
+
+fn get_optional_count(args: &ArgMatches, arg_name: &str) -> Option<usize> {
+    args.value_of(arg_name).map(|value| {
+        value.parse::<usize>().map_err(|_err| {
+            format!("argument {} invalid value: {}", arg_name, value)
+        }).or_bail()
+    })
+}
+
+fn get_optional_seconds(args: &ArgMatches, arg_name: &str) -> Option<Duration> {
+    args.value_of(arg_name).map(|value| {
+        value.parse::<i64>().map(Duration::seconds).map_err(|_err| {
+            format!("argument {} invalid value: {}", arg_name, value)
+        }).or_bail()
+    })
+}
+
 fn parse_arg_or_exit<T: FromStr + Default>(args: &ArgMatches, arg_name: &str, default: T) -> T {
     args.value_of(arg_name)
         .map(|value| {
@@ -0,0 +1,329 @@
+//! Gauges for diagnosing latency within the server. Each gauge is a cheap, shared counter that's updated as work is
+//! enqueued and dequeued, so it can be read at any time from any thread without coordinating with the work itself.
+
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::{Stream, Sink, Poll, Async, AsyncSink, StartSend};
+use futures::task::{self, Task};
+use futures::sync::mpsc::{self, UnboundedSender, UnboundedReceiver, SendError};
+
+/// A value that can go up and down, shared between all of its clones
+#[derive(Debug, Clone)]
+pub struct Gauge(Arc<AtomicUsize>);
+
+impl Gauge {
+    pub fn new() -> Gauge {
+        Gauge(Arc::new(AtomicUsize::new(0)))
+    }
+
+    pub fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn decrement(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Increments the gauge only if its current value is less than `limit`, and returns whether it was incremented. Since
+    /// the check and the increment are a single atomic operation, this can be used to bound the work that's tracked by it
+    pub fn increment_if_below(&self, limit: usize) -> bool {
+        let mut current = self.0.load(Ordering::Relaxed);
+        while current < limit {
+            let previous = self.0.compare_and_swap(current, current + 1, Ordering::Relaxed);
+            if previous == current {
+                return true;
+            }
+            current = previous;
+        }
+        false
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that only goes up, shared between all of its clones. Unlike an `AtomicCounterWriter`, any number of clones
+/// may add to it, so it can be updated by every connection that uses the same event stream
+#[derive(Debug, Clone)]
+pub struct Counter(Arc<AtomicUsize>);
+
+impl Counter {
+    pub fn new() -> Counter {
+        Counter(Arc::new(AtomicUsize::new(0)))
+    }
+
+    pub fn add(&self, amount: usize) {
+        self.0.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts the event data that's transferred between clients and a single event stream
+#[derive(Debug, Clone)]
+pub struct StreamMetrics {
+    /// The total size of the bodies of events that clients have produced and that were successfully persisted
+    pub bytes_produced: Counter,
+    /// The total size of the event bodies that have been sent to consumers. Only the prefix counts for consumers that
+    /// requested `body_prefix_bytes`
+    pub bytes_consumed: Counter,
+}
+
+impl StreamMetrics {
+    pub fn new() -> StreamMetrics {
+        StreamMetrics {
+            bytes_produced: Counter::new(),
+            bytes_consumed: Counter::new(),
+        }
+    }
+}
+
+/// Creates an unbounded channel where both ends share a gauge of the number of messages that have been sent but not yet received
+pub fn metered_unbounded<T>() -> (MeteredSender<T>, MeteredReceiver<T>) {
+    metered_bounded(usize::max_value())
+}
+
+/// Creates a channel where both ends share a gauge of the number of messages that have been sent but not yet received.
+/// Sending through the `Sink` is `NotReady` while `capacity` messages are waiting, and the sending task is notified once
+/// the receiver takes one of them. `unbounded_send` ignores the capacity, so that a message can always be sent from
+/// outside of a task, and so that small responses aren't held up behind a backlog of events. `capacity` must be at
+/// least 1
+pub fn metered_bounded<T>(capacity: usize) -> (MeteredSender<T>, MeteredReceiver<T>) {
+    let (tx, rx) = mpsc::unbounded();
+    let depth = Gauge::new();
+    let parked = Arc::new(Mutex::new(Vec::new()));
+    let sender = MeteredSender {
+        inner: tx,
+        depth: depth.clone(),
+        capacity: capacity,
+        parked: parked.clone(),
+    };
+    let receiver = MeteredReceiver {
+        inner: rx,
+        depth: depth,
+        capacity: capacity,
+        parked: parked,
+    };
+    (sender, receiver)
+}
+
+#[derive(Debug)]
+pub struct MeteredSender<T> {
+    inner: UnboundedSender<T>,
+    depth: Gauge,
+    capacity: usize,
+    /// tasks that are waiting for the receiver to take messages so that they can send more
+    parked: Arc<Mutex<Vec<Task>>>,
+}
+
+impl <T> Clone for MeteredSender<T> {
+    fn clone(&self) -> Self {
+        MeteredSender {
+            inner: self.inner.clone(),
+            depth: self.depth.clone(),
+            capacity: self.capacity,
+            parked: self.parked.clone(),
+        }
+    }
+}
+
+impl <T> MeteredSender<T> {
+    pub fn unbounded_send(&self, message: T) -> Result<(), SendError<T>> {
+        // increment first so that the receiver can never observe a negative depth
+        self.depth.increment();
+        self.inner.unbounded_send(message).map_err(|err| {
+            self.depth.decrement();
+            err
+        })
+    }
+
+    pub fn depth_gauge(&self) -> &Gauge {
+        &self.depth
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Parks the current task, unless it's already waiting. A task that's woken spuriously would otherwise be added again
+    /// every time it's polled
+    fn park(&self) {
+        let mut parked = self.parked.lock().unwrap();
+        if !parked.iter().any(|task| task.will_notify_current()) {
+            parked.push(task::current());
+        }
+    }
+}
+
+impl <T> Sink for MeteredSender<T> {
+    type SinkItem = T;
+    type SinkError = SendError<T>;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        // the depth is incremented only if there's room, so that concurrent senders can never go over the capacity
+        if !self.depth.increment_if_below(self.capacity) {
+            self.park();
+            // the receiver may have taken messages before this task was parked, in which case it won't be notified
+            if !self.depth.increment_if_below(self.capacity) {
+                return Ok(AsyncSink::NotReady(item));
+            }
+        }
+        let result = self.inner.start_send(item);
+        match result {
+            Ok(AsyncSink::Ready) => {}
+            _ => self.depth.decrement()
+        }
+        result
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.inner.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        self.inner.close()
+    }
+}
+
+#[derive(Debug)]
+pub struct MeteredReceiver<T> {
+    inner: UnboundedReceiver<T>,
+    depth: Gauge,
+    capacity: usize,
+    parked: Arc<Mutex<Vec<Task>>>,
+}
+
+impl <T> MeteredReceiver<T> {
+    pub fn depth_gauge(&self) -> &Gauge {
+        &self.depth
+    }
+}
+
+impl <T> Stream for MeteredReceiver<T> {
+    type Item = T;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let result = self.inner.poll();
+        if let Ok(Async::Ready(Some(_))) = result {
+            self.depth.decrement();
+            if self.depth.get() < self.capacity {
+                let parked = mem::replace(&mut *self.parked.lock().unwrap(), Vec::new());
+                for task in parked {
+                    task.notify();
+                }
+            }
+        }
+        result
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::Future;
+
+    #[test]
+    fn counter_is_shared_between_clones() {
+        let counter = Counter::new();
+        let clone = counter.clone();
+
+        counter.add(5);
+        clone.add(7);
+        assert_eq!(12, counter.get());
+        assert_eq!(12, clone.get());
+    }
+
+    #[test]
+    fn increment_if_below_stops_incrementing_at_the_limit() {
+        let gauge = Gauge::new();
+        assert!(gauge.increment_if_below(2));
+        assert!(gauge.clone().increment_if_below(2));
+        assert!(!gauge.increment_if_below(2));
+        assert_eq!(2, gauge.get());
+
+        gauge.decrement();
+        assert!(gauge.increment_if_below(2));
+        assert!(!gauge.increment_if_below(0));
+    }
+
+    #[test]
+    fn depth_gauge_tracks_messages_that_have_not_yet_been_received() {
+        let (tx, rx) = metered_unbounded::<u32>();
+        let gauge = rx.depth_gauge().clone();
+
+        tx.unbounded_send(1).unwrap();
+        let tx = tx.send(2).wait().unwrap();
+        assert_eq!(2, gauge.get());
+
+        let (message, rx) = rx.into_future().wait().map_err(|_| ()).unwrap();
+        assert_eq!(Some(1), message);
+        assert_eq!(1, gauge.get());
+
+        drop(rx);
+        assert!(tx.unbounded_send(3).is_err());
+        assert_eq!(1, gauge.get());
+    }
+
+    struct NotifiedFlag(AtomicUsize);
+
+    impl ::futures::executor::Notify for NotifiedFlag {
+        fn notify(&self, _id: usize) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn bounded_sink_waits_for_the_receiver_once_it_is_full() {
+        use futures::executor::{spawn, NotifyHandle};
+
+        let (tx, rx) = metered_bounded::<u32>(2);
+        let tx = tx.send(1).wait().unwrap();
+        let tx = tx.send(2).wait().unwrap();
+        // responses can still be sent past the capacity
+        tx.unbounded_send(3).unwrap();
+        assert_eq!(3, rx.depth_gauge().get());
+
+        let flag = Arc::new(NotifiedFlag(AtomicUsize::new(0)));
+        let notify = NotifyHandle::from(flag.clone());
+        let mut send = spawn(tx.send(4));
+        assert!(send.poll_future_notify(&notify, 0).unwrap().is_not_ready());
+
+        // still at the capacity after taking one message, so the sender must keep waiting
+        let (message, rx) = rx.into_future().wait().map_err(|_| ()).unwrap();
+        assert_eq!(Some(1), message);
+        assert!(send.poll_future_notify(&notify, 0).unwrap().is_not_ready());
+
+        let (message, rx) = rx.into_future().wait().map_err(|_| ()).unwrap();
+        assert_eq!(Some(2), message);
+        assert!(flag.0.load(Ordering::SeqCst) > 0);
+        assert!(send.poll_future_notify(&notify, 0).unwrap().is_ready());
+
+        let received = rx.take(2).collect().wait().unwrap();
+        assert_eq!(vec![3, 4], received);
+    }
+
+    #[test]
+    fn a_task_that_is_polled_repeatedly_while_full_is_only_parked_once() {
+        use futures::executor::{spawn, NotifyHandle};
+
+        let (tx, rx) = metered_bounded::<u32>(1);
+        let tx = tx.send(1).wait().unwrap();
+        let parked = tx.parked.clone();
+
+        let flag = Arc::new(NotifiedFlag(AtomicUsize::new(0)));
+        let notify = NotifyHandle::from(flag.clone());
+        let mut send = spawn(tx.send(2));
+        for _ in 0..5 {
+            assert!(send.poll_future_notify(&notify, 0).unwrap().is_not_ready());
+        }
+        assert_eq!(1, parked.lock().unwrap().len());
+        assert_eq!(1, rx.depth_gauge().get());
+    }
+}
@@ -5,25 +5,55 @@ use futures::stream::Stream;
 
 use event::OwnedFloEvent;
 use engine::{ConnectionId, ReceivedProtocolMessage};
-use protocol::MessageStream;
+use protocol::{MessageStream, Framing};
+use atomics::AtomicBoolWriter;
+use super::{IdleTimeout, ReadMemoryLimit, MemoryLimitedReader};
 
 
 /// New implementation, that just provides a `Stream` of `ProtocolMessage`s.
 /// Theres a lot of duplicated code here, at the moment, until `ClientMessageStream` is removed
 pub struct ProtocolMessageStream<R: Read> {
     connection_id: ConnectionId,
-    message_reader: MessageStream<R, OwnedFloEvent>,
-    connected: bool
+    message_reader: MessageStream<MemoryLimitedReader<R>, OwnedFloEvent>,
+    connected: bool,
+    /// Tells the `ServerMessageStream` for the connection to use CBOR framing
+    cbor_framing: AtomicBoolWriter,
+    /// if set, then the connection is closed when no complete message is received before this fires
+    read_timeout: Option<IdleTimeout>,
 }
 
 impl <R: Read> ProtocolMessageStream<R> {
-    pub fn new(connection_id: ConnectionId, reader: R) -> ProtocolMessageStream<R> {
+    pub fn new(connection_id: ConnectionId, reader: R, read_buffer_size: usize, cbor_framing: AtomicBoolWriter) -> ProtocolMessageStream<R> {
         ProtocolMessageStream {
             connection_id: connection_id,
-            message_reader: MessageStream::new(reader),
+            message_reader: MessageStream::with_read_buffer_size(MemoryLimitedReader::new(reader, ReadMemoryLimit::unlimited()), read_buffer_size),
             connected: true,
+            cbor_framing: cbor_framing,
+            read_timeout: None,
         }
     }
+
+    pub fn with_read_timeout(mut self, read_timeout: Option<IdleTimeout>) -> ProtocolMessageStream<R> {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// Counts the bytes that are read from this connection, but not yet parsed into messages, against the given `limit`
+    pub fn with_read_memory_limit(mut self, limit: ReadMemoryLimit) -> ProtocolMessageStream<R> {
+        self.message_reader.get_mut().set_limit(limit);
+        self
+    }
+
+    fn check_read_timeout(&mut self) -> Poll<Option<ReceivedProtocolMessage>, io::Error> {
+        if let Some(ref mut timeout) = self.read_timeout {
+            if let Err(io_err) = timeout.check() {
+                warn!("Read timed out for connection_id: {}: {}", self.connection_id, io_err);
+                self.connected = false;
+                return Err(io_err);
+            }
+        }
+        Ok(Async::NotReady)
+    }
 }
 
 impl <R: Read> Stream for ProtocolMessageStream<R> {
@@ -37,11 +67,21 @@ impl <R: Read> Stream for ProtocolMessageStream<R> {
             return Ok(Async::Ready(None));
         }
 
-        match self.message_reader.read_next() {
+        let result = self.message_reader.read_next();
+        let buffered = self.message_reader.buffered_bytes();
+        self.message_reader.get_mut().set_buffered(buffered);
+
+        match result {
             Ok(message) => {
+                if self.message_reader.framing() == Some(Framing::Cbor) {
+                    self.cbor_framing.set(true);
+                }
+                if let Some(ref mut timeout) = self.read_timeout {
+                    timeout.reset();
+                }
                 Ok(Async::Ready(Some(message)))
             }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => self.check_read_timeout(),
             Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
                 info!("EOF for connection_id: {}", self.connection_id);
                 Ok(Async::Ready(None))
@@ -55,3 +95,86 @@ impl <R: Read> Stream for ProtocolMessageStream<R> {
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use futures::Future;
+    use tokio_core::reactor::Core;
+
+    /// Hands out `data` in reads of whatever size is asked for, and records the size of each buffer that's passed in
+    struct RecordingReader {
+        data: io::Cursor<Vec<u8>>,
+        read_sizes: Rc<RefCell<Vec<usize>>>,
+    }
+
+    impl Read for RecordingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.read_sizes.borrow_mut().push(buf.len());
+            self.data.read(buf)
+        }
+    }
+
+    #[test]
+    fn large_event_is_read_across_buffer_boundaries_using_the_configured_read_buffer_size() {
+        use protocol::{MessageWriter, ProtocolMessage, ProduceEvent, Compression};
+        use event::ActorId;
+
+        let produce = ProtocolMessage::ProduceEvent(ProduceEvent {
+            op_id: 7,
+            partition: 1 as ActorId,
+            namespace: "/some/namespace".to_owned(),
+            parent_id: None,
+            ttl: None,
+            compression: Compression::None,
+            trace: false,
+            data: (0..1000).map(|i| (i % 251) as u8).collect(),
+        });
+        let mut bytes = Vec::new();
+        MessageWriter::new_owned(produce.clone()).write(&mut bytes).expect("failed to write message");
+
+        let read_sizes = Rc::new(RefCell::new(Vec::new()));
+        let reader = RecordingReader {
+            data: io::Cursor::new(bytes),
+            read_sizes: read_sizes.clone(),
+        };
+        let mut subject = ProtocolMessageStream::new(1, reader, 32, AtomicBoolWriter::with_value(false));
+
+        let result = subject.poll().expect("failed to read message");
+        let expected = Async::Ready(Some(produce));
+        assert_eq!(expected, result);
+
+        // the first read fills the whole configured buffer, and the rest of the event takes many more reads
+        let read_sizes = read_sizes.borrow();
+        assert_eq!(32, read_sizes[0]);
+        assert!(read_sizes.len() > 1000 / 64);
+    }
+
+    struct NeverReady;
+
+    impl Read for NeverReady {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::WouldBlock, "no data yet"))
+        }
+    }
+
+    #[test]
+    fn stream_fails_with_timed_out_error_when_no_message_is_received_before_the_read_timeout() {
+        let mut reactor = Core::new().unwrap();
+        let timeout = IdleTimeout::new(Duration::from_millis(50), &reactor.handle()).unwrap();
+        let subject = ProtocolMessageStream::new(1, NeverReady, ::protocol::BUFFER_LENGTH, AtomicBoolWriter::with_value(false))
+                .with_read_timeout(Some(timeout));
+
+        let start = Instant::now();
+        let result = reactor.run(subject.into_future());
+        let (err, mut subject) = result.err().expect("expected the read to time out");
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // the stream is finished after the error
+        assert_eq!(Async::Ready(None), subject.poll().unwrap());
+    }
+}
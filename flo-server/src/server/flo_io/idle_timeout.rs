@@ -0,0 +1,36 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Async};
+use tokio_core::reactor::{Handle, Timeout};
+
+/// Fails a connection that's gone too long without making any progress in one direction. The timeout is reset each time
+/// progress is made, and only checked while the connection is blocked.
+pub struct IdleTimeout {
+    duration: Duration,
+    timeout: Timeout,
+}
+
+impl IdleTimeout {
+    pub fn new(duration: Duration, handle: &Handle) -> io::Result<IdleTimeout> {
+        let timeout = Timeout::new(duration, handle)?;
+        Ok(IdleTimeout {
+            duration: duration,
+            timeout: timeout,
+        })
+    }
+
+    pub fn reset(&mut self) {
+        self.timeout.reset(Instant::now() + self.duration);
+    }
+
+    /// Returns a `TimedOut` error if the timeout has fired. Otherwise, the current task will be notified when it does
+    pub fn check(&mut self) -> io::Result<()> {
+        match self.timeout.poll()? {
+            Async::Ready(()) => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, format!("No progress was made for {:?}", self.duration)))
+            }
+            Async::NotReady => Ok(())
+        }
+    }
+}
@@ -1,6 +1,59 @@
 mod client_message_stream;
 mod server_message_stream;
+mod idle_timeout;
+mod read_memory;
+
+use std::io;
+use std::time::Duration;
+
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+#[allow(deprecated)]
+use tokio_core::io::{Io, ReadHalf};
+
+use engine::{ConnectionId, ClientReceiver};
+use atomics::AtomicBoolWriter;
 
 pub use self::client_message_stream::ProtocolMessageStream;
-pub use self::server_message_stream::ServerMessageStream;
+pub use self::server_message_stream::{ServerMessageStream, ServerWriteStream};
+pub use self::idle_timeout::IdleTimeout;
+pub use self::read_memory::{ReadMemoryLimit, MemoryLimitedReader};
+
+#[allow(deprecated)]
+pub type ServerReadStream = ReadHalf<TcpStream>;
+
+/// Splits the `tcp_stream` into the stream of messages received from the client, and the stream that writes the messages
+/// from `client_rx` to the client. If a timeout is given, then the corresponding stream fails with a `TimedOut` error when
+/// it goes that long without making progress. Reads time out when no message is received from the client at all, and
+/// writes time out only when the client stops reading what's sent to it. Messages from the client are read into a buffer
+/// that starts out at `read_buffer_size` bytes, and any bytes that haven't yet been parsed count against `read_memory_limit`.
+pub fn setup_message_streams(connection_id: ConnectionId,
+                             tcp_stream: TcpStream,
+                             client_rx: ClientReceiver,
+                             read_buffer_size: usize,
+                             read_memory_limit: ReadMemoryLimit,
+                             read_timeout: Option<Duration>,
+                             write_timeout: Option<Duration>,
+                             handle: &Handle) -> io::Result<(ProtocolMessageStream<ServerReadStream>, ServerMessageStream<ServerWriteStream>)> {
+    #[allow(deprecated)]
+    let (tcp_reader, tcp_writer) = tcp_stream.split();
+
+    let read_timeout = match read_timeout {
+        Some(duration) => Some(IdleTimeout::new(duration, handle)?),
+        None => None
+    };
+    let write_timeout = match write_timeout {
+        Some(duration) => Some(IdleTimeout::new(duration, handle)?),
+        None => None
+    };
+
+    // set once the client's first message shows that it's using CBOR framing
+    let cbor_framing = AtomicBoolWriter::with_value(false);
+    let server_to_client = ServerMessageStream::new(connection_id, client_rx, tcp_writer, cbor_framing.reader())
+            .with_write_timeout(write_timeout);
+    let client_to_server = ProtocolMessageStream::new(connection_id, tcp_reader, read_buffer_size, cbor_framing)
+            .with_read_timeout(read_timeout)
+            .with_read_memory_limit(read_memory_limit);
 
+    Ok((client_to_server, server_to_client))
+}
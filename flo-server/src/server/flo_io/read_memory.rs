@@ -0,0 +1,287 @@
+use std::io::{self, Read};
+use std::cmp;
+use std::fmt::{self, Debug};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::task::{self, Task};
+
+/// Limits the memory used by bytes that have been read from client connections, but not yet parsed into complete messages.
+/// Without a limit, a flood of connections that each send part of a very large frame could exhaust the server's memory.
+///
+/// When the total across all connections reaches `max_total`, then connections stop reading until other connections
+/// complete their messages or are closed. Bytes that were already read can still be parsed while a connection is blocked.
+/// Since every blocked connection may be waiting on the rest of its own frame, this should be combined with a client read
+/// timeout so that stalled connections eventually get closed. A connection that reaches `max_per_connection` without
+/// completing a message is closed with an error, since it could never make progress.
+///
+/// The limits are approximate when connections are read on multiple threads at once, since each read is checked against
+/// the total independently.
+#[derive(Clone)]
+pub struct ReadMemoryLimit {
+    inner: Arc<LimitInner>,
+}
+
+struct LimitInner {
+    max_total: Option<usize>,
+    max_per_connection: Option<usize>,
+    total: AtomicUsize,
+    blocked_tasks: Mutex<Vec<Task>>,
+}
+
+impl ReadMemoryLimit {
+    pub fn new(max_total: Option<usize>, max_per_connection: Option<usize>) -> ReadMemoryLimit {
+        ReadMemoryLimit {
+            inner: Arc::new(LimitInner {
+                max_total: max_total,
+                max_per_connection: max_per_connection,
+                total: AtomicUsize::new(0),
+                blocked_tasks: Mutex::new(Vec::new()),
+            })
+        }
+    }
+
+    pub fn unlimited() -> ReadMemoryLimit {
+        ReadMemoryLimit::new(None, None)
+    }
+
+    /// Returns the total number of bytes that have been read from all connections, but not yet parsed into messages
+    pub fn buffered_bytes(&self) -> usize {
+        self.inner.total.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of bytes that may be read before reaching `max_total`
+    fn total_remaining(&self) -> usize {
+        self.inner.max_total.map(|max| max.saturating_sub(self.buffered_bytes())).unwrap_or(::std::usize::MAX)
+    }
+
+    fn add(&self, amount: usize) {
+        self.inner.total.fetch_add(amount, Ordering::SeqCst);
+    }
+
+    fn release(&self, amount: usize) {
+        if amount == 0 {
+            return;
+        }
+        self.inner.total.fetch_sub(amount, Ordering::SeqCst);
+        if self.total_remaining() > 0 {
+            let tasks = ::std::mem::replace(&mut *self.inner.blocked_tasks.lock().unwrap(), Vec::new());
+            for task in tasks {
+                task.notify();
+            }
+        }
+    }
+
+    fn block_current_task(&self) {
+        self.inner.blocked_tasks.lock().unwrap().push(task::current());
+    }
+}
+
+impl Debug for ReadMemoryLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ReadMemoryLimit{{ max_total: {:?}, max_per_connection: {:?}, buffered_bytes: {} }}",
+               self.inner.max_total, self.inner.max_per_connection, self.buffered_bytes())
+    }
+}
+
+/// Wraps the reader for a single connection, and keeps track of how many of the bytes that were read from it are still
+/// buffered. Reads are shortened so that they won't go over either limit, and return `WouldBlock` while the total limit is
+/// reached. The current task is notified once memory is released by another connection.
+pub struct MemoryLimitedReader<R: Read> {
+    reader: R,
+    limit: ReadMemoryLimit,
+    /// the number of bytes that this connection has added to the total
+    buffered: usize,
+}
+
+impl <R: Read> MemoryLimitedReader<R> {
+    pub fn new(reader: R, limit: ReadMemoryLimit) -> MemoryLimitedReader<R> {
+        MemoryLimitedReader {
+            reader: reader,
+            limit: limit,
+            buffered: 0,
+        }
+    }
+
+    /// Replaces the limit that this reader counts against. Anything that's already buffered is moved to the new limit
+    pub fn set_limit(&mut self, limit: ReadMemoryLimit) {
+        self.limit.release(self.buffered);
+        limit.add(self.buffered);
+        self.limit = limit;
+    }
+
+    /// Updates the number of bytes from this connection that are still buffered, once some of them have been parsed
+    pub fn set_buffered(&mut self, buffered: usize) {
+        if buffered < self.buffered {
+            self.limit.release(self.buffered - buffered);
+        } else {
+            self.limit.add(buffered - self.buffered);
+        }
+        self.buffered = buffered;
+    }
+
+    fn allowed_read_len(&self) -> io::Result<usize> {
+        if let Some(max) = self.limit.inner.max_per_connection {
+            if self.buffered >= max {
+                let message = format!("Connection has buffered {} bytes without completing a message, which reaches the limit of {} bytes", self.buffered, max);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+        }
+        let connection_remaining = self.limit.inner.max_per_connection.map(|max| max - self.buffered).unwrap_or(::std::usize::MAX);
+        Ok(cmp::min(connection_remaining, self.limit.total_remaining()))
+    }
+}
+
+impl <R: Read> Read for MemoryLimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut allowed = self.allowed_read_len()?;
+        if allowed == 0 {
+            self.limit.block_current_task();
+            // memory may have been released before this task was added to the blocked tasks
+            allowed = self.allowed_read_len()?;
+            if allowed == 0 {
+                debug!("Blocking read because {} buffered bytes have reached the limit", self.limit.buffered_bytes());
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "Read memory limit reached"));
+            }
+        }
+
+        let read_len = cmp::min(allowed, buf.len());
+        let nread = self.reader.read(&mut buf[..read_len])?;
+        self.buffered += nread;
+        self.limit.add(nread);
+        Ok(nread)
+    }
+}
+
+impl <R: Read> Drop for MemoryLimitedReader<R> {
+    fn drop(&mut self) {
+        self.limit.release(self.buffered);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::{future, Future, Async};
+    use futures::stream::Stream;
+    use protocol::{MessageWriter, ProtocolMessage, ProduceEvent, Compression};
+    use atomics::AtomicBoolWriter;
+    use server::flo_io::ProtocolMessageStream;
+
+    /// Hands out `data`, and then returns `WouldBlock` forever, like a client that stopped sending part way through a frame
+    struct StalledReader(io::Cursor<Vec<u8>>);
+
+    impl Read for StalledReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(io::Error::new(io::ErrorKind::WouldBlock, "no more data yet")),
+                n => Ok(n)
+            }
+        }
+    }
+
+    fn produce_bytes(data_len: usize) -> (ProtocolMessage<::event::OwnedFloEvent>, Vec<u8>) {
+        let produce = ProtocolMessage::ProduceEvent(ProduceEvent {
+            op_id: 1,
+            partition: 1,
+            namespace: "/foo".to_owned(),
+            parent_id: None,
+            ttl: None,
+            compression: Compression::None,
+            trace: false,
+            data: vec![9; data_len],
+        });
+        let mut bytes = Vec::new();
+        MessageWriter::new_owned(produce.clone()).write(&mut bytes).expect("failed to write message");
+        (produce, bytes)
+    }
+
+    fn partial_frame_stream(connection_id: usize, frame_len: usize, sent_len: usize, limit: &ReadMemoryLimit) -> ProtocolMessageStream<StalledReader> {
+        let (_, mut bytes) = produce_bytes(frame_len);
+        bytes.truncate(sent_len);
+        ProtocolMessageStream::new(connection_id as ::engine::ConnectionId, StalledReader(io::Cursor::new(bytes)), 1024, AtomicBoolWriter::with_value(false))
+                .with_read_memory_limit(limit.clone())
+    }
+
+    #[test]
+    fn total_buffered_bytes_stay_under_the_limit_while_many_connections_send_partial_frames() {
+        let max_total = 64 * 1024;
+        let limit = ReadMemoryLimit::new(Some(max_total), None);
+        let mut streams = (0..50).map(|i| {
+            partial_frame_stream(i, 32 * 1024, 16 * 1024, &limit)
+        }).collect::<Vec<_>>();
+
+        future::lazy(|| {
+            for _ in 0..5 {
+                for stream in streams.iter_mut() {
+                    assert_eq!(Async::NotReady, stream.poll().expect("failed to poll stream"));
+                    assert!(limit.buffered_bytes() <= max_total);
+                }
+            }
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+
+        // without a limit, these connections would have buffered 800KB
+        let filled = limit.buffered_bytes();
+        assert!(filled > max_total / 2);
+
+        // closing connections releases what they had buffered. The first connections to be polled are the ones that filled up
+        // the limit, while the rest have been blocked without reading anything
+        streams.drain(..25);
+        assert!(limit.buffered_bytes() < filled);
+        streams.clear();
+        assert_eq!(0, limit.buffered_bytes());
+    }
+
+    #[test]
+    fn blocked_connection_resumes_reading_once_another_connection_releases_memory() {
+        let limit = ReadMemoryLimit::new(Some(8 * 1024), None);
+        let mut stalled = Some(partial_frame_stream(1, 32 * 1024, 16 * 1024, &limit));
+        let (produce, bytes) = produce_bytes(16);
+        let mut blocked = ProtocolMessageStream::new(2, StalledReader(io::Cursor::new(bytes)), 1024, AtomicBoolWriter::with_value(false))
+                .with_read_memory_limit(limit.clone());
+
+        future::lazy(|| {
+            while stalled.as_mut().unwrap().poll().expect("failed to poll stalled stream") == Async::NotReady && limit.total_remaining() > 0 {}
+            assert_eq!(Async::NotReady, blocked.poll().expect("failed to poll blocked stream"));
+
+            stalled = None;
+            assert_eq!(Async::Ready(Some(produce)), blocked.poll().expect("failed to poll blocked stream"));
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn event_body_is_allocated_only_as_its_bytes_arrive() {
+        let limit = ReadMemoryLimit::unlimited();
+        let claimed_len = 16 * 1024 * 1024;
+        let sent_len = 4096;
+        let mut subject = partial_frame_stream(1, claimed_len, sent_len, &limit);
+
+        future::lazy(|| {
+            assert_eq!(Async::NotReady, subject.poll().expect("failed to poll stream"));
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+        // the buffered bytes include the capacity of the partially read body, which would be the whole claimed length if it
+        // were allocated from the header
+        assert!(limit.buffered_bytes() > 0);
+        assert!(limit.buffered_bytes() <= sent_len, "buffered bytes: {}", limit.buffered_bytes());
+    }
+
+    #[test]
+    fn connection_fails_when_it_reaches_the_per_connection_limit_without_completing_a_message() {
+        let limit = ReadMemoryLimit::new(None, Some(4096));
+        let mut subject = partial_frame_stream(1, 32 * 1024, 16 * 1024, &limit);
+
+        let result = future::lazy(|| subject.poll()).wait();
+        let err = result.expect_err("expected the connection to fail");
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        // the frame header is parsed, so only the body bytes are still buffered
+        assert!(limit.buffered_bytes() > 0);
+        assert!(limit.buffered_bytes() <= 4096);
+
+        drop(subject);
+        assert_eq!(0, limit.buffered_bytes());
+    }
+}
@@ -1,88 +1,235 @@
-use std::io;
+use std::io::{self, Write};
 
 use futures::stream::Stream;
 use futures::Async;
-use futures::sync::mpsc::UnboundedReceiver;
 use futures::{Future, Poll};
 #[allow(deprecated)]
 use tokio_core::io::WriteHalf;
 use tokio_core::net::TcpStream;
 
-use engine::{ConnectionId, SendProtocolMessage};
-use engine::event_stream::partition::PersistentEvent;
-use protocol::MessageWriter;
+use engine::{ConnectionId, ClientReceiver};
+use protocol::{MessageWriter, Framing};
+use atomics::AtomicBoolReader;
+use super::IdleTimeout;
 
 #[allow(deprecated)]
 pub type ServerWriteStream = WriteHalf<TcpStream>;
 
-pub struct ServerMessageStream {
+/// Once this many bytes of serialized messages are waiting to be written, no more messages are taken from the receiver
+/// until some of them have been written. A single message larger than this is still buffered in its entirety.
+pub const WRITE_BUFFER_FLUSH_BYTES: usize = 64 * 1024;
+
+/// Writes every message that's sent to a connection. Messages are serialized into a single buffer as long as more are
+/// immediately available from the receiver, so that a batch of small messages is sent with one write instead of one
+/// write per message. The buffer is written whenever the receiver has nothing more to give, or once it reaches
+/// `WRITE_BUFFER_FLUSH_BYTES`.
+pub struct ServerMessageStream<W: Write> {
     connection_id: ConnectionId,
-    server_receiver: UnboundedReceiver<SendProtocolMessage>,
-    current_message: Option<MessageWriter<PersistentEvent>>,
-    tcp_stream: ServerWriteStream,
+    server_receiver: ClientReceiver,
+    receiver_done: bool,
+    /// serialized messages that have not yet been completely written
+    write_buffer: Vec<u8>,
+    /// the number of bytes at the start of `write_buffer` that have already been written
+    write_position: usize,
+    tcp_stream: W,
+    cbor_framing: AtomicBoolReader,
+    /// if set, then the connection is closed when buffered messages are left unwritten until this fires
+    write_timeout: Option<IdleTimeout>,
 }
 
-impl ServerMessageStream {
-    pub fn new(connection_id: ConnectionId, server_rx: UnboundedReceiver<SendProtocolMessage>, tcp_stream: ServerWriteStream) -> ServerMessageStream {
+impl <W: Write> ServerMessageStream<W> {
+    pub fn new(connection_id: ConnectionId, server_rx: ClientReceiver, tcp_stream: W, cbor_framing: AtomicBoolReader) -> ServerMessageStream<W> {
         ServerMessageStream {
             connection_id: connection_id,
             server_receiver: server_rx,
-            current_message: None,
+            receiver_done: false,
+            write_buffer: Vec::new(),
+            write_position: 0,
             tcp_stream: tcp_stream,
+            cbor_framing: cbor_framing,
+            write_timeout: None,
+        }
+    }
+
+    pub fn with_write_timeout(mut self, write_timeout: Option<IdleTimeout>) -> ServerMessageStream<W> {
+        self.write_timeout = write_timeout;
+        self
+    }
+
+    fn framing(&self) -> Framing {
+        if self.cbor_framing.get_relaxed() {
+            Framing::Cbor
+        } else {
+            Framing::Binary
         }
     }
 
-    fn needs_next_message(&self) -> bool {
-        self.current_message.as_ref().map(|m| m.is_done()).unwrap_or(true)
+    fn unwritten_len(&self) -> usize {
+        self.write_buffer.len() - self.write_position
+    }
+
+    /// Takes messages from the receiver and serializes them into the write buffer until either the receiver has nothing
+    /// more available or the buffer is full enough to be written
+    fn fill_write_buffer(&mut self) -> io::Result<()> {
+        while !self.receiver_done && self.unwritten_len() < WRITE_BUFFER_FLUSH_BYTES {
+            match self.server_receiver.poll() {
+                Ok(Async::Ready(Some(message))) => {
+                    if self.unwritten_len() == 0 {
+                        // the timeout only applies to writing what's been buffered, not to waiting on the receiver
+                        if let Some(ref mut timeout) = self.write_timeout {
+                            timeout.reset();
+                        }
+                    }
+                    let framing = self.framing();
+                    MessageWriter::with_framing(message, framing).write(&mut self.write_buffer)?;
+                }
+                Ok(Async::Ready(None)) => {
+                    self.receiver_done = true;
+                }
+                Ok(Async::NotReady) => {
+                    return Ok(());
+                }
+                Err(()) => {
+                    warn!("Error reading from message receiver for connection_id: {}, Completing Stream", self.connection_id);
+                    self.receiver_done = true;
+                }
+            }
+        }
+        Ok(())
     }
 }
 
-impl Future for ServerMessageStream {
+impl <W: Write> Future for ServerMessageStream<W> {
     type Item = ();
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         loop {
-            if self.needs_next_message() {
-                match self.server_receiver.poll() {
-                    Ok(Async::Ready(Some(message))) => {
-                        self.current_message = Some(MessageWriter::new_owned(message));
-                    }
-                    Ok(Async::Ready(None)) => {
-                        return Ok(Async::Ready(()));
-                    }
-                    Ok(Async::NotReady) => {
-                        return Ok(Async::NotReady);
-                    }
-                    Err(()) => {
-                        warn!("Error reading from message receiver for connection_id: {}, Completing Stream", self.connection_id);
-                        return Ok(Async::Ready(()));
-                    }
+            self.fill_write_buffer()?;
+
+            if self.unwritten_len() == 0 {
+                self.write_buffer.clear();
+                self.write_position = 0;
+                if self.receiver_done {
+                    return Ok(Async::Ready(()));
+                } else {
+                    return Ok(Async::NotReady);
                 }
             }
 
             let ServerMessageStream {
                 connection_id,
-                ref mut current_message,
+                ref write_buffer,
+                ref mut write_position,
                 ref mut tcp_stream,
+                ref mut write_timeout,
                 .. } = *self;
 
-            if let Some(ref mut message) = current_message.as_mut() {
-                match message.write(tcp_stream) {
-                    Ok(()) => {
-                        // loop back around for another try
-                        trace!("Successfully wrote part of message to connection_id: {}, finished_message: {}", connection_id, message.is_done());
-                    }
-                    Err(ref io_err) if io_err.kind() == io::ErrorKind::WouldBlock => {
-                        return Ok(Async::NotReady);
+            match tcp_stream.write(&write_buffer[*write_position..]) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write buffered messages"));
+                }
+                Ok(n) => {
+                    // loop back around for another try
+                    *write_position += n;
+                    trace!("Wrote {} bytes to connection_id: {}, {} bytes remaining", n, connection_id, write_buffer.len() - *write_position);
+                    if let Some(ref mut timeout) = *write_timeout {
+                        timeout.reset();
                     }
-                    Err(io_err) => {
-                        error!("Error writing message to connection_id: {}, {}", connection_id, io_err);
-                        return Err(io_err);
+                }
+                Err(ref io_err) if io_err.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref io_err) if cfg!(target_os = "macos") && io_err.raw_os_error() == Some(41) => {
+                    // osx is weird, and can sometimes return an EPROTOTYPE when writing
+                    debug!(target: "eprototype", "Retrying write due to error: {:?}", io_err);
+                }
+                Err(ref io_err) if io_err.kind() == io::ErrorKind::WouldBlock => {
+                    // the client isn't reading what's been sent, so this is the only time that the timeout applies
+                    if let Some(ref mut timeout) = *write_timeout {
+                        if let Err(timeout_err) = timeout.check() {
+                            warn!("Write timed out for connection_id: {}: {}", connection_id, timeout_err);
+                            return Err(timeout_err);
+                        }
                     }
+                    return Ok(Async::NotReady);
+                }
+                Err(io_err) => {
+                    error!("Error writing message to connection_id: {}, {}", connection_id, io_err);
+                    return Err(io_err);
                 }
             }
         }
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use engine::create_client_channels;
+    use engine::event_stream::partition::PersistentEvent;
+    use event::FloEventId;
+    use protocol::{ProtocolMessage, EventAck};
+    use atomics::AtomicBoolWriter;
+
+    #[derive(Default)]
+    struct CountingWriter {
+        written: Vec<u8>,
+        write_calls: usize,
+    }
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_calls += 1;
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn ack(counter: u64) -> ProtocolMessage<PersistentEvent> {
+        ProtocolMessage::AckEvent(EventAck {
+            op_id: counter as u32,
+            event_id: FloEventId::new(1, counter),
+        })
+    }
+
+    #[test]
+    fn batch_of_small_messages_is_written_with_a_single_write() {
+        let (tx, rx) = create_client_channels();
+        let mut expected = Vec::new();
+        for counter in 1..101 {
+            MessageWriter::new_owned(ack(counter)).write(&mut expected).unwrap();
+            tx.unbounded_send(ack(counter)).unwrap();
+        }
+        drop(tx);
+
+        let mut writer = CountingWriter::default();
+        ServerMessageStream::new(1, rx, &mut writer, AtomicBoolWriter::with_value(false).reader()).wait().expect("failed to write messages");
+
+        assert_eq!(expected, writer.written);
+        assert_eq!(1, writer.write_calls);
+    }
+
+    #[test]
+    fn buffered_messages_are_written_once_the_buffer_reaches_the_flush_threshold() {
+        let (tx, rx) = create_client_channels();
+        let mut single_message = Vec::new();
+        MessageWriter::new_owned(ack(1)).write(&mut single_message).unwrap();
+        let message_len = single_message.len();
+        // the number of messages that are buffered before each write
+        let messages_per_write = (WRITE_BUFFER_FLUSH_BYTES + message_len - 1) / message_len;
+        let message_count = 2 * messages_per_write + 1;
+        for counter in 0..message_count {
+            tx.unbounded_send(ack(counter as u64 + 1)).unwrap();
+        }
+        drop(tx);
+
+        let mut writer = CountingWriter::default();
+        ServerMessageStream::new(1, rx, &mut writer, AtomicBoolWriter::with_value(false).reader()).wait().expect("failed to write messages");
+
+        assert_eq!(message_count * message_len, writer.written.len());
+        assert_eq!(3, writer.write_calls);
+    }
+}
@@ -1,7 +1,9 @@
 mod flo_io;
 mod server_options;
+mod setup_limit;
 
-use futures::{Stream, Sink, Future};
+use futures::{Stream, Sink, Future, future};
+use futures::future::Either;
 use tokio_core::net::{TcpStream, TcpListener};
 
 use event_loops;
@@ -10,19 +12,21 @@ use std::net::{SocketAddr, Ipv4Addr, SocketAddrV4};
 use std::io;
 
 pub use self::server_options::{ServerOptions, MemoryLimit, MemoryUnit};
+pub use self::setup_limit::{SetupLimiter, SetupPermit};
 
+/// The default maximum number of connections that may be waiting to be set up at once
+pub const DEFAULT_MAX_PENDING_CONNECTION_SETUPS: usize = 128;
 
 
 pub fn run(options: ServerOptions) -> io::Result<()> {
-    #[allow(deprecated)]
-    use tokio_core::io::Io;
     use engine::{ControllerOptions,
                      start_controller,
                      system_stream_name,
-                     create_client_channels,
-                     ConnectionHandler};
-    use engine::event_stream::EventStreamOptions;
-    use self::flo_io::{ProtocolMessageStream, ServerMessageStream};
+                     create_client_channels_with_capacity,
+                     ConnectionHandler,
+                     ReplayLimiter};
+    use engine::event_stream::{EventStreamOptions, FsyncPolicy};
+    use self::flo_io::{setup_message_streams, ReadMemoryLimit};
 
     const ONE_GB: usize = 1024 * 1024 * 1024;
 
@@ -31,17 +35,36 @@ pub fn run(options: ServerOptions) -> io::Result<()> {
     let controller_options = ControllerOptions {
         storage_dir: options.data_dir.clone(),
         default_stream_options: EventStreamOptions{
-            name: system_stream_name(),
+            // standalone servers have no system stream, so the default stream gets the usual default name
+            name: if options.standalone { EventStreamOptions::default().name } else { system_stream_name() },
             num_partitions: 1,
             event_retention: options.event_retention_duration,
             max_segment_duration: options.event_eviction_period,
             segment_max_size_bytes: ONE_GB,
+            encryption: None,
+            validate_parent: false,
+            starting_counter: 1,
+            max_cursor_lifetime: None,
+            fsync_policy: FsyncPolicy::default(),
         },
+        standalone: options.standalone,
+        dedicated_event_loop: false,
+        client_channel_capacity: options.client_channel_capacity,
     };
 
     let engine_ref = start_controller(controller_options, event_loop_handles.next_handle())?;
 
     let server_port = options.port;
+    let max_cursors_per_connection = options.max_cursors_per_connection;
+    let max_in_flight_produce_bytes = options.max_in_flight_produce_bytes;
+    // validated to be positive, so they can always be converted
+    let client_read_timeout = options.client_read_timeout.map(|timeout| timeout.to_std().unwrap());
+    let client_write_timeout = options.client_write_timeout.map(|timeout| timeout.to_std().unwrap());
+    let read_buffer_size = options.read_buffer_size;
+    let read_memory_limit = ReadMemoryLimit::new(options.max_buffered_read_memory.map(|limit| limit.as_bytes()),
+                                                 options.max_connection_buffered_read_memory.map(|limit| limit.as_bytes()));
+    let replay_limiter = options.max_concurrent_replays.map(ReplayLimiter::new);
+    let setup_limiter = SetupLimiter::new(options.max_pending_connection_setups, engine_ref.connections_pending_setup().clone());
     let address: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), server_port));
     let listener = ::std::net::TcpListener::bind(address)?;
 
@@ -56,44 +79,62 @@ pub fn run(options: ServerOptions) -> io::Result<()> {
         let incoming = listener.incoming();
         incoming.map_err(|io_err| {
             error!("Error creating new connection: {:?}", io_err);
-        }).for_each(move |(tcp_stream, client_addr): (TcpStream, SocketAddr)| {
+        }).for_each(move |(mut tcp_stream, client_addr): (TcpStream, SocketAddr)| {
+            let setup_permit = match setup_limiter.admit(&mut tcp_stream, client_addr) {
+                Some(permit) => permit,
+                None => return Ok(()),
+            };
             tcp_stream.set_nodelay(true).map_err(|io_err| {
                 error!("Error setting NODELAY. Nagle yet lives!: {:?}", io_err);
                 ()
             })?;
             let client_engine_ref = engine_ref.clone();
             let connection_id = client_engine_ref.next_connection_id();
-            let remote_handle = event_loop_handles.next_handle();
+            let (remote_handle, active_tasks) = event_loop_handles.next_handle_with_gauge();
 
-            let (client_tx, client_rx) = create_client_channels();
+            let (client_tx, client_rx) = create_client_channels_with_capacity(client_engine_ref.client_channel_capacity());
+            let client_read_memory_limit = read_memory_limit.clone();
+            let client_replay_limiter = replay_limiter.clone();
 
             info!("Opened connection_id: {} to address: {}", connection_id, client_addr);
+            active_tasks.increment();
 
             remote_handle.spawn(move |client_handle| {
 
-                #[allow(deprecated)]
-                let (tcp_reader, tcp_writer) = tcp_stream.split();
-
-                let server_to_client = ServerMessageStream::new(connection_id, client_rx, tcp_writer);
-
-                let client_message_stream = ProtocolMessageStream::new(connection_id, tcp_reader);
-                let connection_handler = ConnectionHandler::new(
+                let streams = setup_message_streams(connection_id, tcp_stream, client_rx, read_buffer_size, client_read_memory_limit, client_read_timeout, client_write_timeout, client_handle);
+                let (client_message_stream, server_to_client) = match streams {
+                    Ok(streams) => streams,
+                    Err(io_err) => {
+                        error!("Failed to set up connection_id: {}: {:?}", connection_id, io_err);
+                        active_tasks.decrement();
+                        return Either::A(future::ok(()));
+                    }
+                };
+                let mut connection_handler = ConnectionHandler::new(
                     connection_id,
                     client_tx.clone(),
                     client_engine_ref,
-                     client_handle.clone());
+                     client_handle.clone())
+                        .with_max_cursors_per_connection(max_cursors_per_connection)
+                        .with_max_in_flight_produce_bytes(max_in_flight_produce_bytes);
+                if let Some(limiter) = client_replay_limiter {
+                    connection_handler = connection_handler.with_replay_limiter(limiter);
+                }
+                // the connection is fully set up, so it no longer counts against the limit
+                drop(setup_permit);
 
                 let client_to_server = connection_handler
                         .send_all(client_message_stream)
                         .map(|_| ());
 
-                client_to_server.select(server_to_client).then(move |res| {
+                Either::B(client_to_server.select(server_to_client).then(move |res| {
                     if let Err((err, _)) = res {
                         warn!("Closing connection: {} due to err: {:?}", connection_id, err);
                     }
                     info!("Closed connection_id: {} to address: {}", connection_id, client_addr);
+                    active_tasks.decrement();
                     Ok(())
-                })
+                }))
 
             });
 
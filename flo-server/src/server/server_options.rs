@@ -1,4 +1,5 @@
 use std::path::PathBuf;
+use std::str::FromStr;
 use chrono::Duration;
 use std::net::SocketAddr;
 
@@ -38,6 +39,31 @@ impl MemoryLimit {
     }
 }
 
+impl FromStr for MemoryLimit {
+    type Err = String;
+
+    /// Parses an amount followed by an optional unit, such as "512MB", "2048 kb", or "1024". The unit is case insensitive,
+    /// and may be one of B, KB, or MB. An amount without a unit is in bytes.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let trimmed = s.trim();
+        let unit_start = trimmed.find(|c: char| !c.is_digit(10)).unwrap_or(trimmed.len());
+        let (amount_str, unit_str) = trimmed.split_at(unit_start);
+
+        let amount = amount_str.parse::<usize>().map_err(|_| {
+            format!("Invalid memory limit: '{}', must start with a non-negative integer amount", s)
+        })?;
+        let unit = match unit_str.trim().to_uppercase().as_str() {
+            "" | "B" => MemoryUnit::Byte,
+            "KB" => MemoryUnit::Kilobyte,
+            "MB" => MemoryUnit::Megabyte,
+            other @ _ => {
+                return Err(format!("Invalid memory limit: '{}', unknown unit: '{}', must be one of B, KB, or MB", s, other));
+            }
+        };
+        Ok(MemoryLimit::new(amount, unit))
+    }
+}
+
 #[derive(PartialEq, Clone)]
 pub struct ServerOptions {
     pub port: u16,
@@ -48,6 +74,37 @@ pub struct ServerOptions {
     pub cluster_addresses: Option<Vec<SocketAddr>>,
     pub actor_id: ActorId,
     pub max_io_threads: Option<usize>,
+    /// If true, then the server runs as a single node without a system stream, and `cluster_addresses` must not be set
+    pub standalone: bool,
+    /// The maximum number of cursors that a single connection may have active at once
+    pub max_cursors_per_connection: usize,
+    /// The maximum total size in bytes of events that a single connection may have received but not yet persisted
+    pub max_in_flight_produce_bytes: usize,
+    /// If set, then a connection is closed when it goes this long without receiving a message from the client. Clients
+    /// that may be idle for longer, such as consumers awaiting new events, should send `Heartbeat` messages
+    pub client_read_timeout: Option<Duration>,
+    /// If set, then a connection is closed when the client stops reading the messages that are sent to it for this long
+    pub client_write_timeout: Option<Duration>,
+    /// The size in bytes of the buffer that messages from each client are initially read into. A larger buffer lets
+    /// more messages be read with each syscall, but that memory is allocated for every connection. Messages larger than
+    /// the buffer are still read correctly, at the cost of growing the buffer for that connection
+    pub read_buffer_size: usize,
+    /// If set, then reading from client connections is paused whenever the bytes that have been read from all connections,
+    /// but not yet parsed into complete messages, add up to this limit. Reads resume once other connections complete
+    /// their messages or are closed, so this should be used along with `client_read_timeout`
+    pub max_buffered_read_memory: Option<MemoryLimit>,
+    /// If set, then a connection is closed when it has read this much without completing a message. This also limits the
+    /// size of the largest event that can be produced
+    pub max_connection_buffered_read_memory: Option<MemoryLimit>,
+    /// If set, then at most this many consumers across all connections may be replaying older segments at once. Others
+    /// wait until a consumer catches up or reaches the end of its batch. Consumers reading recent events are not limited
+    pub max_concurrent_replays: Option<usize>,
+    /// The maximum number of accepted connections that may be waiting to be set up at once. Connections that are accepted
+    /// while this many are still being set up are refused with a `ServerBusy` error
+    pub max_pending_connection_setups: usize,
+    /// The maximum number of messages that may be waiting to be written to each client. Once a client falls this far
+    /// behind, its consumers stop reading events until it catches up
+    pub client_channel_capacity: usize,
 }
 
 
@@ -60,6 +117,178 @@ impl ServerOptions {
                                self.event_retention_duration.num_hours()));
         }
 
+        if self.standalone && self.cluster_addresses.is_some() {
+            return Err("Cluster addresses cannot be given when running in standalone mode".to_owned());
+        }
+
+        if self.client_read_timeout.map(|timeout| timeout <= Duration::zero()).unwrap_or(false) {
+            return Err("Client read timeout must be greater than 0".to_owned());
+        }
+        if self.client_write_timeout.map(|timeout| timeout <= Duration::zero()).unwrap_or(false) {
+            return Err("Client write timeout must be greater than 0".to_owned());
+        }
+        if self.read_buffer_size == 0 {
+            return Err("Read buffer size must be greater than 0".to_owned());
+        }
+        if let Some(connection_max) = self.max_connection_buffered_read_memory {
+            if connection_max.as_bytes() == 0 {
+                return Err("Max connection buffered read memory must be greater than 0".to_owned());
+            }
+            if self.max_buffered_read_memory.map(|total_max| connection_max.as_bytes() > total_max.as_bytes()).unwrap_or(false) {
+                return Err("Max connection buffered read memory cannot be greater than the max buffered read memory".to_owned());
+            }
+        }
+        if self.max_buffered_read_memory.map(|total_max| total_max.as_bytes() == 0).unwrap_or(false) {
+            return Err("Max buffered read memory must be greater than 0".to_owned());
+        }
+        if self.max_concurrent_replays == Some(0) {
+            return Err("Max concurrent replays must be greater than 0".to_owned());
+        }
+        if self.max_pending_connection_setups == 0 {
+            return Err("Max pending connection setups must be greater than 0".to_owned());
+        }
+        if self.client_channel_capacity == 0 {
+            return Err("Client channel capacity must be greater than 0".to_owned());
+        }
+
         Ok(())
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn options() -> ServerOptions {
+        ServerOptions {
+            port: 3000,
+            data_dir: PathBuf::from("."),
+            event_retention_duration: Duration::days(2),
+            event_eviction_period: Duration::hours(1),
+            max_cache_memory: MemoryLimit::new(512, MemoryUnit::Megabyte),
+            cluster_addresses: None,
+            actor_id: 1,
+            max_io_threads: None,
+            standalone: true,
+            max_cursors_per_connection: 64,
+            max_in_flight_produce_bytes: 1024 * 1024,
+            client_read_timeout: None,
+            client_write_timeout: None,
+            read_buffer_size: 8 * 1024,
+            max_buffered_read_memory: None,
+            max_connection_buffered_read_memory: None,
+            max_concurrent_replays: None,
+            max_pending_connection_setups: 128,
+            client_channel_capacity: 1024,
+        }
+    }
+
+    #[test]
+    fn validate_returns_error_when_the_connection_read_memory_limit_is_larger_than_the_total() {
+        let mut subject = options();
+        subject.max_buffered_read_memory = Some(MemoryLimit::new(64, MemoryUnit::Megabyte));
+        subject.max_connection_buffered_read_memory = Some(MemoryLimit::new(1024, MemoryUnit::Kilobyte));
+        assert!(subject.validate().is_ok());
+
+        subject.max_connection_buffered_read_memory = Some(MemoryLimit::new(65, MemoryUnit::Megabyte));
+        assert!(subject.validate().is_err());
+
+        // without a total limit, any positive connection limit is fine
+        subject.max_buffered_read_memory = None;
+        assert!(subject.validate().is_ok());
+
+        subject.max_connection_buffered_read_memory = Some(MemoryLimit::new(0, MemoryUnit::Byte));
+        assert!(subject.validate().is_err());
+    }
+
+    #[test]
+    fn validate_returns_error_when_a_client_timeout_is_not_positive() {
+        let mut subject = options();
+        subject.client_read_timeout = Some(Duration::seconds(30));
+        subject.client_write_timeout = Some(Duration::seconds(10));
+        assert!(subject.validate().is_ok());
+
+        subject.client_read_timeout = Some(Duration::zero());
+        assert!(subject.validate().is_err());
+
+        subject.client_read_timeout = None;
+        subject.client_write_timeout = Some(Duration::seconds(-1));
+        assert!(subject.validate().is_err());
+    }
+
+    #[test]
+    fn memory_limit_is_parsed_from_an_amount_and_optional_unit() {
+        assert_eq!(Ok(MemoryLimit::new(1024, MemoryUnit::Byte)), "1024".parse::<MemoryLimit>());
+        assert_eq!(Ok(MemoryLimit::new(10, MemoryUnit::Byte)), "10B".parse::<MemoryLimit>());
+        assert_eq!(Ok(MemoryLimit::new(2048, MemoryUnit::Kilobyte)), "2048KB".parse::<MemoryLimit>());
+        assert_eq!(Ok(MemoryLimit::new(512, MemoryUnit::Megabyte)), "512MB".parse::<MemoryLimit>());
+        assert_eq!(Ok(MemoryLimit::new(512, MemoryUnit::Megabyte)), " 512 mb ".parse::<MemoryLimit>());
+        assert_eq!(Ok(MemoryLimit::new(64, MemoryUnit::Kilobyte)), "64Kb".parse::<MemoryLimit>());
+    }
+
+    #[test]
+    fn memory_limit_parse_returns_error_for_invalid_input() {
+        let err = "512GB".parse::<MemoryLimit>().unwrap_err();
+        assert!(err.contains("unknown unit: 'GB'"), "unexpected error: {}", err);
+
+        let err = "MB".parse::<MemoryLimit>().unwrap_err();
+        assert!(err.contains("non-negative integer amount"), "unexpected error: {}", err);
+
+        assert!("".parse::<MemoryLimit>().is_err());
+        assert!("-5MB".parse::<MemoryLimit>().is_err());
+        assert!("1.5MB".parse::<MemoryLimit>().is_err());
+    }
+
+    #[test]
+    fn validate_returns_error_when_max_concurrent_replays_is_zero() {
+        let mut subject = options();
+        subject.max_concurrent_replays = Some(0);
+        assert!(subject.validate().is_err());
+
+        subject.max_concurrent_replays = Some(1);
+        assert!(subject.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_returns_error_when_max_pending_connection_setups_is_zero() {
+        let mut subject = options();
+        subject.max_pending_connection_setups = 0;
+        assert!(subject.validate().is_err());
+
+        subject.max_pending_connection_setups = 1;
+        assert!(subject.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_returns_error_when_client_channel_capacity_is_zero() {
+        let mut subject = options();
+        subject.client_channel_capacity = 0;
+        assert!(subject.validate().is_err());
+
+        subject.client_channel_capacity = 1;
+        assert!(subject.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_returns_error_when_read_buffer_size_is_zero() {
+        let mut subject = options();
+        subject.read_buffer_size = 0;
+        assert!(subject.validate().is_err());
+
+        subject.read_buffer_size = 1;
+        assert!(subject.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_returns_error_when_standalone_server_has_cluster_addresses() {
+        let mut subject = options();
+        assert!(subject.validate().is_ok());
+
+        subject.cluster_addresses = Some(vec!["127.0.0.1:3001".parse().unwrap()]);
+        assert!(subject.validate().is_err());
+
+        subject.standalone = false;
+        assert!(subject.validate().is_ok());
+    }
+}
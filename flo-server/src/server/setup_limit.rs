@@ -0,0 +1,154 @@
+use std::io::{self, Write};
+use std::net::SocketAddr;
+
+use protocol::{ProtocolMessage, ErrorMessage, ErrorKind};
+use event::OwnedFloEvent;
+use metrics::Gauge;
+
+/// Bounds the number of accepted connections that are still being set up. Setup happens on one of the event loops, so a
+/// burst of new connections would otherwise queue an unbounded amount of work there. Connections that are accepted while
+/// the limit is reached should be refused instead of waiting for a permit.
+#[derive(Debug, Clone)]
+pub struct SetupLimiter {
+    max_pending: usize,
+    connections_pending_setup: Gauge,
+}
+
+impl SetupLimiter {
+    /// Creates a limiter that counts pending connections using the given gauge, which is normally the one that's exposed
+    /// by `EngineRef::connections_pending_setup`
+    pub fn new(max_pending: usize, connections_pending_setup: Gauge) -> SetupLimiter {
+        SetupLimiter {
+            max_pending: max_pending,
+            connections_pending_setup: connections_pending_setup,
+        }
+    }
+
+    pub fn max_pending(&self) -> usize {
+        self.max_pending
+    }
+
+    /// The number of connections that currently hold a permit
+    pub fn connections_pending_setup(&self) -> &Gauge {
+        &self.connections_pending_setup
+    }
+
+    /// Returns a permit that's held until the connection is set up, or `None` if the maximum number of connections are
+    /// already being set up
+    pub fn try_acquire(&self) -> Option<SetupPermit> {
+        if self.connections_pending_setup.increment_if_below(self.max_pending) {
+            Some(SetupPermit {
+                connections_pending_setup: self.connections_pending_setup.clone(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Called for each connection as it's accepted. Returns a permit if the connection may be set up, or else refuses the
+    /// connection by writing a `ServerBusy` error to it and returns `None`, in which case the connection should be closed
+    pub fn admit<W: Write>(&self, connection: &mut W, client_addr: SocketAddr) -> Option<SetupPermit> {
+        let permit = self.try_acquire();
+        if permit.is_none() {
+            warn!("Refusing connection from address: {} because {} connections are already being set up",
+                  client_addr, self.max_pending);
+            if let Err(io_err) = refuse_connection(connection) {
+                debug!("Failed to send error to refused connection from address: {}: {:?}", client_addr, io_err);
+            }
+        }
+        permit
+    }
+}
+
+/// Counts against the `SetupLimiter` that it came from until it's dropped
+#[derive(Debug)]
+pub struct SetupPermit {
+    connections_pending_setup: Gauge,
+}
+
+impl Drop for SetupPermit {
+    fn drop(&mut self) {
+        self.connections_pending_setup.decrement();
+    }
+}
+
+/// Writes a `ServerBusy` error to a connection that's being refused, so that the client can tell that it should retry
+/// later, rather than just seeing the connection close. The client hasn't sent anything yet, so the error always uses the
+/// binary framing, and its op_id is 0. The connection should be closed afterwards whether or not the write succeeds.
+pub fn refuse_connection<W: Write>(writer: &mut W) -> io::Result<()> {
+    let message: ProtocolMessage<OwnedFloEvent> = ProtocolMessage::Error(ErrorMessage {
+        op_id: 0,
+        kind: ErrorKind::ServerBusy,
+        description: "Too many connections are being set up, try again later".to_owned(),
+    });
+    let mut buffer = [0; 128];
+    let len = message.serialize(&mut buffer);
+    writer.write_all(&buffer[..len])?;
+    writer.flush()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+    use protocol::parse_any;
+
+    #[test]
+    fn permits_are_released_when_dropped() {
+        let gauge = Gauge::new();
+        let subject = SetupLimiter::new(2, gauge.clone());
+        let first = subject.try_acquire().expect("expected first permit");
+        let _second = subject.try_acquire().expect("expected second permit");
+        assert!(subject.try_acquire().is_none());
+        assert_eq!(2, gauge.get());
+
+        drop(first);
+        assert_eq!(1, gauge.get());
+        assert!(subject.try_acquire().is_some());
+        assert_eq!(1, gauge.get());
+    }
+
+    #[test]
+    fn connections_beyond_the_limit_are_refused_with_server_busy_error() {
+        let subject = SetupLimiter::new(2, Gauge::new());
+        let client_addr = "127.0.0.1:3000".parse().unwrap();
+
+        // a burst of connections that are all accepted before any of them are set up
+        let mut permits = Vec::new();
+        let mut clients = Vec::new();
+        for _ in 0..5 {
+            let (mut server_end, client_end) = UnixStream::pair().expect("failed to create connection");
+            if let Some(permit) = subject.admit(&mut server_end, client_addr) {
+                permits.push(permit);
+            }
+            // refused connections are closed right away, and the accepted ones haven't been sent anything yet
+            drop(server_end);
+            clients.push(client_end);
+        }
+        assert_eq!(2, permits.len());
+        assert_eq!(2, subject.connections_pending_setup().get());
+
+        let mut refused = 0;
+        for (index, client) in clients.iter_mut().enumerate() {
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).expect("failed to read from connection");
+            if index < 2 {
+                assert!(received.is_empty());
+                continue;
+            }
+            match parse_any(&received).unwrap() {
+                (remaining, ProtocolMessage::Error(ref err)) if err.kind == ErrorKind::ServerBusy && remaining.is_empty() => refused += 1,
+                other @ _ => panic!("expected ServerBusy error, got: {:?}", other),
+            }
+        }
+        assert_eq!(3, refused);
+
+        // once the accepted connections are set up, new ones are accepted again
+        permits.clear();
+        assert_eq!(0, subject.connections_pending_setup().get());
+        let (mut server_end, _client_end) = UnixStream::pair().expect("failed to create connection");
+        assert!(subject.admit(&mut server_end, client_addr).is_some());
+    }
+}
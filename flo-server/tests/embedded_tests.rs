@@ -3,23 +3,38 @@ extern crate env_logger;
 extern crate tempdir;
 extern crate flo_client_lib;
 extern crate flo_server;
+extern crate flo_protocol;
+extern crate flo_event;
 extern crate futures;
 extern crate tokio_core;
 extern crate chrono;
 
 extern crate log;
 
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use tokio_core::reactor::Core;
-use futures::{Stream, Future};
+use tokio_core::reactor::{Core, Timeout};
+use futures::{Stream, Future, Sink, Async, future, stream};
 
-use flo_server::embedded::{EmbeddedFloServer, ControllerOptions, EventStreamOptions, run_embedded_server};
+use flo_server::atomics::AtomicBoolWriter;
+use flo_server::embedded::{EmbeddedFloServer, ControllerOptions, EventStreamOptions, run_embedded_server, DEFAULT_CLIENT_CHANNEL_CAPACITY};
+use flo_server::engine::{EngineRef, ConnectionHandler, ConnectionId, ClientReceiver, ReplayLimiter, PIPE_TAG_PREFIX,
+                         create_client_channels, create_client_channels_with_capacity, system_stream_name};
+use flo_server::engine::event_stream::{EventStreamRef, init_new_event_stream};
+use flo_server::engine::event_stream::partition::EventFilter;
 
-use flo_client_lib::{VersionVector, FloEventId, Event, EventCounter, ActorId};
+use flo_event::FloEvent;
+use flo_protocol::{ProtocolMessage, ProduceEvent, NewConsumerStart, ClientAnnounce, CursorInfo, ReadConsistency,
+                   EventAck, TraceStage, ErrorKind, StreamDescriptor, StreamList, CountEvents, CountResult, GetConsumerLag,
+                   GetTag, TagList, StreamTag};
+
+use flo_client_lib::{VersionVector, FloEventId, Event, EventCounter, ActorId, ErrorKind as ClientErrorKind};
 use flo_client_lib::codec::{EventCodec, StringCodec};
+use flo_client_lib::async::{AsyncConnection, ErrorType};
+use flo_client_lib::async::ops::{EventToProduce, MAX_PIPELINED_PRODUCES};
 
 fn default_test_options() -> EventStreamOptions {
     Default::default()
@@ -40,6 +55,9 @@ fn integration_test<F>(test_name: &'static str, stream_opts: EventStreamOptions,
     let controller_options = ControllerOptions {
         storage_dir: tmp_dir.path().to_owned(),
         default_stream_options: stream_opts,
+        standalone: false,
+        dedicated_event_loop: false,
+        client_channel_capacity: DEFAULT_CLIENT_CHANNEL_CAPACITY,
     };
     let reactor = Core::new().expect("failed to create reactor");
     let embedded_server = run_embedded_server(controller_options, reactor.remote()).expect("failed to run embedded server");
@@ -47,6 +65,64 @@ fn integration_test<F>(test_name: &'static str, stream_opts: EventStreamOptions,
     fun(embedded_server, reactor);
 }
 
+/// Keeps the storage for an `engine_fixture` stream alive until the end of the test
+struct StreamDir {
+    _tmp_dir: tempdir::TempDir,
+    _status: AtomicBoolWriter,
+}
+
+/// Creates an engine with only the system stream, for tests that send protocol messages directly to a `ConnectionHandler`
+fn engine_fixture(test_name: &str, stream_opts: EventStreamOptions) -> (Core, EngineRef, StreamDir) {
+    let _ = env_logger::init();
+    let tmp_dir = tempdir::TempDir::new(test_name).expect("failed to create temp dir");
+    let reactor = Core::new().expect("failed to create reactor");
+    let status = AtomicBoolWriter::with_value(true);
+
+    let options = EventStreamOptions {
+        name: system_stream_name(),
+        ..stream_opts
+    };
+    let stream = init_new_event_stream(tmp_dir.path().join("system"), options, status.reader(), reactor.remote()).expect("failed to init stream");
+    let mut streams = HashMap::new();
+    streams.insert(system_stream_name(), stream);
+    let engine = EngineRef::new(streams);
+
+    (reactor, engine, StreamDir { _tmp_dir: tmp_dir, _status: status })
+}
+
+fn connect(engine: &EngineRef, reactor: &Core, connection_id: ConnectionId) -> (ConnectionHandler, ClientReceiver) {
+    let (client_sender, client_receiver) = create_client_channels();
+    let handler = ConnectionHandler::new(connection_id, client_sender, engine.clone(), reactor.handle());
+    (handler, client_receiver)
+}
+
+/// Appends the events straight to the partition, without going through a connection, and returns the last id
+fn produce_directly(stream: &mut EventStreamRef, partition: ActorId, op_id: u32, events: Vec<ProduceEvent>) -> FloEventId {
+    stream.get_partition(partition).unwrap()
+            .produce(1, op_id, events).expect("failed to send produce")
+            .wait().expect("failed to receive produce result")
+            .expect("failed to produce")
+            .last_id
+}
+
+fn produce_event(op_id: u32, namespace: &str, data: &str) -> ProduceEvent {
+    ProduceEvent {
+        op_id: op_id,
+        namespace: namespace.to_owned(),
+        data: data.to_owned().into_bytes(),
+        ..Default::default()
+    }
+}
+
+fn consumer_start(op_id: u32, namespace: &str) -> NewConsumerStart {
+    NewConsumerStart {
+        op_id: op_id,
+        version_vector: vec![FloEventId::new(1, 0)],
+        namespace: namespace.to_owned(),
+        ..Default::default()
+    }
+}
+
 fn run_future<T: Debug, E: Debug, F: Future<Item=T, Error=E> + Debug>(reactor: &mut Core, future: F) -> T {
     use tokio_core::reactor::Timeout;
     use futures::future::Either;
@@ -101,6 +177,148 @@ fn consumer_stops_and_restarts_consuming() {
     });
 }
 
+#[test]
+fn producer_awaits_the_stream_reaching_an_event_produced_by_another_producer() {
+    integration_test("await stream position", default_test_options(), |server, mut reactor| {
+        let other = server.connect_client::<String>("other_producer".to_owned(), codec(), reactor.handle());
+        let other = reactor.run(other.connect()).expect("failed to connect producer");
+        let waiter = server.connect_client::<String>("waiting_producer".to_owned(), codec(), reactor.handle());
+        let waiter = reactor.run(waiter.connect()).expect("failed to connect producer");
+
+        let (own_id, waiter) = run_future(&mut reactor, waiter.produce_to(1, "/waiter", None, "first".to_owned()));
+        let target = FloEventId::new(1, own_id.event_counter + 2);
+
+        // the other producer's events are only produced once the waiter has started awaiting them
+        let others_events = other.produce_to(1, "/other", None, "second".to_owned()).and_then(|(_, other)| {
+            other.produce_to(1, "/other", None, "third".to_owned())
+        });
+        reactor.handle().spawn(others_events.map(|_| ()).map_err(|err| panic!("failed to produce: {:?}", err)));
+        let waiter = run_future(&mut reactor, waiter.await_stream_position(target));
+
+        // the waiter's next event comes after everything the other producer produced
+        let (next_id, waiter) = run_future(&mut reactor, waiter.produce_to(1, "/waiter", None, "fourth".to_owned()));
+        assert_eq!(FloEventId::new(1, 4), next_id);
+
+        // a target on a partition that doesn't exist can never be reached
+        let err = reactor.run(waiter.await_stream_position(FloEventId::new(9, 1))).expect_err("expected an error");
+        match err.error {
+            ErrorType::Server(ref message) => assert_eq!(ClientErrorKind::InvalidVersionVector, message.kind),
+            ref other => panic!("expected InvalidVersionVector, got: {:?}", other),
+        }
+    });
+}
+
+#[test]
+fn requester_receives_the_reply_to_its_request_from_a_responder() {
+    integration_test("await reply", default_test_options(), |server, mut reactor| {
+        let responder = server.connect_client::<String>("responder".to_owned(), codec(), reactor.handle());
+        let responder = reactor.run(responder.connect()).expect("failed to connect responder");
+        let requester = server.connect_client::<String>("requester".to_owned(), codec(), reactor.handle());
+        let requester = reactor.run(requester.connect()).expect("failed to connect requester");
+
+        // the responder replies to the first request, after producing an unrelated event to the same namespace
+        let mut vv = VersionVector::new();
+        vv.set(FloEventId::new(1, 0));
+        let respond = responder.consume("/requests", &vv, Some(1), true).into_future()
+                .map_err(|err| panic!("failed to consume request: {:?}", err.0))
+                .and_then(|(request, consume)| {
+                    let request = request.expect("responder received no request");
+                    let responder: AsyncConnection<String> = consume.into();
+                    responder.produce_to(1, "/replies", None, "unrelated".to_owned()).and_then(move |(_, responder)| {
+                        responder.produce_to(1, "/replies", Some(request.id), format!("reply to: {}", request.data))
+                    }).map_err(|err| panic!("failed to produce reply: {:?}", err))
+                });
+        reactor.handle().spawn(respond.map(|_| ()));
+
+        let request = EventToProduce::witout_parent(1, "/requests", "ping".to_owned());
+        let await_reply = requester.produce_and_await_reply(request, "/replies", Duration::from_millis(500), &reactor.handle());
+        let (reply, requester) = run_future(&mut reactor, await_reply);
+        assert_eq!(Some(FloEventId::new(1, 1)), reply.parent_id);
+        assert_eq!("/replies", reply.namespace);
+        assert_eq!("reply to: ping", reply.data);
+
+        // with nobody left to respond, the next request times out and the connection can still be used afterwards
+        let request = EventToProduce::witout_parent(1, "/requests", "anyone there?".to_owned());
+        let err = reactor.run(requester.produce_and_await_reply(request, "/replies", Duration::from_millis(50), &reactor.handle()))
+                .expect_err("expected a timeout");
+        match err.error {
+            ::flo_client_lib::async::ErrorType::Io(ref io_err) => assert_eq!(::std::io::ErrorKind::TimedOut, io_err.kind()),
+            ref other => panic!("expected TimedOut, got: {:?}", other),
+        }
+        let requester = err.connection.expect("connection was not returned after the timeout");
+        let (id, _) = run_future(&mut reactor, requester.produce_to(1, "/requests", None, "still connected".to_owned()));
+        assert_eq!(FloEventId::new(1, 5), id);
+    });
+}
+
+#[test]
+fn stream_status_returns_the_head_of_each_partition_without_consuming() {
+    integration_test("stream status", default_test_options(), |server, mut reactor| {
+        let connection = server.connect_client::<String>("status_client".to_owned(), codec(), reactor.handle());
+        let connection = reactor.run(connection.connect()).expect("failed to connect client");
+        let current_stream = connection.current_stream().cloned().unwrap();
+
+        let (_, connection) = run_future(&mut reactor, connection.produce_to(1, "/foo", None, "first".to_owned()));
+        let (last_id, connection) = run_future(&mut reactor, connection.produce_to(1, "/foo", None, "second".to_owned()));
+
+        // the default stream of a server that isn't standalone is registered as the system stream
+        let (status, connection) = run_future(&mut reactor, connection.stream_status(system_stream_name()));
+        assert_eq!(current_stream.name, status.name);
+        assert_eq!(Some(&current_stream), connection.current_stream());
+        let partition = status.partitions.iter().find(|p| p.partition_num == 1).expect("missing partition 1");
+        assert_eq!(last_id.event_counter, partition.head);
+
+        let err = reactor.run(connection.stream_status("no-such-stream")).expect_err("expected an error");
+        match err.error_type {
+            ErrorType::Server(ref message) => assert_eq!(ClientErrorKind::NoSuchStream, message.kind),
+            ref other => panic!("expected NoSuchStream, got: {:?}", other),
+        }
+    });
+}
+
+#[test]
+fn produce_batch_returns_a_result_for_each_event_and_continues_past_errors() {
+    let options = EventStreamOptions {
+        validate_parent: true,
+        ..Default::default()
+    };
+    integration_test("produce batch", options, |server, mut reactor| {
+        let connection = server.connect_client::<String>("batch_producer".to_owned(), codec(), reactor.handle());
+        let connection = reactor.run(connection.connect()).expect("failed to connect producer");
+
+        // enough events that they can't all be in flight at once, with one that has a parent that doesn't exist
+        let event_count = MAX_PIPELINED_PRODUCES * 2 + 1;
+        let bad_index = MAX_PIPELINED_PRODUCES + 3;
+        let events = (0..event_count).map(|i| {
+            let parent = if i == bad_index { Some(FloEventId::new(1, 9999)) } else { None };
+            EventToProduce::new(1, "/batch", parent, format!("event {}", i))
+        }).collect::<Vec<_>>();
+
+        let (results, connection) = run_future(&mut reactor, connection.produce_batch(events));
+        assert_eq!(event_count, results.len());
+
+        let mut expected_counter = 1;
+        for (i, result) in results.iter().enumerate() {
+            if i == bad_index {
+                match *result {
+                    Err(ErrorType::Server(ref err)) => assert_eq!(ClientErrorKind::InvalidEventId, err.kind),
+                    ref other @ _ => panic!("expected InvalidEventId error, got: {:?}", other),
+                }
+            } else {
+                assert_eq!(FloEventId::new(1, expected_counter), *result.as_ref().expect("failed to produce event"));
+                expected_counter += 1;
+            }
+        }
+
+        let mut vv = VersionVector::new();
+        vv.set(FloEventId::new(1, 0));
+        let events = run_future(&mut reactor, connection.consume("/batch", &vv, None, false).collect());
+        assert_eq!(event_count - 1, events.len());
+        assert_eq!("event 0", events[0].data);
+        assert_eq!(format!("event {}", event_count - 1), events[event_count - 2].data);
+    });
+}
+
 #[test]
 fn oldest_events_are_dropped_from_beginning_of_stream_after_time_based_expiration() {
     let retention_duration = chrono::Duration::milliseconds(300);
@@ -138,6 +356,8 @@ fn oldest_events_are_dropped_from_beginning_of_stream_after_time_based_expiratio
             } else {
                 break;
             }
+            // a segment is never removed while it's being read, so give the partition a chance to see it with no consumers
+            thread::sleep(Duration::from_millis(50));
         }
         let future = connection.consume("/*", &vv, Some(1), false).into_future();
         let (maybe_event, _) = run_future(&mut reactor, future);
@@ -290,6 +510,118 @@ fn consumer_receives_event_as_it_is_produced() {
     });
 }
 
+fn consume_from_tail_after_producing(test_name: &'static str, existing_events: usize) {
+    integration_test(test_name, default_test_options(), move |server, mut reactor| {
+        let mut producer_client = server.connect_client::<String>("producer".to_owned(), codec(), reactor.handle());
+        producer_client = reactor.run(producer_client.connect()).expect("failed to connect producer");
+        for i in 0..existing_events {
+            let (_, client) = run_future(&mut reactor, producer_client.produce_to(1, "/foo", None, format!("old event {}", i)));
+            producer_client = client;
+        }
+
+        let join_handle = thread::spawn(move || {
+            let mut consumer_core = Core::new().unwrap();
+            let mut consumer_client = server.connect_client::<String>("consumer".to_owned(), codec(), consumer_core.handle());
+            consumer_client = consumer_core.run(consumer_client.connect()).expect("failed to connect consumer");
+
+            let stream = consumer_client.consume_from_tail("/foo", Some(1));
+            run_future(&mut consumer_core, stream.collect())
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        let (id, _) = run_future(&mut reactor, producer_client.produce_to(1, "/foo", None, "new event".to_owned()));
+
+        let consumed_events = join_handle.join().expect("failed to run consumer");
+        assert_eq!(1, consumed_events.len());
+        assert_eq!(id, consumed_events[0].id);
+        assert_eq!("new event", consumed_events[0].data);
+    });
+}
+
+#[test]
+fn consumer_from_tail_receives_only_events_produced_after_it_starts() {
+    consume_from_tail_after_producing("consume from tail", 3);
+}
+
+#[test]
+fn consumer_from_tail_of_an_empty_stream_receives_the_first_event() {
+    consume_from_tail_after_producing("consume from tail of empty stream", 0);
+}
+
+#[test]
+fn consumer_from_tail_of_a_stream_with_multiple_partitions_does_not_replay_existing_events() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("consume-from-tail-partitions", EventStreamOptions {
+        num_partitions: 2,
+        ..Default::default()
+    });
+    let mut stream = engine.get_default_stream();
+
+    // counters are shared by both partitions, so partition 1's highest counter ends up greater than its number of events
+    produce_directly(&mut stream, 1, 1, vec![produce_event(1, "/foo", "old 1")]);
+    produce_directly(&mut stream, 2, 2, vec![produce_event(2, "/foo", "old 2"), produce_event(2, "/foo", "old 3")]);
+    produce_directly(&mut stream, 1, 3, vec![produce_event(3, "/foo", "old 4")]);
+
+    let (handler, client_receiver) = connect(&engine, &reactor, 1);
+    let start = ProtocolMessage::NewStartConsuming(NewConsumerStart {
+        start_at_tail: true,
+        version_vector: Vec::new(),
+        ..consumer_start(4, "/foo")
+    });
+    let _handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+
+    let (first, client_receiver) = run_future(&mut reactor, client_receiver.into_future());
+    match first {
+        Some(ProtocolMessage::CursorCreated(CursorInfo {op_id, ..})) => assert_eq!(4, op_id),
+        other @ _ => panic!("expected CursorCreated, got: {:?}", other),
+    }
+    let (second, client_receiver) = run_future(&mut reactor, client_receiver.into_future());
+    assert_eq!(Some(ProtocolMessage::AwaitingEvents), second);
+
+    let id = produce_directly(&mut stream, 1, 5, vec![produce_event(5, "/foo", "new")]);
+    assert_eq!(FloEventId::new(1, 5), id);
+    let (third, _) = run_future(&mut reactor, client_receiver.into_future());
+    match third {
+        Some(ProtocolMessage::ReceiveEvent(ref event)) => assert_eq!(&b"new"[..], event.data()),
+        other @ _ => panic!("expected only the new event, got: {:?}", other),
+    }
+}
+
+#[test]
+fn a_message_that_only_the_server_sends_is_rejected_and_ends_the_connection() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("unexpected-message", Default::default());
+    let (mut handler, client_receiver) = connect(&engine, &reactor, 1);
+
+    let ack = ProtocolMessage::AckEvent(EventAck { op_id: 6, event_id: FloEventId::new(1, 1) });
+    assert!(handler.handle_incoming_message(ack).is_err());
+
+    let (response, _) = run_future(&mut reactor, client_receiver.into_future());
+    match response {
+        Some(ProtocolMessage::Error(ref err)) => {
+            assert_eq!(6, err.op_id);
+            assert_eq!(ErrorKind::UnexpectedMessage, err.kind);
+        }
+        other @ _ => panic!("expected UnexpectedMessage error, got: {:?}", other),
+    }
+}
+
+#[test]
+fn announce_with_a_consume_batch_size_of_zero_is_rejected() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("announce-zero-batch-size", Default::default());
+    let (handler, client_receiver) = connect(&engine, &reactor, 1);
+    let announce = ProtocolMessage::Announce(ClientAnnounce {
+        protocol_version: 1,
+        op_id: 3,
+        client_name: "zero-batch-size".to_owned(),
+        consume_batch_size: Some(0),
+    });
+    let _handler = reactor.run(handler.send(announce)).expect("failed to send announce");
+    let (response, _) = run_future(&mut reactor, client_receiver.into_future());
+    match response {
+        Some(ProtocolMessage::Error(ref err)) if err.op_id == 3 && err.kind == ErrorKind::InvalidConsumerState => {}
+        other @ _ => panic!("expected InvalidConsumerState error, got: {:?}", other),
+    }
+}
+
 #[test]
 fn produce_many_events_then_consume() {
     integration_test("produce many events", default_test_options(), |server, mut reactor| {
@@ -350,3 +682,1692 @@ fn startup_embedded_server_and_connect_async_client() {
 }
 
 
+
+#[test]
+fn list_streams_describes_every_stream_on_the_server() {
+    let _ = env_logger::init();
+    let tmp_dir = tempdir::TempDir::new("list-streams").expect("failed to create temp dir");
+    let mut reactor = Core::new().expect("failed to create reactor");
+    let status = AtomicBoolWriter::with_value(true);
+
+    let mut streams = HashMap::new();
+    for (name, partitions) in vec![(system_stream_name(), 1), ("other".to_owned(), 2)] {
+        let options = EventStreamOptions {
+            name: name.clone(),
+            num_partitions: partitions,
+            ..Default::default()
+        };
+        let stream = init_new_event_stream(tmp_dir.path().join(&name), options, status.reader(), reactor.remote()).expect("failed to init stream");
+        streams.insert(name, stream);
+    }
+
+    let produce = ProduceEvent {
+        op_id: 1,
+        partition: 2,
+        namespace: "/foo".to_owned(),
+        data: "some data".to_owned().into_bytes(),
+        ..Default::default()
+    };
+    produce_directly(streams.get_mut("other").unwrap(), 2, 1, vec![produce.clone(), produce]);
+
+    let other_stored_bytes = streams["other"].stored_bytes();
+    assert!(other_stored_bytes > 0);
+
+    let engine = EngineRef::new(streams);
+    let (handler, client_receiver) = connect(&engine, &reactor, 1);
+    let _handler = reactor.run(handler.send(ProtocolMessage::ListStreams(7))).expect("failed to send ListStreams");
+
+    let (response, _) = run_future(&mut reactor, client_receiver.into_future());
+    let expected = ProtocolMessage::StreamList(StreamList {
+        op_id: 7,
+        streams: vec![
+            StreamDescriptor {
+                name: "other".to_owned(),
+                event_count: 2,
+                head: FloEventId::new(2, 2),
+                tail: FloEventId::new(2, 1),
+                writable: true,
+                stored_bytes: other_stored_bytes,
+            },
+            StreamDescriptor {
+                name: system_stream_name(),
+                event_count: 0,
+                head: FloEventId::zero(),
+                tail: FloEventId::zero(),
+                writable: true,
+                stored_bytes: 0,
+            },
+        ],
+    });
+    assert_eq!(Some(expected), response);
+}
+
+#[test]
+fn client_channel_depth_rises_while_consumer_is_stalled() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("client-channel-depth", Default::default());
+    let mut stream = engine.get_default_stream();
+    let mut partition = stream.get_partition(1).unwrap().clone();
+
+    // nothing ever reads from the client receiver, just like a client that has stopped reading from its socket
+    let (client_sender, client_receiver) = create_client_channels();
+    let depth = client_receiver.depth_gauge().clone();
+    let handler = ConnectionHandler::new(1, client_sender, engine, reactor.handle());
+    let start = ProtocolMessage::NewStartConsuming(consumer_start(3, "/*"));
+    let _handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+    let depth_before_burst = depth.get();
+
+    let burst_size = 20;
+    let events = (0..burst_size).map(|_| {
+        produce_event(1, "/foo", "some data")
+    }).collect();
+    partition.produce(2, 1, events).expect("failed to send produce")
+            .wait().expect("failed to receive produce result")
+            .expect("failed to produce");
+
+    let start_time = Instant::now();
+    while depth.get() < depth_before_burst + burst_size && start_time.elapsed() < Duration::from_secs(2) {
+        reactor.turn(Some(Duration::from_millis(10)));
+    }
+    // an AwaitingEvents message may also be waiting in the channel, depending on when the consumer was first polled
+    assert!(depth.get() >= depth_before_burst + burst_size, "expected depth to rise by at least {} from {}, but was: {}", burst_size, depth_before_burst, depth.get());
+    drop(client_receiver);
+}
+
+#[test]
+fn consumer_receives_cursor_created_then_awaiting_events_when_no_events_match() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("consume-empty-namespace", Default::default());
+    let mut stream = engine.get_default_stream();
+
+    // the stream is not empty, but none of its events match the consumer's namespace
+    let produce = produce_event(1, "/bar", "some data");
+    produce_directly(&mut stream, 1, 1, vec![produce]);
+
+    let (handler, client_receiver) = connect(&engine, &reactor, 1);
+    let start = ProtocolMessage::NewStartConsuming(consumer_start(4, "/foo/*"));
+    let _handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+
+    let (first, client_receiver) = run_future(&mut reactor, client_receiver.into_future());
+    match first {
+        Some(ProtocolMessage::CursorCreated(CursorInfo {op_id, ..})) => assert_eq!(4, op_id),
+        other @ _ => panic!("expected CursorCreated, got: {:?}", other),
+    }
+    let (second, _) = run_future(&mut reactor, client_receiver.into_future());
+    assert_eq!(Some(ProtocolMessage::AwaitingEvents), second);
+}
+
+#[test]
+fn messages_from_concurrent_cursors_are_attributed_by_op_id_when_cursor_op_ids_is_set() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("cursor-op-ids", Default::default());
+    let mut stream = engine.get_default_stream();
+    let produce = produce_event(1, "/foo/bar", "some data");
+    produce_directly(&mut stream, 1, 1, vec![produce]);
+
+    let (mut handler, mut client_receiver) = connect(&engine, &reactor, 1);
+    for op_id in vec![4, 5] {
+        let start = ProtocolMessage::NewStartConsuming(NewConsumerStart {
+            cursor_op_ids: true,
+            ..consumer_start(op_id, "/foo/*")
+        });
+        handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+    }
+
+    // each cursor sends CursorCreated, then the event, then AwaitingEvents, but the two cursors may be interleaved
+    let mut messages_by_op_id = HashMap::new();
+    for _ in 0..6 {
+        let (message, receiver) = run_future(&mut reactor, client_receiver.into_future());
+        client_receiver = receiver;
+        let message = message.expect("client receiver ended early");
+        messages_by_op_id.entry(message.get_op_id()).or_insert_with(Vec::new).push(message);
+    }
+
+    for op_id in vec![4, 5] {
+        let messages = messages_by_op_id.remove(&op_id).expect("no messages for op_id");
+        assert_eq!(3, messages.len(), "op_id: {} got messages: {:?}", op_id, messages);
+        assert!(match messages[0] { ProtocolMessage::CursorCreated(_) => true, _ => false });
+        match messages[1] {
+            ProtocolMessage::CursorMessage(_, ref message) => {
+                match **message {
+                    ProtocolMessage::ReceiveEvent(ref event) => assert_eq!("/foo/bar", event.namespace()),
+                    ref other @ _ => panic!("expected ReceiveEvent, got: {:?}", other),
+                }
+            }
+            ref other @ _ => panic!("expected CursorMessage, got: {:?}", other),
+        }
+        assert_eq!(ProtocolMessage::CursorMessage(op_id, Box::new(ProtocolMessage::AwaitingEvents)), messages[2]);
+    }
+    assert!(messages_by_op_id.is_empty());
+}
+
+#[test]
+fn stopping_one_cursor_leaves_the_other_cursors_on_the_connection_running() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("stop-one-cursor", Default::default());
+    let mut stream = engine.get_default_stream();
+
+    let (mut handler, mut client_receiver) = connect(&engine, &reactor, 1);
+    for op_id in vec![4, 5] {
+        let start = ProtocolMessage::NewStartConsuming(NewConsumerStart {
+            cursor_op_ids: true,
+            ..consumer_start(op_id, "/foo/*")
+        });
+        handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+    }
+    // CursorCreated and AwaitingEvents for each cursor
+    for _ in 0..4 {
+        let (_, receiver) = run_future(&mut reactor, client_receiver.into_future());
+        client_receiver = receiver;
+    }
+
+    let _handler = reactor.run(handler.send(ProtocolMessage::StopConsuming(4))).expect("failed to stop consuming");
+    let (status, receiver) = run_future(&mut reactor, client_receiver.into_future());
+    client_receiver = receiver;
+    assert_eq!(Some(4), status.map(|message| message.get_op_id()));
+
+    produce_directly(&mut stream, 1, 1, vec![produce_event(1, "/foo/bar", "some data")]);
+    let (message, _) = run_future(&mut reactor, client_receiver.into_future());
+    match message {
+        Some(ProtocolMessage::CursorMessage(5, ref message)) => {
+            match **message {
+                ProtocolMessage::ReceiveEvent(ref event) => assert_eq!("/foo/bar", event.namespace()),
+                ref other @ _ => panic!("expected ReceiveEvent, got: {:?}", other),
+            }
+        }
+        other @ _ => panic!("expected an event for op_id: 5, got: {:?}", other),
+    }
+}
+
+#[test]
+fn each_next_batch_resumes_only_the_cursor_that_has_waited_longest() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("next-batch-per-cursor", Default::default());
+    let mut stream = engine.get_default_stream();
+    let events = (0..4).map(|i| produce_event(i + 1, "/foo/bar", "some data")).collect();
+    produce_directly(&mut stream, 1, 1, events);
+
+    let (handler, mut client_receiver) = connect(&engine, &reactor, 1);
+    let announce = ProtocolMessage::Announce(ClientAnnounce {
+        protocol_version: 1,
+        op_id: 3,
+        client_name: "next-batch-test".to_owned(),
+        consume_batch_size: Some(1),
+    });
+    let mut handler = reactor.run(handler.send(announce)).expect("failed to send announce");
+    let (_, receiver) = run_future(&mut reactor, client_receiver.into_future());
+    client_receiver = receiver;
+
+    let next_message = |reactor: &mut Core, receiver: ClientReceiver| {
+        let (message, receiver) = run_future(reactor, receiver.into_future());
+        (message.expect("client receiver ended early"), receiver)
+    };
+
+    let start = ProtocolMessage::NewStartConsuming(NewConsumerStart {
+        cursor_op_ids: true,
+        ..consumer_start(4, "/foo/*")
+    });
+    handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+    // CursorCreated, one event, then EndOfBatch, before the second cursor is started
+    for _ in 0..3 {
+        let (_, receiver) = next_message(&mut reactor, client_receiver);
+        client_receiver = receiver;
+    }
+    let start = ProtocolMessage::NewStartConsuming(NewConsumerStart {
+        cursor_op_ids: true,
+        ..consumer_start(5, "/foo/*")
+    });
+    handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+    for _ in 0..3 {
+        let (_, receiver) = next_message(&mut reactor, client_receiver);
+        client_receiver = receiver;
+    }
+
+    // both cursors are now waiting, and only the first one to finish its batch gets this one
+    let _handler = reactor.run(handler.send(ProtocolMessage::NextBatch)).expect("failed to send NextBatch");
+    let (event, receiver) = next_message(&mut reactor, client_receiver);
+    assert_eq!(4, event.get_op_id());
+    let (end_of_batch, _) = next_message(&mut reactor, receiver);
+    assert_eq!(ProtocolMessage::CursorMessage(4, Box::new(ProtocolMessage::EndOfBatch)), end_of_batch);
+}
+
+#[test]
+fn cold_consumers_replaying_at_once_are_limited_while_produces_stay_responsive() {
+    // small segments, so that replaying from the start reads through many segments before reaching the newest one
+    let (mut reactor, engine, _stream_dir) = engine_fixture("replay-limit", EventStreamOptions {
+        segment_max_size_bytes: 4096,
+        ..Default::default()
+    });
+    let mut stream = engine.get_default_stream();
+    let mut partition = stream.get_partition(1).unwrap().clone();
+    let produce = |op_id: u32| {
+        ProduceEvent {
+            op_id: op_id,
+            partition: 1,
+            namespace: "/foo/bar".to_owned(),
+            data: vec![7; 100],
+            ..Default::default()
+        }
+    };
+    let existing_events = 300;
+    partition.produce(1, 1, (0..existing_events).map(|i| produce(i + 1)).collect()).expect("failed to send produce")
+            .wait().expect("failed to receive produce result")
+            .expect("failed to produce");
+
+    let max_replays = 2;
+    let limiter = ReplayLimiter::new(max_replays);
+    let (handler, mut client_receiver) = connect(&engine, &reactor, 1);
+    let mut handler = handler.with_replay_limiter(limiter.clone());
+    let consumer_count = 10;
+    for op_id in 1..(consumer_count + 1) {
+        let start = ProtocolMessage::NewStartConsuming(NewConsumerStart {
+            op_id: op_id,
+            version_vector: vec![FloEventId::new(1, 0)],
+            namespace: "/foo/*".to_owned(),
+            // paces each replay so that it spans many polls, rather than finishing within one
+            max_delivery_rate: Some(1000),
+            cursor_op_ids: true,
+            ..Default::default()
+        });
+        handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+    }
+
+    let expected_events = existing_events as usize + 1;
+    let mut events_by_op_id: HashMap<u32, usize> = HashMap::new();
+    let mut max_active_replays = 0;
+    let mut live_produce_time = None;
+    let start_time = Instant::now();
+    while events_by_op_id.values().filter(|count| **count == expected_events).count() < consumer_count as usize {
+        assert!(start_time.elapsed() < Duration::from_secs(20), "consumers did not finish, received: {:?}", events_by_op_id);
+        reactor.turn(Some(Duration::from_millis(1)));
+        reactor.run(future::lazy(|| {
+            while let Async::Ready(Some(message)) = client_receiver.poll().expect("failed to poll client receiver") {
+                if let ProtocolMessage::CursorMessage(op_id, ref message) = message {
+                    if let ProtocolMessage::ReceiveEvent(_) = **message {
+                        *events_by_op_id.entry(op_id).or_insert(0) += 1;
+                    }
+                }
+            }
+            Ok::<(), ()>(())
+        })).unwrap();
+
+        let active = limiter.active_replays();
+        assert!(active <= max_replays, "expected at most {} active replays, but there were {}", max_replays, active);
+        max_active_replays = ::std::cmp::max(max_active_replays, active);
+
+        if live_produce_time.is_none() && active > 0 {
+            let produce_start = Instant::now();
+            partition.produce(2, 1, vec![produce(existing_events + 1)]).expect("failed to send produce")
+                    .wait().expect("failed to receive produce result")
+                    .expect("failed to produce");
+            live_produce_time = Some(produce_start.elapsed());
+        }
+    }
+
+    assert_eq!(max_replays, max_active_replays);
+    let live_produce_time = live_produce_time.expect("no produce was made while consumers were replaying");
+    assert!(live_produce_time < Duration::from_secs(1), "produce took {:?} while consumers were replaying", live_produce_time);
+
+    // every consumer has caught up, so they've all released their permits
+    while limiter.active_replays() > 0 && start_time.elapsed() < Duration::from_secs(25) {
+        reactor.turn(Some(Duration::from_millis(10)));
+    }
+    assert_eq!(0, limiter.active_replays());
+}
+
+#[test]
+fn other_connections_stay_responsive_while_a_partition_is_stuck_on_slow_storage() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("slow-storage", EventStreamOptions {
+        num_partitions: 2,
+        ..Default::default()
+    });
+    let stream = engine.get_default_stream();
+
+    // holds partition 1's thread until the test releases it, the same as a write to a very slow disk would
+    let (release, blocked) = ::std::sync::mpsc::channel::<()>();
+    let _scan = stream.partitions()[0].scan(1, move |_| {
+        let _ = blocked.recv();
+    }).expect("failed to send scan");
+
+    let (mut slow_handler, slow_receiver) = connect(&engine, &reactor, 1);
+    let slow_produce = ProduceEvent { partition: 1, ..produce_event(1, "/slow", "slow") };
+    let start = Instant::now();
+    slow_handler.handle_incoming_message(ProtocolMessage::ProduceEvent(slow_produce)).expect("failed to handle produce");
+    assert!(start.elapsed() < Duration::from_millis(100), "sending the produce blocked for {:?}", start.elapsed());
+
+    let (fast_handler, fast_receiver) = connect(&engine, &reactor, 2);
+    let fast_produce = ProduceEvent { partition: 2, ..produce_event(1, "/fast", "fast") };
+    let _fast_handler = run_future(&mut reactor, fast_handler.send(ProtocolMessage::ProduceEvent(fast_produce)));
+    let (message, _) = run_future(&mut reactor, fast_receiver.into_future());
+    assert_eq!(Some(ProtocolMessage::AckEvent(EventAck { op_id: 1, event_id: FloEventId::new(2, 1) })), message);
+
+    release.send(()).expect("failed to release partition");
+    let _slow_handler = run_future(&mut reactor, slow_handler.flush());
+    let (message, _) = run_future(&mut reactor, slow_receiver.into_future());
+    assert_eq!(Some(ProtocolMessage::AckEvent(EventAck { op_id: 1, event_id: FloEventId::new(1, 2) })), message);
+}
+
+#[test]
+fn bytes_consumed_are_counted_for_each_event_sent_to_a_consumer() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("bytes-consumed", Default::default());
+    let mut stream = engine.get_default_stream();
+
+    let bodies = vec!["hello", "flo consumer"];
+    let produces = bodies.iter().enumerate().map(|(i, body)| {
+        ProduceEvent {
+            op_id: i as u32 + 1,
+            partition: 1,
+            namespace: "/foo/bar".to_owned(),
+            data: body.to_string().into_bytes(),
+            ..Default::default()
+        }
+    }).collect();
+    produce_directly(&mut stream, 1, 1, produces);
+
+    let (handler, client_receiver) = connect(&engine, &reactor, 1);
+    let start = ProtocolMessage::NewStartConsuming(consumer_start(4, "/foo/*"));
+    let _handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+
+    let (first, mut client_receiver) = run_future(&mut reactor, client_receiver.into_future());
+    match first {
+        Some(ProtocolMessage::CursorCreated(CursorInfo {op_id, ..})) => assert_eq!(4, op_id),
+        other @ _ => panic!("expected CursorCreated, got: {:?}", other),
+    }
+    for _ in 0..bodies.len() {
+        let (message, rx) = run_future(&mut reactor, client_receiver.into_future());
+        match message {
+            Some(ProtocolMessage::ReceiveEvent(_)) => {}
+            other @ _ => panic!("expected ReceiveEvent, got: {:?}", other),
+        }
+        client_receiver = rx;
+    }
+    let (awaiting, _) = run_future(&mut reactor, client_receiver.into_future());
+    assert_eq!(Some(ProtocolMessage::AwaitingEvents), awaiting);
+
+    let expected_bytes = bodies.iter().map(|body| body.len()).sum::<usize>();
+    let metrics = engine.stream_metrics();
+    assert_eq!(1, metrics.len());
+    assert_eq!(expected_bytes, metrics[0].1.bytes_consumed.get());
+    assert_eq!(0, metrics[0].1.bytes_produced.get());
+}
+
+#[test]
+fn consumer_with_quorum_read_consistency_receives_events_from_a_single_server() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("consume-quorum", Default::default());
+    let mut stream = engine.get_default_stream();
+
+    let produce = produce_event(1, "/foo", "some data");
+    produce_directly(&mut stream, 1, 1, vec![produce]);
+
+    let (handler, client_receiver) = connect(&engine, &reactor, 1);
+    let start = ProtocolMessage::NewStartConsuming(NewConsumerStart {
+        read_consistency: ReadConsistency::Quorum,
+        ..consumer_start(4, "/foo")
+    });
+    let _handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+
+    let (first, client_receiver) = run_future(&mut reactor, client_receiver.into_future());
+    match first {
+        Some(ProtocolMessage::CursorCreated(CursorInfo {op_id, ..})) => assert_eq!(4, op_id),
+        other @ _ => panic!("expected CursorCreated, got: {:?}", other),
+    }
+    let (second, _) = run_future(&mut reactor, client_receiver.into_future());
+    match second {
+        Some(ProtocolMessage::ReceiveEvent(ref event)) => assert_eq!(FloEventId::new(1, 1), *event.id()),
+        other @ _ => panic!("expected the produced event, got: {:?}", other),
+    }
+}
+
+#[test]
+fn consumer_with_error_on_empty_receives_an_error_instead_of_awaiting_events_when_no_events_match() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("consume-error-on-empty", Default::default());
+    let mut stream = engine.get_default_stream();
+
+    // the stream is not empty, but none of its events match the consumer's namespace
+    let produce = produce_event(1, "/bar", "some data");
+    produce_directly(&mut stream, 1, 1, vec![produce]);
+
+    let (handler, client_receiver) = connect(&engine, &reactor, 1);
+    let start = ProtocolMessage::NewStartConsuming(NewConsumerStart {
+        error_on_empty: true,
+        ..consumer_start(4, "/foo/*")
+    });
+    let _handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+
+    let (first, client_receiver) = run_future(&mut reactor, client_receiver.into_future());
+    match first {
+        Some(ProtocolMessage::CursorCreated(CursorInfo {op_id, ..})) => assert_eq!(4, op_id),
+        other @ _ => panic!("expected CursorCreated, got: {:?}", other),
+    }
+    let (second, _) = run_future(&mut reactor, client_receiver.into_future());
+    match second {
+        Some(ProtocolMessage::Error(ref err)) if err.op_id == 4 && err.kind == ErrorKind::NoMatchingEvents => {}
+        other @ _ => panic!("expected NoMatchingEvents error, got: {:?}", other),
+    }
+}
+
+#[test]
+fn cursors_are_stopped_by_the_server_after_the_max_cursor_lifetime_unless_they_opt_out() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("max-cursor-lifetime", EventStreamOptions {
+        max_cursor_lifetime: Some(chrono::Duration::milliseconds(200)),
+        ..Default::default()
+    });
+    let stream = engine.get_default_stream();
+    let mut producer_stream = stream.clone();
+
+    let start = |op_id: u32, unlimited_lifetime: bool| {
+        ProtocolMessage::NewStartConsuming(NewConsumerStart {
+            unlimited_lifetime: unlimited_lifetime,
+            ..consumer_start(op_id, "/foo")
+        })
+    };
+
+    let (bulk_handler, bulk_receiver) = connect(&engine, &reactor, 1);
+    let _bulk_handler = reactor.run(bulk_handler.send(start(3, false))).expect("failed to start consuming");
+
+    let (tail_handler, tail_receiver) = connect(&engine, &reactor, 2);
+    let _tail_handler = reactor.run(tail_handler.send(start(4, true))).expect("failed to start consuming");
+
+    let (message, bulk_receiver) = run_future(&mut reactor, bulk_receiver.into_future());
+    match message {
+        Some(ProtocolMessage::CursorCreated(CursorInfo {op_id, ..})) => assert_eq!(3, op_id),
+        other @ _ => panic!("expected CursorCreated, got: {:?}", other),
+    }
+    let (message, bulk_receiver) = run_future(&mut reactor, bulk_receiver.into_future());
+    assert_eq!(Some(ProtocolMessage::AwaitingEvents), message);
+    let (message, _) = run_future(&mut reactor, bulk_receiver.into_future());
+    assert_eq!(Some(ProtocolMessage::StopConsuming(3)), message);
+
+    // the live tailing cursor has outlived the limit as well, and still receives newly produced events
+    reactor.run(Timeout::new(Duration::from_millis(100), &reactor.handle()).unwrap()).unwrap();
+    let produce = produce_event(1, "/foo", "live");
+    produce_directly(&mut producer_stream, 1, 1, vec![produce]);
+
+    let mut tail_receiver = tail_receiver;
+    let mut received = Vec::new();
+    while received.len() < 3 {
+        let (message, next) = run_future(&mut reactor, tail_receiver.into_future());
+        tail_receiver = next;
+        received.push(message.expect("tail receiver was closed"));
+    }
+    match received[0] {
+        ProtocolMessage::CursorCreated(CursorInfo {op_id, ..}) => assert_eq!(4, op_id),
+        ref other => panic!("expected CursorCreated, got: {:?}", other),
+    }
+    assert_eq!(ProtocolMessage::AwaitingEvents, received[1]);
+    match received[2] {
+        ProtocolMessage::ReceiveEvent(ref event) => assert_eq!(&b"live"[..], event.data()),
+        ref other => panic!("expected the new event, got: {:?}", other),
+    }
+}
+
+#[test]
+fn starting_more_cursors_than_the_connection_limit_is_rejected_while_existing_cursors_keep_working() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("too-many-cursors", Default::default());
+    let mut stream = engine.get_default_stream();
+    let mut partition = stream.get_partition(1).unwrap().clone();
+    let mut produce = |op_id: u32| {
+        let produce = produce_event(op_id, "/foo", "some data");
+        partition.produce(1, op_id, vec![produce]).expect("failed to send produce")
+                .wait().expect("failed to receive produce result")
+                .expect("failed to produce");
+    };
+    produce(1);
+
+    let (handler, mut client_receiver) = connect(&engine, &reactor, 1);
+    let mut handler = handler.with_max_cursors_per_connection(2);
+    for op_id in 4..7 {
+        let start = ProtocolMessage::NewStartConsuming(consumer_start(op_id, "/foo"));
+        handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+    }
+
+    let mut cursors_created = Vec::new();
+    let mut errors = Vec::new();
+    let mut received_events = 0;
+    while received_events < 2 || cursors_created.len() < 2 || errors.is_empty() {
+        let (message, receiver) = run_future(&mut reactor, client_receiver.into_future());
+        client_receiver = receiver;
+        match message {
+            Some(ProtocolMessage::CursorCreated(info)) => cursors_created.push(info.op_id),
+            Some(ProtocolMessage::Error(err)) => errors.push((err.op_id, err.kind)),
+            Some(ProtocolMessage::ReceiveEvent(_)) => received_events += 1,
+            Some(ProtocolMessage::AwaitingEvents) => {}
+            other @ _ => panic!("unexpected message: {:?}", other),
+        }
+    }
+    cursors_created.sort();
+    assert_eq!(vec![4, 5], cursors_created);
+    assert_eq!(vec![(6, ErrorKind::TooManyCursors)], errors);
+
+    // both of the original cursors still receive new events
+    produce(2);
+    let mut received_events = 0;
+    while received_events < 2 {
+        let (message, receiver) = run_future(&mut reactor, client_receiver.into_future());
+        client_receiver = receiver;
+        match message {
+            Some(ProtocolMessage::ReceiveEvent(_)) => received_events += 1,
+            Some(ProtocolMessage::AwaitingEvents) => {}
+            other @ _ => panic!("unexpected message: {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn starting_a_cursor_with_the_op_id_of_an_active_cursor_is_rejected() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("reused-op-id", Default::default());
+    let mut stream = engine.get_default_stream();
+    let mut partition = stream.get_partition(1).unwrap().clone();
+
+    // a long-running client whose op_ids are about to wrap around
+    let op_id = u32::max_value();
+    let (mut handler, mut client_receiver) = connect(&engine, &reactor, 1);
+    for _ in 0..2 {
+        let start = ProtocolMessage::NewStartConsuming(consumer_start(op_id, "/foo"));
+        handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+    }
+
+    let mut cursors_created = 0;
+    let mut errors = Vec::new();
+    while cursors_created < 1 || errors.is_empty() {
+        let (message, receiver) = run_future(&mut reactor, client_receiver.into_future());
+        client_receiver = receiver;
+        match message {
+            Some(ProtocolMessage::CursorCreated(_)) => cursors_created += 1,
+            Some(ProtocolMessage::Error(err)) => errors.push((err.op_id, err.kind)),
+            Some(ProtocolMessage::AwaitingEvents) => {}
+            other @ _ => panic!("unexpected message: {:?}", other),
+        }
+    }
+    assert_eq!(vec![(op_id, ErrorKind::InvalidConsumerState)], errors);
+
+    // the original cursor is unaffected
+    let produce = produce_event(1, "/foo", "some data");
+    partition.produce(1, 1, vec![produce]).expect("failed to send produce")
+            .wait().expect("failed to receive produce result")
+            .expect("failed to produce");
+    loop {
+        let (message, receiver) = run_future(&mut reactor, client_receiver.into_future());
+        client_receiver = receiver;
+        match message {
+            Some(ProtocolMessage::ReceiveEvent(_)) => break,
+            Some(ProtocolMessage::AwaitingEvents) => {}
+            other @ _ => panic!("unexpected message: {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn interleaved_produces_to_different_namespaces_on_one_connection_are_assigned_ids_in_submission_order() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("produce-ordering", Default::default());
+
+    let namespaces = ["/foo", "/bar/baz", "/qux"];
+    let produces = (1..31).map(|op_id| {
+        Ok::<_, ::std::io::Error>(ProtocolMessage::ProduceEvent(ProduceEvent {
+            op_id: op_id,
+            partition: 1,
+            namespace: namespaces[op_id as usize % namespaces.len()].to_owned(),
+            data: format!("event {}", op_id).into_bytes(),
+            ..Default::default()
+        }))
+    }).collect::<Vec<_>>();
+
+    let (handler, mut client_receiver) = connect(&engine, &reactor, 1);
+    reactor.run(handler.send_all(stream::iter_result(produces))).expect("failed to produce events");
+
+    let mut acks = Vec::new();
+    while acks.len() < 30 {
+        let (message, receiver) = run_future(&mut reactor, client_receiver.into_future());
+        client_receiver = receiver;
+        match message {
+            Some(ProtocolMessage::AckEvent(ack)) => acks.push((ack.op_id, ack.event_id)),
+            other @ _ => panic!("expected AckEvent, got: {:?}", other),
+        }
+    }
+
+    let op_ids = acks.iter().map(|&(op_id, _)| op_id).collect::<Vec<_>>();
+    assert_eq!((1..31).collect::<Vec<u32>>(), op_ids);
+    for (i, &(_, event_id)) in acks.iter().enumerate() {
+        assert_eq!(FloEventId::new(1, i as EventCounter + 1), event_id);
+    }
+}
+
+#[test]
+fn produce_is_rejected_when_validating_parents_and_the_parent_does_not_exist() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("validate-parent", EventStreamOptions {
+        num_partitions: 2,
+        validate_parent: true,
+        ..Default::default()
+    });
+
+    let produce = |op_id: u32, partition: ActorId, parent_id: Option<FloEventId>| {
+        Ok::<_, ::std::io::Error>(ProtocolMessage::ProduceEvent(ProduceEvent {
+            op_id: op_id,
+            partition: partition,
+            namespace: "/foo".to_owned(),
+            parent_id: parent_id,
+            data: "some data".to_owned().into_bytes(),
+            ..Default::default()
+        }))
+    };
+    let messages = vec![
+        produce(1, 1, None),
+        // the counter exists, but in a different partition
+        produce(2, 2, Some(FloEventId::new(2, 1))),
+        produce(3, 2, Some(FloEventId::new(1, 7))),
+        produce(4, 2, Some(FloEventId::new(9, 1))),
+        produce(5, 2, Some(FloEventId::new(1, 1))),
+    ];
+
+    let (handler, mut client_receiver) = connect(&engine, &reactor, 1);
+    reactor.run(handler.send_all(stream::iter_result(messages))).expect("failed to produce events");
+
+    let mut responses = Vec::new();
+    while responses.len() < 5 {
+        let (message, receiver) = run_future(&mut reactor, client_receiver.into_future());
+        client_receiver = receiver;
+        responses.push(message.expect("client channel closed"));
+    }
+
+    assert_eq!(ProtocolMessage::AckEvent(EventAck { op_id: 1, event_id: FloEventId::new(1, 1) }), responses[0]);
+    for (i, op_id) in (2..5).enumerate() {
+        match responses[i + 1] {
+            ProtocolMessage::Error(ref err) => {
+                assert_eq!(op_id, err.op_id);
+                assert_eq!(ErrorKind::InvalidEventId, err.kind);
+            }
+            ref other @ _ => panic!("expected error for op_id: {}, got: {:?}", op_id, other),
+        }
+    }
+    assert_eq!(ProtocolMessage::AckEvent(EventAck { op_id: 5, event_id: FloEventId::new(2, 2) }), responses[4]);
+}
+
+#[test]
+fn traced_produce_is_acked_and_then_followed_by_a_trace_of_its_stages() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("trace-produce", EventStreamOptions {
+        num_partitions: 1,
+        validate_parent: true,
+        ..Default::default()
+    });
+
+    let produce = |op_id: u32, parent_id: Option<FloEventId>, trace: bool| {
+        Ok::<_, ::std::io::Error>(ProtocolMessage::ProduceEvent(ProduceEvent {
+            parent_id: parent_id,
+            trace: trace,
+            ..produce_event(op_id, "/foo", "some data")
+        }))
+    };
+    // the second produce goes through the parent check before it's queued
+    let messages = vec![
+        produce(1, None, false),
+        produce(2, Some(FloEventId::new(1, 1)), true),
+    ];
+
+    let (handler, mut client_receiver) = connect(&engine, &reactor, 1);
+    reactor.run(handler.send_all(stream::iter_result(messages))).expect("failed to produce events");
+
+    let mut responses = Vec::new();
+    while responses.len() < 3 {
+        let (message, receiver) = run_future(&mut reactor, client_receiver.into_future());
+        client_receiver = receiver;
+        responses.push(message.expect("client channel closed"));
+    }
+
+    assert_eq!(ProtocolMessage::AckEvent(EventAck { op_id: 1, event_id: FloEventId::new(1, 1) }), responses[0]);
+    assert_eq!(ProtocolMessage::AckEvent(EventAck { op_id: 2, event_id: FloEventId::new(1, 2) }), responses[1]);
+    let trace = match responses[2] {
+        ProtocolMessage::Trace(ref trace) => trace.clone(),
+        ref other @ _ => panic!("expected Trace, got: {:?}", other),
+    };
+    assert_eq!(2, trace.op_id);
+
+    let stages = trace.stages.iter().map(|timing| timing.stage).collect::<Vec<_>>();
+    assert_eq!(vec![TraceStage::Received, TraceStage::Queued, TraceStage::Written, TraceStage::Acked], stages);
+    assert_eq!(0, trace.stages[0].elapsed_micros);
+    for pair in trace.stages.windows(2) {
+        assert!(pair[0].elapsed_micros <= pair[1].elapsed_micros, "stage timings are not monotonic: {:?}", trace.stages);
+    }
+}
+
+#[test]
+fn traced_consumer_start_is_followed_by_a_trace_of_its_stages_before_any_events() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("trace-consumer-start", EventStreamOptions {
+        num_partitions: 2,
+        ..Default::default()
+    });
+    let mut stream = engine.get_default_stream();
+    produce_directly(&mut stream, 1, 1, vec![produce_event(1, "/foo", "some data")]);
+
+    let (handler, client_receiver) = connect(&engine, &reactor, 1);
+    let start = ProtocolMessage::NewStartConsuming(NewConsumerStart {
+        trace: true,
+        ..consumer_start(4, "/foo")
+    });
+    let _handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+
+    let (first, client_receiver) = run_future(&mut reactor, client_receiver.into_future());
+    match first {
+        Some(ProtocolMessage::CursorCreated(CursorInfo {op_id, ..})) => assert_eq!(4, op_id),
+        other @ _ => panic!("expected CursorCreated, got: {:?}", other),
+    }
+    let (second, client_receiver) = run_future(&mut reactor, client_receiver.into_future());
+    let trace = match second {
+        Some(ProtocolMessage::Trace(trace)) => trace,
+        other @ _ => panic!("expected Trace, got: {:?}", other),
+    };
+    assert_eq!(4, trace.op_id);
+
+    let stages = trace.stages.iter().map(|timing| timing.stage).collect::<Vec<_>>();
+    assert_eq!(vec![TraceStage::Received, TraceStage::Queued, TraceStage::Opened, TraceStage::Acked], stages);
+    assert_eq!(0, trace.stages[0].elapsed_micros);
+    for pair in trace.stages.windows(2) {
+        assert!(pair[0].elapsed_micros <= pair[1].elapsed_micros, "stage timings are not monotonic: {:?}", trace.stages);
+    }
+
+    let (third, _) = run_future(&mut reactor, client_receiver.into_future());
+    match third {
+        Some(ProtocolMessage::ReceiveEvent(ref event)) => assert_eq!(&b"some data"[..], event.data()),
+        other @ _ => panic!("expected the event after the trace, got: {:?}", other),
+    }
+}
+
+#[test]
+fn pipelined_produces_are_held_back_and_rejected_once_they_would_exceed_the_in_flight_limit() {
+    use futures::AsyncSink;
+    use flo_server::engine::ReceivedProtocolMessage;
+
+    let (mut reactor, engine, _stream_dir) = engine_fixture("in-flight-limit", Default::default());
+    let stream = engine.get_default_stream();
+    let produce = |op_id: u32, size: usize| {
+        ProtocolMessage::ProduceEvent(ProduceEvent {
+            op_id: op_id,
+            partition: 1,
+            namespace: "/foo".to_owned(),
+            data: vec![7; size],
+            ..Default::default()
+        })
+    };
+
+    let (handler, client_receiver) = connect(&engine, &reactor, 1);
+    let mut handler = handler.with_max_in_flight_produce_bytes(1000);
+
+    // an event that's larger than the limit on its own is always rejected
+    handler.handle_incoming_message(produce(1, 1500)).expect("failed to handle produce");
+    let (message, client_receiver) = run_future(&mut reactor, client_receiver.into_future());
+    match message {
+        Some(ProtocolMessage::Error(ref err)) if err.op_id == 1 && err.kind == ErrorKind::ServerBusy => {}
+        other @ _ => panic!("expected ServerBusy error, got: {:?}", other),
+    }
+
+    // holds the partition's thread, so that the first produce stays in flight
+    let (release, blocked) = ::std::sync::mpsc::channel::<()>();
+    let _scan = stream.partitions()[0].scan(1, move |_| {
+        let _ = blocked.recv();
+    }).expect("failed to send scan");
+
+    fn start_send(reactor: &mut Core, handler: &mut ConnectionHandler, message: ReceivedProtocolMessage) -> AsyncSink<ReceivedProtocolMessage> {
+        reactor.run(future::lazy(|| handler.start_send(message))).expect("start_send failed")
+    }
+    assert_eq!(AsyncSink::Ready, start_send(&mut reactor, &mut handler, produce(2, 600)));
+    // 600 more bytes would put the connection over the limit, so that's rejected without waiting for the first
+    assert_eq!(AsyncSink::Ready, start_send(&mut reactor, &mut handler, produce(3, 600)));
+    let (message, client_receiver) = run_future(&mut reactor, client_receiver.into_future());
+    match message {
+        Some(ProtocolMessage::Error(ref err)) if err.op_id == 3 && err.kind == ErrorKind::ServerBusy => {}
+        other @ _ => panic!("expected ServerBusy error, got: {:?}", other),
+    }
+    // a smaller event fits, so it's only held back until the first is acknowledged
+    match start_send(&mut reactor, &mut handler, produce(4, 300)) {
+        AsyncSink::NotReady(_) => {}
+        other @ _ => panic!("expected the produce to be held back, got: {:?}", other),
+    }
+
+    release.send(()).expect("failed to release partition");
+    reactor.run(future::poll_fn(|| handler.poll_complete())).expect("failed to complete produce");
+    let (message, client_receiver) = run_future(&mut reactor, client_receiver.into_future());
+    assert_eq!(Some(ProtocolMessage::AckEvent(EventAck { op_id: 2, event_id: FloEventId::new(1, 1) })), message);
+    assert_eq!(AsyncSink::Ready, start_send(&mut reactor, &mut handler, produce(4, 300)));
+    reactor.run(future::poll_fn(|| handler.poll_complete())).expect("failed to complete produce");
+    let (message, _) = run_future(&mut reactor, client_receiver.into_future());
+    assert_eq!(Some(ProtocolMessage::AckEvent(EventAck { op_id: 4, event_id: FloEventId::new(1, 2) })), message);
+}
+
+#[test]
+fn events_produced_with_a_ttl_are_not_read_after_they_expire() {
+    let _ = env_logger::init();
+    let tmp_dir = tempdir::TempDir::new("event-ttl").expect("failed to create temp dir");
+    let reactor = Core::new().expect("failed to create reactor");
+    let status = AtomicBoolWriter::with_value(true);
+
+    let options = EventStreamOptions {
+        name: system_stream_name(),
+        ..Default::default()
+    };
+    let mut stream = init_new_event_stream(tmp_dir.path().join("system"), options, status.reader(), reactor.remote()).expect("failed to init stream");
+    let mut partition = stream.get_partition(1).unwrap().clone();
+
+    for (op_id, ttl) in vec![(1, None), (2, Some(Duration::from_secs(1))), (3, None)] {
+        let produce = ProduceEvent {
+            ttl: ttl,
+            ..produce_event(op_id, "/cache/invalidate", "some data")
+        };
+        partition.produce(1, op_id, vec![produce]).expect("failed to send produce")
+                .wait().expect("failed to receive produce result")
+                .expect("failed to produce");
+    }
+
+    let read_counters = || {
+        let reader = partition.read(1, EventFilter::All, 0).expect("failed to send read")
+                .wait().expect("failed to receive reader");
+        reader.map(|result| result.expect("failed to read event").id().event_counter).collect::<Vec<_>>()
+    };
+
+    assert_eq!(vec![1, 2, 3], read_counters());
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(vec![1, 3], read_counters());
+}
+
+#[test]
+fn consumer_receives_only_the_requested_prefix_of_each_event_body() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("consume-body-prefix", Default::default());
+    let mut stream = engine.get_default_stream();
+    for (op_id, data) in vec![(1, "envelope:body"), (2, "env")] {
+        let produce = ProduceEvent {
+            op_id: op_id,
+            partition: 1,
+            namespace: "/foo".to_owned(),
+            data: data.to_owned().into_bytes(),
+            ..Default::default()
+        };
+        produce_directly(&mut stream, 1, op_id, vec![produce]);
+    }
+
+    let (handler, mut client_receiver) = connect(&engine, &reactor, 1);
+    let start = ProtocolMessage::NewStartConsuming(NewConsumerStart {
+        max_events: 2,
+        body_prefix_bytes: Some(8),
+        ..consumer_start(4, "/foo")
+    });
+    let _handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+
+    let mut received = Vec::new();
+    while received.len() < 2 {
+        let (message, receiver) = run_future(&mut reactor, client_receiver.into_future());
+        client_receiver = receiver;
+        match message {
+            Some(ProtocolMessage::CursorCreated(_)) => {}
+            Some(ProtocolMessage::ReceiveEventPrefix(event, total_len)) => {
+                received.push((String::from_utf8(event.data().to_vec()).unwrap(), event.data_len(), total_len));
+            }
+            other @ _ => panic!("unexpected message: {:?}", other),
+        }
+    }
+    let expected = vec![
+        ("envelope".to_owned(), 8, 13),
+        ("env".to_owned(), 3, 3),
+    ];
+    assert_eq!(expected, received);
+}
+
+#[test]
+fn consumer_with_a_max_delivery_rate_receives_a_backlog_no_faster_than_the_rate() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("consume-max-delivery-rate", Default::default());
+    let mut stream = engine.get_default_stream();
+    let backlog = (1..61).map(|op_id| {
+        produce_event(op_id, "/foo", "some data")
+    }).collect();
+    produce_directly(&mut stream, 1, 1, backlog);
+
+    let (handler, mut client_receiver) = connect(&engine, &reactor, 1);
+    let start = ProtocolMessage::NewStartConsuming(NewConsumerStart {
+        max_events: 60,
+        max_delivery_rate: Some(100),
+        ..consumer_start(4, "/foo")
+    });
+    let start_time = Instant::now();
+    let _handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+
+    let mut received = 0;
+    while received < 60 {
+        let (message, receiver) = run_future(&mut reactor, client_receiver.into_future());
+        client_receiver = receiver;
+        match message {
+            Some(ProtocolMessage::CursorCreated(_)) => {}
+            Some(ProtocolMessage::ReceiveEvent(_)) => received += 1,
+            other @ _ => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    // a tenth of a second's worth of events can be sent right away, and the other 50 are paced at 100 per second
+    let elapsed = start_time.elapsed();
+    assert!(elapsed >= Duration::from_millis(450), "received 60 events in: {:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(1500), "received 60 events in: {:?}", elapsed);
+}
+
+#[test]
+fn consumer_with_a_namespace_regex_receives_only_matching_events_and_pathological_regexes_are_rejected() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("consume-namespace-regex", Default::default());
+    let mut stream = engine.get_default_stream();
+    let namespaces = vec!["/orders/1/shipped", "/orders/abc/shipped", "/orders/2/shipped/late", "/orders/3/shipped"];
+    let events = namespaces.iter().enumerate().map(|(i, namespace)| {
+        ProduceEvent {
+            op_id: i as u32 + 1,
+            partition: 1,
+            namespace: namespace.to_string(),
+            data: "some data".to_owned().into_bytes(),
+            ..Default::default()
+        }
+    }).collect();
+    produce_directly(&mut stream, 1, 1, events);
+
+    let (handler, mut client_receiver) = connect(&engine, &reactor, 1);
+    let start = ProtocolMessage::NewStartConsuming(NewConsumerStart {
+        max_events: 2,
+        namespace_regex: Some(r"/orders/\d+/shipped".to_owned()),
+        ..consumer_start(4, "/**/*")
+    });
+    let handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+
+    let mut received = Vec::new();
+    while received.len() < 2 {
+        let (message, receiver) = run_future(&mut reactor, client_receiver.into_future());
+        client_receiver = receiver;
+        match message {
+            Some(ProtocolMessage::CursorCreated(_)) => {}
+            Some(ProtocolMessage::ReceiveEvent(event)) => received.push(event.namespace().to_owned()),
+            other @ _ => panic!("unexpected message: {:?}", other),
+        }
+    }
+    assert_eq!(vec!["/orders/1/shipped".to_owned(), "/orders/3/shipped".to_owned()], received);
+
+    let start = ProtocolMessage::NewStartConsuming(NewConsumerStart {
+        namespace_regex: Some("((a{100}){100}){100}".to_owned()),
+        ..consumer_start(5, "/**/*")
+    });
+    let _handler = reactor.run(handler.send(start)).expect("failed to send consumer start");
+
+    loop {
+        let (message, receiver) = run_future(&mut reactor, client_receiver.into_future());
+        client_receiver = receiver;
+        match message {
+            Some(ProtocolMessage::Error(err)) => {
+                assert_eq!(5, err.op_id);
+                assert_eq!(ErrorKind::InvalidNamespaceRegex, err.kind);
+                break;
+            }
+            Some(_) => {}
+            None => panic!("client channel closed before the consumer start was rejected"),
+        }
+    }
+}
+
+#[test]
+fn swapping_a_stream_leaves_active_consumers_on_the_old_stream_and_new_consumers_use_the_new_one() {
+    fn produce(stream: &mut EventStreamRef, data: &[&str]) {
+        let events = data.iter().map(|data| {
+            ProduceEvent {
+                op_id: 1,
+                partition: 1,
+                namespace: "/foo".to_owned(),
+                data: data.to_string().into_bytes(),
+                ..Default::default()
+            }
+        }).collect();
+        produce_directly(stream, 1, 1, events);
+    }
+
+    fn receive_events(count: usize, client_receiver: &mut ClientReceiver, reactor: &mut Core) -> Vec<String> {
+        let mut received = Vec::new();
+        while received.len() < count {
+            match run_future(reactor, client_receiver.by_ref().into_future()).0 {
+                Some(ProtocolMessage::ReceiveEvent(event)) => received.push(String::from_utf8(event.data().to_vec()).unwrap()),
+                Some(_) => {}
+                None => panic!("client channel closed"),
+            }
+        }
+        received
+    }
+
+    fn start(op_id: u32) -> ProtocolMessage<flo_event::OwnedFloEvent> {
+        ProtocolMessage::NewStartConsuming(consumer_start(op_id, "/foo"))
+    }
+
+    let _ = env_logger::init();
+    let tmp_dir = tempdir::TempDir::new("swap-stream").expect("failed to create temp dir");
+    let mut reactor = Core::new().expect("failed to create reactor");
+    let status = AtomicBoolWriter::with_value(true);
+
+    let options = EventStreamOptions {
+        name: system_stream_name(),
+        ..Default::default()
+    };
+    let mut old_stream = init_new_event_stream(tmp_dir.path().join("system"), options.clone(), status.reader(), reactor.remote()).expect("failed to init stream");
+    produce(&mut old_stream, &["old 1", "old 2"]);
+
+    let mut streams = HashMap::new();
+    streams.insert(system_stream_name(), old_stream);
+    let engine = EngineRef::new(streams);
+
+    let (handler, mut client_receiver) = connect(&engine, &reactor, 1);
+    let handler = reactor.run(handler.send(start(4))).expect("failed to start consuming");
+
+    let mut received = receive_events(2, &mut client_receiver, &mut reactor);
+
+    // the replacement is built in the background, while the consumer is waiting on the old stream
+    let mut new_stream = init_new_event_stream(tmp_dir.path().join("system-v2"), options, status.reader(), reactor.remote()).expect("failed to init stream");
+    produce(&mut new_stream, &["new 1", "new 2", "new 3"]);
+    let mut old_stream = engine.swap_stream(&system_stream_name(), new_stream).expect("failed to swap stream");
+
+    // the active consumer keeps its place in the old stream
+    produce(&mut old_stream, &["old 3"]);
+    received.extend(receive_events(1, &mut client_receiver, &mut reactor));
+    assert_eq!(vec!["old 1".to_owned(), "old 2".to_owned(), "old 3".to_owned()], received);
+
+    let handler = reactor.run(handler.send(ProtocolMessage::StopConsuming(5))).expect("failed to stop consuming");
+    let _handler = reactor.run(handler.send(start(6))).expect("failed to start consuming");
+
+    let received = receive_events(3, &mut client_receiver, &mut reactor);
+    assert_eq!(vec!["new 1".to_owned(), "new 2".to_owned(), "new 3".to_owned()], received);
+}
+
+#[test]
+fn count_events_counts_matching_events_without_sending_them() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("count-events", EventStreamOptions {
+        num_partitions: 2,
+        ..Default::default()
+    });
+    let mut stream = engine.get_default_stream();
+    // counters are assigned in order across both partitions: 1.1, 2.2, 1.3, 2.4, 1.5, 2.6
+    let namespaces = vec!["/orders/1", "/orders/2", "/customers/1", "/orders/3", "/orders/4/shipped", "/orders/5"];
+    for (i, namespace) in namespaces.iter().enumerate() {
+        let partition = (i % 2) as u16 + 1;
+        let produce = ProduceEvent {
+            op_id: 1,
+            partition: partition,
+            namespace: namespace.to_string(),
+            data: "some data".to_owned().into_bytes(),
+            ..Default::default()
+        };
+        produce_directly(&mut stream, partition, 1, vec![produce]);
+    }
+
+    let (mut handler, mut client_receiver) = connect(&engine, &reactor, 1);
+
+    let requests = vec![
+        (4, "/orders/*", None),
+        (5, "/orders/*", Some(FloEventId::new(1, 3))),
+        (6, "/**/*", None),
+        (7, "/nothing/*", None),
+    ];
+    for (op_id, namespace, since) in requests {
+        handler = reactor.run(handler.send(ProtocolMessage::CountEvents(CountEvents {
+            op_id: op_id,
+            namespace: namespace.to_owned(),
+            since: since,
+        }))).expect("failed to send count");
+    }
+
+    let mut results = Vec::new();
+    for _ in 0..4 {
+        let (message, receiver) = run_future(&mut reactor, client_receiver.into_future());
+        client_receiver = receiver;
+        match message {
+            Some(ProtocolMessage::CountResult(result)) => results.push(result),
+            other @ _ => panic!("expected CountResult, got: {:?}", other),
+        }
+    }
+    results.sort_by_key(|result| result.op_id);
+    let expected = vec![
+        CountResult { op_id: 4, count: 4 },
+        CountResult { op_id: 5, count: 2 },
+        CountResult { op_id: 6, count: 6 },
+        CountResult { op_id: 7, count: 0 },
+    ];
+    assert_eq!(expected, results);
+
+    let _handler = reactor.run(handler.send(ProtocolMessage::CountEvents(CountEvents {
+        op_id: 8,
+        namespace: "/foo[unclosed".to_owned(),
+        since: None,
+    }))).expect("failed to send count");
+    let (message, _) = run_future(&mut reactor, client_receiver.into_future());
+    match message {
+        Some(ProtocolMessage::Error(ref err)) if err.op_id == 8 && err.kind == ErrorKind::InvalidNamespaceGlob => {}
+        other @ _ => panic!("expected InvalidNamespaceGlob error, got: {:?}", other),
+    }
+}
+
+#[test]
+fn consumer_lag_sums_events_after_the_version_vector_across_partitions() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("consumer-lag", EventStreamOptions {
+        num_partitions: 2,
+        ..Default::default()
+    });
+    let mut stream = engine.get_default_stream();
+    // counters are assigned in order across both partitions: 1.1, 2.2, 1.3, 2.4, 1.5, 2.6
+    let namespaces = vec!["/orders/1", "/orders/2", "/customers/1", "/orders/3", "/orders/4/shipped", "/orders/5"];
+    for (i, namespace) in namespaces.iter().enumerate() {
+        let partition = (i % 2) as u16 + 1;
+        let produce = ProduceEvent {
+            op_id: 1,
+            partition: partition,
+            namespace: namespace.to_string(),
+            data: "some data".to_owned().into_bytes(),
+            ..Default::default()
+        };
+        produce_directly(&mut stream, partition, 1, vec![produce]);
+    }
+
+    let (mut handler, mut client_receiver) = connect(&engine, &reactor, 1);
+
+    let requests = vec![
+        (4, "/orders/*", Vec::new()),
+        (5, "/orders/*", vec![FloEventId::new(1, 3), FloEventId::new(2, 2)]),
+        (6, "/**/*", vec![FloEventId::new(2, 4)]),
+        (7, "/**/*", vec![FloEventId::new(1, 5), FloEventId::new(2, 6)]),
+    ];
+    for (op_id, namespace, version_vector) in requests {
+        handler = reactor.run(handler.send(ProtocolMessage::GetConsumerLag(GetConsumerLag {
+            op_id: op_id,
+            namespace: namespace.to_owned(),
+            version_vector: version_vector,
+        }))).expect("failed to send get consumer lag");
+    }
+
+    let mut results = Vec::new();
+    for _ in 0..4 {
+        let (message, receiver) = run_future(&mut reactor, client_receiver.into_future());
+        client_receiver = receiver;
+        match message {
+            Some(ProtocolMessage::CountResult(result)) => results.push(result),
+            other @ _ => panic!("expected CountResult, got: {:?}", other),
+        }
+    }
+    results.sort_by_key(|result| result.op_id);
+    let expected = vec![
+        CountResult { op_id: 4, count: 4 },
+        CountResult { op_id: 5, count: 2 },
+        CountResult { op_id: 6, count: 4 },
+        CountResult { op_id: 7, count: 0 },
+    ];
+    assert_eq!(expected, results);
+
+    let _handler = reactor.run(handler.send(ProtocolMessage::GetConsumerLag(GetConsumerLag {
+        op_id: 8,
+        namespace: "/foo[unclosed".to_owned(),
+        version_vector: Vec::new(),
+    }))).expect("failed to send get consumer lag");
+    let (message, _) = run_future(&mut reactor, client_receiver.into_future());
+    match message {
+        Some(ProtocolMessage::Error(ref err)) if err.op_id == 8 && err.kind == ErrorKind::InvalidNamespaceGlob => {}
+        other @ _ => panic!("expected InvalidNamespaceGlob error, got: {:?}", other),
+    }
+}
+
+#[test]
+fn ack_subscription_yields_the_id_of_each_event_as_it_is_persisted() {
+    let _ = env_logger::init();
+    let tmp_dir = tempdir::TempDir::new("ack-subscription").expect("failed to create temp dir");
+    let reactor = Core::new().expect("failed to create reactor");
+    let status = AtomicBoolWriter::with_value(true);
+
+    let options = EventStreamOptions {
+        name: system_stream_name(),
+        num_partitions: 2,
+        ..Default::default()
+    };
+    let stream = init_new_event_stream(tmp_dir.path().join("system"), options, status.reader(), reactor.remote()).expect("failed to init stream");
+    let mut streams = HashMap::new();
+    streams.insert(system_stream_name(), stream);
+    let engine = EngineRef::new(streams);
+
+    let acks = engine.subscribe_acks(&system_stream_name());
+    let missing = engine.subscribe_acks("nope");
+
+    let mut expected = Vec::new();
+    for (op_id, partition_num) in vec![(1, 1), (2, 2), (3, 1), (4, 1), (5, 2)] {
+        let produce = ProduceEvent {
+            op_id: op_id,
+            partition: partition_num,
+            namespace: "/foo".to_owned(),
+            data: "some data".to_owned().into_bytes(),
+            ..Default::default()
+        };
+        let id = produce_directly(&mut engine.get_default_stream(), partition_num, op_id, vec![produce]);
+        expected.push(id);
+    }
+
+    let received = acks.take(5).collect().wait().expect("failed to receive acks");
+    assert_eq!(expected, received);
+    assert!(missing.collect().wait().expect("failed to read missing stream").is_empty());
+}
+
+#[test]
+fn consumer_can_start_from_a_tagged_event() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("consume-from-tag", EventStreamOptions {
+        num_partitions: 2,
+        ..Default::default()
+    });
+    let mut stream = engine.get_default_stream();
+    let mut ids = Vec::new();
+    for (op_id, partition_num) in vec![(1, 1), (2, 2), (3, 1), (4, 2), (5, 1)] {
+        let produce = ProduceEvent {
+            op_id: op_id,
+            partition: partition_num,
+            namespace: "/foo".to_owned(),
+            data: "some data".to_owned().into_bytes(),
+            ..Default::default()
+        };
+        let id = produce_directly(&mut stream, partition_num, op_id, vec![produce]);
+        ids.push(id);
+    }
+
+    engine.tag_stream(&system_stream_name(), "v1.2".to_owned(), ids[2]).expect("failed to tag stream");
+
+    let (handler, mut client_receiver) = connect(&engine, &reactor, 1);
+    let expected_tag = StreamTag {
+        name: "v1.2".to_owned(),
+        event_id: ids[2],
+    };
+
+    let handler = reactor.run(handler.send(ProtocolMessage::ListTags(2))).expect("failed to send ListTags");
+    let (response, receiver) = run_future(&mut reactor, client_receiver.into_future());
+    client_receiver = receiver;
+    assert_eq!(Some(ProtocolMessage::TagList(TagList { op_id: 2, tags: vec![expected_tag.clone()] })), response);
+
+    let handler = reactor.run(handler.send(ProtocolMessage::GetTag(GetTag { op_id: 3, name: "v1.2".to_owned() }))).expect("failed to send GetTag");
+    let (response, receiver) = run_future(&mut reactor, client_receiver.into_future());
+    client_receiver = receiver;
+    assert_eq!(Some(ProtocolMessage::TagList(TagList { op_id: 3, tags: vec![expected_tag] })), response);
+
+    let handler = reactor.run(handler.send(ProtocolMessage::GetTag(GetTag { op_id: 4, name: "nope".to_owned() }))).expect("failed to send GetTag");
+    let (response, receiver) = run_future(&mut reactor, client_receiver.into_future());
+    client_receiver = receiver;
+    match response {
+        Some(ProtocolMessage::Error(ref err)) if err.op_id == 4 && err.kind == ErrorKind::NoSuchTag => {}
+        other @ _ => panic!("expected NoSuchTag error, got: {:?}", other),
+    }
+
+    let start = ProtocolMessage::NewStartConsuming(NewConsumerStart {
+        op_id: 5,
+        version_vector: Vec::new(),
+        max_events: 2,
+        namespace: "/foo".to_owned(),
+        start_tag: Some("v1.2".to_owned()),
+        ..Default::default()
+    });
+    let _handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+
+    let mut received = Vec::new();
+    while received.len() < 2 {
+        let (message, receiver) = run_future(&mut reactor, client_receiver.into_future());
+        client_receiver = receiver;
+        match message {
+            Some(ProtocolMessage::CursorCreated(_)) => {}
+            Some(ProtocolMessage::ReceiveEvent(event)) => received.push(*event.id()),
+            other @ _ => panic!("unexpected message: {:?}", other),
+        }
+    }
+    received.sort();
+    assert_eq!(&ids[3..], &received[..]);
+}
+
+#[test]
+fn snapshot_consumer_stops_after_the_events_that_existed_when_it_started() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("snapshot-consumer", EventStreamOptions {
+        num_partitions: 2,
+        ..Default::default()
+    });
+    let stream = engine.get_default_stream();
+    let mut producer_stream = stream.clone();
+    let mut produce = |op_id: u32, partition_num: u16| {
+        let produce = ProduceEvent {
+            op_id: op_id,
+            partition: partition_num,
+            namespace: "/foo".to_owned(),
+            data: "some data".to_owned().into_bytes(),
+            ..Default::default()
+        };
+        produce_directly(&mut producer_stream, partition_num, op_id, vec![produce])
+    };
+    let mut existing_ids = Vec::new();
+    for (op_id, partition_num) in vec![(1, 1), (2, 2), (3, 1), (4, 2)] {
+        existing_ids.push(produce(op_id, partition_num));
+    }
+
+    let (handler, mut client_receiver) = connect(&engine, &reactor, 1);
+    // a small batch size pauses the consumer partway through, so that more events can be produced while it's reading
+    let announce = ProtocolMessage::Announce(ClientAnnounce {
+        protocol_version: 1,
+        op_id: 4,
+        client_name: "snapshot-test".to_owned(),
+        consume_batch_size: Some(2),
+    });
+    let handler = reactor.run(handler.send(announce)).expect("failed to send announce");
+    let (status, receiver) = run_future(&mut reactor, client_receiver.into_future());
+    client_receiver = receiver;
+    match status {
+        Some(ProtocolMessage::StreamStatus(_)) => {}
+        other @ _ => panic!("expected StreamStatus, got: {:?}", other),
+    }
+    let start = ProtocolMessage::NewStartConsuming(NewConsumerStart {
+        op_id: 5,
+        version_vector: vec![FloEventId::new(1, 0), FloEventId::new(2, 0)],
+        namespace: "/foo".to_owned(),
+        snapshot: true,
+        ..Default::default()
+    });
+    let handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+
+    let mut received = Vec::new();
+    loop {
+        let (message, receiver) = run_future(&mut reactor, client_receiver.into_future());
+        client_receiver = receiver;
+        match message {
+            Some(ProtocolMessage::CursorCreated(CursorInfo {op_id, ..})) => assert_eq!(5, op_id),
+            Some(ProtocolMessage::ReceiveEvent(event)) => received.push(*event.id()),
+            Some(ProtocolMessage::EndOfBatch) => break,
+            other @ _ => panic!("unexpected message: {:?}", other),
+        }
+    }
+    assert_eq!(2, received.len());
+
+    produce(6, 1);
+    produce(7, 2);
+    let mut handler = reactor.run(handler.send(ProtocolMessage::NextBatch)).expect("failed to send NextBatch");
+
+    loop {
+        let (message, receiver) = run_future(&mut reactor, client_receiver.into_future());
+        client_receiver = receiver;
+        match message {
+            Some(ProtocolMessage::ReceiveEvent(event)) => received.push(*event.id()),
+            Some(ProtocolMessage::EndOfBatch) => {
+                handler = reactor.run(handler.send(ProtocolMessage::NextBatch)).expect("failed to send NextBatch");
+            }
+            Some(ProtocolMessage::StopConsuming(op_id)) => {
+                assert_eq!(5, op_id);
+                break;
+            }
+            other @ _ => panic!("unexpected message: {:?}", other),
+        }
+    }
+    received.sort();
+    assert_eq!(existing_ids, received);
+}
+
+#[test]
+fn consumer_stops_reading_while_its_client_is_not_receiving_messages() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("stalled-consumer", Default::default());
+    let mut stream = engine.get_default_stream();
+    let event_count = 200;
+    for op_id in 0..event_count {
+        let produce = produce_event(op_id, "/foo", "some data");
+        produce_directly(&mut stream, 1, op_id, vec![produce]);
+    }
+
+    let capacity = 10;
+    let (client_sender, client_receiver) = create_client_channels_with_capacity(capacity);
+    let depth = client_receiver.depth_gauge().clone();
+    let handler = ConnectionHandler::new(1, client_sender, engine, reactor.handle());
+    let start = ProtocolMessage::NewStartConsuming(consumer_start(3, "/foo"));
+    let _handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+
+    // give the consumer plenty of time to read, while nothing is taken from the receiver
+    reactor.run(Timeout::new(Duration::from_millis(100), &reactor.handle()).unwrap()).unwrap();
+    // the CursorCreated message is sent regardless of the capacity
+    assert!(depth.get() <= capacity + 1, "expected at most {} waiting messages, but there were {}", capacity + 1, depth.get());
+
+    // once the client starts receiving again, every event is still delivered
+    let mut client_receiver = client_receiver;
+    let mut received = Vec::new();
+    while received.len() < event_count as usize {
+        let (message, next) = run_future(&mut reactor, client_receiver.into_future());
+        client_receiver = next;
+        match message {
+            Some(ProtocolMessage::CursorCreated(_)) => {}
+            Some(ProtocolMessage::ReceiveEvent(event)) => received.push(event.id().event_counter),
+            other @ _ => panic!("unexpected message: {:?}", other),
+        }
+    }
+    assert_eq!((1..(event_count as u64 + 1)).collect::<Vec<_>>(), received);
+}
+
+#[test]
+fn caught_up_consumer_is_sent_stream_idle_each_time_its_interval_passes_without_events() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("idle-signal", Default::default());
+    let mut stream = engine.get_default_stream();
+    for op_id in 0..3 {
+        let produce = produce_event(op_id, "/foo", "some data");
+        produce_directly(&mut stream, 1, op_id, vec![produce]);
+    }
+
+    let (handler, client_receiver) = connect(&engine, &reactor, 1);
+    let start = ProtocolMessage::NewStartConsuming(NewConsumerStart {
+        unlimited_lifetime: true,
+        idle_signal_interval: Some(50),
+        ..consumer_start(3, "/foo")
+    });
+    let _handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+
+    let mut client_receiver = client_receiver;
+    let mut received = Vec::new();
+    loop {
+        let (message, next) = run_future(&mut reactor, client_receiver.into_future());
+        client_receiver = next;
+        match message {
+            Some(ProtocolMessage::CursorCreated(_)) => {}
+            Some(ProtocolMessage::ReceiveEvent(event)) => received.push(event.id().event_counter),
+            Some(ProtocolMessage::AwaitingEvents) => break,
+            other @ _ => panic!("unexpected message: {:?}", other),
+        }
+    }
+    assert_eq!(vec![1, 2, 3], received);
+
+    let caught_up = Instant::now();
+    for _ in 0..2 {
+        let (message, next) = run_future(&mut reactor, client_receiver.into_future());
+        client_receiver = next;
+        match message {
+            Some(ProtocolMessage::StreamIdle(idle)) => {
+                assert_eq!(3, idle.op_id);
+                assert_eq!(FloEventId::new(1, 3), idle.tail_id);
+            }
+            other @ _ => panic!("expected StreamIdle but got: {:?}", other),
+        }
+    }
+    assert!(caught_up.elapsed() >= Duration::from_millis(100), "StreamIdle was sent before the interval passed");
+}
+
+#[test]
+fn consumer_start_with_an_idle_signal_interval_of_zero_is_rejected() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("idle-signal-zero", Default::default());
+    let (handler, client_receiver) = connect(&engine, &reactor, 1);
+    let start = ProtocolMessage::NewStartConsuming(NewConsumerStart {
+        idle_signal_interval: Some(0),
+        ..consumer_start(3, "/foo")
+    });
+    let _handler = reactor.run(handler.send(start)).expect("failed to start consuming");
+    let (response, _) = run_future(&mut reactor, client_receiver.into_future());
+    match response {
+        Some(ProtocolMessage::Error(ref err)) if err.op_id == 3 && err.kind == ErrorKind::InvalidConsumerState => {}
+        other @ _ => panic!("expected InvalidConsumerState error, got: {:?}", other),
+    }
+}
+
+#[test]
+fn consumer_that_does_not_see_own_writes_skips_events_produced_on_its_connection() {
+    let (mut reactor, engine, _stream_dir) = engine_fixture("see-own-writes", Default::default());
+
+    let produce = |op_id: u32, data: &str| {
+        ProtocolMessage::ProduceEvent(ProduceEvent {
+            op_id: op_id,
+            partition: 1,
+            namespace: "/foo".to_owned(),
+            data: data.to_owned().into_bytes(),
+            ..Default::default()
+        })
+    };
+    let start = |op_id: u32, see_own_writes: bool| {
+        ProtocolMessage::NewStartConsuming(NewConsumerStart {
+            unlimited_lifetime: true,
+            cursor_op_ids: true,
+            see_own_writes: see_own_writes,
+            ..consumer_start(op_id, "/foo")
+        })
+    };
+
+    let (own_sender, own_receiver) = create_client_channels();
+    let (other_sender, _other_receiver) = create_client_channels();
+    let own_handler = ConnectionHandler::new(1, own_sender, engine.clone(), reactor.handle());
+    let other_handler = ConnectionHandler::new(2, other_sender, engine, reactor.handle());
+
+    let own_handler = reactor.run(own_handler.send(start(1, false))).expect("failed to start consuming");
+    let own_handler = reactor.run(own_handler.send(produce(2, "own"))).expect("failed to produce");
+    let _other_handler = reactor.run(other_handler.send(produce(3, "other"))).expect("failed to produce");
+    let _own_handler = reactor.run(own_handler.send(start(4, true))).expect("failed to start consuming");
+
+    let mut own_receiver = own_receiver;
+    let mut received = HashMap::new();
+    let mut awaiting = 0;
+    while awaiting < 2 || received.get(&1).map(|ids: &Vec<FloEventId>| ids.is_empty()).unwrap_or(true) {
+        let (message, next) = run_future(&mut reactor, own_receiver.into_future());
+        own_receiver = next;
+        match message {
+            Some(ProtocolMessage::CursorCreated(_)) | Some(ProtocolMessage::AckEvent(_)) => {}
+            Some(ProtocolMessage::CursorMessage(op_id, message)) => match *message {
+                ProtocolMessage::ReceiveEvent(event) => received.entry(op_id).or_insert_with(Vec::new).push(*event.id()),
+                ProtocolMessage::AwaitingEvents => awaiting += 1,
+                other @ _ => panic!("unexpected cursor message: {:?}", other),
+            },
+            other @ _ => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    // the event produced on this connection is only received by the consumer that sees its own writes
+    assert_eq!(Some(&vec![FloEventId::new(1, 2)]), received.get(&1));
+    assert_eq!(Some(&vec![FloEventId::new(1, 1), FloEventId::new(1, 2)]), received.get(&4));
+}
+
+#[test]
+fn embedded_server_with_a_dedicated_event_loop_expires_events_without_the_callers_reactor_running() {
+    let _ = env_logger::init();
+    let tmp_dir = tempdir::TempDir::new("dedicated-event-loop").expect("failed to create temp dir");
+    let retention_duration = chrono::Duration::milliseconds(300);
+    let segment_duration = chrono::Duration::milliseconds(100);
+    let controller_options = ControllerOptions {
+        storage_dir: tmp_dir.path().to_owned(),
+        default_stream_options: EventStreamOptions {
+            event_retention: retention_duration,
+            max_segment_duration: segment_duration,
+            segment_max_size_bytes: 999999,
+            ..Default::default()
+        },
+        standalone: false,
+        dedicated_event_loop: true,
+        client_channel_capacity: DEFAULT_CLIENT_CHANNEL_CAPACITY,
+    };
+
+    // the caller's reactor is never run, so any tasks spawned on it would never make progress
+    let callers_reactor = Core::new().expect("failed to create reactor");
+    let server = run_embedded_server(controller_options, callers_reactor.remote()).expect("failed to run embedded server");
+
+    let mut client_reactor = Core::new().expect("failed to create reactor");
+    let mut connection = server.connect_client::<String>("producer".to_owned(), codec(), client_reactor.handle());
+    connection = client_reactor.run(connection.connect()).expect("failed to connect producer");
+    for _ in 0..3 {
+        let (_, conn) = run_future(&mut client_reactor, connection.produce_to(1, "/foo", None, String::new()));
+        connection = conn;
+    }
+
+    // expiration is driven by the tick timer, which has to be running on the dedicated event loop
+    thread::sleep((retention_duration + segment_duration * 3).to_std().unwrap());
+
+    let mut vv = VersionVector::new();
+    vv.set(FloEventId::new(1, 0));
+    let (maybe_event, _) = run_future(&mut client_reactor, connection.consume("/*", &vv, Some(1), false).into_future());
+    assert!(maybe_event.is_none(), "Expected all events to have expired, but got: {:?}", maybe_event);
+}
+
+#[test]
+fn pipe_produces_transformed_events_to_the_destination_and_resumes_from_where_it_left_off() {
+    fn produce(stream: &mut EventStreamRef, partition: ActorId, namespace: &str, data: &str) {
+        let event = ProduceEvent {
+            op_id: 1,
+            partition: partition,
+            namespace: namespace.to_owned(),
+            data: data.to_owned().into_bytes(),
+            ..Default::default()
+        };
+        produce_directly(stream, partition, 1, vec![event]);
+    }
+
+    fn contents(stream: &EventStreamRef) -> Vec<(String, String)> {
+        stream.events_since(&[]).unwrap().map(|event| {
+            let event = event.unwrap();
+            (event.namespace().to_owned(), String::from_utf8(event.data().to_vec()).unwrap())
+        }).collect()
+    }
+
+    let _ = env_logger::init();
+    let tmp_dir = tempdir::TempDir::new("pipe-streams").expect("failed to create temp dir");
+    let reactor = Core::new().expect("failed to create reactor");
+    let status = AtomicBoolWriter::with_value(true);
+
+    let source_options = EventStreamOptions {
+        name: system_stream_name(),
+        num_partitions: 2,
+        ..Default::default()
+    };
+    let mut source = init_new_event_stream(tmp_dir.path().join("system"), source_options, status.reader(), reactor.remote()).expect("failed to init stream");
+    let dest_options = EventStreamOptions {
+        name: "shipped".to_owned(),
+        ..Default::default()
+    };
+    let dest = init_new_event_stream(tmp_dir.path().join("shipped"), dest_options, status.reader(), reactor.remote()).expect("failed to init stream");
+
+    produce(&mut source, 1, "/orders/1", "shipped");
+    produce(&mut source, 2, "/orders/2", "pending");
+    produce(&mut source, 2, "/orders/3", "shipped");
+
+    let mut streams = HashMap::new();
+    streams.insert(system_stream_name(), source.clone());
+    streams.insert("shipped".to_owned(), dest.clone());
+    let engine = EngineRef::new(streams).with_storage_dir(tmp_dir.path().to_owned());
+
+    let transform = |event: &flo_server::engine::event_stream::partition::PersistentEvent| {
+        if event.data() == b"shipped" {
+            Some(format!("{} has shipped", event.namespace()).into_bytes())
+        } else {
+            None
+        }
+    };
+
+    let produced = engine.pipe(&system_stream_name(), "shipped", transform).expect("failed to pipe events");
+    assert_eq!(2, produced);
+    assert_eq!(vec![
+        ("/orders/1".to_owned(), "/orders/1 has shipped".to_owned()),
+        ("/orders/3".to_owned(), "/orders/3 has shipped".to_owned()),
+    ], contents(&dest));
+
+    // the position is kept as a tag on the source stream, so only the new events are piped the next time
+    let tag = format!("{}shipped", PIPE_TAG_PREFIX);
+    assert_eq!(Some(FloEventId::new(2, 3)), source.tags().get(&tag));
+    produce(&mut source, 1, "/orders/4", "shipped");
+    let produced = engine.pipe(&system_stream_name(), "shipped", transform).expect("failed to pipe events");
+    assert_eq!(1, produced);
+    assert_eq!(3, contents(&dest).len());
+    assert_eq!(("/orders/4".to_owned(), "/orders/4 has shipped".to_owned()), contents(&dest)[2]);
+
+    // pausing writes part way through stops piping before the next event, and the rest are piped once writes resume
+    produce(&mut source, 1, "/orders/5", "shipped");
+    produce(&mut source, 2, "/orders/6", "shipped");
+    let pause_after_first = |event: &flo_server::engine::event_stream::partition::PersistentEvent| {
+        engine.pause_writes();
+        transform(event)
+    };
+    assert!(engine.pipe(&system_stream_name(), "shipped", pause_after_first).is_err());
+    assert_eq!(4, contents(&dest).len());
+    assert!(engine.pipe(&system_stream_name(), "shipped", transform).is_err());
+    engine.resume_writes();
+    let produced = engine.pipe(&system_stream_name(), "shipped", transform).expect("failed to pipe events");
+    assert_eq!(1, produced);
+    assert_eq!(("/orders/6".to_owned(), "/orders/6 has shipped".to_owned()), contents(&dest)[4]);
+
+    assert!(engine.pipe("nonexistent", "shipped", transform).is_err());
+}
+